@@ -0,0 +1,63 @@
+//! Assembly of generated sources with many forward references, run with `cargo bench`
+#![feature(test)]
+extern crate test;
+
+use rvasm::arch::RiscVSpec;
+use rvasm::emit::flatbin::emit_flat_binary;
+use rvasm::parser::ast_from_str;
+use std::fmt::Write;
+use test::Bencher;
+
+fn load_rv32i() -> RiscVSpec {
+    let mut rv = RiscVSpec::new();
+    rv.load_single_cfg_file(std::path::Path::new("./cfg/rv32i.toml"))
+        .expect("Parse error");
+    rv
+}
+
+/// `count` labels each jumping to one defined after all of them, so every jump waits until
+/// the end while the labels before it get defined
+fn forward_jumps(count: usize) -> String {
+    let mut src = String::new();
+    for i in 0..count {
+        writeln!(src, "from{}: jal zero, to{}", i, i).unwrap();
+    }
+    for i in 0..count {
+        writeln!(src, "to{}: addi a0, a0, {}", i, i % 2048).unwrap();
+    }
+    src
+}
+
+/// `count` functions with their sizes given before any of them, so every size waits for the
+/// end label of its function
+fn sizes_ahead(count: usize) -> String {
+    let mut src = String::new();
+    for i in 0..count {
+        writeln!(src, ".size f{}, f{}_end - f{}", i, i, i).unwrap();
+    }
+    for i in 0..count {
+        writeln!(src, "f{}:\naddi a0, a0, 1\nf{}_end:", i, i).unwrap();
+    }
+    src
+}
+
+#[bench]
+fn bench_forward_jumps(b: &mut Bencher) {
+    let rv = load_rv32i();
+    let ast = ast_from_str(&forward_jumps(4000), &rv).unwrap();
+    b.iter(|| emit_flat_binary(&rv, &ast).unwrap());
+}
+
+#[bench]
+fn bench_forward_jumps_100k(b: &mut Bencher) {
+    let rv = load_rv32i();
+    let ast = ast_from_str(&forward_jumps(50_000), &rv).unwrap();
+    b.iter(|| emit_flat_binary(&rv, &ast).unwrap());
+}
+
+#[bench]
+fn bench_sizes_ahead_100k(b: &mut Bencher) {
+    let rv = load_rv32i();
+    let ast = ast_from_str(&sizes_ahead(50_000), &rv).unwrap();
+    b.iter(|| emit_flat_binary(&rv, &ast).unwrap());
+}
@@ -0,0 +1,58 @@
+//! Editing a large source in a session against assembling it anew, run with `cargo bench`
+#![feature(test)]
+extern crate test;
+
+use rvasm::arch::RiscVSpec;
+use rvasm::Assembler;
+use std::fmt::Write;
+use test::Bencher;
+
+fn load_rv32i() -> RiscVSpec {
+    let mut rv = RiscVSpec::new();
+    rv.load_single_cfg_file(std::path::Path::new("./cfg/rv32i.toml"))
+        .expect("Parse error");
+    rv
+}
+
+/// `count` commented loops counting down
+fn loops(count: usize) -> String {
+    let mut src = String::new();
+    for i in 0..count {
+        writeln!(src, "; loop {}", i).unwrap();
+        writeln!(src, "loop{}: addi a0, a0, -1", i).unwrap();
+        writeln!(src, "    bne a0, zero, loop{} - $", i).unwrap();
+    }
+    src
+}
+
+#[bench]
+fn bench_assemble_anew(b: &mut Bencher) {
+    let rv = load_rv32i();
+    let src = loops(10_000);
+    let assembler = Assembler::new(&rv);
+    b.iter(|| assembler.assemble_str(&src).unwrap());
+}
+
+#[bench]
+fn bench_session_edit_comment(b: &mut Bencher) {
+    let rv = load_rv32i();
+    let mut session = Assembler::new(&rv).session(&loops(10_000));
+    let at = session.source().len() / 2;
+    let at = at + session.source()[at..].find("; loop").unwrap() + 2;
+    b.iter(|| {
+        session.edit(at..at, "x");
+        session.edit(at..at + 1, "");
+    });
+}
+
+#[bench]
+fn bench_session_edit_instruction(b: &mut Bencher) {
+    let rv = load_rv32i();
+    let mut session = Assembler::new(&rv).session(&loops(10_000));
+    let at = session.source().len() / 2;
+    let at = at + session.source()[at..].find(", -1").unwrap() + 3;
+    b.iter(|| {
+        session.edit(at..at + 1, "2");
+        session.edit(at..at + 1, "1");
+    });
+}
@@ -0,0 +1,82 @@
+/* C interface of rvasm, for the shared library built with
+ *   cargo rustc --release --features capi --crate-type cdylib
+ * Keep in sync with src/capi.rs. */
+#ifndef RVASM_H
+#define RVASM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Assembled without errors */
+#define RVASM_OK 0
+/* The source has errors, which the diagnostics give */
+#define RVASM_ERROR 1
+/* The arguments are invalid or the architecture can't be loaded, the reason is the diagnostic */
+#define RVASM_INVALID 2
+
+/* What to assemble for and how, null pointers and zeroes being the defaults */
+typedef struct rvasm_options {
+    /* Architecture like "RV32IMC" or "rv64gc", from the specs built into the library
+     * (RV32I if null) */
+    const char *arch;
+    /* TOML spec with custom instructions, formats and constants to load on top, or null */
+    const char *spec;
+    /* Generate position-independent code */
+    int pic;
+    /* Relax calls and address loads into shorter sequences where they reach */
+    int relax;
+    /* Warn about arithmetic instructions writing to x0 */
+    int warn_x0;
+} rvasm_options;
+
+typedef struct rvasm_symbol {
+    char *name;
+    uint64_t value;
+} rvasm_symbol;
+
+/* Source line (counting from 1) of the instruction at address */
+typedef struct rvasm_line {
+    uint64_t address;
+    size_t line;
+} rvasm_line;
+
+typedef struct rvasm_diagnostic {
+    /* 0 for an error, 1 for a warning, 2 for a note */
+    int severity;
+    char *message;
+} rvasm_diagnostic;
+
+/* Everything assembling gave, owned by the caller until rvasm_result_free. Arrays without
+ * items are null. */
+typedef struct rvasm_result {
+    /* The flat binary image, empty if assembly failed */
+    uint8_t *bytes;
+    size_t len;
+    /* Sorted by name */
+    rvasm_symbol *symbols;
+    size_t symbol_count;
+    /* Sorted by address */
+    rvasm_line *lines;
+    size_t line_count;
+    rvasm_diagnostic *diagnostics;
+    size_t diagnostic_count;
+} rvasm_result;
+
+/* Assembles the NUL-terminated source into result, giving RVASM_OK, RVASM_ERROR or
+ * RVASM_INVALID. The result is filled in also when assembly fails, with the diagnostics
+ * saying why. options may be null. */
+int rvasm_assemble(const char *source, const rvasm_options *options, rvasm_result *result);
+
+/* Frees everything in a result filled in by rvasm_assemble and empties it, so freeing it
+ * again does nothing */
+void rvasm_result_free(rvasm_result *result);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -1,5 +1,8 @@
+use crate::intern::ascii_lowercase;
+use crate::parser::Node;
 use smallvec::SmallVec;
 use std::collections::HashMap;
+#[cfg(feature = "fs")]
 use std::path::PathBuf;
 use toml;
 
@@ -81,6 +84,8 @@ impl BitRangeMap {
 pub enum FieldType {
     Register,
     Value,
+    /// A value that can also be given by the name of a control and status register
+    Csr,
 }
 
 #[derive(Clone, Debug)]
@@ -89,10 +94,86 @@ pub struct InstructionField {
     pub vtype: FieldType,
     /// Total length of the value in bits
     pub length: i32,
+    /// Whether the value is interpreted as two's complement, only meaningful for values
+    pub signed: bool,
+    /// Registers the field can hold if it's restricted, like x8-x15 for compressed forms
+    pub allowed_registers: Option<Vec<i32>>,
+    /// Names the value can also be given by, like the rounding modes of floating-point
+    /// instructions
+    pub value_names: Vec<(String, u64)>,
     pub encoding: SmallVec<[BitRangeMap; 2]>,
 }
 
 impl InstructionField {
+    /// Checks if the value can be represented in the field, respecting its signedness
+    pub fn value_fits(&self, value: u64) -> bool {
+        if self.length >= 64 {
+            true
+        } else if self.length <= 0 {
+            value == 0
+        } else if self.signed {
+            let half = 1i64 << (self.length - 1);
+            (-half..half).contains(&(value as i64))
+        } else {
+            value >> self.length == 0
+        }
+    }
+
+    /// Multiple the value has to be, when its lowest bits aren't encoded (like the always even
+    /// offsets of branches)
+    pub fn value_alignment(&self) -> u64 {
+        let lowest = self
+            .encoding
+            .iter()
+            .map(|e| e.value_first)
+            .min()
+            .unwrap_or(0);
+        1u64.checked_shl(lowest as u32).unwrap_or(1)
+    }
+
+    /// Smallest and largest value the field can hold, as a multiple of its alignment
+    pub fn value_range(&self) -> (i64, i64) {
+        let (min, max) = if self.length <= 0 {
+            (0, 0)
+        } else if self.length >= 64 {
+            (i64::MIN, i64::MAX)
+        } else if self.signed {
+            let half = 1i64 << (self.length - 1);
+            (-half, half - 1)
+        } else if self.length == 63 {
+            (0, i64::MAX)
+        } else {
+            (0, (1i64 << self.length) - 1)
+        };
+        let align = self.value_alignment() as i64;
+        (min, max - max.rem_euclid(align))
+    }
+
+    /// Whether the field can hold the register: one of its allowed registers if it's restricted,
+    /// else one whose number it can encode (so x registers and f registers don't mix)
+    pub fn register_allowed(&self, register: i32) -> bool {
+        match &self.allowed_registers {
+            Some(allowed) => allowed.contains(&register),
+            None => register >= 0 && (self.length >= 31 || register < 1 << self.length),
+        }
+    }
+
+    /// Value given by one of the names of the field
+    pub fn named_value(&self, name: &str) -> Option<u64> {
+        self.value_names
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| *v)
+    }
+
+    /// First name of the value, if it has one
+    pub fn value_name(&self, value: u64) -> Option<&str> {
+        self.value_names
+            .iter()
+            .find(|(_, v)| *v == value)
+            .map(|(n, _)| n.as_ref())
+    }
+
     fn calculate_last_encoded_bit_index(&self) -> i32 {
         self.encoding
             .iter()
@@ -134,6 +215,14 @@ pub struct InstructionDefinition {
     pub args: Vec<usize>,
     /// Indices into InstructionFormat.fields paired with assigned values
     pub fields: Vec<(usize, u64)>,
+    /// Indices into InstructionFormat.fields of args that can be omitted, paired with their default values
+    pub defaults: Vec<(usize, u64)>,
+    /// Alignment of the instruction in bits, if stricter than IALIGN
+    pub align: Option<u64>,
+    /// Extension that has to be enabled to use the instruction, like "M"
+    pub extension: Option<String>,
+    /// Code of the spec defining the instruction, like "RV32I" or "C"
+    pub spec_code: String,
 }
 
 impl InstructionDefinition {
@@ -148,13 +237,40 @@ impl InstructionDefinition {
         spec.get_instruction_format(self.format_idx).unwrap()
     }
 
+    /// Distributes `given` operands over the args, returning for each arg either None (taken from
+    /// the operands in order) or its default value. Omitted operands are filled from the last
+    /// defaulted arg backwards. Returns None if the operand count can't be matched.
+    pub fn default_arg_slots(&self, given: usize) -> Option<Vec<Option<u64>>> {
+        if given > self.args.len() {
+            return None;
+        }
+        let mut missing = self.args.len() - given;
+        let mut slots = vec![None; self.args.len()];
+        for (slot, argid) in slots.iter_mut().zip(self.args.iter()).rev() {
+            if missing == 0 {
+                break;
+            }
+            if let Some((_, v)) = self.defaults.iter().find(|(fi, _)| fi == argid) {
+                *slot = Some(*v);
+                missing -= 1;
+            }
+        }
+        if missing == 0 {
+            Some(slots)
+        } else {
+            None
+        }
+    }
+
     pub fn encode_into(
         &self,
         bytes: &mut [u8],
         spec: &RiscVSpec,
         argvals: &[u64],
-    ) -> Result<(), ()> {
-        assert_eq!(argvals.len(), self.args.len());
+    ) -> Result<(), EncodeError> {
+        if argvals.len() != self.args.len() {
+            return Err(EncodeError::ArgumentCount);
+        }
         let fmt = self.get_format(spec);
         for (fldid, fldval) in self.fields.iter() {
             let fld: &InstructionField = &fmt.fields[*fldid];
@@ -162,8 +278,18 @@ impl InstructionDefinition {
                 .iter()
                 .for_each(|e| e.encode_into(bytes, *fldval));
         }
-        for (argid, argval) in self.args.iter().zip(argvals) {
+        for (i, (argid, argval)) in self.args.iter().zip(argvals).enumerate() {
             let arg: &InstructionField = &fmt.fields[*argid];
+            if let FieldType::Value | FieldType::Csr = arg.vtype {
+                if !arg.value_fits(*argval) {
+                    let (min, max) = arg.value_range();
+                    return Err(EncodeError::OutOfRange(i, *argval as i64, min, max));
+                }
+                let align = arg.value_alignment();
+                if argval % align != 0 {
+                    return Err(EncodeError::Misaligned(i, *argval as i64, align));
+                }
+            }
             arg.encoding
                 .iter()
                 .for_each(|e| e.encode_into(bytes, *argval));
@@ -172,6 +298,345 @@ impl InstructionDefinition {
     }
 }
 
+/// A pseudo-instruction declared by a spec, expanded like a macro called with its operands
+#[derive(Clone, Debug)]
+pub struct PseudoDefinition {
+    pub name: String,
+    /// Names of the operands, in order
+    pub params: Vec<String>,
+    /// Elements the pseudo-instruction stands for, with the operands written `\param` like in
+    /// macro bodies
+    pub expansion: Vec<Node>,
+}
+
+/// The line of an expansion with the operand names in it written as macro parameters
+fn expansion_template(line: &str, params: &[String]) -> String {
+    let line = line.trim();
+    let (mnemonic, operands) = line
+        .find(char::is_whitespace)
+        .map_or((line, ""), |i| line.split_at(i));
+    let mut out = mnemonic.to_owned();
+    let mut chars = operands.chars().peekable();
+    let mut previous = ' ';
+    while let Some(c) = chars.next() {
+        let starts_name = (c.is_ascii_alphabetic() || c == '_')
+            && !(previous.is_ascii_alphanumeric() || "_.\\%$".contains(previous));
+        if !starts_name {
+            out.push(c);
+            previous = c;
+            continue;
+        }
+        let mut name = c.to_string();
+        while let Some(&n) = chars.peek() {
+            if !(n.is_ascii_alphanumeric() || n == '_') {
+                break;
+            }
+            name.push(n);
+            chars.next();
+        }
+        if params.contains(&name) {
+            out.push('\\');
+        }
+        out.push_str(&name);
+        previous = 'a';
+    }
+    out
+}
+
+/// Reason the operands of an instruction couldn't be encoded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    ArgumentCount,
+    /// Index of the operand, its value and the smallest and largest value its field allows
+    OutOfRange(usize, i64, i64, i64),
+    /// Index of the operand, its value and the multiple it has to be, like 2 for branch offsets
+    Misaligned(usize, i64, u64),
+}
+
+/// Mnemonics of standard extensions, to tell which one is missing when they aren't defined
+const STANDARD_EXTENSION_MNEMONICS: &[(&str, &[&str])] = &[
+    (
+        "RV64I",
+        &[
+            "lwu", "ld", "sd", "addiw", "slliw", "srliw", "sraiw", "addw", "subw", "sllw", "srlw",
+            "sraw",
+        ],
+    ),
+    (
+        "M",
+        &[
+            "mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu", "mulw", "divw",
+            "divuw", "remw", "remuw",
+        ],
+    ),
+    (
+        "A",
+        &[
+            "lr.w",
+            "sc.w",
+            "amoswap.w",
+            "amoadd.w",
+            "amoxor.w",
+            "amoand.w",
+            "amoor.w",
+            "amomin.w",
+            "amomax.w",
+            "amominu.w",
+            "amomaxu.w",
+            "lr.d",
+            "sc.d",
+            "amoswap.d",
+            "amoadd.d",
+            "amoxor.d",
+            "amoand.d",
+            "amoor.d",
+            "amomin.d",
+            "amomax.d",
+            "amominu.d",
+            "amomaxu.d",
+        ],
+    ),
+    (
+        "F",
+        &[
+            "flw",
+            "fsw",
+            "fadd.s",
+            "fsub.s",
+            "fmul.s",
+            "fdiv.s",
+            "fsqrt.s",
+            "fmadd.s",
+            "fmsub.s",
+            "fnmadd.s",
+            "fnmsub.s",
+            "fsgnj.s",
+            "fsgnjn.s",
+            "fsgnjx.s",
+            "fmin.s",
+            "fmax.s",
+            "fcvt.w.s",
+            "fcvt.wu.s",
+            "fcvt.s.w",
+            "fcvt.s.wu",
+            "fmv.x.w",
+            "fmv.w.x",
+            "feq.s",
+            "flt.s",
+            "fle.s",
+            "fclass.s",
+        ],
+    ),
+    (
+        "D",
+        &[
+            "fld",
+            "fsd",
+            "fadd.d",
+            "fsub.d",
+            "fmul.d",
+            "fdiv.d",
+            "fsqrt.d",
+            "fmadd.d",
+            "fmsub.d",
+            "fnmadd.d",
+            "fnmsub.d",
+            "fsgnj.d",
+            "fsgnjn.d",
+            "fsgnjx.d",
+            "fmin.d",
+            "fmax.d",
+            "fcvt.s.d",
+            "fcvt.d.s",
+            "fcvt.w.d",
+            "fcvt.wu.d",
+            "fcvt.d.w",
+            "fcvt.d.wu",
+            "feq.d",
+            "flt.d",
+            "fle.d",
+            "fclass.d",
+        ],
+    ),
+    (
+        "Zicsr",
+        &["csrrw", "csrrs", "csrrc", "csrrwi", "csrrsi", "csrrci"],
+    ),
+    ("Zifencei", &["fence.i"]),
+    (
+        "Zba",
+        &[
+            "sh1add",
+            "sh2add",
+            "sh3add",
+            "add.uw",
+            "sh1add.uw",
+            "sh2add.uw",
+            "sh3add.uw",
+            "slli.uw",
+        ],
+    ),
+    (
+        "Zbb",
+        &[
+            "andn", "orn", "xnor", "clz", "ctz", "cpop", "clzw", "ctzw", "cpopw", "max", "maxu",
+            "min", "minu", "sext.b", "sext.h", "zext.h", "rol", "ror", "rori", "rolw", "rorw",
+            "roriw", "orc.b", "rev8",
+        ],
+    ),
+    ("Zbc", &["clmul", "clmulh", "clmulr"]),
+    (
+        "Zbs",
+        &[
+            "bclr", "bclri", "bext", "bexti", "binv", "binvi", "bset", "bseti",
+        ],
+    ),
+];
+
+/// Standard extension defining the instruction, for the ones not in the base ISA
+pub fn standard_extension_of(mnemonic: &str) -> Option<&'static str> {
+    let mnemonic = mnemonic.to_ascii_lowercase();
+    let mnemonic = ORDERING_SUFFIXES
+        .iter()
+        .find_map(|(suffix, _, _)| mnemonic.strip_suffix(suffix))
+        .unwrap_or(&mnemonic);
+    // too many to list, and no other extension's mnemonics start with a v
+    if mnemonic.starts_with('v') && mnemonic.contains('.') {
+        return Some("V");
+    }
+    STANDARD_EXTENSION_MNEMONICS
+        .iter()
+        .find(|(_, mnemonics)| mnemonics.contains(&mnemonic))
+        .map(|(ext, _)| *ext)
+}
+
+/// Extensions G stands for next to the base integer ISA
+const GENERAL_EXTENSIONS: &[&str] = &["M", "A", "F", "D", "Zicsr", "Zifencei"];
+
+/// The standard specs by the names of their files, for load_arch_builtin
+const BUILTIN_SPECS: &[(&str, &str)] = &[
+    ("rv32i", include_str!("../cfg/rv32i.toml")),
+    ("rv64i", include_str!("../cfg/rv64i.toml")),
+    ("m", include_str!("../cfg/m.toml")),
+    ("a", include_str!("../cfg/a.toml")),
+    ("f", include_str!("../cfg/f.toml")),
+    ("d", include_str!("../cfg/d.toml")),
+    ("c", include_str!("../cfg/c.toml")),
+    ("v", include_str!("../cfg/v.toml")),
+    ("zicsr", include_str!("../cfg/zicsr.toml")),
+    ("zifencei", include_str!("../cfg/zifencei.toml")),
+    ("zba", include_str!("../cfg/zba.toml")),
+    ("zbb", include_str!("../cfg/zbb.toml")),
+    ("zbc", include_str!("../cfg/zbc.toml")),
+    ("zbs", include_str!("../cfg/zbs.toml")),
+];
+
+/// Base ISA and extensions to load for an arch spec, like RV32I, M, F and Zicsr for
+/// RV32IMFZicsr. Also takes standard ISA strings like `rv32imac_zicsr` (in any case, with the
+/// multi-letter extensions after underscores), where version numbers like the `2p1` of
+/// `rv32i2p1` are ignored and G stands for IMAFD with Zicsr and Zifencei.
+pub fn arch_components(arch_spec: &str) -> Result<Vec<String>, LoadError> {
+    use regex::Regex;
+
+    let has_upper = arch_spec.chars().any(|c| c.is_ascii_uppercase());
+    let has_lower = arch_spec.chars().any(|c| c.is_ascii_lowercase());
+    if has_upper && has_lower && !arch_spec.contains('_') {
+        let re = Regex::new(r"(RV[0-9]+[A-Za-z])((?:[A-Z][a-z]*)*)").unwrap();
+        let cap = re.captures(arch_spec).ok_or(LoadError::InvalidArchSpec)?;
+        // the base, then every extension after it
+        let extension_re = Regex::new(r"[A-Z][a-z]*").unwrap();
+        return Ok(std::iter::once(cap.get(1).unwrap())
+            .chain(extension_re.find_iter(cap.get(2).map_or("", |m| m.as_str())))
+            .map(|m| m.as_str().to_owned())
+            .collect());
+    }
+
+    let lower = arch_spec.to_ascii_lowercase();
+    let version = r"(?:[0-9]+(?:p[0-9]+)?)?";
+    let base_re = Regex::new(&format!(r"^rv([0-9]+)([ieg]){}", version)).unwrap();
+    let cap = base_re.captures(&lower).ok_or(LoadError::InvalidArchSpec)?;
+    let xlen = cap.get(1).unwrap().as_str();
+    let mut out = Vec::new();
+    let mut push = |code: String| {
+        if !out.contains(&code) {
+            out.push(code);
+        }
+    };
+    match cap.get(2).unwrap().as_str() {
+        "g" => {
+            push(format!("RV{}I", xlen));
+            GENERAL_EXTENSIONS.iter().for_each(|e| push(e.to_string()));
+        }
+        base => push(format!("RV{}{}", xlen, base.to_ascii_uppercase())),
+    }
+    // S, X and Z start the multi-letter extensions
+    let letters_re = Regex::new(&format!(r"^(?:[a-rt-wy]{})*$", version)).unwrap();
+    let letter_re = Regex::new(&format!(r"([a-z]){}", version)).unwrap();
+    let multi_re = Regex::new(r"^([zsx][a-z0-9]+?)(?:[0-9]+p[0-9]+)?$").unwrap();
+    // the letters after the base (with its version), then the underscore separated extensions
+    let rest = &lower[cap.get(0).unwrap().end()..];
+    for (i, part) in rest.split('_').enumerate() {
+        if letters_re.is_match(part) && !(i > 0 && part.is_empty()) {
+            for letter in letter_re.captures_iter(part) {
+                match &letter[1] {
+                    "g" => GENERAL_EXTENSIONS.iter().for_each(|e| push(e.to_string())),
+                    l => push(l.to_ascii_uppercase()),
+                }
+            }
+        } else if let Some(multi) = multi_re.captures(part) {
+            let name = &multi[1];
+            push(name[..1].to_ascii_uppercase() + &name[1..]);
+        } else {
+            return Err(LoadError::InvalidArchSpec);
+        }
+    }
+    Ok(out)
+}
+
+/// Memory ordering suffixes of atomic mnemonics, with the values of their aq and rl bits
+const ORDERING_SUFFIXES: &[(&str, u64, u64)] = &[(".aq", 1, 0), (".rl", 0, 1), (".aqrl", 1, 1)];
+
+/// Instructions with the ordering suffixes, like amoadd.w.aq, for an instruction whose format has
+/// aq and rl fields it leaves unassigned. The base mnemonic encodes them as zeroes.
+fn ordering_variants(
+    fmt: &InstructionFormat,
+    insn: &InstructionDefinition,
+) -> Vec<InstructionDefinition> {
+    let free = |name: &str| {
+        let fi = fmt.fields.iter().position(|f| f.name == name)?;
+        let used = insn.args.contains(&fi) || insn.fields.iter().any(|(f, _)| *f == fi);
+        (!used).then_some(fi)
+    };
+    match (free("aq"), free("rl")) {
+        (Some(aq), Some(rl)) => ORDERING_SUFFIXES
+            .iter()
+            .map(|(suffix, aq_bit, rl_bit)| {
+                let mut variant = insn.clone();
+                variant.name = format!("{}{}", insn.name, suffix);
+                variant.fields.extend([(aq, *aq_bit), (rl, *rl_bit)]);
+                variant
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Whether the spec with the code provides the extension, by being it or (for one letter) by
+/// having it among the letters of a base ISA code, so "I" is provided by both RV32I and RV64I
+fn code_provides(code: &str, extension: &str) -> bool {
+    if code.eq_ignore_ascii_case(extension) {
+        return true;
+    }
+    let base_letters = code
+        .strip_prefix("RV")
+        .map(|rest| rest.trim_start_matches(|c: char| c.is_ascii_digit()))
+        .unwrap_or("");
+    extension.len() == 1
+        && base_letters
+            .to_ascii_uppercase()
+            .contains(&extension.to_ascii_uppercase())
+}
+
 #[derive(Debug, Default)]
 pub struct RiscVSpec {
     // Meta
@@ -180,6 +645,8 @@ pub struct RiscVSpec {
     loaded_specs: Vec<String>,
     // Consts
     consts: HashMap<String, u64>,
+    // Control and status registers
+    csrs: HashMap<String, u64>,
     // Registers
     registers: HashMap<i32, Register>,
     register_name_lookup: HashMap<String, i32>,
@@ -188,6 +655,8 @@ pub struct RiscVSpec {
     // Instructions
     instructions: Vec<InstructionDefinition>,
     instruction_name_lookup: HashMap<String, usize>,
+    // Pseudo-instructions
+    pseudo_instructions: HashMap<String, PseudoDefinition>,
 }
 
 pub struct AbiFileInfo<'a> {
@@ -213,12 +682,37 @@ impl RiscVSpec {
         v
     }
 
+    /// Whether a loaded spec provides the extension, either as its code (like "Zicsr") or as one
+    /// of the letters of a base ISA code (like the M of "RV32IM")
+    pub fn extension_enabled(&self, extension: &str) -> bool {
+        self.loaded_codes
+            .iter()
+            .any(|code| code_provides(code, extension))
+    }
+
     // Consts
 
     pub fn get_const(&self, name: &str) -> Option<u64> {
         self.consts.get(name).copied()
     }
 
+    // CSRs
+
+    /// Address of the control and status register, names are case sensitive like registers
+    pub fn get_csr(&self, name: &str) -> Option<u64> {
+        self.csrs.get(name).copied()
+    }
+
+    /// Name of the control and status register at the address, the first in alphabetical order
+    /// if there are several
+    pub fn get_csr_name(&self, address: u64) -> Option<&str> {
+        self.csrs
+            .iter()
+            .filter(|(_, a)| **a == address)
+            .map(|(name, _)| name.as_str())
+            .min()
+    }
+
     // Registers
 
     pub fn get_register(&self, rnum: i32) -> Option<&Register> {
@@ -260,10 +754,18 @@ impl RiscVSpec {
     /// Automatically converts name to lowercase
     pub fn get_instruction_by_name(&self, name: &str) -> Option<&InstructionDefinition> {
         self.instruction_name_lookup
-            .get(&name.to_ascii_lowercase())
+            .get(ascii_lowercase(name).as_ref())
             .and_then(|i| self.get_instruction(*i))
     }
 
+    pub fn get_pseudo_instruction(&self, name: &str) -> Option<&PseudoDefinition> {
+        self.pseudo_instructions.get(ascii_lowercase(name).as_ref())
+    }
+
+    pub fn get_all_pseudo_instructions(&self) -> impl Iterator<Item = &PseudoDefinition> {
+        self.pseudo_instructions.values()
+    }
+
     pub fn get_all_instructions(&self) -> &[InstructionDefinition] {
         &self.instructions
     }
@@ -272,6 +774,8 @@ impl RiscVSpec {
 #[derive(Clone, Debug)]
 pub enum LoadError {
     MalformedTOML,
+    /// The line that isn't valid YAML
+    MalformedYAML(usize),
     InvalidArchSpec,
     DependencyCycle,
     RequirementNotFound(String),
@@ -279,9 +783,40 @@ pub enum LoadError {
     MissingNode(String),
     BadType(String),
     DuplicateInstruction(String),
+    DuplicateInstructionFormat(String),
     BadInstructionFormat(String),
+    /// Key of a field (or of an instruction's value for one) and what's wrong with its bits
+    BadFieldLayout(String, String),
+    /// Key of a pseudo-instruction's definition and what's wrong with it
+    BadPseudoInstruction(String, String),
 }
 
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use LoadError::*;
+        match self {
+            MalformedTOML => write!(f, "the configuration isn't valid TOML"),
+            MalformedYAML(line) => write!(f, "the configuration isn't valid YAML (line {})", line),
+            InvalidArchSpec => write!(f, "the architecture isn't a valid RISC-V ISA string"),
+            DependencyCycle => write!(f, "the configurations require each other in a cycle"),
+            RequirementNotFound(code) => write!(f, "no configuration provides '{}'", code),
+            ConstNotFound(name) => write!(f, "constant '{}' is never defined", name),
+            MissingNode(key) => write!(f, "'{}' is missing", key),
+            BadType(key) => write!(f, "'{}' has the wrong type", key),
+            DuplicateInstruction(name) => write!(f, "instruction '{}' is defined twice", name),
+            DuplicateInstructionFormat(name) => {
+                write!(f, "instruction format '{}' is defined twice", name)
+            }
+            BadInstructionFormat(name) => write!(f, "instruction format '{}' is invalid", name),
+            BadFieldLayout(key, problem) | BadPseudoInstruction(key, problem) => {
+                write!(f, "'{}' {}", key, problem)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
 // Creation & Parsing
 impl RiscVSpec {
     pub fn new() -> Self {
@@ -289,53 +824,101 @@ impl RiscVSpec {
     }
 
     pub fn load_single_cfg_string(&mut self, content: &str) -> Result<(), LoadError> {
-        let val = Self::string_to_toml(content)?;
-        self.load_single_toml(&val)
+        self.load_extension_string(content, false)
     }
 
+    #[cfg(feature = "fs")]
     pub fn load_single_cfg_file(&mut self, path: &std::path::Path) -> Result<(), LoadError> {
+        self.load_extension_file(path, false)
+    }
+
+    /// Merges a custom extension into the loaded specs. Instructions and instruction formats
+    /// with the name of loaded ones are errors, unless `overrides` lets them replace those
+    /// (instructions loaded before keep the format they were defined with).
+    pub fn load_extension_string(
+        &mut self,
+        content: &str,
+        overrides: bool,
+    ) -> Result<(), LoadError> {
+        let val = Self::string_to_toml(content)?;
+        self.load_single_toml(&val, overrides)
+    }
+
+    /// Same as load_extension_string for the YAML form of a spec, with the same keys
+    pub fn load_extension_yaml_string(
+        &mut self,
+        content: &str,
+        overrides: bool,
+    ) -> Result<(), LoadError> {
+        let val = crate::yaml::parse(content).map_err(LoadError::MalformedYAML)?;
+        self.load_single_toml(&val, overrides)
+    }
+
+    /// Loads a TOML spec, or a YAML one if the extension is `.yaml` or `.yml`
+    #[cfg(feature = "fs")]
+    pub fn load_extension_file(
+        &mut self,
+        path: &std::path::Path,
+        overrides: bool,
+    ) -> Result<(), LoadError> {
         let content = std::fs::read_to_string(path).map_err(|_| LoadError::InvalidArchSpec)?;
-        self.load_single_cfg_string(&content)
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => self.load_extension_yaml_string(&content, overrides),
+            _ => self.load_extension_string(&content, overrides),
+        }
     }
 
+    /// Loads the specs of the architecture and the extensions it names from the first of the
+    /// directories having each
+    #[cfg(feature = "fs")]
     pub fn load_arch_cfg(
         &mut self,
         std_paths: &[PathBuf],
         arch_spec: &str,
         verbose: bool,
     ) -> Result<(), LoadError> {
-        use petgraph::prelude::*;
-        use regex::Regex;
-
-        let mut docs = Vec::new();
-        let re = Regex::new(r"(RV[0-9]+[A-Za-z])([A-Z][a-z]*)*").unwrap();
-        let cap = re.captures(arch_spec);
-        if cap.is_none() {
-            return Err(LoadError::InvalidArchSpec);
-        }
-        let cap = cap.unwrap();
-        for fp in cap.iter().skip(1) {
-            if fp.is_none() {
-                continue;
-            }
-            let fp = fp.unwrap();
-            let mut loaded = false;
+        self.load_arch_with(arch_spec, |component| {
             for path in std_paths.iter() {
                 let mut p: PathBuf = path.clone();
-                p.push(&fp.as_str().to_ascii_lowercase());
+                p.push(component.to_ascii_lowercase());
                 p.set_extension("toml");
                 if let Ok(strdata) = std::fs::read_to_string(&p) {
-                    docs.push(Self::string_to_toml(&strdata)?);
                     if verbose {
                         let pstr = p.as_os_str().to_string_lossy();
-                        eprintln!("Found {} spec in {}", fp.as_str(), pstr);
+                        eprintln!("Found {} spec in {}", component, pstr);
                     }
-                    loaded = true;
-                    break;
+                    return Some(strdata);
                 }
             }
-            if !loaded {
-                return Err(LoadError::RequirementNotFound(fp.as_str().to_owned()));
+            None
+        })
+    }
+
+    /// Same as load_arch_cfg with the specs of the `cfg` directory built into the library, for
+    /// loading them without a filesystem
+    pub fn load_arch_builtin(&mut self, arch_spec: &str) -> Result<(), LoadError> {
+        self.load_arch_with(arch_spec, |component| {
+            let name = component.to_ascii_lowercase();
+            BUILTIN_SPECS
+                .iter()
+                .find(|(file, _)| *file == name)
+                .map(|(_, content)| (*content).to_owned())
+        })
+    }
+
+    /// Loads the specs of the components of the architecture, as `find` gives their text, in
+    /// the order of their requirements
+    fn load_arch_with<F>(&mut self, arch_spec: &str, mut find: F) -> Result<(), LoadError>
+    where
+        F: FnMut(&str) -> Option<String>,
+    {
+        use petgraph::prelude::*;
+
+        let mut docs = Vec::new();
+        for fp in arch_components(arch_spec)? {
+            match find(&fp) {
+                Some(content) => docs.push(Self::string_to_toml(&content)?),
+                None => return Err(LoadError::RequirementNotFound(fp)),
             }
         }
 
@@ -370,12 +953,12 @@ impl RiscVSpec {
                     let code = rq
                         .as_str()
                         .ok_or_else(|| LoadError::BadType("meta.requires item".to_owned()))?;
-                    let other = codes.get(code);
+                    let other = codes.iter().find(|(c, _)| code_provides(c, code));
                     if other.is_none() {
                         return Err(LoadError::RequirementNotFound(code.to_owned()));
                     }
                     let nidx = nodes[i];
-                    let oidx = *other.unwrap();
+                    let oidx = *other.unwrap().1;
                     depgraph.add_edge(nidx, oidx, ());
                 }
             }
@@ -388,7 +971,7 @@ impl RiscVSpec {
         }
         let res = res.unwrap();
         for node in res.iter().rev() {
-            self.load_single_toml(&docs[depgraph[*node]])?;
+            self.load_single_toml(&docs[depgraph[*node]], false)?;
         }
 
         Ok(())
@@ -419,7 +1002,27 @@ impl RiscVSpec {
         }
     }
 
-    fn load_single_toml(&mut self, doc: &toml::Value) -> Result<(), LoadError> {
+    /// Checks a value an instruction gives a field fits in it, as an unsigned number or (for
+    /// signed fields) a two's complement one
+    fn check_fixed_value(
+        field: &InstructionField,
+        key: String,
+        value: i64,
+    ) -> Result<(), LoadError> {
+        let fits = field.length >= 64
+            || (value >= 0 && value >> field.length == 0)
+            || (field.signed && field.value_fits(value as u64));
+        if fits {
+            Ok(())
+        } else {
+            Err(LoadError::BadFieldLayout(
+                key,
+                format!("value {} doesn't fit in {} bits", value, field.length),
+            ))
+        }
+    }
+
+    fn load_single_toml(&mut self, doc: &toml::Value, overrides: bool) -> Result<(), LoadError> {
         #[allow(non_snake_case)]
         let MissingNode = |s: &'static str| LoadError::MissingNode(s.to_owned());
         #[allow(non_snake_case)]
@@ -427,6 +1030,7 @@ impl RiscVSpec {
 
         let meta = doc.get("meta").ok_or_else(|| MissingNode("meta"))?;
         let consts = doc.get("consts");
+        let csrs = doc.get("csrs");
         let registers = doc.get("registers");
         let instruction_formats = doc.get("instruction_formats");
         let instructions = doc.get("instructions");
@@ -461,7 +1065,7 @@ impl RiscVSpec {
                 .ok_or_else(|| BadType("meta.requires"))?;
             for rq in list.iter() {
                 let code = rq.as_str().ok_or_else(|| BadType("meta.requires item"))?;
-                if !self.loaded_codes.iter().any(|s| s == code) {
+                if !self.loaded_codes.iter().any(|s| code_provides(s, code)) {
                     return Err(LoadError::RequirementNotFound(code.to_owned()));
                 }
             }
@@ -476,6 +1080,15 @@ impl RiscVSpec {
             }
         }
 
+        // parse csrs
+        if let Some(csrs) = csrs {
+            let csrs = csrs.as_table().ok_or_else(|| BadType("csrs"))?;
+            for (k, v) in csrs.iter() {
+                let address = Self::toml_int(&self.consts, format!("csrs.{}", k), v)? as u64;
+                self.csrs.insert(k.to_owned(), address);
+            }
+        }
+
         // parse registers
         if let Some(registers) = registers {
             let registers = registers.as_table().ok_or_else(|| BadType("registers"))?;
@@ -522,6 +1135,32 @@ impl RiscVSpec {
                     self.registers.get_mut(&number).unwrap().size_in_bits = length;
                 }
             }
+            // more names for registers defined before, by number or by one of their names
+            if let Some(register_aliases) = registers.get("aliases") {
+                let register_aliases = register_aliases
+                    .as_table()
+                    .ok_or_else(|| BadType("registers.aliases"))?;
+                for (alias, register) in register_aliases.iter() {
+                    let number = match register.as_str() {
+                        Some(rname) => self
+                            .registers
+                            .values()
+                            .find(|r| r.names.iter().any(|n| n == rname))
+                            .map(|r| r.index),
+                        None => register
+                            .as_integer()
+                            .map(|i| i as i32)
+                            .filter(|i| self.registers.contains_key(i)),
+                    };
+                    let number = number.ok_or_else(|| {
+                        LoadError::BadType(format!("registers.aliases.{}", alias))
+                    })?;
+                    let names = &mut self.registers.get_mut(&number).unwrap().names;
+                    if !names.contains(alias) {
+                        names.push(alias.to_owned());
+                    }
+                }
+            }
         }
 
         // parse instruction_formats
@@ -542,6 +1181,9 @@ impl RiscVSpec {
                         name: fldname.to_owned(),
                         vtype: FieldType::Value,
                         length: 0,
+                        signed: false,
+                        allowed_registers: None,
+                        value_names: Vec::new(),
                         encoding: Default::default(),
                     };
                     let fldtype = fldtable
@@ -566,6 +1208,9 @@ impl RiscVSpec {
                         "register" => {
                             fld.vtype = FieldType::Register;
                         }
+                        "csr" => {
+                            fld.vtype = FieldType::Csr;
+                        }
                         _ => {
                             return Err(LoadError::BadType(format!(
                                 "instruction_formats.{}.{}.type",
@@ -583,6 +1228,44 @@ impl RiscVSpec {
                             ))
                         })?,
                     )? as i32;
+                    if let Some(signed) = fldtable.get("signed") {
+                        fld.signed = signed.as_bool().ok_or_else(|| {
+                            LoadError::BadType(format!(
+                                "instruction_formats.{}.{}.signed",
+                                fmtname, fldname
+                            ))
+                        })?;
+                    }
+                    if let Some(allowed) = fldtable.get("registers") {
+                        let key = format!("instruction_formats.{}.{}.registers", fmtname, fldname);
+                        let allowed = allowed
+                            .as_array()
+                            .ok_or_else(|| LoadError::BadType(key.clone()))?;
+                        let mut registers = Vec::new();
+                        for reg in allowed.iter() {
+                            // registers can be given by any of their names
+                            let reg = match reg.as_str() {
+                                Some(rname) => self
+                                    .registers
+                                    .values()
+                                    .find(|r| r.names.iter().any(|n| n == rname))
+                                    .map(|r| r.index),
+                                None => reg.as_integer().map(|i| i as i32),
+                            };
+                            registers.push(reg.ok_or_else(|| LoadError::BadType(key.clone()))?);
+                        }
+                        fld.allowed_registers = Some(registers);
+                    }
+                    if let Some(names) = fldtable.get("names") {
+                        let key = format!("instruction_formats.{}.{}.names", fmtname, fldname);
+                        let names = names
+                            .as_table()
+                            .ok_or_else(|| LoadError::BadType(key.clone()))?;
+                        for (name, value) in names.iter() {
+                            let value = Self::toml_int(&self.consts, key.clone(), value)?;
+                            fld.value_names.push((name.to_owned(), value as u64));
+                        }
+                    }
                     let fldencoding = fldtable
                         .get("encoding")
                         .ok_or_else(|| {
@@ -615,23 +1298,80 @@ impl RiscVSpec {
                             &self.consts,
                             format!("instruction_formats.{}.{}.encoding[][]", fmtname, fldname),
                             &subenc[0],
-                        )? as i32;
+                        )?;
                         let vbegin = Self::toml_int(
                             &self.consts,
                             format!("instruction_formats.{}.{}.encoding[][]", fmtname, fldname),
                             &subenc[1],
-                        )? as i32;
+                        )?;
                         let ibegin = Self::toml_int(
                             &self.consts,
                             format!("instruction_formats.{}.{}.encoding[][]", fmtname, fldname),
                             &subenc[2],
-                        )? as i32;
+                        )?;
+                        if vbegin < 0 || ibegin < 0 || vend < vbegin || vend >= 64 {
+                            return Err(LoadError::BadType(format!(
+                                "instruction_formats.{}.{}.encoding[][] range",
+                                fmtname, fldname
+                            )));
+                        }
+
+                        if fld.length < 64 && vend >= fld.length as i64 {
+                            return Err(LoadError::BadFieldLayout(
+                                format!("instruction_formats.{}.{}", fmtname, fldname),
+                                format!("encodes bit {} of a {}-bit value", vend, fld.length),
+                            ));
+                        }
+                        // no instruction is longer than ILEN, the same default as emitting
+                        let ilen = self.consts.get("ILEN").map_or(32, |l| *l as i64);
+                        let ilast = ibegin.saturating_add(vend - vbegin);
+                        if ilast >= ilen {
+                            return Err(LoadError::BadFieldLayout(
+                                format!("instruction_formats.{}.{}", fmtname, fldname),
+                                format!("encodes bit {} of a {}-bit instruction", ilast, ilen),
+                            ));
+                        }
 
-                        fld.encoding.push(BitRangeMap::new(vend, vbegin, ibegin));
+                        fld.encoding.push(BitRangeMap::new(
+                            vend as i32,
+                            vbegin as i32,
+                            ibegin as i32,
+                        ));
                     }
                     fmt.fields.push(fld);
                 }
+                // every bit of the instruction comes from one field
+                let mut encoded_by: Vec<Option<&str>> = Vec::new();
+                for fld in fmt.fields.iter() {
+                    for enc in fld.encoding.iter() {
+                        for bit in enc.instruction_first..=enc.instruction_last() {
+                            let bit = bit as usize;
+                            if encoded_by.len() <= bit {
+                                encoded_by.resize(bit + 1, None);
+                            }
+                            if let Some(other) = encoded_by[bit].replace(&fld.name) {
+                                return Err(LoadError::BadFieldLayout(
+                                    format!("instruction_formats.{}.{}", fmtname, fld.name),
+                                    format!(
+                                        "overlaps field '{}' at instruction bit {}",
+                                        other, bit
+                                    ),
+                                ));
+                            }
+                        }
+                    }
+                }
+                // a format without any encoded bits would emit zero-length instructions
+                if fmt.fields.iter().all(|f| f.encoding.is_empty()) {
+                    return Err(LoadError::BadInstructionFormat(format!(
+                        "instruction_formats.{}",
+                        fmtname
+                    )));
+                }
                 fmt.ilen = fmt.calculate_last_encoded_bit_index() as usize + 1;
+                if !overrides && self.instruction_formats.iter().any(|f| f.name == fmt.name) {
+                    return Err(LoadError::DuplicateInstructionFormat(fmt.name));
+                }
                 self.instruction_formats.push(fmt);
             }
         }
@@ -669,12 +1409,15 @@ impl RiscVSpec {
                     .as_table()
                     .ok_or_else(|| LoadError::BadType(format!("instructions.{}.fields", iname)))?;
 
+                let idefaults = itable.get("defaults");
+
                 let mut insn = InstructionDefinition::new(iname.clone());
+                insn.spec_code = self.loaded_codes.last().cloned().unwrap_or_default();
 
                 insn.format_idx = self
                     .instruction_formats
                     .iter()
-                    .position(|x| x.name == iformat)
+                    .rposition(|x| x.name == iformat)
                     .ok_or_else(|| {
                         LoadError::BadInstructionFormat(format!("instructions.{}.format", iname))
                     })?;
@@ -714,17 +1457,102 @@ impl RiscVSpec {
                                 iname, fname
                             ))
                         })?;
+                    Self::check_fixed_value(
+                        &fmt.fields[fi],
+                        format!("instructions.{}.fields[{}]", iname, fname),
+                        fv,
+                    )?;
                     insn.fields.push((fi, fv as u64));
                 }
 
-                if self
-                    .instruction_name_lookup
-                    .insert(iname.clone(), self.instructions.len())
-                    .is_some()
-                {
-                    return Err(LoadError::DuplicateInstruction(iname.clone()));
+                // values that differ on 64-bit cores, like the selectors next to wider shift amounts
+                let rv64_fields = itable.get("fields_rv64");
+                if let (Some(rv64_fields), Some(64)) = (rv64_fields, self.consts.get("XLEN")) {
+                    let rv64_fields = rv64_fields.as_table().ok_or_else(|| {
+                        LoadError::BadType(format!("instructions.{}.fields_rv64", iname))
+                    })?;
+                    for (fname, fv) in rv64_fields.iter() {
+                        let key = format!("instructions.{}.fields_rv64[{}]", iname, fname);
+                        let fv = Self::toml_int(&self.consts, key.clone(), fv)?;
+                        let fi = fmt
+                            .fields
+                            .iter()
+                            .position(|x| x.name == fname.as_ref())
+                            .ok_or_else(|| LoadError::BadInstructionFormat(key.clone()))?;
+                        Self::check_fixed_value(&fmt.fields[fi], key, fv)?;
+                        insn.fields.retain(|(f, _)| *f != fi);
+                        insn.fields.push((fi, fv as u64));
+                    }
+                }
+
+                if let Some(idefaults) = idefaults {
+                    let idefaults = idefaults.as_table().ok_or_else(|| {
+                        LoadError::BadType(format!("instructions.{}.defaults", iname))
+                    })?;
+                    for (fname, dv) in idefaults.iter() {
+                        let key = format!("instructions.{}.defaults[{}]", iname, fname);
+                        let fi = insn
+                            .args
+                            .iter()
+                            .copied()
+                            .find(|fi| fmt.fields[*fi].name == fname.as_str())
+                            .ok_or_else(|| LoadError::BadInstructionFormat(key.clone()))?;
+                        let dv = match (fmt.fields[fi].vtype, dv.as_str()) {
+                            // registers can be given by any of their names
+                            (FieldType::Register, Some(rname)) => self
+                                .registers
+                                .values()
+                                .find(|r| r.names.iter().any(|n| n == rname))
+                                .map(|r| r.index as u64)
+                                .ok_or(LoadError::BadType(key))?,
+                            (FieldType::Csr, Some(cname)) if self.csrs.contains_key(cname) => {
+                                self.csrs[cname]
+                            }
+                            _ => Self::toml_int(&self.consts, key, dv)? as u64,
+                        };
+                        insn.defaults.push((fi, dv));
+                    }
+                }
+
+                if let Some(ialign) = itable.get("align") {
+                    let key = format!("instructions.{}.align", iname);
+                    let ialign = Self::toml_int(&self.consts, key.clone(), ialign)?;
+                    // must be a whole number of bytes
+                    if ialign <= 0 || ialign % 8 != 0 || !(ialign as u64 / 8).is_power_of_two() {
+                        return Err(LoadError::BadType(key));
+                    }
+                    insn.align = Some(ialign as u64);
+                }
+
+                if let Some(extension) = itable.get("extension") {
+                    insn.extension = Some(
+                        extension
+                            .as_str()
+                            .ok_or_else(|| {
+                                LoadError::BadType(format!("instructions.{}.extension", iname))
+                            })?
+                            .to_owned(),
+                    );
+                }
+
+                let variants = ordering_variants(fmt, &insn);
+                for insn in std::iter::once(insn).chain(variants) {
+                    if self.pseudo_instructions.contains_key(&insn.name) {
+                        if !overrides {
+                            return Err(LoadError::DuplicateInstruction(insn.name));
+                        }
+                        self.pseudo_instructions.remove(&insn.name);
+                    }
+                    match self.instruction_name_lookup.get(&insn.name) {
+                        Some(&idx) if overrides => self.instructions[idx] = insn,
+                        Some(_) => return Err(LoadError::DuplicateInstruction(insn.name)),
+                        None => {
+                            self.instruction_name_lookup
+                                .insert(insn.name.clone(), self.instructions.len());
+                            self.instructions.push(insn);
+                        }
+                    }
                 }
-                self.instructions.push(insn);
             }
         }
 
@@ -735,6 +1563,81 @@ impl RiscVSpec {
                 self.register_name_lookup.insert(name.to_owned(), *num);
             }
         }
+
+        // parse pseudo_instructions, once the registers they can name are known
+        if let Some(pseudo_instructions) = doc.get("pseudo_instructions") {
+            let pseudo_instructions = pseudo_instructions
+                .as_table()
+                .ok_or_else(|| BadType("pseudo_instructions"))?;
+            for (pname, ptable) in pseudo_instructions.iter() {
+                let pname = pname.to_ascii_lowercase();
+                let key = |field: &str| format!("pseudo_instructions.{}.{}", pname, field);
+                let list = |field: &str| -> Result<Vec<String>, LoadError> {
+                    let items = ptable
+                        .get(field)
+                        .ok_or_else(|| LoadError::MissingNode(key(field)))?
+                        .as_array()
+                        .ok_or_else(|| LoadError::BadType(key(field)))?;
+                    items
+                        .iter()
+                        .map(|item| item.as_str().map(str::to_owned))
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| LoadError::BadType(format!("{}[] item", key(field))))
+                };
+                let params = list("args")?;
+                for (i, param) in params.iter().enumerate() {
+                    let valid = param.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                        && param.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                    if !valid || params[..i].contains(param) {
+                        return Err(LoadError::BadPseudoInstruction(
+                            key("args"),
+                            format!("can't have '{}' as an operand name", param),
+                        ));
+                    }
+                }
+                let mut expansion = Vec::new();
+                for (i, line) in list("expansion")?.iter().enumerate() {
+                    let bad = |problem: &str| {
+                        LoadError::BadPseudoInstruction(
+                            format!("{}[{}]", key("expansion"), i),
+                            problem.to_owned(),
+                        )
+                    };
+                    let template = expansion_template(line, &params);
+                    match crate::parser::ast_from_str(&template, self) {
+                        Ok(Node::Root(elements)) if !elements.is_empty() => {
+                            // the offsets are into the template, errors go to the call instead
+                            expansion.extend(elements.into_iter().map(|e| match e {
+                                Node::Located(_, box inner) => inner,
+                                e => e,
+                            }))
+                        }
+                        Ok(_) => return Err(bad("is empty")),
+                        Err(_) => return Err(bad("doesn't parse as assembly")),
+                    }
+                }
+                if expansion.is_empty() {
+                    return Err(LoadError::BadPseudoInstruction(
+                        key("expansion"),
+                        "is empty".to_owned(),
+                    ));
+                }
+                if !overrides
+                    && (self.instruction_name_lookup.contains_key(&pname)
+                        || self.pseudo_instructions.contains_key(&pname))
+                {
+                    return Err(LoadError::DuplicateInstruction(pname));
+                }
+                self.pseudo_instructions.insert(
+                    pname.clone(),
+                    PseudoDefinition {
+                        name: pname,
+                        params,
+                        expansion,
+                    },
+                );
+            }
+        }
         Ok(())
     }
 }
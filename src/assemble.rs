@@ -0,0 +1,699 @@
+//! Single call assembly of source text into a structured result, for embedding the assembler
+//! into other programs like a web playground
+
+use crate::arch;
+use crate::arch::LoadError;
+use crate::emit::{
+    flatbin, AssembledImage, Diagnostic, EmitError, EmitOptions, EmittedInstruction,
+};
+use crate::parser::{self, parse_error_message, IncludeError, Node, ParseError};
+use crate::report::json_escape;
+use crate::session::Session;
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Address of an emitted instruction and the source line it came from (counting from 1)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct LineMapEntry {
+    pub address: u64,
+    /// Index of the source file in the paths given to assemble_files, each followed by the files
+    /// it includes, 0 for assemble
+    pub file: usize,
+    pub line: usize,
+}
+
+/// Everything produced by assembling a source, including the reason it failed
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AssembleResult {
+    /// The flat binary image, empty if assembly failed
+    pub bytes: Vec<u8>,
+    pub symbols: BTreeMap<String, u64>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Sorted by address
+    pub line_map: Vec<LineMapEntry>,
+}
+
+impl AssembleResult {
+    pub fn succeeded(&self) -> bool {
+        !self
+            .diagnostics
+            .iter()
+            .any(|d| matches!(d, Diagnostic::Error(_)))
+    }
+}
+
+/// Any error of the crate, for callers that want a single type to propagate with `?`
+#[derive(Debug)]
+pub enum AsmError {
+    /// Loading the instruction set configuration failed
+    Load(LoadError),
+    /// Reading or splicing in a file failed
+    Include(IncludeError),
+    Parse(ParseError),
+    Emit(EmitError),
+    /// assemble_instruction: the source isn't a single instruction, with the number of elements
+    /// it has
+    NotOneInstruction(usize),
+    /// assemble_instruction: a symbol that would have to be a label
+    UnknownSymbol(String),
+}
+
+impl AsmError {
+    /// Byte offset in the source of the element causing the error, if known (see
+    /// EmitError::location)
+    pub fn location(&self) -> Option<usize> {
+        match self {
+            AsmError::Emit(e) => e.location(),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AsmError::Load(e) => write!(f, "error loading the configuration: {}", e),
+            AsmError::Include(e) => e.fmt(f),
+            AsmError::Parse(e) => write!(f, "Parse error: {}", parse_error_text(e)),
+            AsmError::Emit(e) => e.fmt(f),
+            AsmError::NotOneInstruction(n) => {
+                write!(f, "expected a single instruction, got {} elements", n)
+            }
+            AsmError::UnknownSymbol(name) => {
+                write!(f, "symbol '{}' would have to be a label", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AsmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AsmError::Load(e) => Some(e),
+            AsmError::Include(e) => Some(e),
+            AsmError::Emit(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<LoadError> for AsmError {
+    fn from(e: LoadError) -> Self {
+        AsmError::Load(e)
+    }
+}
+
+impl From<IncludeError> for AsmError {
+    fn from(e: IncludeError) -> Self {
+        AsmError::Include(e)
+    }
+}
+
+impl From<EmitError> for AsmError {
+    fn from(e: EmitError) -> Self {
+        AsmError::Emit(e)
+    }
+}
+
+/// Assembles `source` holding exactly one instruction (no labels or directives other than
+/// .insn) at address 0 and returns its bytes, all of them for pseudo-instructions.
+/// Symbols other than the spec's constants are errors, nothing gets deferred.
+pub fn assemble_instruction(spec: &arch::RiscVSpec, source: &str) -> Result<Vec<u8>, AsmError> {
+    let ast = parser::ast_from_str(source, spec).map_err(AsmError::Parse)?;
+    let elements = match &ast {
+        Node::Root(nodes) => nodes.len(),
+        _ => 1,
+    };
+    let insn = match &ast {
+        Node::Root(nodes) if elements == 1 => match &nodes[0] {
+            Node::Located(_, inner) => inner.as_ref(),
+            node => node,
+        },
+        _ => return Err(AsmError::NotOneInstruction(elements)),
+    };
+    match insn {
+        Node::Instruction(iname, _)
+            if !iname.starts_with('.') || iname.eq_ignore_ascii_case(".insn") =>
+        {
+            if let Some(symbol) = insn
+                .referenced_symbols()
+                .into_iter()
+                .find(|sym| spec.get_const(sym).is_none())
+            {
+                return Err(AsmError::UnknownSymbol(symbol.to_string()));
+            }
+        }
+        _ => return Err(AsmError::NotOneInstruction(elements)),
+    }
+    // the location is always the start of the source
+    flatbin::emit_image(spec, &ast, &EmitOptions::default())
+        .map(|image| image.bytes)
+        .map_err(|e| AsmError::Emit(e.into_kind()))
+}
+
+/// Line and column (both counting from 1, the column in characters) of a byte offset
+pub fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(source.len());
+    let before = source.get(..offset).unwrap_or(source);
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line = before.matches('\n').count() + 1;
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// The `(name, source)` pair holding the offset into the concatenation of the sources, and the
+/// offset within that source
+fn locate<'a>(files: &[(&'a str, &'a str)], offset: usize) -> Option<(&'a str, &'a str, usize)> {
+    let mut base = 0;
+    for (i, (name, source)) in files.iter().enumerate() {
+        if offset < base + source.len() || i + 1 == files.len() {
+            return Some((name, source, offset.saturating_sub(base)));
+        }
+        base += source.len();
+    }
+    None
+}
+
+/// Formats an emission error like `file:line:col: error: message`, finding the file among the
+/// `(name, source)` pairs by the offset into their concatenation. Names can be empty.
+pub fn format_emit_error(files: &[(&str, &str)], error: &EmitError) -> String {
+    match error.location().and_then(|offset| locate(files, offset)) {
+        Some((name, source, offset)) => {
+            let (line, column) = line_column(source, offset);
+            let prefix = if name.is_empty() {
+                String::new()
+            } else {
+                format!("{}:", name)
+            };
+            format!("{}{}:{}: error: {}", prefix, line, column, error)
+        }
+        None => format!("error: {}", error),
+    }
+}
+
+/// Like format_emit_error, followed by the source line of the error with carets under the
+/// element causing it (up to the end of its line, without a trailing comment):
+///
+/// ```text
+/// main.s:3:5: error: operand 3 of 'addi' is 5000, out of range [-2048, 2047]
+///   |
+/// 3 |     addi a0, a0, 5000
+///   |     ^^^^^^^^^^^^^^^^^
+/// ```
+pub fn render_emit_error(files: &[(&str, &str)], error: &EmitError) -> String {
+    let message = format_emit_error(files, error);
+    match error.location().and_then(|offset| locate(files, offset)) {
+        Some((_, source, offset)) => {
+            let offset = offset.min(source.len());
+            snippet(&message, source, offset, element_length(source, offset))
+        }
+        None => message,
+    }
+}
+
+/// Like the Display of the error, but the parse errors are each followed by their source line
+/// with carets under the token the parser stopped at, like render_emit_error does. The text of
+/// the file is found by its name among `sources`, as resolve_includes fills them in.
+pub fn render_include_error(sources: &[(String, String)], error: &IncludeError) -> String {
+    let (name, errors) = match error {
+        IncludeError::Parse(name, errors) => (name, errors),
+        e => return e.to_string(),
+    };
+    let source = sources
+        .iter()
+        .rev()
+        .find(|(n, _)| n == name)
+        .map_or("", |(_, source)| source.as_str());
+    let prefix = match name.is_empty() {
+        true => String::new(),
+        false => format!("{}:", name),
+    };
+    let rendered: Vec<String> = errors
+        .iter()
+        .map(|e| {
+            let message = format!(
+                "{}{}:{}: error: Parse error: {}",
+                prefix,
+                e.line,
+                e.column,
+                parse_error_message(e)
+            );
+            let offset = e.offset.min(source.len());
+            snippet(&message, source, offset, token_length(source, offset))
+        })
+        .collect();
+    rendered.join("\n")
+}
+
+/// The message followed by the line of `source` holding `offset`, with `length` carets from it
+fn snippet(message: &str, source: &str, offset: usize, length: usize) -> String {
+    let (line, column) = line_column(source, offset);
+    let text = source.lines().nth(line - 1).unwrap_or("");
+    // tabs stay tabs, so the carets line up however they're shown
+    let indent: String = text
+        .chars()
+        .take(column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let gutter = " ".repeat(line.to_string().len());
+    format!(
+        "{}\n{} |\n{} | {}\n{} | {}{}",
+        message,
+        gutter,
+        line,
+        text.trim_end(),
+        gutter,
+        indent,
+        "^".repeat(length.max(1))
+    )
+}
+
+/// Characters of the token at `offset`: up to the next space, comma, parenthesis or comment
+fn token_length(source: &str, offset: usize) -> usize {
+    let rest = source.get(offset..).unwrap_or("");
+    rest.chars()
+        .take_while(|c| !c.is_whitespace() && !",();".contains(*c))
+        .count()
+}
+
+/// Characters of the element at `offset` up to the end of its line, without a trailing comment
+fn element_length(source: &str, offset: usize) -> usize {
+    let rest = source.get(offset..).unwrap_or("");
+    let line = rest.lines().next().unwrap_or("");
+    line.split(';')
+        .next()
+        .unwrap_or("")
+        .trim_end()
+        .chars()
+        .count()
+}
+
+/// The error as a JSON object on one line, for editors and CI tools: its `code` (the name of its
+/// kind, like `ValueOutOfRange`), `severity`, `message`, and the `file`, `line`, `column`
+/// (counting from 1) and `length` in characters of the element causing it, which are `null`
+/// if it has no location (the file also if its name is empty)
+pub fn emit_error_json(files: &[(&str, &str)], error: &EmitError) -> String {
+    let location = error.location().and_then(|offset| locate(files, offset));
+    let span = match location {
+        Some((name, source, offset)) => {
+            let offset = offset.min(source.len());
+            let (line, column) = line_column(source, offset);
+            let file = if name.is_empty() {
+                "null".to_owned()
+            } else {
+                format!("\"{}\"", json_escape(name))
+            };
+            format!(
+                "\"file\": {}, \"line\": {}, \"column\": {}, \"length\": {}",
+                file,
+                line,
+                column,
+                element_length(source, offset)
+            )
+        }
+        None => "\"file\": null, \"line\": null, \"column\": null, \"length\": null".to_owned(),
+    };
+    format!(
+        "{{\"code\": \"{}\", \"severity\": \"error\", \"message\": \"{}\", {}}}",
+        error.code(),
+        json_escape(&error.to_string()),
+        span
+    )
+}
+
+/// The parse error after the line and column (counting from 1) it's at
+fn parse_error_text(e: &ParseError) -> String {
+    format!("{}:{}: {}", e.line, e.column, parse_error_message(e))
+}
+
+pub(crate) fn parse_error_diagnostic(e: &ParseError) -> Diagnostic {
+    Diagnostic::Error(format!("Parse error: {}", parse_error_text(e)))
+}
+
+/// An error splicing in the included files as JSON objects like the ones of emit_error_json,
+/// one per line, with the file, line and column of each parse error
+pub fn include_error_json(error: &IncludeError) -> String {
+    let object = |message: &str, span: String| {
+        format!(
+            "{{\"code\": \"{}\", \"severity\": \"error\", \"message\": \"{}\", {}}}",
+            error.code(),
+            json_escape(message),
+            span
+        )
+    };
+    match error {
+        IncludeError::Parse(name, errors) => {
+            let file = match name.is_empty() {
+                true => "null".to_owned(),
+                false => format!("\"{}\"", json_escape(name)),
+            };
+            let objects: Vec<String> = errors
+                .iter()
+                .map(|e| {
+                    let span = format!(
+                        "\"file\": {}, \"line\": {}, \"column\": {}, \"length\": null",
+                        file, e.line, e.column
+                    );
+                    object(&format!("Parse error: {}", parse_error_message(e)), span)
+                })
+                .collect();
+            objects.join("\n")
+        }
+        e => object(
+            &e.to_string(),
+            "\"file\": null, \"line\": null, \"column\": null, \"length\": null".to_owned(),
+        ),
+    }
+}
+
+/// A diagnostic without a location, like a warning of the emitter, as a JSON
+/// object like the ones of emit_error_json (the warnings have a `null` code)
+pub fn diagnostic_json(code: Option<&str>, diagnostic: &Diagnostic) -> String {
+    let (severity, message) = match diagnostic {
+        Diagnostic::Error(m) => ("error", m),
+        Diagnostic::Warning(m) => ("warning", m),
+        Diagnostic::Info(m) => ("info", m),
+    };
+    format!(
+        "{{\"code\": {}, \"severity\": \"{}\", \"message\": \"{}\", \"file\": null, \"line\": null, \"column\": null, \"length\": null}}",
+        code.map_or("null".to_owned(), |c| format!("\"{}\"", json_escape(c))),
+        severity,
+        json_escape(message)
+    )
+}
+
+/// Parses and assembles `source`, reporting errors as diagnostics instead of failing
+pub fn assemble(spec: &arch::RiscVSpec, source: &str, options: &EmitOptions) -> AssembleResult {
+    into_result(assemble_artifact(spec, source, options))
+}
+
+/// Reads, parses and assembles the files as one unit, sharing their labels
+/// (see flatbin::emit_image_files), with the files they include spliced in.
+/// Errors are reported as diagnostics naming the file.
+#[cfg(feature = "fs")]
+pub fn assemble_files(
+    spec: &arch::RiscVSpec,
+    paths: &[PathBuf],
+    options: &EmitOptions,
+) -> AssembleResult {
+    into_result(assemble_files_artifact(spec, paths, options))
+}
+
+fn into_result(assembled: Result<Artifact, Diagnostics>) -> AssembleResult {
+    match assembled {
+        Ok(artifact) => AssembleResult {
+            line_map: artifact.line_map,
+            symbols: artifact.image.symbols.into_iter().collect(),
+            diagnostics: artifact.image.diagnostics,
+            bytes: artifact.image.bytes,
+        },
+        Err(Diagnostics(diagnostics)) => AssembleResult {
+            diagnostics,
+            ..Default::default()
+        },
+    }
+}
+
+fn assemble_artifact(
+    spec: &arch::RiscVSpec,
+    source: &str,
+    options: &EmitOptions,
+) -> Result<Artifact, Diagnostics> {
+    let ast = parser::ast_from_str_all(source, spec)
+        .map_err(|errors| Diagnostics(errors.iter().map(parse_error_diagnostic).collect()))?;
+    emit_artifact(spec, &ast, source, options)
+}
+
+/// Emits the tree parsed from `source`, the errors formatted with their line in it
+pub(crate) fn emit_artifact(
+    spec: &arch::RiscVSpec,
+    ast: &Node,
+    source: &str,
+    options: &EmitOptions,
+) -> Result<Artifact, Diagnostics> {
+    match flatbin::emit_image(spec, ast, options) {
+        Ok(image) => Ok(Artifact::new(&[source], image)),
+        Err(e) => Err(Diagnostics(
+            e.all()
+                .iter()
+                .map(|error| Diagnostic::Error(format_emit_error(&[("", source)], error)))
+                .collect(),
+        )),
+    }
+}
+
+#[cfg(feature = "fs")]
+fn assemble_files_artifact(
+    spec: &arch::RiscVSpec,
+    paths: &[PathBuf],
+    options: &EmitOptions,
+) -> Result<Artifact, Diagnostics> {
+    let failed = |message: String| Diagnostics(vec![Diagnostic::Error(message)]);
+    let read = crate::parallel::map(options.jobs, paths.iter().collect(), |path| {
+        let name = path.to_string_lossy().into_owned();
+        match std::fs::read_to_string(path) {
+            Ok(source) => Ok((name, source)),
+            Err(e) => Err(failed(format!("{}: {}", name, e))),
+        }
+    });
+    let read = read.into_iter().collect::<Result<Vec<_>, _>>()?;
+    let names: Vec<String> = read.iter().map(|(name, _)| name.clone()).collect();
+    // offsets count from the start of the first file, as if the files were concatenated
+    let mut sources = Vec::new();
+    let trees = parser::resolve_includes_of_files(
+        spec,
+        read,
+        &options.include_dirs,
+        &mut sources,
+        options.jobs,
+    )
+    // a line for each of the parse errors
+    .map_err(|e| {
+        let message = e.to_string();
+        Diagnostics(
+            message
+                .lines()
+                .map(|l| Diagnostic::Error(l.to_owned()))
+                .collect(),
+        )
+    })?;
+    let files: Vec<(String, Node)> = names.into_iter().zip(trees).collect();
+    match flatbin::emit_image_files(spec, &files, options) {
+        Ok(image) => {
+            let sources: Vec<&str> = sources.iter().map(|(_, s)| s.as_str()).collect();
+            Ok(Artifact::new(&sources, image))
+        }
+        Err(e) => {
+            let files: Vec<(&str, &str)> = sources
+                .iter()
+                .map(|(name, source)| (name.as_str(), source.as_str()))
+                .collect();
+            Err(Diagnostics(
+                e.all()
+                    .iter()
+                    .map(|error| Diagnostic::Error(format_emit_error(&files, error)))
+                    .collect(),
+            ))
+        }
+    }
+}
+
+/// Maps the instructions to lines of the concatenated sources, the files being numbered in
+/// the order of the sources
+pub fn line_map(sources: &[&str], instructions: &[EmittedInstruction]) -> Vec<LineMapEntry> {
+    let mut file_starts = Vec::new();
+    let mut line_starts = Vec::new();
+    let mut base = 0;
+    for source in sources.iter() {
+        file_starts.push(base);
+        line_starts.push(
+            source
+                .match_indices('\n')
+                .map(|(i, _)| base + i + 1)
+                .collect::<Vec<usize>>(),
+        );
+        base += source.len();
+    }
+    instructions
+        .iter()
+        .filter_map(|insn| {
+            let offset = insn.source_offset?;
+            let file = file_starts
+                .partition_point(|start| *start <= offset)
+                .saturating_sub(1);
+            Some(LineMapEntry {
+                address: insn.address,
+                file,
+                line: line_starts[file].partition_point(|start| *start <= offset) + 1,
+            })
+        })
+        .collect()
+}
+
+/// The spec and options to assemble with, set up once for any number of sources:
+///
+/// ```ignore
+/// let artifact = Assembler::new(&spec)
+///     .with_defines([("STACK_SIZE", 0x1000)])
+///     .assemble_str("li sp, STACK_SIZE\n")?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct Assembler<'spec> {
+    spec: &'spec arch::RiscVSpec,
+    options: EmitOptions,
+}
+
+impl<'spec> Assembler<'spec> {
+    pub fn new(spec: &'spec arch::RiscVSpec) -> Self {
+        Assembler {
+            spec,
+            options: EmitOptions::default(),
+        }
+    }
+
+    /// Replaces all the options, including the defines and include directories set before
+    pub fn with_options(mut self, options: EmitOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Adds symbols defined before the source, like `--defsym`
+    pub fn with_defines<I, S>(mut self, defines: I) -> Self
+    where
+        I: IntoIterator<Item = (S, u64)>,
+        S: Into<String>,
+    {
+        self.options.defsyms.extend(
+            defines
+                .into_iter()
+                .map(|(name, value)| (name.into(), value)),
+        );
+        self
+    }
+
+    /// Adds directories to search for `.include`d files, after the one of the including file
+    pub fn with_include_dirs<I, P>(mut self, dirs: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.options
+            .include_dirs
+            .extend(dirs.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn options(&self) -> &EmitOptions {
+        &self.options
+    }
+
+    pub fn assemble_str(&self, source: &str) -> Result<Artifact, Diagnostics> {
+        assemble_artifact(self.spec, source, &self.options)
+    }
+
+    /// A session assembling `source` again as it's edited (see session::Session)
+    pub fn session(&self, source: &str) -> Session<'spec> {
+        Session::new(self.spec, source, self.options.clone())
+    }
+
+    /// Reads and assembles the file, with the files it includes spliced in
+    #[cfg(feature = "fs")]
+    pub fn assemble_file<P: AsRef<Path>>(&self, path: P) -> Result<Artifact, Diagnostics> {
+        self.assemble_files(&[path.as_ref().to_path_buf()])
+    }
+
+    /// Assembles the files as one unit, like assemble_files
+    #[cfg(feature = "fs")]
+    pub fn assemble_files(&self, paths: &[PathBuf]) -> Result<Artifact, Diagnostics> {
+        assemble_files_artifact(self.spec, paths, &self.options)
+    }
+}
+
+/// A successfully assembled source
+#[derive(Clone, Debug)]
+pub struct Artifact {
+    image: AssembledImage,
+    line_map: Vec<LineMapEntry>,
+}
+
+impl Artifact {
+    pub(crate) fn new(sources: &[&str], image: AssembledImage) -> Self {
+        Artifact {
+            line_map: line_map(sources, &image.instructions),
+            image,
+        }
+    }
+
+    /// The flat binary image
+    pub fn bytes(&self) -> &[u8] {
+        &self.image.bytes
+    }
+
+    pub fn symbols(&self) -> &HashMap<String, u64> {
+        &self.image.symbols
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.image.symbols.get(name).copied()
+    }
+
+    /// Every emitted instruction with its address, operands and bytes, sorted by address
+    pub fn instructions(&self) -> &[EmittedInstruction] {
+        &self.image.instructions
+    }
+
+    /// The instruction starting at the address
+    pub fn instruction_at(&self, address: u64) -> Option<&EmittedInstruction> {
+        let instructions = &self.image.instructions;
+        let i = instructions.partition_point(|insn| insn.address < address);
+        instructions.get(i).filter(|insn| insn.address == address)
+    }
+
+    /// Source line of each instruction, sorted by address
+    pub fn line_map(&self) -> &[LineMapEntry] {
+        &self.line_map
+    }
+
+    /// Warnings and notes of the assembly
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.image.diagnostics
+    }
+
+    /// Everything the emitter produced, for the output backends
+    pub fn image(&self) -> &AssembledImage {
+        &self.image
+    }
+
+    pub fn into_image(self) -> AssembledImage {
+        self.image
+    }
+}
+
+/// Why assembling failed: the errors, formatted with their file and line
+#[derive(Clone, Debug)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub fn messages(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|d| match d {
+            Diagnostic::Error(m) | Diagnostic::Warning(m) | Diagnostic::Info(m) => m.as_str(),
+        })
+    }
+}
+
+impl std::fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, message) in self.messages().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
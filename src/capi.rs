@@ -0,0 +1,247 @@
+//! C interface for calling the assembler in-process, like from emulators and editor plugins.
+//! Built as a shared library with `cargo rustc --release --features capi --crate-type cdylib`,
+//! `include/rvasm.h` declares it.
+
+use crate::arch::RiscVSpec;
+use crate::assemble::{assemble, AssembleResult};
+use crate::emit::{Diagnostic, EmitOptions};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+
+/// Assembled without errors
+pub const RVASM_OK: c_int = 0;
+/// The source has errors, which the diagnostics give
+pub const RVASM_ERROR: c_int = 1;
+/// The arguments are invalid or the architecture can't be loaded, the reason is the diagnostic
+pub const RVASM_INVALID: c_int = 2;
+
+/// What to assemble for and how, null pointers and zeroes being the defaults
+#[repr(C)]
+pub struct RvasmOptions {
+    /// Architecture like `RV32IMC` or `rv64gc`, from the specs built into the library (RV32I
+    /// if null)
+    pub arch: *const c_char,
+    /// TOML spec with custom instructions, formats and constants to load on top, or null
+    pub spec: *const c_char,
+    /// Generate position-independent code
+    pub pic: c_int,
+    /// Relax calls and address loads into shorter sequences where they reach
+    pub relax: c_int,
+    /// Warn about arithmetic instructions writing to x0
+    pub warn_x0: c_int,
+}
+
+#[repr(C)]
+pub struct RvasmSymbol {
+    pub name: *mut c_char,
+    pub value: u64,
+}
+
+/// Source line (counting from 1) of the instruction at `address`
+#[repr(C)]
+pub struct RvasmLine {
+    pub address: u64,
+    pub line: usize,
+}
+
+#[repr(C)]
+pub struct RvasmDiagnostic {
+    /// 0 for an error, 1 for a warning, 2 for a note
+    pub severity: c_int,
+    pub message: *mut c_char,
+}
+
+/// Everything assembling gave, owned by the caller until rvasm_result_free. Arrays without
+/// items are null.
+#[repr(C)]
+pub struct RvasmResult {
+    /// The flat binary image, empty if assembly failed
+    pub bytes: *mut u8,
+    pub len: usize,
+    /// Sorted by name
+    pub symbols: *mut RvasmSymbol,
+    pub symbol_count: usize,
+    /// Sorted by address
+    pub lines: *mut RvasmLine,
+    pub line_count: usize,
+    pub diagnostics: *mut RvasmDiagnostic,
+    pub diagnostic_count: usize,
+}
+
+/// The items as a C array and its length, null if there are none
+fn into_raw<T>(items: Vec<T>) -> (*mut T, usize) {
+    if items.is_empty() {
+        return (std::ptr::null_mut(), 0);
+    }
+    let len = items.len();
+    (Box::into_raw(items.into_boxed_slice()) as *mut T, len)
+}
+
+/// Frees an array made by into_raw
+unsafe fn free_raw<T>(items: *mut T, len: usize) -> Vec<T> {
+    if items.is_null() {
+        return Vec::new();
+    }
+    Box::from_raw(std::ptr::slice_from_raw_parts_mut(items, len)).into_vec()
+}
+
+/// C string of the text, without the NUL bytes it can't hold
+fn c_string(text: &str) -> *mut c_char {
+    CString::new(text.replace('\0', ""))
+        .unwrap_or_default()
+        .into_raw()
+}
+
+/// The string argument, none for a null pointer
+unsafe fn str_arg<'a>(ptr: *const c_char, what: &str) -> Result<Option<&'a str>, String> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map(Some)
+        .map_err(|_| format!("{} isn't valid UTF-8", what))
+}
+
+unsafe fn assemble_c(
+    source: *const c_char,
+    options: *const RvasmOptions,
+) -> Result<AssembleResult, String> {
+    let source = str_arg(source, "the source")?.ok_or("the source is null")?;
+    let mut spec = RiscVSpec::new();
+    let mut emit_options = EmitOptions::default();
+    let mut arch = "RV32I";
+    if let Some(options) = options.as_ref() {
+        arch = str_arg(options.arch, "the architecture")?.unwrap_or(arch);
+        emit_options.pic = options.pic != 0;
+        emit_options.relax = options.relax != 0;
+        emit_options.lint_x0_writes = options.warn_x0 != 0;
+    }
+    spec.load_arch_builtin(arch)
+        .map_err(|e| format!("can't load architecture {}: {}", arch, e))?;
+    if let Some(extension) = options
+        .as_ref()
+        .map_or(Ok(None), |o| str_arg(o.spec, "the spec"))?
+    {
+        spec.load_extension_string(extension, false)
+            .map_err(|e| format!("can't load the spec: {}", e))?;
+    }
+    Ok(assemble(&spec, source, &emit_options))
+}
+
+/// Assembles the NUL-terminated source into `result`, giving RVASM_OK, RVASM_ERROR or
+/// RVASM_INVALID. The result is filled in also when assembly fails, with the diagnostics
+/// saying why.
+///
+/// # Safety
+///
+/// `source` has to be a NUL-terminated string, `options` null or pointing to options whose
+/// strings are null or NUL-terminated, and `result` valid for writing a result, which the
+/// caller frees with rvasm_result_free.
+#[no_mangle]
+pub unsafe extern "C" fn rvasm_assemble(
+    source: *const c_char,
+    options: *const RvasmOptions,
+    result: *mut RvasmResult,
+) -> c_int {
+    if result.is_null() {
+        return RVASM_INVALID;
+    }
+    // unwinding into C is undefined, so a bug shows as an invalid call
+    let assembled =
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| assemble_c(source, options)))
+            .unwrap_or_else(|_| Err("the assembler panicked".to_owned()));
+    let (code, assembled) = match assembled {
+        Ok(assembled) if assembled.succeeded() => (RVASM_OK, assembled),
+        Ok(assembled) => (RVASM_ERROR, assembled),
+        Err(message) => (
+            RVASM_INVALID,
+            AssembleResult {
+                diagnostics: vec![Diagnostic::Error(message)],
+                ..Default::default()
+            },
+        ),
+    };
+
+    let (bytes, len) = into_raw(assembled.bytes);
+    let (symbols, symbol_count) = into_raw(
+        assembled
+            .symbols
+            .iter()
+            .map(|(name, value)| RvasmSymbol {
+                name: c_string(name),
+                value: *value,
+            })
+            .collect(),
+    );
+    let (lines, line_count) = into_raw(
+        assembled
+            .line_map
+            .iter()
+            .map(|entry| RvasmLine {
+                address: entry.address,
+                line: entry.line,
+            })
+            .collect(),
+    );
+    let (diagnostics, diagnostic_count) = into_raw(
+        assembled
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let (severity, message) = match diagnostic {
+                    Diagnostic::Error(m) => (0, m),
+                    Diagnostic::Warning(m) => (1, m),
+                    Diagnostic::Info(m) => (2, m),
+                };
+                RvasmDiagnostic {
+                    severity,
+                    message: c_string(message),
+                }
+            })
+            .collect(),
+    );
+    result.write(RvasmResult {
+        bytes,
+        len,
+        symbols,
+        symbol_count,
+        lines,
+        line_count,
+        diagnostics,
+        diagnostic_count,
+    });
+    code
+}
+
+/// Frees everything in a result filled in by rvasm_assemble and empties it, so freeing it
+/// again does nothing
+///
+/// # Safety
+///
+/// `result` has to be null or a result written by rvasm_assemble, not changed since.
+#[no_mangle]
+pub unsafe extern "C" fn rvasm_result_free(result: *mut RvasmResult) {
+    let result = match result.as_mut() {
+        Some(result) => result,
+        None => return,
+    };
+    free_raw(result.bytes, result.len);
+    for symbol in free_raw(result.symbols, result.symbol_count) {
+        drop(CString::from_raw(symbol.name));
+    }
+    free_raw(result.lines, result.line_count);
+    for diagnostic in free_raw(result.diagnostics, result.diagnostic_count) {
+        drop(CString::from_raw(diagnostic.message));
+    }
+    *result = RvasmResult {
+        bytes: std::ptr::null_mut(),
+        len: 0,
+        symbols: std::ptr::null_mut(),
+        symbol_count: 0,
+        lines: std::ptr::null_mut(),
+        line_count: 0,
+        diagnostics: std::ptr::null_mut(),
+        diagnostic_count: 0,
+    };
+}
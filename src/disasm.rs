@@ -0,0 +1,337 @@
+//! Decoding machine code back into instructions, with the formats and field encodings of the
+//! spec that assembles them
+
+use crate::arch;
+use crate::emit::Operand;
+use crate::report::register_name;
+use std::fmt::Write;
+
+/// An instruction found in the machine code
+#[derive(Clone, Debug)]
+pub struct DecodedInstruction<'spec> {
+    pub address: u64,
+    pub insn: &'spec arch::InstructionDefinition,
+    /// Values of the args of the instruction, in their order
+    pub operands: Vec<Operand>,
+    pub bytes: Vec<u8>,
+}
+
+/// Part of the machine code, either a decoded instruction or bytes no instruction of the spec
+/// matches
+#[derive(Clone, Debug)]
+pub enum Disassembled<'spec> {
+    Instruction(DecodedInstruction<'spec>),
+    Unknown { address: u64, bytes: Vec<u8> },
+}
+
+/// Bits of the instruction word set by the encoding of the field
+fn field_mask(field: &arch::InstructionField) -> u64 {
+    field
+        .encoding
+        .iter()
+        .map(|e| e.value_bitmask() >> e.value_first << e.instruction_first)
+        .fold(0, |a, b| a | b)
+}
+
+/// Bits of the value of the field that are encoded, like all but the lowest of branch offsets
+fn value_mask(field: &arch::InstructionField) -> u64 {
+    field
+        .encoding
+        .iter()
+        .map(|e| e.value_bitmask())
+        .fold(0, |a, b| a | b)
+}
+
+fn decode_field(field: &arch::InstructionField, word: u64) -> u64 {
+    let value = field.encoding.iter().fold(0, |value, e| {
+        value | ((word >> e.instruction_first) << e.value_first & e.value_bitmask())
+    });
+    if field.signed && (1..64).contains(&field.length) {
+        let shift = 64 - field.length;
+        (((value << shift) as i64) >> shift) as u64
+    } else {
+        value
+    }
+}
+
+/// Bits of the instruction word the definition fixes and their values: its assigned fields and
+/// the fields of its format it doesn't use, which are encoded as zeroes
+fn fixed_bits(spec: &arch::RiscVSpec, insn: &arch::InstructionDefinition) -> (u64, u64) {
+    let fmt = insn.get_format(spec);
+    let mut mask = 0;
+    let mut bits = 0;
+    for (fi, field) in fmt.fields.iter().enumerate() {
+        if insn.args.contains(&fi) {
+            continue;
+        }
+        let value = insn
+            .fields
+            .iter()
+            .find(|(fid, _)| *fid == fi)
+            .map_or(0, |(_, v)| *v);
+        for e in field.encoding.iter() {
+            mask |= e.value_bitmask() >> e.value_first << e.instruction_first;
+            bits |= (value & e.value_bitmask()) >> e.value_first << e.instruction_first;
+        }
+    }
+    (mask, bits)
+}
+
+/// Operands of the instruction if the word is an encoding of it, with how many of its bits it
+/// pins down (fixed fields and registers restricted to one choice), to prefer the most specific
+/// match like c.jr over c.mv
+fn decode_with(
+    spec: &arch::RiscVSpec,
+    insn: &arch::InstructionDefinition,
+    word: u64,
+) -> Option<(Vec<Operand>, u32)> {
+    let fmt = insn.get_format(spec);
+    let (mask, bits) = fixed_bits(spec, insn);
+    if word & mask != bits {
+        return None;
+    }
+    let mut specificity = mask.count_ones();
+    let mut operands = Vec::with_capacity(insn.args.len());
+    for argid in insn.args.iter() {
+        let field = &fmt.fields[*argid];
+        let value = decode_field(field, word);
+        operands.push(match field.vtype {
+            arch::FieldType::Register => match &field.allowed_registers {
+                // the encoded bits tell which of the allowed registers it is
+                Some(allowed) => {
+                    let register = *allowed
+                        .iter()
+                        .find(|r| **r as u64 & value_mask(field) == value)?;
+                    if allowed.len() == 1 {
+                        specificity += field_mask(field).count_ones();
+                    }
+                    Operand::Register(register)
+                }
+                None => Operand::Register(value as i32),
+            },
+            arch::FieldType::Value | arch::FieldType::Csr => Operand::Value(value),
+        });
+    }
+    Some((operands, specificity))
+}
+
+/// Decodes the instruction at the start of `bytes`, read little endian, among the ones of the
+/// spec whose extension is enabled. Returns the instruction and its operands, its length is the
+/// one of its format.
+pub fn decode<'spec>(
+    spec: &'spec arch::RiscVSpec,
+    bytes: &[u8],
+) -> Option<(&'spec arch::InstructionDefinition, Vec<Operand>)> {
+    let mut best: Option<(&arch::InstructionDefinition, Vec<Operand>, u32)> = None;
+    for insn in spec.get_all_instructions() {
+        if let Some(ext) = &insn.extension {
+            if !spec.extension_enabled(ext) {
+                continue;
+            }
+        }
+        let len = insn.get_format(spec).ilen.div_ceil(8);
+        if len == 0 || len > bytes.len() || len > 8 {
+            continue;
+        }
+        let word = bytes[..len]
+            .iter()
+            .rev()
+            .fold(0u64, |w, b| w << 8 | *b as u64);
+        if let Some((operands, specificity)) = decode_with(spec, insn, word) {
+            if best.as_ref().is_none_or(|(_, _, s)| specificity > *s) {
+                best = Some((insn, operands, specificity));
+            }
+        }
+    }
+    best.map(|(insn, operands, _)| (insn, operands))
+}
+
+/// Decodes the bytes (little endian) an instruction of the spec was encoded into, like the
+/// disassembler does. Gives what they decode as instead if that isn't the instruction with the
+/// operands it was encoded with, like when fields of its format overlap or lose bits, or its
+/// fixed fields are the ones of another instruction.
+pub fn check_round_trip(
+    spec: &arch::RiscVSpec,
+    insn: &arch::InstructionDefinition,
+    bytes: &[u8],
+    operands: &[Operand],
+) -> Result<(), String> {
+    let word = bytes.iter().rev().fold(0u64, |w, b| w << 8 | *b as u64);
+    let own = decode_with(spec, insn, word).map(|(decoded, _)| decoded);
+    // the fields of a format written with .insn can be any instruction, only they have to match
+    if spec.get_instruction_by_name(&insn.name).is_none() {
+        return match own {
+            Some(decoded) if decoded == operands => Ok(()),
+            Some(decoded) => Err(instruction_text(spec, insn, &decoded)),
+            None => Err("no instruction".to_owned()),
+        };
+    }
+    let same = own.is_some_and(|decoded| decoded == operands);
+    match decode(spec, bytes) {
+        // an instruction written the same way, like one defined again under another name,
+        // assembles back the same
+        Some((found, decoded))
+            if same
+                && (found.name == insn.name
+                    || instruction_text(spec, found, &decoded)
+                        == instruction_text(spec, insn, operands)) =>
+        {
+            Ok(())
+        }
+        Some((found, decoded)) => Err(instruction_text(spec, found, &decoded)),
+        None => Err("no instruction".to_owned()),
+    }
+}
+
+/// Decodes the machine code placed at `base`, one instruction after the other. Bytes that don't
+/// decode are skipped by IALIGN, so the instructions after them are still found.
+pub fn disassemble<'spec>(
+    spec: &'spec arch::RiscVSpec,
+    bytes: &[u8],
+    base: u64,
+) -> Vec<Disassembled<'spec>> {
+    let ialign = (spec.get_const("IALIGN").unwrap_or(32) as usize / 8).max(1);
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let address = base + pos as u64;
+        match decode(spec, &bytes[pos..]) {
+            Some((insn, operands)) => {
+                let len = insn.get_format(spec).ilen.div_ceil(8);
+                out.push(Disassembled::Instruction(DecodedInstruction {
+                    address,
+                    insn,
+                    operands,
+                    bytes: bytes[pos..pos + len].to_vec(),
+                }));
+                pos += len;
+            }
+            None => {
+                let len = ialign.min(bytes.len() - pos);
+                out.push(Disassembled::Unknown {
+                    address,
+                    bytes: bytes[pos..pos + len].to_vec(),
+                });
+                pos += len;
+            }
+        }
+    }
+    out
+}
+
+/// How many of the operands to write: trailing values the defaults give back when left out and
+/// that have no name to write them with, like the vm of unmasked vector instructions
+fn shown_operands(
+    fmt: &arch::InstructionFormat,
+    insn: &arch::InstructionDefinition,
+    operands: &[Operand],
+) -> usize {
+    let mut shown = operands.len();
+    while shown > 0 {
+        let field = &fmt.fields[insn.args[shown - 1]];
+        let default = insn
+            .defaults
+            .iter()
+            .find(|(fi, _)| *fi == insn.args[shown - 1]);
+        match (operands[shown - 1], default) {
+            (Operand::Value(v), Some((_, d)))
+                if matches!(field.vtype, arch::FieldType::Value)
+                    && v == *d
+                    && field.value_name(v).is_none() => {}
+            _ => break,
+        }
+        // the defaults have to fill exactly the left out args
+        let fills = insn.default_arg_slots(shown - 1).is_some_and(|slots| {
+            slots
+                .iter()
+                .enumerate()
+                .all(|(i, s)| s.is_some() == (i >= shown - 1))
+        });
+        if !fills {
+            break;
+        }
+        shown -= 1;
+    }
+    shown
+}
+
+/// The instruction in the syntax the assembler takes, like `lw a0, 4(sp)`: registers by ABI
+/// name, CSRs by name if the spec has one for them, and a value followed by an address register
+/// written as a memory operand
+pub fn instruction_text(
+    spec: &arch::RiscVSpec,
+    insn: &arch::InstructionDefinition,
+    operands: &[Operand],
+) -> String {
+    let fmt = insn.get_format(spec);
+    let shown = shown_operands(fmt, insn, operands);
+    let mut texts: Vec<String> = insn
+        .args
+        .iter()
+        .zip(&operands[..shown])
+        .map(|(argid, op)| match (*op, fmt.fields[*argid].vtype) {
+            (Operand::Register(r), _) => register_name(spec, r),
+            (Operand::Value(v), arch::FieldType::Csr) => match spec.get_csr_name(v) {
+                Some(name) => name.to_owned(),
+                None => format!("0x{:x}", v),
+            },
+            (Operand::Value(v), _) => match fmt.fields[*argid].value_name(v) {
+                Some(name) => name.to_owned(),
+                None if (-4096..4096).contains(&(v as i64)) => format!("{}", v as i64),
+                None => format!("0x{:x}", v),
+            },
+        })
+        .collect();
+    // addresses are in x registers, unlike the v0 after the immediate of vmerge.vim
+    let memory_operand = insn.args[..texts.len()].windows(2).position(|pair| {
+        let (offset, base) = (&fmt.fields[pair[0]], &fmt.fields[pair[1]]);
+        matches!(offset.vtype, arch::FieldType::Value)
+            && matches!(base.vtype, arch::FieldType::Register)
+            && base
+                .allowed_registers
+                .as_ref()
+                .is_none_or(|allowed| allowed.iter().all(|r| *r < 32))
+    });
+    if let Some(at) = memory_operand {
+        let register = texts.remove(at + 1);
+        texts[at] = format!("{}({})", texts[at], register);
+    }
+    if texts.is_empty() {
+        insn.name.clone()
+    } else {
+        format!("{} {}", insn.name, texts.join(", "))
+    }
+}
+
+/// Text of the disassembly, one line per instruction with its address and encoding (written
+/// as a little endian number) like `00000004: 00150513 addi a0, a0, 1`. Bytes that don't
+/// decode are written as `.half`, `.word` or `.byte` data, so the text assembles back into the
+/// same machine code.
+pub fn render_disassembly(spec: &arch::RiscVSpec, bytes: &[u8], base: u64) -> String {
+    let mut out = String::new();
+    for item in disassemble(spec, bytes, base) {
+        let (address, bytes, text) = match &item {
+            Disassembled::Instruction(d) => (
+                d.address,
+                &d.bytes,
+                instruction_text(spec, d.insn, &d.operands),
+            ),
+            Disassembled::Unknown { address, bytes } => {
+                let value = bytes.iter().rev().fold(0u64, |w, b| w << 8 | *b as u64);
+                let text = match bytes.len() {
+                    2 => format!(".half 0x{:04x}", value),
+                    4 => format!(".word 0x{:08x}", value),
+                    _ => {
+                        let each: Vec<String> =
+                            bytes.iter().map(|b| format!("0x{:02x}", b)).collect();
+                        format!(".byte {}", each.join(", "))
+                    }
+                };
+                (*address, bytes, text)
+            }
+        };
+        let encoding: String = bytes.iter().rev().map(|b| format!("{:02x}", b)).collect();
+        writeln!(out, "{:08x}: {:8} {}", address, encoding, text).unwrap();
+    }
+    out
+}
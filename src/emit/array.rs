@@ -0,0 +1,122 @@
+//! The image as an array in C or Rust source, for embedding a program into a host-side
+//! simulator or test harness
+
+use super::{AssembledImage, EmitError, OutputBackend};
+use std::fmt::Write;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArrayLanguage {
+    /// A header with `const uint8_t program[N] = {...};`
+    C,
+    /// A module with `pub static PROGRAM: [u8; N] = [...];`
+    Rust,
+}
+
+impl ArrayLanguage {
+    /// The name the array gets unless one is given
+    pub fn default_name(self) -> &'static str {
+        match self {
+            ArrayLanguage::C => "program",
+            ArrayLanguage::Rust => "PROGRAM",
+        }
+    }
+}
+
+/// Source code declaring the flat image as an array of little endian words of `word_bytes`
+/// bytes (1, 2, 4 or 8), the last one padded with zeroes
+pub struct SourceArray {
+    pub language: ArrayLanguage,
+    pub name: String,
+    pub word_bytes: usize,
+    /// Words on each line
+    pub per_line: usize,
+    /// Start each line with a comment giving the address of its first word
+    pub address_comments: bool,
+}
+
+impl SourceArray {
+    pub fn new(language: ArrayLanguage) -> Self {
+        SourceArray {
+            language,
+            name: language.default_name().to_owned(),
+            word_bytes: 1,
+            per_line: 16,
+            address_comments: false,
+        }
+    }
+
+    /// The source text declaring the array. Fails for a name that isn't an identifier, and
+    /// for an empty image in C.
+    pub fn render(&self, image: &AssembledImage) -> Result<String, EmitError> {
+        let mut chars = self.name.chars();
+        let identifier = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !identifier {
+            return Err(EmitError::InvalidArrayName(self.name.clone()));
+        }
+        if image.bytes.is_empty() && self.language == ArrayLanguage::C {
+            return Err(EmitError::EmptyArray);
+        }
+        let word_bytes = self.word_bytes.clamp(1, 8);
+        let words: Vec<u64> = image
+            .bytes
+            .chunks(word_bytes)
+            .map(|chunk| {
+                let mut word = [0; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            })
+            .collect();
+        let bits = word_bytes * 8;
+
+        let mut out = String::new();
+        match self.language {
+            ArrayLanguage::C => {
+                writeln!(out, "#include <stdint.h>\n").unwrap();
+                writeln!(
+                    out,
+                    "const uint{}_t {}[{}] = {{",
+                    bits,
+                    self.name,
+                    words.len()
+                )
+                .unwrap();
+            }
+            ArrayLanguage::Rust => {
+                writeln!(
+                    out,
+                    "pub static {}: [u{}; {}] = [",
+                    self.name,
+                    bits,
+                    words.len()
+                )
+                .unwrap();
+            }
+        }
+        for (i, line) in words.chunks(self.per_line.max(1)).enumerate() {
+            out.push_str("    ");
+            if self.address_comments {
+                let offset = i * self.per_line.max(1) * word_bytes;
+                write!(out, "/* 0x{:08x} */ ", image.address_of(offset)).unwrap();
+            }
+            let line: Vec<String> = line
+                .iter()
+                .map(|word| format!("0x{:0width$x}", word, width = word_bytes * 2))
+                .collect();
+            writeln!(out, "{},", line.join(", ")).unwrap();
+        }
+        out.push_str(match self.language {
+            ArrayLanguage::C => "};\n",
+            ArrayLanguage::Rust => "];\n",
+        });
+        Ok(out)
+    }
+}
+
+impl OutputBackend for SourceArray {
+    fn serialize(&self, image: &AssembledImage) -> Result<Vec<u8>, EmitError> {
+        Ok(self.render(image)?.into_bytes())
+    }
+}
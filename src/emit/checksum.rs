@@ -0,0 +1,59 @@
+//! Checksums over ranges of the output, for bootloaders checking the image they load
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// CRC-32 as used by zlib and Ethernet (reflected polynomial 0xedb88320)
+    Crc32,
+    /// Sum of the bytes, truncated to 16 bits
+    Sum16,
+    /// Sum of the bytes, truncated to 32 bits
+    Sum32,
+}
+
+impl ChecksumAlgorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_ref() {
+            "crc32" => Some(ChecksumAlgorithm::Crc32),
+            "sum16" => Some(ChecksumAlgorithm::Sum16),
+            "sum32" => Some(ChecksumAlgorithm::Sum32),
+            _ => None,
+        }
+    }
+
+    /// Number of bytes the checksum is stored in
+    pub fn size(self) -> usize {
+        match self {
+            ChecksumAlgorithm::Crc32 | ChecksumAlgorithm::Sum32 => 4,
+            ChecksumAlgorithm::Sum16 => 2,
+        }
+    }
+
+    pub fn compute(self, bytes: &[u8]) -> u64 {
+        match self {
+            ChecksumAlgorithm::Crc32 => crc32(bytes) as u64,
+            ChecksumAlgorithm::Sum16 => sum(bytes) & 0xffff,
+            ChecksumAlgorithm::Sum32 => sum(bytes) & 0xffff_ffff,
+        }
+    }
+}
+
+fn sum(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0u64, |acc, b| acc.wrapping_add(*b as u64))
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in bytes.iter() {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,156 @@
+//! Compressed (RVC) forms picked for instructions whose operands fit them, when the C extension
+//! is loaded
+
+use crate::arch;
+use crate::intern::ascii_lowercase;
+
+/// Size of every compressed instruction
+pub const COMPRESSED_BYTES: usize = 2;
+
+/// Instructions some operands of which have a compressed form
+const COMPRESSIBLE: &[&str] = &[
+    "addi", "lui", "lw", "sw", "jal", "jalr", "beq", "bne", "slli", "srli", "srai", "andi", "add",
+    "sub", "xor", "or", "and", "ebreak",
+];
+
+const ZERO: u64 = 0;
+const RA: u64 = 1;
+const SP: u64 = 2;
+
+/// Whether `iname` has a compressed form for some of its operands
+pub fn compressible(iname: &str) -> bool {
+    COMPRESSIBLE.iter().any(|c| c.eq_ignore_ascii_case(iname))
+}
+
+/// Compressed instruction doing the same as `iname` with the operand values `args` (in the
+/// order of its args, registers by number) and the operands to encode it with. Only the
+/// instructions the spec defines are candidates, and only if the operands fit their fields.
+pub fn compress<'spec>(
+    spec: &'spec arch::RiscVSpec,
+    iname: &str,
+    args: &[u64],
+) -> Option<(&'spec arch::InstructionDefinition, Vec<u64>)> {
+    if !spec.extension_enabled("C") {
+        return None;
+    }
+    // RV64 encodes c.addiw where RV32 has c.jal
+    let wide = spec.get_const("XLEN").unwrap_or(32) > 32;
+    candidates(iname, args)
+        .into_iter()
+        .filter(|(cname, _)| !(wide && *cname == "c.jal"))
+        .find_map(|(cname, cargs)| {
+            let cinsn = spec.get_instruction_by_name(cname)?;
+            fits(spec, cinsn, &cargs).then_some((cinsn, cargs))
+        })
+}
+
+/// Whether the values fit the fields of the instruction: registers in the ones its fields
+/// allow, values in their range and at their alignment
+fn fits(spec: &arch::RiscVSpec, insn: &arch::InstructionDefinition, args: &[u64]) -> bool {
+    let fmt = insn.get_format(spec);
+    if fmt.ilen.div_ceil(8) != COMPRESSED_BYTES || args.len() != insn.args.len() {
+        return false;
+    }
+    insn.args.iter().zip(args).all(|(fi, v)| {
+        let field = &fmt.fields[*fi];
+        match field.vtype {
+            arch::FieldType::Register => field.register_allowed(*v as i32),
+            arch::FieldType::Value | arch::FieldType::Csr => {
+                field.value_fits(*v) && *v % field.value_alignment() == 0
+            }
+        }
+    })
+}
+
+/// Compressed forms that could do the same, best first, with the conditions the fields can't
+/// express (like registers having to be the same or not x0)
+fn candidates(iname: &str, args: &[u64]) -> Vec<(&'static str, Vec<u64>)> {
+    let lname = ascii_lowercase(iname);
+    let mut out = Vec::new();
+    match (lname.as_ref(), args) {
+        ("addi", &[rd, rs1, imm]) => {
+            if rd == ZERO && rs1 == ZERO && imm == 0 {
+                out.push(("c.nop", vec![]));
+            }
+            if rd == SP && rs1 == SP && imm != 0 {
+                out.push(("c.addi16sp", vec![SP, imm]));
+            }
+            if rd != ZERO && rd == rs1 && imm != 0 {
+                out.push(("c.addi", vec![rd, imm]));
+            }
+            if rd != ZERO && rs1 == ZERO {
+                out.push(("c.li", vec![rd, imm]));
+            }
+            if rd != ZERO && rs1 != ZERO && imm == 0 {
+                out.push(("c.mv", vec![rd, rs1]));
+            }
+            if rs1 == SP && imm != 0 {
+                out.push(("c.addi4spn", vec![rd, SP, imm]));
+            }
+        }
+        // the 20 bits of lui sign-extend into the 18 of c.lui
+        ("lui", &[rd, imm]) if rd != ZERO && rd != SP && imm != 0 => {
+            out.push(("c.lui", vec![rd, imm as u32 as i32 as u64]));
+        }
+        ("lw", &[rd, imm, rs1]) => {
+            if rs1 == SP && rd != ZERO {
+                out.push(("c.lwsp", vec![rd, imm, SP]));
+            }
+            out.push(("c.lw", vec![rd, imm, rs1]));
+        }
+        ("sw", &[rs2, imm, rs1]) => {
+            if rs1 == SP {
+                out.push(("c.swsp", vec![rs2, imm, SP]));
+            }
+            out.push(("c.sw", vec![rs2, imm, rs1]));
+        }
+        ("jal", &[ZERO, imm]) => out.push(("c.j", vec![imm])),
+        ("jal", &[RA, imm]) => out.push(("c.jal", vec![imm])),
+        ("jalr", &[ZERO, rs1, 0]) if rs1 != ZERO => out.push(("c.jr", vec![rs1])),
+        ("jalr", &[RA, rs1, 0]) if rs1 != ZERO => out.push(("c.jalr", vec![rs1])),
+        ("beq" | "bne", &[rs1, rs2, imm]) if rs1 == ZERO || rs2 == ZERO => {
+            let cname = if lname == "beq" { "c.beqz" } else { "c.bnez" };
+            out.push((cname, vec![rs1.max(rs2), imm]));
+        }
+        ("slli", &[rd, rs1, shamt]) if rd != ZERO && rd == rs1 && shamt != 0 => {
+            out.push(("c.slli", vec![rd, shamt]));
+        }
+        ("srli" | "srai" | "andi", &[rd, rs1, imm]) if rd == rs1 => {
+            let cname = match lname.as_ref() {
+                "srli" => "c.srli",
+                "srai" => "c.srai",
+                _ => "c.andi",
+            };
+            if imm != 0 || lname == "andi" {
+                out.push((cname, vec![rd, imm]));
+            }
+        }
+        ("add", &[rd, rs1, rs2]) if rd != ZERO => {
+            if rs1 == ZERO && rs2 != ZERO {
+                out.push(("c.mv", vec![rd, rs2]));
+            } else if rs2 == ZERO && rs1 != ZERO {
+                out.push(("c.mv", vec![rd, rs1]));
+            } else if rd == rs1 && rs2 != ZERO {
+                out.push(("c.add", vec![rd, rs2]));
+            } else if rd == rs2 && rs1 != ZERO {
+                out.push(("c.add", vec![rd, rs1]));
+            }
+        }
+        ("sub", &[rd, rs1, rs2]) if rd == rs1 => out.push(("c.sub", vec![rd, rs2])),
+        // the others don't care about the order of their sources
+        ("xor" | "or" | "and", &[rd, rs1, rs2]) if rd == rs1 || rd == rs2 => {
+            let other = if rd == rs1 { rs2 } else { rs1 };
+            out.push((
+                match lname.as_ref() {
+                    "xor" => "c.xor",
+                    "or" => "c.or",
+                    _ => "c.and",
+                },
+                vec![rd, other],
+            ));
+        }
+        ("ebreak", &[]) => out.push(("c.ebreak", vec![])),
+        _ => {}
+    }
+    out
+}
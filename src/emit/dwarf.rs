@@ -0,0 +1,322 @@
+//! Minimal DWARF debug information generation
+
+/// A row of the line number table: code at `address` comes from `line` of `files[file]`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineRow {
+    pub address: u64,
+    pub file: usize,
+    pub line: u64,
+}
+
+/// The source files of the code and the line of each of its instructions
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    /// Names as debuggers are given them, relative ones to `directory`
+    pub files: Vec<String>,
+    pub directory: String,
+    pub producer: String,
+    pub rows: Vec<LineRow>,
+}
+
+/// Contiguous code from `start` to `end`, like a section, and the rows of its lines
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CodeRange {
+    pub start: u64,
+    pub end: u64,
+    pub rows: Vec<LineRow>,
+}
+
+/// A value at `offset` into a debug section that a linker has to relocate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fixup {
+    /// The address `addend` bytes past the start of the code range with index `range`
+    Address {
+        offset: usize,
+        range: usize,
+        addend: u64,
+    },
+    /// A 4-byte offset into the named debug section
+    SectionOffset {
+        offset: usize,
+        section: &'static str,
+    },
+}
+
+/// An encoded debug section and the values in it to relocate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugSection {
+    pub name: &'static str,
+    pub contents: Vec<u8>,
+    pub fixups: Vec<Fixup>,
+}
+
+// Line number program header parameters
+const MIN_INSTRUCTION_LENGTH: u8 = 1;
+const LINE_BASE: i64 = -5;
+const LINE_RANGE: u8 = 14;
+const OPCODE_BASE: u8 = 13;
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+// Standard opcodes
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+// Extended opcodes
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+// Debugging information entry of the compile unit
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_CHILDREN_NO: u8 = 0;
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_LANGUAGE: u64 = 0x13;
+const DW_AT_COMP_DIR: u64 = 0x1b;
+const DW_AT_PRODUCER: u64 = 0x25;
+const DW_AT_RANGES: u64 = 0x55;
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_SEC_OFFSET: u64 = 0x17;
+const DW_LANG_MIPS_ASSEMBLER: u16 = 0x8001;
+
+pub fn write_uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn write_sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_address(out: &mut Vec<u8>, address: u64, address_size: u8) {
+    out.extend_from_slice(&address.to_le_bytes()[..address_size as usize]);
+}
+
+/// Encodes a DWARF 4 `.debug_line` section holding a single sequence that covers the rows
+/// (in any order) and ends at `end_address`. File indices refer to `files`.
+pub fn debug_line(
+    files: &[String],
+    rows: &[LineRow],
+    end_address: u64,
+    address_size: u8,
+) -> Vec<u8> {
+    let range = CodeRange {
+        start: rows.iter().map(|r| r.address).min().unwrap_or(end_address),
+        end: end_address,
+        rows: rows.to_vec(),
+    };
+    debug_line_ranges(files, &[range], address_size).0
+}
+
+/// Encodes a `.debug_line` section with a sequence for each range, and the start addresses of
+/// the sequences to relocate
+fn debug_line_ranges(
+    files: &[String],
+    ranges: &[CodeRange],
+    address_size: u8,
+) -> (Vec<u8>, Vec<Fixup>) {
+    let mut header = vec![
+        MIN_INSTRUCTION_LENGTH,
+        // maximum_operations_per_instruction
+        1,
+        // default_is_stmt
+        1,
+        LINE_BASE as u8,
+        LINE_RANGE,
+        OPCODE_BASE,
+    ];
+    header.extend_from_slice(&STANDARD_OPCODE_LENGTHS);
+    // no include_directories, file names are used as given
+    header.push(0);
+    for file in files.iter() {
+        header.extend_from_slice(file.as_bytes());
+        header.push(0);
+        // directory index, modification time, file length
+        header.extend_from_slice(&[0, 0, 0]);
+    }
+    header.push(0);
+
+    // unit_length, version and header_length come before the header
+    let program_offset = 4 + 2 + 4 + header.len();
+    let mut program = Vec::new();
+    let mut fixups = Vec::new();
+    for (i, range) in ranges.iter().enumerate() {
+        let mut rows = range.rows.clone();
+        rows.sort_by_key(|r| r.address);
+        let mut address = range.start;
+        let mut file = 0;
+        let mut line: u64 = 1;
+        program.push(0);
+        write_uleb128(&mut program, 1 + address_size as u64);
+        program.push(DW_LNE_SET_ADDRESS);
+        fixups.push(Fixup::Address {
+            offset: program_offset + program.len(),
+            range: i,
+            addend: 0,
+        });
+        write_address(&mut program, address, address_size);
+        for row in rows.iter() {
+            if row.file != file {
+                program.push(DW_LNS_SET_FILE);
+                write_uleb128(&mut program, row.file as u64 + 1);
+                file = row.file;
+            }
+            let address_delta = (row.address - address) / MIN_INSTRUCTION_LENGTH as u64;
+            let line_delta = row.line as i64 - line as i64;
+            // a special opcode advances both at once, when the deltas are small enough
+            let special = Some(line_delta - LINE_BASE)
+                .filter(|adjusted| (0..LINE_RANGE as i64).contains(adjusted))
+                .and_then(|adjusted| {
+                    (LINE_RANGE as u64)
+                        .checked_mul(address_delta)?
+                        .checked_add(adjusted as u64 + OPCODE_BASE as u64)
+                })
+                .filter(|special| *special <= 255);
+            if let Some(special) = special {
+                program.push(special as u8);
+            } else {
+                if address_delta != 0 {
+                    program.push(DW_LNS_ADVANCE_PC);
+                    write_uleb128(&mut program, address_delta);
+                }
+                if line_delta != 0 {
+                    program.push(DW_LNS_ADVANCE_LINE);
+                    write_sleb128(&mut program, line_delta);
+                }
+                program.push(DW_LNS_COPY);
+            }
+            address = row.address;
+            line = row.line;
+        }
+        if range.end > address {
+            program.push(DW_LNS_ADVANCE_PC);
+            write_uleb128(
+                &mut program,
+                (range.end - address) / MIN_INSTRUCTION_LENGTH as u64,
+            );
+        }
+        program.extend_from_slice(&[0, 1, DW_LNE_END_SEQUENCE]);
+    }
+
+    let mut out = Vec::new();
+    // unit_length covers everything after itself: version, header_length, header and program
+    let unit_length = 2 + 4 + header.len() + program.len();
+    out.extend_from_slice(&(unit_length as u32).to_le_bytes());
+    out.extend_from_slice(&4u16.to_le_bytes());
+    out.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&program);
+    (out, fixups)
+}
+
+/// The `.debug_info`, `.debug_abbrev`, `.debug_line` and `.debug_ranges` sections of a single
+/// compile unit for the code ranges, named after the first of the files
+pub fn debug_sections(
+    info: &DebugInfo,
+    ranges: &[CodeRange],
+    address_size: u8,
+) -> Vec<DebugSection> {
+    let mut abbrev = Vec::new();
+    write_uleb128(&mut abbrev, 1);
+    write_uleb128(&mut abbrev, DW_TAG_COMPILE_UNIT);
+    abbrev.push(DW_CHILDREN_NO);
+    let attributes = [
+        (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+        (DW_AT_LOW_PC, DW_FORM_ADDR),
+        (DW_AT_RANGES, DW_FORM_SEC_OFFSET),
+        (DW_AT_NAME, DW_FORM_STRING),
+        (DW_AT_COMP_DIR, DW_FORM_STRING),
+        (DW_AT_PRODUCER, DW_FORM_STRING),
+        (DW_AT_LANGUAGE, DW_FORM_DATA2),
+    ];
+    for (attribute, form) in attributes.iter() {
+        write_uleb128(&mut abbrev, *attribute);
+        write_uleb128(&mut abbrev, *form);
+    }
+    // the end of the attributes, then of the abbreviations
+    abbrev.extend_from_slice(&[0, 0, 0]);
+
+    // offsets count from the start of the section, past the unit_length
+    let mut unit = vec![0; 4];
+    let mut unit_fixups = Vec::new();
+    unit.extend_from_slice(&4u16.to_le_bytes());
+    let mut section_offset = |unit: &mut Vec<u8>, section| {
+        unit_fixups.push(Fixup::SectionOffset {
+            offset: unit.len(),
+            section,
+        });
+        unit.extend_from_slice(&0u32.to_le_bytes());
+    };
+    section_offset(&mut unit, ".debug_abbrev");
+    unit.push(address_size);
+    write_uleb128(&mut unit, 1);
+    section_offset(&mut unit, ".debug_line");
+    // the ranges are absolute addresses
+    write_address(&mut unit, 0, address_size);
+    section_offset(&mut unit, ".debug_ranges");
+    let name = info.files.first().map_or("", String::as_str);
+    for string in [name, info.directory.as_str(), info.producer.as_str()].iter() {
+        unit.extend_from_slice(string.as_bytes());
+        unit.push(0);
+    }
+    unit.extend_from_slice(&DW_LANG_MIPS_ASSEMBLER.to_le_bytes());
+    let unit_length = (unit.len() - 4) as u32;
+    unit[..4].copy_from_slice(&unit_length.to_le_bytes());
+
+    let mut range_list = Vec::new();
+    let mut range_fixups = Vec::new();
+    for (i, range) in ranges.iter().enumerate() {
+        for (address, addend) in [(range.start, 0), (range.end, range.end - range.start)].iter() {
+            range_fixups.push(Fixup::Address {
+                offset: range_list.len(),
+                range: i,
+                addend: *addend,
+            });
+            write_address(&mut range_list, *address, address_size);
+        }
+    }
+    // the end of the list
+    range_list.resize(range_list.len() + 2 * address_size as usize, 0);
+
+    let (line, line_fixups) = debug_line_ranges(&info.files, ranges, address_size);
+    vec![
+        DebugSection {
+            name: ".debug_info",
+            contents: unit,
+            fixups: unit_fixups,
+        },
+        DebugSection {
+            name: ".debug_abbrev",
+            contents: abbrev,
+            fixups: Vec::new(),
+        },
+        DebugSection {
+            name: ".debug_line",
+            contents: line,
+            fixups: line_fixups,
+        },
+        DebugSection {
+            name: ".debug_ranges",
+            contents: range_list,
+            fixups: range_fixups,
+        },
+    ]
+}
@@ -0,0 +1,605 @@
+//! ELF32/ELF64 object files, for linking the output or loading it with standard tools
+
+use super::dwarf::{self, CodeRange, DebugInfo, Fixup};
+use super::flatbin::emit_image;
+use super::{
+    relocation_type_number, AssembledImage, EmitError, EmitOptions, OutputBackend, SectionKind,
+    SymbolBinding, SymbolType,
+};
+use crate::arch;
+use crate::parser::Node;
+
+const EM_RISCV: u16 = 243;
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EF_RISCV_RVC: u32 = 0x1;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const STT_SECTION: u8 = 3;
+const R_RISCV_32: u32 = 1;
+const R_RISCV_64: u32 = 2;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ElfKind {
+    /// An object file for a linker, symbol values and relocations are relative to their section
+    Relocatable,
+    /// A file ready to be loaded, with a program header per section and the entry point of the
+    /// image as the entry
+    Executable,
+}
+
+impl ElfKind {
+    /// An executable if the image has an entry point (.entry or `_start`) and leaves no
+    /// relocations for a linker, a relocatable object otherwise
+    pub fn for_image(image: &AssembledImage) -> Self {
+        if image.entry_address().is_some() && image.relocations.is_empty() {
+            ElfKind::Executable
+        } else {
+            ElfKind::Relocatable
+        }
+    }
+}
+
+/// Data sections that aren't writable, by the names GNU as gives them
+pub(crate) fn read_only(name: &str) -> bool {
+    name.starts_with(".rodata") || name.starts_with(".srodata")
+}
+
+/// ELF output, 64-bit when `is_64` is set
+pub struct Elf {
+    pub kind: ElfKind,
+    pub is_64: bool,
+    /// e_flags, like EF_RISCV_RVC for code using compressed instructions
+    pub flags: u32,
+    /// DWARF line number information to add, in `.debug_line` and `.debug_info`
+    pub debug: Option<DebugInfo>,
+}
+
+impl Elf {
+    /// The class and flags matching the assembled architecture: ELF64 for XLEN 64 and above
+    pub fn for_spec(spec: &arch::RiscVSpec, kind: ElfKind) -> Self {
+        Elf {
+            kind,
+            is_64: spec.get_const("XLEN").unwrap_or(32) > 32,
+            flags: if spec.extension_enabled("C") {
+                EF_RISCV_RVC
+            } else {
+                0
+            },
+            debug: None,
+        }
+    }
+}
+
+/// Assembles the source into an ELF file of the kind picked by `ElfKind::for_image`, leaving
+/// the symbols it doesn't define to the linker
+pub fn emit_elf(spec: &arch::RiscVSpec, ast: &Node) -> Result<Vec<u8>, EmitError> {
+    let options = EmitOptions {
+        external_symbols: true,
+        ..Default::default()
+    };
+    let image = emit_image(spec, ast, &options)?;
+    Elf::for_spec(spec, ElfKind::for_image(&image)).serialize(&image)
+}
+
+struct Writer {
+    out: Vec<u8>,
+    is_64: bool,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.out.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.out.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.out.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// An address, offset or size, which take the width of the class
+    fn word(&mut self, v: u64) {
+        if self.is_64 {
+            self.out.extend_from_slice(&v.to_le_bytes());
+        } else {
+            self.u32(v as u32);
+        }
+    }
+
+    /// Pads with zeroes until the position is `rem` modulo `align`
+    fn align_to(&mut self, align: usize, rem: usize) {
+        while self.out.len() % align != rem % align {
+            self.out.push(0);
+        }
+    }
+}
+
+/// A string table under construction
+struct StringTable(Vec<u8>);
+
+impl StringTable {
+    fn new() -> Self {
+        StringTable(vec![0])
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        if s.is_empty() {
+            return 0;
+        }
+        let index = self.0.len() as u32;
+        self.0.extend_from_slice(s.as_bytes());
+        self.0.push(0);
+        index
+    }
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+struct Symbol {
+    name: String,
+    value: u64,
+    size: u64,
+    info: u8,
+    shndx: u16,
+}
+
+impl Elf {
+    fn symbols(&self, image: &AssembledImage) -> Vec<Symbol> {
+        // private labels keep the names they have in the image, telling relocations apart
+        let mut symbols: Vec<Symbol> = image
+            .symbols
+            .iter()
+            .chain(image.private_labels.iter())
+            .map(|(name, addr)| {
+                let section = image.label_section(*addr);
+                let (shndx, value) = match section {
+                    Some(i) if self.kind == ElfKind::Relocatable => {
+                        (i as u16 + 1, *addr - image.sections[i].base)
+                    }
+                    Some(i) => (i as u16 + 1, *addr),
+                    None => (SHN_ABS, *addr),
+                };
+                let kind = image
+                    .symbol_types
+                    .get(name)
+                    .copied()
+                    .unwrap_or(SymbolType::NoType);
+                Symbol {
+                    name: name.clone(),
+                    value,
+                    size: image.symbol_sizes.get(name).copied().unwrap_or(0),
+                    info: image.symbol_binding(name).elf_binding() << 4 | kind.elf_type(),
+                    shndx,
+                }
+            })
+            .collect();
+        // exported and relocated symbols defined elsewhere
+        let undefined = image
+            .symbol_bindings
+            .iter()
+            .filter(|(_, binding)| **binding != SymbolBinding::Local)
+            .map(|(name, _)| name.as_str())
+            .chain(image.relocations.iter().map(|r| r.symbol.as_str()));
+        for name in undefined {
+            if name.is_empty() || symbols.iter().any(|s| s.name == name) {
+                continue;
+            }
+            let binding = match image.symbol_binding(name) {
+                SymbolBinding::Local => SymbolBinding::Global,
+                binding => binding,
+            };
+            symbols.push(Symbol {
+                name: name.to_owned(),
+                value: 0,
+                size: 0,
+                info: binding.elf_binding() << 4,
+                shndx: SHN_UNDEF,
+            });
+        }
+        // local symbols have to come first
+        symbols.sort_by(|a, b| {
+            (a.info >> 4 != 0, a.shndx, a.value, &a.name).cmp(&(
+                b.info >> 4 != 0,
+                b.shndx,
+                b.value,
+                &b.name,
+            ))
+        });
+        symbols
+    }
+}
+
+impl OutputBackend for Elf {
+    fn serialize(&self, image: &AssembledImage) -> Result<Vec<u8>, EmitError> {
+        let executable = self.kind == ElfKind::Executable;
+        let (ehsize, phentsize, shentsize) = if self.is_64 {
+            (64, 56, 64)
+        } else {
+            (52, 32, 40)
+        };
+        let phnum = if executable { image.sections.len() } else { 0 };
+        let mut w = Writer {
+            out: vec![0; ehsize + phnum * phentsize],
+            is_64: self.is_64,
+        };
+        let mut shstrtab = StringTable::new();
+        let mut headers = vec![SectionHeader {
+            name: 0,
+            kind: 0,
+            flags: 0,
+            addr: 0,
+            offset: 0,
+            size: 0,
+            link: 0,
+            info: 0,
+            align: 0,
+            entsize: 0,
+        }];
+
+        for section in image.sections.iter() {
+            // loadable contents have to be congruent to their address modulo the alignment
+            w.align_to(4, section.base as usize);
+            let offset = w.out.len() as u64;
+            // .bss takes no room in the file
+            if section.kind != SectionKind::Bss {
+                let end = section.offset + section.size;
+                w.out
+                    .extend_from_slice(image.bytes.get(section.offset..end).unwrap_or_default());
+            }
+            let flags = match section.kind {
+                SectionKind::Text => SHF_ALLOC | SHF_EXECINSTR,
+                SectionKind::Data if read_only(&section.name) => SHF_ALLOC,
+                SectionKind::Data | SectionKind::Bss => SHF_ALLOC | SHF_WRITE,
+            };
+            headers.push(SectionHeader {
+                name: shstrtab.add(&section.name),
+                kind: if section.kind == SectionKind::Bss {
+                    SHT_NOBITS
+                } else {
+                    SHT_PROGBITS
+                },
+                flags,
+                addr: if executable { section.base } else { 0 },
+                offset,
+                size: section.size as u64,
+                link: 0,
+                info: 0,
+                align: 4,
+                entsize: 0,
+            });
+        }
+
+        // the debug sections, with the sections of code they have lines of
+        let mut code_sections = Vec::new();
+        let mut debug_sections = Vec::new();
+        if let Some(debug) = &self.debug {
+            let mut ranges = Vec::new();
+            for (i, section) in image.sections.iter().enumerate() {
+                let end = section.base + section.size as u64;
+                let rows: Vec<_> = debug
+                    .rows
+                    .iter()
+                    .filter(|r| r.address >= section.base && r.address < end)
+                    .copied()
+                    .collect();
+                if !rows.is_empty() {
+                    code_sections.push(i as u16 + 1);
+                    ranges.push(CodeRange {
+                        start: section.base,
+                        end,
+                        rows,
+                    });
+                }
+            }
+            let address_size = if self.is_64 { 8 } else { 4 };
+            for section in dwarf::debug_sections(debug, &ranges, address_size) {
+                let offset = w.out.len() as u64;
+                w.out.extend_from_slice(&section.contents);
+                let name = shstrtab.add(section.name);
+                debug_sections.push((headers.len() as u16, section));
+                headers.push(SectionHeader {
+                    name,
+                    kind: SHT_PROGBITS,
+                    flags: 0,
+                    addr: 0,
+                    offset,
+                    size: w.out.len() as u64 - offset,
+                    link: 0,
+                    info: 0,
+                    align: 1,
+                    entsize: 0,
+                });
+            }
+        }
+
+        let mut symbols = self.symbols(image);
+        // the debug sections of an object file point into sections through their symbols
+        if !executable {
+            let section_symbols = code_sections
+                .iter()
+                .copied()
+                .chain(debug_sections.iter().map(|(index, _)| *index))
+                .map(|shndx| Symbol {
+                    name: String::new(),
+                    value: 0,
+                    size: 0,
+                    info: STT_SECTION,
+                    shndx,
+                })
+                .collect::<Vec<_>>();
+            symbols.splice(0..0, section_symbols);
+        }
+        let section_symbol = |shndx: u16| {
+            symbols
+                .iter()
+                .position(|s| s.info == STT_SECTION && s.shndx == shndx)
+                .map_or(0, |i| i as u64 + 1)
+        };
+        let symtab_index = headers.len() as u32;
+        let mut strtab = StringTable::new();
+        w.align_to(if self.is_64 { 8 } else { 4 }, 0);
+        let symtab_offset = w.out.len() as u64;
+        // the null symbol
+        w.out
+            .resize(w.out.len() + if self.is_64 { 24 } else { 16 }, 0);
+        for sym in symbols.iter() {
+            let name = strtab.add(image.label_name(&sym.name));
+            w.u32(name);
+            if self.is_64 {
+                w.u8(sym.info);
+                w.u8(0);
+                w.u16(sym.shndx);
+                w.word(sym.value);
+                w.word(sym.size);
+            } else {
+                w.word(sym.value);
+                w.word(sym.size);
+                w.u8(sym.info);
+                w.u8(0);
+                w.u16(sym.shndx);
+            }
+        }
+        let first_global = 1 + symbols.iter().take_while(|s| s.info >> 4 == 0).count();
+        headers.push(SectionHeader {
+            name: shstrtab.add(".symtab"),
+            kind: SHT_SYMTAB,
+            flags: 0,
+            addr: 0,
+            offset: symtab_offset,
+            size: w.out.len() as u64 - symtab_offset,
+            link: symtab_index + 1,
+            info: first_global as u32,
+            align: if self.is_64 { 8 } else { 4 },
+            entsize: if self.is_64 { 24 } else { 16 },
+        });
+        let strtab_offset = w.out.len() as u64;
+        w.out.extend_from_slice(&strtab.0);
+        headers.push(SectionHeader {
+            name: shstrtab.add(".strtab"),
+            kind: SHT_STRTAB,
+            flags: 0,
+            addr: 0,
+            offset: strtab_offset,
+            size: strtab.0.len() as u64,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        });
+
+        for (i, section) in image.sections.iter().enumerate() {
+            let end = section.base + section.size as u64;
+            let relocations: Vec<_> = image
+                .relocations
+                .iter()
+                .chain(image.section_relocations.iter().filter(|_| !executable))
+                .filter(|r| r.offset >= section.base && r.offset < end)
+                .collect();
+            if relocations.is_empty() {
+                continue;
+            }
+            w.align_to(if self.is_64 { 8 } else { 4 }, 0);
+            let offset = w.out.len() as u64;
+            for reloc in relocations {
+                let kind = relocation_type_number(&reloc.kind)
+                    .ok_or_else(|| EmitError::UnknownRelocation(reloc.kind.clone()))?;
+                let sym = match symbols.iter().position(|s| s.name == reloc.symbol) {
+                    Some(index) if !reloc.symbol.is_empty() => index as u64 + 1,
+                    _ => 0,
+                };
+                w.word(if executable {
+                    reloc.offset
+                } else {
+                    reloc.offset - section.base
+                });
+                if self.is_64 {
+                    w.word(sym << 32 | kind as u64);
+                } else {
+                    w.word(sym << 8 | kind as u64);
+                }
+                w.word(reloc.addend as u64);
+            }
+            headers.push(SectionHeader {
+                name: shstrtab.add(&format!(".rela{}", section.name)),
+                kind: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                addr: 0,
+                offset,
+                size: w.out.len() as u64 - offset,
+                link: symtab_index,
+                info: i as u32 + 1,
+                align: if self.is_64 { 8 } else { 4 },
+                entsize: if self.is_64 { 24 } else { 12 },
+            });
+        }
+
+        for (index, section) in debug_sections.iter() {
+            if executable || section.fixups.is_empty() {
+                continue;
+            }
+            w.align_to(if self.is_64 { 8 } else { 4 }, 0);
+            let offset = w.out.len() as u64;
+            for fixup in section.fixups.iter() {
+                let (at, shndx, kind, addend) = match *fixup {
+                    Fixup::Address {
+                        offset,
+                        range,
+                        addend,
+                    } => (
+                        offset,
+                        code_sections[range],
+                        if self.is_64 { R_RISCV_64 } else { R_RISCV_32 },
+                        addend,
+                    ),
+                    Fixup::SectionOffset { offset, section } => {
+                        let target = debug_sections
+                            .iter()
+                            .find(|(_, s)| s.name == section)
+                            .map_or(0, |(index, _)| *index);
+                        (offset, target, R_RISCV_32, 0)
+                    }
+                };
+                w.word(at as u64);
+                let sym = section_symbol(shndx);
+                if self.is_64 {
+                    w.word(sym << 32 | kind as u64);
+                } else {
+                    w.word(sym << 8 | kind as u64);
+                }
+                w.word(addend);
+            }
+            headers.push(SectionHeader {
+                name: shstrtab.add(&format!(".rela{}", section.name)),
+                kind: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                addr: 0,
+                offset,
+                size: w.out.len() as u64 - offset,
+                link: symtab_index,
+                info: *index as u32,
+                align: if self.is_64 { 8 } else { 4 },
+                entsize: if self.is_64 { 24 } else { 12 },
+            });
+        }
+
+        let shstrndx = headers.len();
+        let shstrtab_name = shstrtab.add(".shstrtab");
+        let shstrtab_offset = w.out.len() as u64;
+        w.out.extend_from_slice(&shstrtab.0);
+        headers.push(SectionHeader {
+            name: shstrtab_name,
+            kind: SHT_STRTAB,
+            flags: 0,
+            addr: 0,
+            offset: shstrtab_offset,
+            size: shstrtab.0.len() as u64,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        });
+
+        w.align_to(if self.is_64 { 8 } else { 4 }, 0);
+        let shoff = w.out.len() as u64;
+        for header in headers.iter() {
+            w.u32(header.name);
+            w.u32(header.kind);
+            w.word(header.flags);
+            w.word(header.addr);
+            w.word(header.offset);
+            w.word(header.size);
+            w.u32(header.link);
+            w.u32(header.info);
+            w.word(header.align);
+            w.word(header.entsize);
+        }
+
+        // the file and program headers, now that the offsets are known
+        let body = std::mem::take(&mut w.out);
+        w.out.extend_from_slice(&[0x7f, b'E', b'L', b'F']);
+        w.u8(if self.is_64 { 2 } else { 1 });
+        // little endian, current version, System V ABI
+        w.u8(1);
+        w.u8(1);
+        w.out.resize(16, 0);
+        w.u16(if executable { ET_EXEC } else { ET_REL });
+        w.u16(EM_RISCV);
+        w.u32(1);
+        let entry = image
+            .entry_address()
+            .unwrap_or_else(|| image.sections.first().map_or(0, |s| s.base));
+        w.word(if executable { entry } else { 0 });
+        w.word(if executable { ehsize as u64 } else { 0 });
+        w.word(shoff);
+        w.u32(self.flags);
+        w.u16(ehsize as u16);
+        w.u16(if executable { phentsize as u16 } else { 0 });
+        w.u16(phnum as u16);
+        w.u16(shentsize as u16);
+        w.u16(headers.len() as u16);
+        w.u16(shstrndx as u16);
+        for (section, header) in image
+            .sections
+            .iter()
+            .zip(headers.iter().skip(1))
+            .take(phnum)
+        {
+            let flags = if header.flags & SHF_EXECINSTR != 0 {
+                PF_R | PF_X
+            } else if header.flags & SHF_WRITE != 0 {
+                PF_R | PF_W
+            } else {
+                PF_R
+            };
+            w.u32(PT_LOAD);
+            if self.is_64 {
+                w.u32(flags);
+            }
+            w.word(header.offset);
+            w.word(section.base);
+            w.word(section.base);
+            w.word(if header.kind == SHT_NOBITS {
+                0
+            } else {
+                header.size
+            });
+            w.word(header.size);
+            if !self.is_64 {
+                w.u32(flags);
+            }
+            w.word(4);
+        }
+        let mut out = body;
+        out[..w.out.len()].copy_from_slice(&w.out);
+        Ok(out)
+    }
+}
@@ -1,129 +1,3105 @@
+use super::checksum::ChecksumAlgorithm;
+use super::compress::{compress, compressible, COMPRESSED_BYTES};
+use super::pseudo::{expand_pseudo, long_branch, relaxation, LongBranch, Relaxation};
+use super::{
+    relocation_type_number, AssembledImage, Diagnostic, EmitError, EmitOptions, EmittedElement,
+    EmittedInstruction, ImageSection, Operand, OutputBackend, Relocation, SectionKind,
+    SymbolBinding, SymbolList, SymbolType,
+};
 use crate::arch;
+use crate::disasm;
+use crate::intern::{ascii_lowercase, Symbol};
 use crate::parser::Node;
 use smallvec::SmallVec;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-#[derive(Clone, Debug)]
-pub enum EmitError {
-    UnexpectedNodeType(String),
-    InvalidInstruction(String),
-    InvalidArgumentCount(String),
-    InvalidArgumentType(String, usize),
-    InvalidEncoding(String),
-    DuplicateLabel(String),
-    DuplicateConstant(String),
+/// Raw memory image output, written as-is starting from address 0
+pub struct FlatBinary;
+
+impl OutputBackend for FlatBinary {
+    fn serialize(&self, image: &AssembledImage) -> Result<Vec<u8>, EmitError> {
+        Ok(image.bytes.clone())
+    }
 }
 
 pub fn emit_flat_binary(spec: &arch::RiscVSpec, ast: &Node) -> Result<Vec<u8>, EmitError> {
-    let mut state = BinaryEmitState {
-        out_buf: Vec::new(),
-        out_pos: 0,
-        deferred: Vec::new(),
-        label_set: HashMap::new(),
-        local_label_set: HashMap::new(),
-        const_set: HashMap::new(),
+    emit_image(spec, ast, &EmitOptions::default()).map(|image| image.bytes)
+}
+
+/// Raw memory image with what's needed to link it against other code and to start it
+#[derive(Clone, Debug, Default)]
+pub struct LinkableBinary {
+    pub bytes: Vec<u8>,
+    /// Symbols declared .global or .weak, sorted by address
+    pub symbols: SymbolList,
+    /// Where execution starts, from .entry or `_start`
+    pub entry: Option<u64>,
+}
+
+/// Same as emit_flat_binary, also returning the addresses of the symbols declared .global or
+/// .weak and the entry point. Declaring a symbol that's never defined is an error.
+pub fn emit_flat_binary_with_symbols(
+    spec: &arch::RiscVSpec,
+    ast: &Node,
+) -> Result<LinkableBinary, EmitError> {
+    let image = emit_image(spec, ast, &EmitOptions::default())?;
+    Ok(LinkableBinary {
+        symbols: image.exported_symbols()?,
+        entry: image.entry_address(),
+        bytes: image.bytes,
+    })
+}
+
+pub fn emit_image(
+    spec: &arch::RiscVSpec,
+    ast: &Node,
+    options: &EmitOptions,
+) -> Result<AssembledImage, EmitError> {
+    emit_image_with_hook(spec, ast, options, None)
+}
+
+/// Emits several parsed files (their names and Roots) as one image. Labels are shared between
+/// the files (only the exported ones with EmitOptions.file_private_labels), while local labels
+/// and conditional blocks end with the file defining them. The files are emitted on
+/// EmitOptions.jobs threads and linked (see link::emit_separately), or one after the other when
+/// a file depends on the ones before, like by using their constants or macros. Either way a
+/// file starts where the one before ends.
+pub fn emit_image_files(
+    spec: &arch::RiscVSpec,
+    files: &[(String, Node)],
+    options: &EmitOptions,
+) -> Result<AssembledImage, EmitError> {
+    let private: Vec<(String, Node)>;
+    let mut hidden = HashSet::new();
+    let files = if options.file_private_labels && files.len() > 1 {
+        let renamed = crate::parallel::map(options.jobs, files.iter().collect(), |(name, ast)| {
+            let (ast, names) = hide_private_labels(name, ast, options)?;
+            Ok(((name.clone(), ast), names))
+        });
+        let renamed = renamed.into_iter().collect::<Result<Vec<_>, EmitError>>()?;
+        private = renamed
+            .into_iter()
+            .map(|(file, names)| {
+                hidden.extend(names);
+                file
+            })
+            .collect();
+        &private
+    } else {
+        files
+    };
+    let mut image = match super::link::emit_separately(spec, files, options) {
+        Some(image) => image,
+        None => {
+            let emit_files = |state: &mut BinaryEmitState<'_>| {
+                for (name, ast) in files.iter() {
+                    state.current_file = Some(name.clone());
+                    emit_file(spec, state, ast)?;
+                    emit_deferred(spec, state)?;
+                    state.local_label_set.clear();
+                }
+                finish_deferred(spec, state)
+            };
+            let emit = |state: &mut BinaryEmitState<'_>| {
+                let result = emit_files(state);
+                state.with_errors(result)
+            };
+            emit_looking_ahead(spec, options, None, &emit)?.into_image(spec)?
+        }
+    };
+    for name in hidden {
+        if let Some(address) = image.symbols.remove(&name) {
+            image.private_labels.insert(name, address);
+        }
+    }
+    Ok(image)
+}
+
+/// Emits the elements of one of the files of emit_image_files, which must close its conditional
+/// blocks and macros
+fn emit_file(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+    ast: &Node,
+) -> Result<(), EmitError> {
+    match ast {
+        Node::Root(nodes) => {
+            for node in nodes.iter() {
+                let result = emit_element(spec, state, node);
+                state.recover(node, result)?;
+            }
+        }
+        _ => emit_element(spec, state, ast)?,
+    }
+    if let Some(frame) = state.conditionals.last() {
+        return Err(frame.unterminated());
+    }
+    if let Some(collecting) = state.macro_definition.as_ref() {
+        return Err(collecting.unclosed());
+    }
+    Ok(())
+}
+
+/// Emits one of the files of emit_image_files on its own from `base`, for link::emit_separately.
+/// The labels it doesn't define are relocated like with EmitOptions.external_symbols, the uses
+/// of its own ones are kept as they are.
+pub(super) fn emit_unit(
+    spec: &arch::RiscVSpec,
+    name: &str,
+    ast: &Node,
+    base: u64,
+    options: &EmitOptions,
+) -> Result<AssembledImage, EmitError> {
+    let options = EmitOptions {
+        external_symbols: true,
+        strip_local: false,
+        ..options.clone()
+    };
+    let emit = |state: &mut BinaryEmitState<'_>| {
+        state.unit = true;
+        if base != 0 {
+            state.placements = vec![(0, base)];
+        }
+        state.current_file = Some(name.to_owned());
+        let result = emit_file(spec, state, ast).and_then(|()| finish_deferred(spec, state));
+        state.with_errors(result)
+    };
+    emit_looking_ahead(spec, &options, None, &emit)?.into_image(spec)
+}
+
+/// Renames the labels the file defines without declaring them .global or .weak to `NAME@FILE`
+/// (which no identifier can be), in their definitions and every use in the file, returning the
+/// new names too. The file name is written with `/`, as a `\` would be taken for a macro
+/// parameter.
+fn hide_private_labels(
+    file: &str,
+    ast: &Node,
+    options: &EmitOptions,
+) -> Result<(Node, Vec<String>), EmitError> {
+    let elements = match ast {
+        Node::Root(nodes) => nodes.as_slice(),
+        node => std::slice::from_ref(node),
+    };
+    let mut defined = HashSet::new();
+    let mut exported = HashSet::new();
+    for element in elements.iter() {
+        match element {
+            Node::Located(_, box Node::Label(name)) | Node::Label(name) => {
+                let numeric = name.starts_with(|c: char| c.is_ascii_digit());
+                if !numeric && !options.local_labels.is_local(name) {
+                    defined.insert(name.clone());
+                }
+            }
+            Node::Located(_, box Node::Instruction(iname, args))
+            | Node::Instruction(iname, args)
+                if [".global", ".globl", ".weak"]
+                    .iter()
+                    .any(|d| d.eq_ignore_ascii_case(iname)) =>
+            {
+                for arg in args.iter() {
+                    if let Node::Argument(box Node::Identifier(name)) = arg {
+                        exported.insert(name.clone());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let qualified = |name: &Symbol| -> Option<Symbol> {
+        (defined.contains(name) && !exported.contains(name))
+            .then(|| format!("{}@{}", name, file.replace('\\', "/")).into())
+    };
+    let ast = ast.rewrite(&mut |node| {
+        Ok(match &node {
+            Node::Identifier(name) => qualified(name).map_or(node, Node::Identifier),
+            Node::Label(name) => qualified(name).map_or(node, Node::Label),
+            _ => node,
+        })
+    })?;
+    let names = defined
+        .iter()
+        .filter_map(qualified)
+        .map(|name| name.to_string());
+    Ok((ast, names.collect()))
+}
+
+/// Called with the address, mnemonic and final bytes of every emitted instruction
+pub type InstructionHook<'a> = &'a mut dyn FnMut(u64, &str, &[u8]);
+
+/// What the emitter did, in the order it happened
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmitEvent<'a> {
+    /// An instruction with its final bytes and the values it was encoded with, in the order of
+    /// the args of its definition (registers by number)
+    Instruction {
+        address: u64,
+        mnemonic: &'a str,
+        argv: &'a [u64],
+        bytes: &'a [u8],
+    },
+    /// .org moving the position
+    Org { from: u64, to: u64 },
+    /// .equ, .define or .set giving a constant its value
+    Constant { name: &'a str, value: u64 },
+}
+
+/// Called with every EmitEvent
+pub type EventHook<'a> = &'a mut dyn FnMut(&EmitEvent);
+
+/// Same as emit_image, also reporting each instruction to `on_instruction` once its bytes are
+/// final. Deferred instructions are reported when they get resolved, so addresses can come out
+/// of order.
+pub fn emit_image_with_hook(
+    spec: &arch::RiscVSpec,
+    ast: &Node,
+    options: &EmitOptions,
+    on_instruction: Option<InstructionHook>,
+) -> Result<AssembledImage, EmitError> {
+    match on_instruction {
+        Some(hook) => {
+            let mut on_event = |event: &EmitEvent| {
+                if let EmitEvent::Instruction {
+                    address,
+                    mnemonic,
+                    bytes,
+                    ..
+                } = event
+                {
+                    hook(*address, mnemonic, bytes);
+                }
+            };
+            emit_image_with_events(spec, ast, options, Some(&mut on_event))
+        }
+        None => emit_image_with_events(spec, ast, options, None),
+    }
+}
+
+/// Same as emit_image, also reporting what the emitter does to `on_event`, to follow how a
+/// spec encodes its instructions. Deferred instructions are reported once, when they get
+/// resolved, and the passes of EmitOptions.relax aren't reported at all.
+pub fn emit_image_with_events(
+    spec: &arch::RiscVSpec,
+    ast: &Node,
+    options: &EmitOptions,
+    on_event: Option<EventHook>,
+) -> Result<AssembledImage, EmitError> {
+    let emit = |state: &mut BinaryEmitState<'_>| emit_binary_recurse(spec, state, ast);
+    emit_looking_ahead(spec, options, on_event, &emit)?.into_image(spec)
+}
+
+/// Runs passes of `emit` (relaxed like EmitOptions.relax asks) until the values taken for
+/// labels and constants further ahead, where a value was needed at once, are the ones they end
+/// up with: each pass takes them from the end of the one before. A source not doing so takes a
+/// single pass, unless `on_event` has to see the events of the last one only.
+fn emit_looking_ahead<'a>(
+    spec: &arch::RiscVSpec,
+    options: &EmitOptions,
+    on_event: Option<EventHook<'a>>,
+    emit: &dyn Fn(&mut BinaryEmitState<'_>) -> Result<(), EmitError>,
+) -> Result<BinaryEmitState<'a>, EmitError> {
+    let mut ahead = HashMap::new();
+    let mut passes = 0;
+    loop {
+        let mut pass = BinaryEmitState::new(options, None);
+        pass.looking_ahead = true;
+        pass.ahead = ahead.clone();
+        let result = relax(spec, &mut pass, emit).and_then(|()| emit(&mut pass));
+        passes += 1;
+        let values = pass.symbol_values();
+        let unsettled: Vec<&(String, Option<u64>, u64)> = pass
+            .looked_ahead
+            .iter()
+            .filter(|(sym, taken, _)| taken.is_none() || values.get(sym) != taken.as_ref())
+            .collect();
+        if unsettled.is_empty() {
+            result?;
+            if on_event.is_none() {
+                if passes > 1 {
+                    pass.info(
+                        1,
+                        format!(
+                            "Looked ahead for the values of symbols in {} passes",
+                            passes
+                        ),
+                    );
+                }
+                return Ok(pass);
+            }
+            break;
+        }
+        // an error before the symbols got defined may go away with their values, otherwise the
+        // ones the whole source doesn't define never get one
+        match result {
+            Err(e) if values == ahead => return Err(e),
+            Err(_) => {}
+            Ok(()) => {
+                if let Some((sym, _, pos)) =
+                    unsettled.iter().find(|(s, _, _)| !values.contains_key(s))
+                {
+                    let similar = pass.suggest_symbol(spec, sym);
+                    return Err(EmitError::UnresolvedSymbol(
+                        source_symbol_name(sym),
+                        *pos,
+                        similar,
+                    ));
+                }
+            }
+        }
+        if passes >= options.max_resolution_passes {
+            let mut symbols: Vec<String> = unsettled.iter().map(|(s, _, _)| s.clone()).collect();
+            symbols.sort();
+            symbols.dedup();
+            return Err(EmitError::ResolutionDidNotConverge(symbols));
+        }
+        ahead = values;
+    }
+    let mut state = BinaryEmitState::new(options, on_event);
+    state.looking_ahead = true;
+    state.ahead = ahead;
+    relax(spec, &mut state, emit)?;
+    emit(&mut state)?;
+    state.info(
+        1,
+        format!(
+            "Looked ahead for the values of symbols in {} passes",
+            passes
+        ),
+    );
+    Ok(state)
+}
+
+/// With EmitOptions.relax, picks the call, tail and la/lla sites and the compressible
+/// instructions waiting for labels that `state` emits in their short form (only the latter
+/// with EmitOptions.relax_compressed), and with EmitOptions.relax_branches the branches and
+/// jumps it emits in their long form because their target is out of reach. The source gets
+/// emitted by `emit` over and over, each time shortening whatever reaches its target (or fits
+/// the compressed form), which brings other targets closer, until nothing changes. A short site
+/// that stops reaching, like when less code before an .align means more padding, keeps its
+/// long form from then on, so this always ends.
+fn relax(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+    emit: &dyn Fn(&mut BinaryEmitState<'_>) -> Result<(), EmitError>,
+) -> Result<(), EmitError> {
+    if !state.options.relax && !state.options.relax_compressed && !state.options.relax_branches {
+        return Ok(());
+    }
+    let mut relaxed = HashSet::new();
+    let mut kept_long = HashSet::new();
+    let mut lengthened = HashSet::new();
+    let mut passes = 0;
+    let sites = loop {
+        let mut pass = BinaryEmitState::new(&state.options, None);
+        pass.looking_ahead = state.looking_ahead;
+        pass.ahead = state.ahead.clone();
+        pass.relaxed = relaxed.clone();
+        pass.lengthened = lengthened.clone();
+        let result = emit(&mut pass);
+        passes += 1;
+        let mut next = HashSet::new();
+        let mut longer = lengthened.clone();
+        for (key, pc, site) in pass.relax_sites.iter() {
+            if let RelaxSite::Branch(_) = site {
+                // lengthened for good, so this always ends
+                if pass.shortens(spec, *pc, site) == Some(false) {
+                    longer.insert(*key);
+                }
+                continue;
+            }
+            match pass.shortens(spec, *pc, site) {
+                Some(true) => {
+                    if !kept_long.contains(key) {
+                        next.insert(*key);
+                    }
+                }
+                Some(false) => {
+                    if relaxed.contains(key) {
+                        kept_long.insert(*key);
+                    }
+                }
+                // the pass failed before the target got defined
+                None => {
+                    if relaxed.contains(key) {
+                        next.insert(*key);
+                    }
+                }
+            }
+        }
+        let grown = longer != lengthened;
+        match result {
+            Ok(()) if next == relaxed && !grown => break pass.relax_sites.len(),
+            // the values looked ahead with may be what failed, for the next look-ahead pass
+            Err(_) if !pass.looked_ahead.is_empty() => break pass.relax_sites.len(),
+            // errors that still show up without relaxing anything aren't caused by it
+            Err(e) if !grown && (relaxed.is_empty() || next == relaxed) => return Err(e),
+            _ => {
+                relaxed = next;
+                lengthened = longer;
+            }
+        }
     };
-    emit_binary_recurse(spec, &mut state, ast).map(move |_| state.out_buf)
+    state.info(
+        1,
+        format!(
+            "Shortened {} and lengthened {} of {} call/tail/la sequences, branches and instructions in {} passes",
+            relaxed.len(),
+            lengthened.len(),
+            sites,
+            passes
+        ),
+    );
+    state.relaxed = relaxed;
+    state.lengthened = lengthened;
+    Ok(())
+}
+
+/// Emitter fed with one piece of source at a time (like lines typed into an interactive
+/// assembler), keeping the labels, constants and output between calls. Lines in other sections
+/// than the code are only emitted by `finish`.
+pub struct IncrementalEmitter {
+    state: BinaryEmitState<'static>,
+}
+
+impl IncrementalEmitter {
+    pub fn new(options: &EmitOptions) -> Self {
+        IncrementalEmitter {
+            state: BinaryEmitState::new(options, None),
+        }
+    }
+
+    /// Emits the elements of `node` (usually the Root parsed from a line) and returns the bytes
+    /// written starting at the current position. Instructions waiting for labels stay zeroes in
+    /// the returned bytes and get patched in `bytes()` once a later line defines the labels.
+    /// If an error is returned, the elements before the failing one are still emitted.
+    pub fn emit_line(&mut self, spec: &arch::RiscVSpec, node: &Node) -> Result<Vec<u8>, EmitError> {
+        let state = &mut self.state;
+        let start = state.out_pos;
+        match node {
+            Node::Root(nodes) => {
+                for node in nodes.iter() {
+                    emit_element(spec, state, node)?;
+                }
+            }
+            _ => emit_element(spec, state, node)?,
+        }
+        emit_deferred(spec, state)?;
+        let end = state.out_pos;
+        if end > start {
+            Ok(state.out_buf[start..end].to_vec())
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    /// The whole output so far
+    pub fn bytes(&self) -> &[u8] {
+        &self.state.out_buf
+    }
+
+    /// Number of instructions still waiting for labels
+    pub fn pending(&self) -> usize {
+        self.state.deferred.len()
+    }
+
+    /// Resolves what's left like at the end of a file and returns the final image
+    pub fn finish(mut self, spec: &arch::RiscVSpec) -> Result<AssembledImage, EmitError> {
+        finish_deferred(spec, &mut self.state)?;
+        self.state.into_image(spec)
+    }
+}
+
+/// Most macro expansions nested in each other, only reached by macros calling themselves
+const MAX_MACRO_DEPTH: usize = 64;
+
+/// A macro defined by .macro NAME PARAMS... and the lines up to its .endm
+#[derive(Clone, Debug)]
+struct Macro {
+    params: Vec<String>,
+    /// Values of the parameters given as PARAM=DEFAULT, for calls leaving them out
+    defaults: Vec<Option<Node>>,
+    body: Vec<Node>,
+}
+
+/// Arguments of each expansion of the body of a .rept or .irp
+enum Repetitions {
+    /// .rept's count, expanding the body without arguments
+    Count(u64),
+    /// .irp's value of its parameter for each expansion
+    Values(Vec<Vec<Node>>),
+}
+
+/// A .macro whose body is still being collected, or the body of a .rept or .irp
+struct MacroDefinition {
+    /// Name of the macro, or the repetition directive
+    name: String,
+    definition: Macro,
+    /// For .rept and .irp, the directive and the arguments of each expansion of the body at
+    /// its .endr, instead of defining a macro
+    repetition: Option<(Node, Repetitions)>,
+    /// Directives in the body waiting for their own .endm (or .endr)
+    nested: usize,
+}
+
+impl MacroDefinition {
+    /// Directives opening and closing the collected body
+    fn delimiters(&self) -> (&'static [&'static str], &'static str) {
+        if self.repetition.is_some() {
+            (&[".rept", ".irp"], ".endr")
+        } else {
+            (&[".macro"], ".endm")
+        }
+    }
+
+    fn unclosed(&self) -> EmitError {
+        match &self.repetition {
+            Some((Node::Located(offset, _), _)) => {
+                EmitError::UnbalancedMacro(self.name.clone()).at(*offset)
+            }
+            Some(_) => EmitError::UnbalancedMacro(self.name.clone()),
+            None => EmitError::UnbalancedMacro(".macro".to_owned()),
+        }
+    }
+}
+
+/// Items waiting for symbols, like instructions and data with the address they get emitted at.
+/// Each one is filed under a symbol it's still missing, so defining a label only retries what
+/// waits for it instead of everything deferred.
+struct Deferred<T> {
+    /// In the order they were deferred, None once emitted
+    entries: Vec<Option<T>>,
+    len: usize,
+    /// Entries by the symbol they wait for
+    waiting: HashMap<Symbol, Vec<usize>>,
+    /// Entries whose symbol got defined, to be retried by the next emit_deferred
+    ready: Vec<usize>,
+}
+
+impl<T> Default for Deferred<T> {
+    fn default() -> Self {
+        Deferred {
+            entries: Vec::new(),
+            len: 0,
+            waiting: HashMap::new(),
+            ready: Vec::new(),
+        }
+    }
+}
+
+impl<T> Deferred<T> {
+    fn push(&mut self, entry: T, missing: Option<Symbol>) {
+        let index = self.entries.len();
+        self.entries.push(Some(entry));
+        self.len += 1;
+        self.wait(index, missing);
+    }
+
+    /// Like push, but retried by the next take_ready, for items that may resolve already
+    fn push_ready(&mut self, entry: T) {
+        self.ready.push(self.entries.len());
+        self.push(entry, None);
+    }
+
+    /// Files the entry under the symbol it's missing. Entries missing none only fail for
+    /// other reasons (like a division by zero), and are left for the last sweep.
+    fn wait(&mut self, index: usize, missing: Option<Symbol>) {
+        if let Some(symbol) = missing {
+            self.waiting.entry(symbol).or_default().push(index);
+        }
+    }
+
+    /// Marks the entries waiting for `symbol` as ready to be retried
+    fn defined(&mut self, symbol: &Symbol) {
+        if let Some(indices) = self.waiting.remove(symbol) {
+            self.ready.extend(indices);
+        }
+    }
+
+    /// Marks all the entries as ready, for the sweeps at the end
+    fn retry_all(&mut self) {
+        self.waiting.clear();
+        self.ready = (0..self.entries.len())
+            .filter(|i| self.entries[*i].is_some())
+            .collect();
+    }
+
+    /// The ready entries in the order they were deferred
+    fn take_ready(&mut self) -> Vec<usize> {
+        let mut ready = std::mem::take(&mut self.ready);
+        ready.sort_unstable();
+        ready.dedup();
+        ready
+    }
+
+    fn get(&self, index: usize) -> Option<&T> {
+        self.entries[index].as_ref()
+    }
+
+    fn remove(&mut self, index: usize) -> Option<T> {
+        let entry = self.entries[index].take();
+        if entry.is_some() {
+            self.len -= 1;
+        }
+        if self.len == 0 {
+            self.entries.clear();
+        }
+        entry
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.iter().flatten()
+    }
 }
 
-#[derive(Debug)]
-struct BinaryEmitState {
+/// An open .if block
+#[derive(Copy, Clone, Debug)]
+struct Conditional {
+    /// Whether the code around the block is emitted
+    enclosing: bool,
+    /// Whether one of the branches was already chosen
+    taken: bool,
+    /// Whether the current branch is emitted
+    active: bool,
+    seen_else: bool,
+    /// The directive opening the block and where it is in the source, for reporting it unclosed
+    opened_by: &'static str,
+    opened_at: Option<usize>,
+}
+
+/// Directives opening a conditional block: .if takes any condition, .ifeq and the others
+/// compare its value (signed) with zero
+const CONDITIONAL_OPENERS: &[&str] = &[
+    ".if", ".ifdef", ".ifndef", ".ifeq", ".ifne", ".ifgt", ".ifge", ".iflt", ".ifle",
+];
+
+impl Conditional {
+    /// The error for the block still being open at the end of its file
+    fn unterminated(&self) -> EmitError {
+        let error = EmitError::UnbalancedConditional(self.opened_by.to_owned());
+        match self.opened_at {
+            Some(offset) => error.at(offset),
+            None => error,
+        }
+    }
+}
+
+/// Kind of contents currently being emitted, deciding how gaps get filled
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum EmitMode {
+    /// Code, padded with NOPs
+    Text,
+    /// Data, padded with zeroes
+    Data,
+}
+
+/// A section other than the code, whose elements are collected to be emitted after it
+struct QueuedSection {
+    name: String,
+    kind: SectionKind,
+    /// Top-level elements in source order, with the file they are from
+    elements: Vec<(Option<String>, Node)>,
+}
+
+/// Space reserved by .checksum, filled in once the whole image is final
+struct PendingChecksum {
+    position: usize,
+    start: Node,
+    end: Node,
+    algorithm: ChecksumAlgorithm,
+}
+
+/// Something `relax` can shorten (or has to lengthen) once the addresses are known
+enum RelaxSite {
+    /// call/tail/la/lla, see pseudo::relaxation
+    Pseudo(Relaxation),
+    /// Instruction waiting for labels, compressed if its operands turn out to fit
+    Compress(Node),
+    /// Branch or jal, replaced by its long form if the target is out of its reach
+    Branch(LongBranch),
+}
+
+/// Identifies a relaxation site across the passes of `relax`. Pseudo-instructions are counted,
+/// instructions by the pseudo-instruction sites before them, whether they come from the
+/// expansion of the last one (and its form) and their count since, so shortening one site
+/// doesn't change which instructions the ones after it are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum SiteKey {
+    Pseudo(usize),
+    Instruction(usize, Option<bool>, usize),
+}
+
+struct BinaryEmitState<'a> {
+    options: EmitOptions,
+    on_event: Option<EventHook<'a>>,
+    current_mode: EmitMode,
+    /// Index into `sections` of the section being written, None for the code
+    current_section: Option<usize>,
+    sections: Vec<QueuedSection>,
+    /// Sections after the code once emitted, and the end of the code before them
+    laid_out: Vec<ImageSection>,
+    code_end: Option<usize>,
     out_buf: Vec<u8>,
+    /// AssembledImage.padding so far
+    padding: usize,
     out_pos: usize,
-    deferred: Vec<(usize, Node)>,
+    /// Start in out_buf and address of each part of the output that EmitOptions.layout placed
+    /// at another address than its offset, sorted by their start
+    placements: Vec<(usize, u64)>,
+    /// Ranges of out_buf that were emitted into, unlike the gaps left by .org
+    written: Vec<std::ops::Range<usize>>,
+    /// End of the last byte emitted in text mode
+    text_end: usize,
+    deferred: Deferred<(usize, Node)>,
     label_set: HashMap<String, u64>,
+    /// File each label was defined in, when emitting several files
+    label_files: HashMap<String, String>,
+    current_file: Option<String>,
     local_label_set: HashMap<String, u64>,
+    /// Every local label defined, kept after their scope ends for the symbol map
+    local_labels: SymbolList,
+    /// Numeric labels like `1:`, by their name and the number of the definition, like `1:0`
+    numeric_label_set: HashMap<String, u64>,
+    /// How many times each numeric label was defined so far, in the order of the source
+    numeric_label_counts: HashMap<String, usize>,
+    /// Whether values needed at once can take symbols further ahead from `ahead`, in the passes
+    /// of emit_looking_ahead
+    looking_ahead: bool,
+    /// Values of the labels and constants at the end of the pass before
+    ahead: HashMap<String, u64>,
+    /// Symbols further ahead taken from `ahead` (None if the pass before didn't define them),
+    /// with the position using them
+    looked_ahead: Vec<(String, Option<u64>, u64)>,
     const_set: HashMap<String, u64>,
+    /// Constants defined with .set, which can be defined again
+    set_symbols: HashSet<String>,
+    /// Constants naming registers, like `.equ TMP, t0`
+    register_aliases: HashMap<String, i32>,
+    relocations: Vec<Relocation>,
+    /// Relocations of the uses of labels a linker placing the sections breaks
+    section_relocations: Vec<Relocation>,
+    /// Relocations with offsets still waiting for labels
+    pending_relocations: Deferred<(Node, Relocation)>,
+    symbol_types: HashMap<String, SymbolType>,
+    symbol_sizes: HashMap<String, u64>,
+    symbol_bindings: HashMap<String, SymbolBinding>,
+    /// Where each exported symbol was declared .global or .weak
+    export_positions: HashMap<String, u64>,
+    /// Label named by .entry and where it was
+    entry: Option<(String, u64)>,
+    /// Symbol sizes with expressions still waiting for labels
+    pending_sizes: Deferred<(String, Node)>,
+    /// Values of `li` still waiting for labels, with where the `li` is in the source, checked to
+    /// fit the `lui`+`addi` it took once they resolve
+    pending_li_values: Deferred<(Node, Option<usize>)>,
+    pending_checksums: Vec<PendingChecksum>,
+    /// Targets of the `%pcrel_hi` operands by the address of their instruction, for `%pcrel_lo`
+    pcrel_hi_targets: HashMap<u64, Node>,
+    /// Address of the auipc each `%pcrel_lo` operand refers to, by the address of its instruction
+    pcrel_lo_auipcs: HashMap<u64, u64>,
+    /// With EmitOptions.external_symbols, the instructions and data emitted using labels, with the
+    /// operands using them and the addresses of the scope-local ones among them
+    label_uses: Vec<(usize, Node, LabelUses)>,
+    /// Source offset of the top-level element being emitted
+    location: Option<usize>,
+    /// Set while deferred instructions get emitted
+    resolving_deferred: bool,
+    /// Set while emitting a file on its own with emit_unit
+    unit: bool,
+    /// First of the written ranges the section being laid out may not overwrite, overlaps with
+    /// the ones before are between sections and get reported as such
+    region_start: usize,
+    /// Relaxation sites emitted in their short form, see `relax`
+    relaxed: HashSet<SiteKey>,
+    /// Branch sites emitted in their long form
+    lengthened: HashSet<SiteKey>,
+    /// Every relaxation site met so far with its address
+    relax_sites: Vec<(SiteKey, u64, RelaxSite)>,
+    /// Relaxation sites of pseudo-instructions so far
+    pseudo_sites: usize,
+    /// Whether the expansion of the last pseudo-instruction site is being emitted, and in which
+    /// form (true for the short one)
+    expanding_site: Option<bool>,
+    /// Instruction sites since the last pseudo-instruction site or its expansion
+    instruction_sites: usize,
+    /// Positions of the deferred instructions reserved only the space of their compressed form
+    compressed_deferred: HashSet<usize>,
+    /// Errors of the elements gone past, up to EmitOptions.max_errors
+    errors: Vec<EmitError>,
+    /// Compressed forms are picked (with the C extension), turned off by .option norvc
+    rvc: bool,
+    /// Settings saved by .option push: rvc and EmitOptions.pic
+    option_stack: Vec<(bool, bool)>,
+    instructions: Vec<EmittedInstruction>,
+    elements: Vec<EmittedElement>,
+    conditionals: Vec<Conditional>,
+    macros: HashMap<String, Macro>,
+    macro_definition: Option<MacroDefinition>,
+    /// Macro expansions the current element is nested in, and all expansions so far
+    macro_depth: usize,
+    macro_expansions: usize,
+    diagnostics: Vec<Diagnostic>,
+    /// Output size at which the next progress report is due
+    next_progress: usize,
 }
 
-impl BinaryEmitState {
-    fn accomodate_bytes(&mut self, byte_count: usize) -> &mut [u8] {
+impl<'a> BinaryEmitState<'a> {
+    fn new(options: &EmitOptions, on_event: Option<EventHook<'a>>) -> Self {
+        let const_set = options.defsyms.iter().cloned().collect();
+        BinaryEmitState {
+            options: options.clone(),
+            on_event,
+            current_mode: EmitMode::Text,
+            current_section: None,
+            sections: Vec::new(),
+            laid_out: Vec::new(),
+            code_end: None,
+            out_buf: Vec::new(),
+            padding: 0,
+            out_pos: 0,
+            placements: options
+                .layout
+                .iter()
+                .filter_map(|layout| layout.region_of(".text"))
+                .map(|region| (0, region.origin))
+                .collect(),
+            written: Vec::new(),
+            text_end: 0,
+            deferred: Deferred::default(),
+            label_set: HashMap::new(),
+            label_files: HashMap::new(),
+            current_file: None,
+            local_label_set: HashMap::new(),
+            local_labels: Vec::new(),
+            numeric_label_set: HashMap::new(),
+            numeric_label_counts: HashMap::new(),
+            looking_ahead: false,
+            ahead: HashMap::new(),
+            looked_ahead: Vec::new(),
+            const_set,
+            set_symbols: HashSet::new(),
+            register_aliases: HashMap::new(),
+            relocations: Vec::new(),
+            section_relocations: Vec::new(),
+            pending_relocations: Deferred::default(),
+            symbol_types: HashMap::new(),
+            symbol_sizes: HashMap::new(),
+            symbol_bindings: HashMap::new(),
+            export_positions: HashMap::new(),
+            entry: None,
+            pending_sizes: Deferred::default(),
+            pending_li_values: Deferred::default(),
+            pending_checksums: Vec::new(),
+            pcrel_hi_targets: HashMap::new(),
+            pcrel_lo_auipcs: HashMap::new(),
+            label_uses: Vec::new(),
+            location: None,
+            resolving_deferred: false,
+            unit: false,
+            region_start: 0,
+            relaxed: HashSet::new(),
+            lengthened: HashSet::new(),
+            relax_sites: Vec::new(),
+            pseudo_sites: 0,
+            expanding_site: None,
+            instruction_sites: 0,
+            compressed_deferred: HashSet::new(),
+            errors: Vec::new(),
+            rvc: true,
+            option_stack: Vec::new(),
+            instructions: Vec::new(),
+            elements: Vec::new(),
+            conditionals: Vec::new(),
+            macros: HashMap::new(),
+            macro_definition: None,
+            macro_depth: 0,
+            macro_expansions: 0,
+            diagnostics: Vec::new(),
+            next_progress: options.progress_interval.max(1),
+        }
+    }
+
+    /// Whether the short form of a relaxation site at `pc` works with the symbols defined so
+    /// far, None if they aren't all defined yet
+    fn shortens(&self, spec: &arch::RiscVSpec, pc: u64, site: &RelaxSite) -> Option<bool> {
+        let consts = |cname: &str| self.find_const(cname, spec);
+        match site {
+            RelaxSite::Pseudo(relaxation) => {
+                match relaxation.target.emitter_simplify(&consts, pc) {
+                    (Node::Integer(v), true) => Some(relaxation.reaches(v, pc)),
+                    _ => None,
+                }
+            }
+            RelaxSite::Branch(branch) => match branch.offset.emitter_simplify(&consts, pc) {
+                (Node::Integer(v), true) => Some(branch.reaches(v)),
+                _ => None,
+            },
+            RelaxSite::Compress(insn) => match insn.emitter_simplify(&consts, pc) {
+                (Node::Instruction(iname, args), true) => {
+                    let argv = args
+                        .iter()
+                        .map(|arg| match arg {
+                            Node::Argument(box Node::Integer(v)) => Some(*v),
+                            Node::Argument(box Node::Register(r)) => Some(*r as u64),
+                            _ => None,
+                        })
+                        .collect::<Option<Vec<_>>>()?;
+                    Some(compress(spec, &iname, &argv).is_some())
+                }
+                _ => None,
+            },
+        }
+    }
+
+    /// Address of the byte at `pos` in out_buf, which is its offset without a layout
+    fn address_at(&self, pos: usize) -> u64 {
+        match self.placements.partition_point(|(start, _)| *start <= pos) {
+            0 => pos as u64,
+            i => {
+                let (start, address) = self.placements[i - 1];
+                address + (pos - start) as u64
+            }
+        }
+    }
+
+    /// Addresses of the code and the sections laid out so far, in the order of the image
+    fn section_ranges(&self) -> Vec<std::ops::Range<u64>> {
+        let text = self.address_at(0);
+        let text = text..text + self.code_end.unwrap_or(self.out_buf.len()) as u64;
+        std::iter::once(text)
+            .chain(self.laid_out.iter().map(|s| s.base..s.base + s.size as u64))
+            .collect()
+    }
+
+    /// Index of the section holding the address among section_ranges
+    fn section_at(&self, address: u64) -> Option<usize> {
+        self.section_ranges()
+            .iter()
+            .position(|range| range.contains(&address))
+    }
+
+    /// Like section_at, a label at the end of a section being in the one starting there if any,
+    /// like AssembledImage::label_section
+    fn label_section(&self, address: u64) -> Option<usize> {
+        let ranges = self.section_ranges();
+        ranges
+            .iter()
+            .position(|range| range.contains(&address))
+            .or_else(|| ranges.iter().position(|range| range.end == address))
+    }
+
+    /// Address of the current position
+    fn pc(&self) -> u64 {
+        self.address_at(self.out_pos)
+    }
+
+    /// Position in out_buf of the address in the part of the output holding `pos`, None if the
+    /// address is before that part
+    fn offset_in_part(&self, pos: usize, address: u64) -> Option<usize> {
+        match self.placements.partition_point(|(start, _)| *start <= pos) {
+            0 => Some(address as usize),
+            i => {
+                let (start, base) = self.placements[i - 1];
+                address.checked_sub(base).map(|d| start + d as usize)
+            }
+        }
+    }
+
+    /// Whether a part of the output placed by the layout starts at `pos`, so the bytes there
+    /// don't continue the written range before
+    fn placed_at(&self, pos: usize) -> bool {
+        self.placements.iter().any(|(start, _)| *start == pos)
+    }
+
+    /// Whether instructions get their compressed form where the operands fit
+    fn compressing(&self, spec: &arch::RiscVSpec) -> bool {
+        self.rvc && spec.extension_enabled("C")
+    }
+
+    /// Passes the event to the hook, if there is one
+    fn notify(&mut self, event: EmitEvent) {
+        if let Some(hook) = &mut self.on_event {
+            hook(&event);
+        }
+    }
+
+    /// Goes past the error of the top-level `node` to the next element, keeping it for the end,
+    /// while EmitOptions.max_errors leaves room for more and the error doesn't leave the blocks
+    /// of the source unknown, like a failing .if or .macro does
+    fn recover(&mut self, node: &Node, result: Result<(), EmitError>) -> Result<(), EmitError> {
+        let error = match result {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let opens_block = match node {
+            Node::Located(_, box Node::Instruction(iname, _)) | Node::Instruction(iname, _) => {
+                is_conditional_directive(iname)
+                    || [".macro", ".endm", ".rept", ".irp", ".endr"]
+                        .iter()
+                        .any(|d| d.eq_ignore_ascii_case(iname))
+            }
+            _ => false,
+        };
+        let fatal = matches!(
+            error.kind(),
+            EmitError::ImageTooLarge(_)
+                | EmitError::UnbalancedConditional(_)
+                | EmitError::UnbalancedMacro(_)
+                | EmitError::UnresolvedInclude
+                | EmitError::ResolutionDidNotConverge(_)
+        );
+        self.errors.extend(error.all().iter().cloned());
+        if opens_block || fatal || self.errors.len() >= self.options.max_errors {
+            return Err(self.take_errors());
+        }
+        Ok(())
+    }
+
+    /// The result of the whole source from the one of its end and the errors gone past before
+    fn with_errors(&mut self, result: Result<(), EmitError>) -> Result<(), EmitError> {
+        if let Err(e) = result {
+            self.errors.extend(e.all().iter().cloned());
+        }
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.take_errors())
+        }
+    }
+
+    fn take_errors(&mut self) -> EmitError {
+        let mut errors = std::mem::take(&mut self.errors);
+        // symbols found undefined at the end are reported where they were used
+        errors.sort_by_key(|e| e.location().unwrap_or(usize::MAX));
+        if errors.len() == 1 {
+            errors.pop().unwrap()
+        } else {
+            EmitError::Multiple(errors)
+        }
+    }
+
+    fn info(&mut self, level: u8, message: String) {
+        if self.options.verbosity >= level {
+            self.diagnostics.push(Diagnostic::Info(message));
+        }
+    }
+
+    /// Reports how far the assembly got each time the output grows past another interval
+    fn report_progress(&mut self) {
+        let size = self.out_buf.len();
+        if self.options.verbosity == 0 || size < self.next_progress {
+            return;
+        }
+        let interval = self.options.progress_interval.max(1);
+        self.next_progress = (size / interval + 1) * interval;
+        let waiting = self.deferred.len();
+        self.info(
+            1,
+            format!(
+                "Progress: {} bytes emitted, {} instructions waiting for labels",
+                size, waiting
+            ),
+        );
+    }
+
+    fn into_image(mut self, spec: &arch::RiscVSpec) -> Result<AssembledImage, EmitError> {
+        let mut sections = vec![ImageSection {
+            name: ".text".to_owned(),
+            kind: SectionKind::Text,
+            base: self.address_at(0),
+            offset: 0,
+            size: self.code_end.unwrap_or(self.out_buf.len()),
+        }];
+        sections.append(&mut self.laid_out);
+        let end_of = |s: &ImageSection| s.base as usize + s.size;
+        let bytes_end = sections
+            .iter()
+            .filter(|s| s.kind != SectionKind::Bss)
+            .map(|s| s.offset + s.size)
+            .max()
+            .unwrap_or(0);
+        let data_end = sections
+            .iter()
+            .filter(|s| s.kind != SectionKind::Bss)
+            .map(end_of)
+            .max()
+            .unwrap_or(0);
+        let end = sections.iter().map(end_of).max().unwrap_or(0);
+        if self.options.end_symbols {
+            let symbols = [
+                ("_etext", self.address_at(0) as usize + self.text_end),
+                ("_edata", data_end),
+                ("_end", end),
+            ];
+            for (name, value) in symbols {
+                if self.label_set.contains_key(name) || self.const_set.contains_key(name) {
+                    continue;
+                }
+                self.label_set.insert(name.to_owned(), value as u64);
+                self.symbol_bindings
+                    .entry(name.to_owned())
+                    .or_insert(SymbolBinding::Global);
+            }
+        }
+        let entry = match self.entry.take() {
+            Some((name, position)) => match self.label_set.get(&name) {
+                Some(address) => Some(*address),
+                None => {
+                    let similar = self.suggest_symbol(spec, &name);
+                    return Err(EmitError::UnresolvedSymbol(name, position, similar));
+                }
+            },
+            None => None,
+        };
+        self.write_checksums(spec)?;
+        // .bss only takes up addresses, its bytes are left out
+        for bss in sections.iter().filter(|s| s.kind == SectionKind::Bss) {
+            let range = bss.offset..bss.offset + bss.size;
+            let bytes = self.out_buf.get(range.clone()).unwrap_or_default();
+            if let Some(i) = bytes.iter().position(|b| *b != 0) {
+                return Err(EmitError::NonZeroBss(bss.name.clone(), bss.base + i as u64));
+            }
+            let mut written = Vec::new();
+            for r in std::mem::take(&mut self.written).into_iter() {
+                written.push(r.start..r.end.min(range.start));
+                written.push(r.start.max(range.end)..r.end);
+            }
+            written.retain(|r| !r.is_empty());
+            self.written = written;
+        }
+        self.out_buf
+            .truncate(bytes_end.max(self.code_end.unwrap_or(0)));
+        let size = self.out_buf.len();
+        let summary = format!(
+            "Assembled {} bytes, {} instructions, {} labels",
+            size,
+            self.instructions.len(),
+            self.label_set.len()
+        );
+        self.info(1, summary);
+        self.instructions.sort_by_key(|i| i.address);
+        // backward .org and deferred instructions can write the same ranges again
+        self.written.sort_by_key(|r| r.start);
+        let mut written: Vec<std::ops::Range<usize>> = Vec::new();
+        for range in self.written.into_iter() {
+            let placed = self
+                .placements
+                .iter()
+                .any(|(start, _)| *start == range.start);
+            match written.last_mut() {
+                Some(last) if range.start < last.end || range.start == last.end && !placed => {
+                    last.end = last.end.max(range.end)
+                }
+                _ => written.push(range),
+            }
+        }
+        let mut local_labels = self.local_labels;
+        local_labels.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        let mut image = AssembledImage {
+            bytes: self.out_buf,
+            written,
+            sections,
+            symbols: self.label_set,
+            private_labels: HashMap::new(),
+            local_labels,
+            constants: self.const_set,
+            symbol_types: self.symbol_types,
+            symbol_sizes: self.symbol_sizes,
+            symbol_bindings: self.symbol_bindings,
+            export_positions: self.export_positions,
+            entry,
+            relocations: self.relocations,
+            section_relocations: self.section_relocations,
+            instructions: self.instructions,
+            elements: self.elements,
+            padding: self.padding,
+            diagnostics: self.diagnostics,
+        };
+        image.check_section_overlaps()?;
+        if let Some(layout) = &self.options.layout {
+            layout.check(&image)?;
+        }
+        if self.options.strip_local {
+            image.strip_local_symbols();
+        }
+        Ok(image)
+    }
+
+    /// Computes the checksums in the order of their directives, so a checksum can cover
+    /// the ones before it. Its own bytes are zero while it's computed.
+    fn write_checksums(&mut self, spec: &arch::RiscVSpec) -> Result<(), EmitError> {
+        for checksum in std::mem::take(&mut self.pending_checksums).into_iter() {
+            let bound = |node: &Node, i| match node.emitter_simplify(
+                &|cname| self.find_const(cname, spec),
+                self.address_at(checksum.position),
+            ) {
+                // from addresses to out_buf, which is the same without a layout
+                (Node::Argument(box Node::Integer(v)), _) => self
+                    .offset_in_part(checksum.position, v)
+                    .ok_or_else(|| EmitError::InvalidArgumentType(".checksum".to_owned(), i)),
+                _ => Err(EmitError::InvalidArgumentType(".checksum".to_owned(), i)),
+            };
+            let start = bound(&checksum.start, 0)?;
+            let end = bound(&checksum.end, 1)?;
+            if start > end || end > self.out_buf.len() {
+                return Err(EmitError::InvalidArgumentType(".checksum".to_owned(), 1));
+            }
+            let value = checksum.algorithm.compute(&self.out_buf[start..end]);
+            let size = checksum.algorithm.size();
+            let bytes = &mut self.out_buf[checksum.position..checksum.position + size];
+            bytes.copy_from_slice(&value.to_le_bytes()[..size]);
+            self.options.data_endianness.arrange(bytes);
+        }
+        Ok(())
+    }
+
+    /// Reserves `byte_count` zeroed bytes at the current position and moves past them.
+    /// After a backward .org the bytes may only go into a gap nothing was emitted into, unless
+    /// EmitOptions.allow_overwrite lets them replace what was there (keeping the bytes after it).
+    /// Deferred instructions go into the space reserved for them, which doesn't count.
+    fn accomodate_bytes(&mut self, byte_count: usize) -> Result<&mut [u8], EmitError> {
         let start_pos = self.out_pos;
         let end_pos = start_pos + byte_count;
+        if !self.options.allow_overwrite && !self.resolving_deferred {
+            let overlap = self.written[self.region_start..]
+                .iter()
+                .find(|r| r.start < end_pos && start_pos < r.end);
+            if let Some(r) = overlap {
+                return Err(EmitError::OrgOverlap(
+                    start_pos.max(r.start) as u64,
+                    end_pos.min(r.end) as u64,
+                ));
+            }
+        }
         if self.out_buf.len() < end_pos {
             self.out_buf.resize(end_pos, 0);
         }
-        self.out_pos = end_pos;
-        &mut self.out_buf[start_pos..end_pos]
-    }
-
-    fn find_const(&self, key: &str, spec: &arch::RiscVSpec) -> Option<u64> {
-        self.label_set
-            .get(key)
-            .or_else(|| self.local_label_set.get(key))
-            .or_else(|| self.const_set.get(key))
-            .copied()
-            .or_else(|| spec.get_const(key))
+        self.out_pos = end_pos;
+        if self.current_mode == EmitMode::Text {
+            self.text_end = self.text_end.max(end_pos);
+        }
+        // the space of deferred instructions was counted as written when it was reserved
+        let continues = !self.placed_at(start_pos);
+        match self.written.last_mut() {
+            _ if self.resolving_deferred => {}
+            Some(last) if last.end == start_pos && continues => last.end = end_pos,
+            _ if byte_count > 0 => self.written.push(start_pos..end_pos),
+            _ => {}
+        }
+        let bytes = &mut self.out_buf[start_pos..end_pos];
+        bytes.fill(0);
+        Ok(bytes)
+    }
+
+    /// Makes the section named `name` the one the following elements go to, code sections all
+    /// being the same one emitted in place
+    fn switch_section(&mut self, name: &str) {
+        if SectionKind::from_name(name) == SectionKind::Text {
+            self.current_section = None;
+            self.current_mode = EmitMode::Text;
+            return;
+        }
+        let index = match self.sections.iter().position(|s| s.name == name) {
+            Some(index) => index,
+            None => {
+                self.sections.push(QueuedSection {
+                    name: name.to_owned(),
+                    kind: SectionKind::from_name(name),
+                    elements: Vec::new(),
+                });
+                self.sections.len() - 1
+            }
+        };
+        self.current_section = Some(index);
+    }
+
+    /// Emits `byte_count` bytes of padding suitable for the current mode: NOPs in text mode
+    /// wherever a whole one fits at an address aligned to its size (the compressed one where
+    /// only that fits, if compressing), zeroes around them
+    fn pad_bytes(&mut self, spec: &arch::RiscVSpec, byte_count: usize) -> Result<(), EmitError> {
+        let mut nops = match self.current_mode {
+            EmitMode::Text => nop_encodings(spec, self.compressing(spec)),
+            EmitMode::Data => Vec::new(),
+        };
+        for nop in nops.iter_mut() {
+            self.options.instruction_endianness.arrange(nop);
+        }
+        let start = self.pc();
+        let bytes = self.accomodate_bytes(byte_count)?;
+        let mut at = 0;
+        while at < bytes.len() {
+            let nop = nops.iter().find(|nop| {
+                (start + at as u64).is_multiple_of(nop.len() as u64)
+                    && at + nop.len() <= bytes.len()
+            });
+            match nop {
+                Some(nop) => {
+                    bytes[at..at + nop.len()].copy_from_slice(nop);
+                    at += nop.len();
+                }
+                None => at += 1,
+            }
+        }
+        Ok(())
+    }
+
+    /// Pads up to the next multiple of `alignment` bytes, doing nothing if already aligned
+    fn align_to(
+        &mut self,
+        spec: &arch::RiscVSpec,
+        alignment: usize,
+        fill: Option<u8>,
+    ) -> Result<(), EmitError> {
+        let pc = self.pc();
+        let byte_count = (pc.next_multiple_of(alignment.max(1) as u64) - pc) as usize;
+        let aligned_pos = self.out_pos + byte_count;
+        if aligned_pos as u64 > self.options.max_image_size {
+            return Err(EmitError::ImageTooLarge(aligned_pos as u64));
+        }
+        self.padding += byte_count;
+        match fill {
+            Some(fill) => self.accomodate_bytes(byte_count)?.fill(fill),
+            None => self.pad_bytes(spec, byte_count)?,
+        }
+        Ok(())
+    }
+
+    /// Whether the source isn't in a skipped conditional block
+    fn emitting(&self) -> bool {
+        self.conditionals.last().is_none_or(|c| c.active)
+    }
+
+    /// Labels, constants (.equ and defsyms) and register aliases can't share a name, so the
+    /// order here only matters for the spec's constants, which the source may shadow
+    /// Records where the top-level element emitted from `start` to the current position came
+    /// from. The elements of macro expansions are left to their call.
+    fn record_element(&mut self, node: &Node, start: usize) {
+        let (offset, inner) = match node {
+            Node::Located(offset, box inner) if self.macro_depth == 0 => (*offset, inner),
+            _ => return,
+        };
+        let name = match inner {
+            Node::Instruction(iname, _) => ascii_lowercase(iname),
+            _ => Default::default(),
+        };
+        let value = match inner {
+            Node::Instruction(_, args) if [".equ", ".define", ".set"].contains(&name.as_ref()) => {
+                match args.first() {
+                    Some(Node::Argument(box Node::Identifier(name))) => {
+                        self.const_set.get(name.as_str()).copied()
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        // .org only moves, over a gap or backward
+        let start = if name == ".org" { self.out_pos } else { start };
+        self.elements.push(EmittedElement {
+            source_offset: offset,
+            address: self.address_at(start),
+            size: self.out_pos.saturating_sub(start),
+            value,
+        });
+    }
+
+    /// Marks the instructions, data, relocations, sizes and `li` values waiting for the symbol as
+    /// ready to be retried
+    fn symbol_defined(&mut self, name: Symbol) {
+        self.deferred.defined(&name);
+        self.pending_relocations.defined(&name);
+        self.pending_sizes.defined(&name);
+        self.pending_li_values.defined(&name);
+    }
+
+    /// First symbol used by the node that isn't defined yet
+    fn missing_symbol(&self, spec: &arch::RiscVSpec, node: &Node) -> Option<Symbol> {
+        node.referenced_symbols()
+            .into_iter()
+            .find(|sym| self.find_const(sym, spec).is_none())
+    }
+
+    /// With EmitOptions.external_symbols, keeps the node emitted at the current position for
+    /// relocate_label_uses if it uses labels
+    fn record_label_uses(&mut self, spec: &arch::RiscVSpec, node: &Node) {
+        if !self.options.external_symbols || self.unit {
+            return;
+        }
+        let is_label = |sym: &str| {
+            self.label_set.contains_key(sym)
+                || self.local_label_set.contains_key(sym)
+                || self.numeric_label_set.contains_key(sym)
+        };
+        let uses = match external_uses(spec, self, node, self.pc(), &is_label) {
+            Some(uses) if !uses.is_empty() => uses,
+            _ => return,
+        };
+        let uses = uses
+            .into_iter()
+            .map(|u| {
+                let local = match self.label_set.contains_key(&u.symbol) {
+                    true => None,
+                    false => self.local_label_set.get(&u.symbol).copied(),
+                };
+                let local = local.or_else(|| self.numeric_label_set.get(&u.symbol).copied());
+                (u, local)
+            })
+            .collect();
+        self.label_uses.push((self.out_pos, node.clone(), uses));
+    }
+
+    /// Value of an operand needed right away, like the size of .space or a constant of .equ.
+    /// When looking ahead, the labels and constants not defined yet take their value at the end of
+    /// the pass before (0 in the first one), which emit_looking_ahead checks they keep.
+    fn value_now(&mut self, spec: &arch::RiscVSpec, arg: &Node) -> Option<u64> {
+        let pc = self.pc();
+        let scope = self.options.local_labels;
+        if self.looking_ahead {
+            for sym in arg.referenced_symbols() {
+                if self.find_const(&sym, spec).is_none() && !scope.is_local(&sym) {
+                    let taken = self.ahead.get(sym.as_str()).copied();
+                    self.looked_ahead.push((sym.to_string(), taken, pc));
+                }
+            }
+        }
+        let value_of = |cname: &str| {
+            self.find_const(cname, spec).or_else(|| {
+                (self.looking_ahead && !scope.is_local(cname))
+                    .then(|| self.ahead.get(cname).copied().unwrap_or(0))
+            })
+        };
+        match arg.emitter_simplify(&value_of, pc) {
+            (Node::Argument(box Node::Integer(v)), _) | (Node::Integer(v), _) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Values of the labels and constants defined so far, for the next pass to look ahead with
+    fn symbol_values(&self) -> HashMap<String, u64> {
+        let mut values = self.const_set.clone();
+        values.extend(self.label_set.iter().map(|(k, v)| (k.clone(), *v)));
+        values.extend(self.numeric_label_set.iter().map(|(k, v)| (k.clone(), *v)));
+        values
+    }
+
+    /// The element with its numeric label definition (`1:`) or the references to them (`1b` for
+    /// the last definition so far, `1f` for the next one) named after the definition they mean,
+    /// like `1:0`. Counted as the elements come in the source, before sections put them aside.
+    fn number_numeric_labels(&mut self, node: &Node) -> Result<Option<Node>, EmitError> {
+        let inner = match node {
+            Node::Located(_, box inner) => inner,
+            _ => node,
+        };
+        if let Node::Label(lname) = inner {
+            if !lname.bytes().all(|c| c.is_ascii_digit()) {
+                return Ok(None);
+            }
+            let count = self
+                .numeric_label_counts
+                .entry(lname.to_string())
+                .or_insert(0);
+            let numbered = Node::Label(format!("{}:{}", lname, count).into());
+            *count += 1;
+            return Ok(Some(match node {
+                Node::Located(offset, _) => Node::Located(*offset, Box::new(numbered)),
+                _ => numbered,
+            }));
+        }
+        if !inner
+            .referenced_symbols()
+            .iter()
+            .any(|sym| numeric_label_reference(sym).is_some())
+        {
+            return Ok(None);
+        }
+        let pos = self.pc();
+        node.rewrite(&mut |n| match n {
+            Node::Identifier(sym) => match numeric_label_reference(&sym) {
+                Some((label, forward)) => {
+                    let count = self.numeric_label_counts.get(label).copied().unwrap_or(0);
+                    match (forward, count) {
+                        (true, _) => Ok(Node::Identifier(format!("{}:{}", label, count).into())),
+                        // nothing before can ever define it
+                        (false, 0) => Err(EmitError::UnresolvedSymbol(sym.to_string(), pos, None)),
+                        (false, _) => {
+                            Ok(Node::Identifier(format!("{}:{}", label, count - 1).into()))
+                        }
+                    }
+                }
+                None => Ok(Node::Identifier(sym)),
+            },
+            n => Ok(n),
+        })
+        .map(Some)
+    }
+
+    /// Defers the node to be emitted at the current position once its symbols are defined
+    fn defer(&mut self, spec: &arch::RiscVSpec, node: Node) {
+        let missing = self.missing_symbol(spec, &node);
+        self.deferred.push((self.out_pos, node), missing);
+    }
+
+    /// The label, constant or register name most similar to a symbol that's never defined
+    fn suggest_symbol(&self, spec: &arch::RiscVSpec, name: &str) -> Option<String> {
+        let registers = spec
+            .get_all_registers()
+            .values()
+            .flat_map(|r| r.names.iter().map(String::as_str));
+        let candidates = self
+            .label_set
+            .keys()
+            .chain(self.const_set.keys())
+            .map(String::as_str)
+            .chain(self.local_labels.iter().map(|(l, _)| l.as_str()))
+            .chain(registers);
+        closest_name(name, candidates)
+    }
+
+    fn find_const(&self, key: &str, spec: &arch::RiscVSpec) -> Option<u64> {
+        self.label_set
+            .get(key)
+            .or_else(|| self.local_label_set.get(key))
+            .or_else(|| self.numeric_label_set.get(key))
+            .or_else(|| self.const_set.get(key))
+            .copied()
+            .or_else(|| spec.get_const(key))
+    }
+
+    /// Records the `%pcrel_hi` operands of the instruction at the current position and replaces
+    /// `%pcrel_lo(label)` operands by the `%lo` of the offset of the target of the `%pcrel_hi`
+    /// at `label`, which has to be defined already.
+    fn pair_pcrel_parts(
+        &mut self,
+        spec: &arch::RiscVSpec,
+        iname: &str,
+        args: &[Node],
+    ) -> Result<Vec<Node>, EmitError> {
+        let pc = self.pc();
+        let mut paired = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            match arg {
+                Node::Argument(box Node::PcrelHi(box target)) => {
+                    let target = target
+                        .emitter_simplify(&|cname| self.find_const(cname, spec), pc)
+                        .0;
+                    self.pcrel_hi_targets.insert(pc, target);
+                    paired.push(arg.clone());
+                }
+                Node::Argument(box Node::PcrelLo(box label)) => {
+                    let hi = match label.emitter_simplify(&|cname| self.find_const(cname, spec), pc)
+                    {
+                        (Node::Integer(address), true) => self
+                            .pcrel_hi_targets
+                            .get(&address)
+                            .map(|target| (address, target.clone())),
+                        _ => None,
+                    };
+                    let (address, target) =
+                        hi.ok_or_else(|| EmitError::InvalidArgumentType(iname.to_owned(), i))?;
+                    self.pcrel_lo_auipcs.insert(pc, address);
+                    let offset = Node::Minus(Box::new(target), Box::new(Node::Integer(address)));
+                    paired.push(Node::Argument(Box::new(Node::Lo(Box::new(offset)))));
+                }
+                _ => paired.push(arg.clone()),
+            }
+        }
+        Ok(paired)
+    }
+
+    /// Replaces names given as operands of CSR fields by the addresses of the CSRs, and the names
+    /// of values of fields that have them (like rounding modes) by the values, unless they are
+    /// labels or constants. Other names can't be CSR numbers.
+    fn resolve_named_values(
+        &self,
+        spec: &arch::RiscVSpec,
+        iname: &str,
+        fmt: &arch::InstructionFormat,
+        specinsn: &arch::InstructionDefinition,
+        mut args: Vec<Node>,
+    ) -> Result<Vec<Node>, EmitError> {
+        for (i, (arg, argid)) in args.iter_mut().zip(specinsn.args.iter()).enumerate() {
+            let field = &fmt.fields[*argid];
+            if let Node::Argument(box Node::Identifier(name)) = &*arg {
+                if self.find_const(name, spec).is_some() {
+                    continue;
+                }
+                let value = match field.vtype {
+                    arch::FieldType::Csr => Some(
+                        spec.get_csr(name)
+                            .ok_or_else(|| EmitError::InvalidArgumentType(iname.to_owned(), i))?,
+                    ),
+                    arch::FieldType::Value => field.named_value(name),
+                    arch::FieldType::Register => None,
+                };
+                if let Some(value) = value {
+                    *arg = Node::Argument(Box::new(Node::Integer(value)));
+                }
+            }
+        }
+        Ok(args)
+    }
+
+    /// Replaces operands naming register aliases by the registers.
+    /// Aliases can't be used in expressions, where an immediate is expected.
+    fn resolve_register_aliases(&self, iname: &str, args: &[Node]) -> Result<Vec<Node>, EmitError> {
+        args.iter()
+            .enumerate()
+            .map(|(i, arg)| match arg {
+                Node::Argument(box Node::Identifier(name)) => {
+                    let mut arg = arg.clone();
+                    if let Some(reg) = self.register_aliases.get(name.as_str()) {
+                        arg = Node::Argument(Box::new(Node::Register(*reg)));
+                    }
+                    Ok(arg)
+                }
+                _ if arg
+                    .referenced_symbols()
+                    .iter()
+                    .any(|sym| self.register_aliases.contains_key(sym.as_str())) =>
+                {
+                    Err(EmitError::InvalidArgumentType(iname.to_owned(), i))
+                }
+                _ => Ok(arg.clone()),
+            })
+            .collect()
+    }
+}
+
+/// Encoding of the canonical NOP (`addi x0, x0, 0`), if the spec defines addi
+/// `addi x0, x0, 0`, followed by `c.nop` if `compressed`, the ones the spec defines
+fn nop_encodings(spec: &arch::RiscVSpec, compressed: bool) -> Vec<Vec<u8>> {
+    let mut nops = Vec::new();
+    let names: &[(&str, &[u64])] = &[("addi", &[0, 0, 0]), ("c.nop", &[])];
+    for (name, args) in names.iter().take(if compressed { 2 } else { 1 }) {
+        if let Some(insn) = spec.get_instruction_by_name(name) {
+            let mut bytes = vec![0; insn.get_format(spec).ilen.div_ceil(8)];
+            if insn.encode_into(&mut bytes, spec, args).is_ok() {
+                nops.push(bytes);
+            }
+        }
+    }
+    nops
+}
+
+/// Major opcodes of integer computational instructions (OP-IMM, LUI, AUIPC, OP, OP-IMM-32, OP-32)
+const ARITHMETIC_OPCODES: [u64; 6] = [
+    0b0010011, 0b0110111, 0b0010111, 0b0110011, 0b0011011, 0b0111011,
+];
+
+/// Checks if an instruction computes a result only to throw it away into x0.
+/// The canonical NOP `addi x0, x0, 0` is an intentional discard and is not reported.
+fn writes_x0_needlessly(
+    fmt: &arch::InstructionFormat,
+    specinsn: &arch::InstructionDefinition,
+    argv: &[u64],
+) -> bool {
+    let field_value = |name: &str| {
+        specinsn
+            .args
+            .iter()
+            .zip(argv)
+            .map(|(fi, v)| (*fi, *v))
+            .chain(specinsn.fields.iter().copied())
+            .find(|(fi, _)| fmt.fields[*fi].name == name)
+            .map(|(_, v)| v)
+    };
+    let arithmetic = field_value("opcode").is_some_and(|op| ARITHMETIC_OPCODES.contains(&op));
+    let rd_is_arg = specinsn.args.iter().any(|fi| fmt.fields[*fi].name == "rd");
+    let nop = field_value("rs1") == Some(0) && field_value("imm") == Some(0);
+    arithmetic && rd_is_arg && field_value("rd") == Some(0) && !nop
+}
+
+/// All the directives handled by emit_binary_recurse
+const DIRECTIVES: &[&str] = &[
+    ".org",
+    ".align",
+    ".p2align",
+    ".balign",
+    ".space",
+    ".skip",
+    ".zero",
+    ".fill",
+    ".equ",
+    ".define",
+    ".set",
+    ".req",
+    ".unreq",
+    ".text",
+    ".data",
+    ".rodata",
+    ".bss",
+    ".section",
+    ".reloc",
+    ".option",
+    ".type",
+    ".size",
+    ".global",
+    ".globl",
+    ".local",
+    ".weak",
+    ".entry",
+    ".if",
+    ".ifdef",
+    ".ifndef",
+    ".ifeq",
+    ".ifne",
+    ".ifgt",
+    ".ifge",
+    ".iflt",
+    ".ifle",
+    ".elseif",
+    ".else",
+    ".endif",
+    ".macro",
+    ".endm",
+    ".rept",
+    ".irp",
+    ".endr",
+    ".insn",
+    ".checksum",
+    ".include",
+    ".incbin",
+    ".rvtable",
+    ".byte",
+    ".half",
+    ".word",
+    ".dword",
+    ".2byte",
+    ".4byte",
+    ".8byte",
+    ".float",
+    ".double",
+    ".ascii",
+    ".asciz",
+    ".string",
+];
+
+/// The label and whether it looks forward, for references to numeric labels like `1f`
+fn numeric_label_reference(symbol: &str) -> Option<(&str, bool)> {
+    let (label, direction) = symbol.split_at(symbol.len().checked_sub(1)?);
+    if label.is_empty() || !label.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    match direction {
+        "f" => Some((label, true)),
+        "b" => Some((label, false)),
+        _ => None,
+    }
+}
+
+/// How the source refers to the symbol, `1f` for a definition of a numeric label like `1:0`
+fn source_symbol_name(symbol: &str) -> String {
+    match symbol.split_once(':') {
+        Some((label, _)) => format!("{}f", label),
+        None => symbol.to_owned(),
+    }
+}
+
+/// Whether either the unsigned or the two's complement value fits in `width` bytes
+fn fits_in_bytes(value: u64, width: usize) -> bool {
+    let bits = 8 * width as u32;
+    bits >= 64 || value >> bits == 0 || (-(1i64 << (bits - 1))..0).contains(&(value as i64))
+}
+
+/// Error for a value of data that doesn't fit in `width` bytes, with the range it has to be in
+/// (signed or unsigned, like `fits_in_bytes`)
+fn data_out_of_range(iname: &str, i: usize, value: u64, width: usize) -> EmitError {
+    let bits = (8 * width as u32).min(63);
+    EmitError::ValueOutOfRange(
+        iname.to_owned(),
+        i,
+        value as i64,
+        -(1i64 << (bits - 1)),
+        ((1u64 << bits) - 1) as i64,
+    )
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diag
+            } else {
+                1 + diag.min(above).min(row[j])
+            };
+            diag = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidate most similar to `name`, if any is close enough to be a typo of it
+pub(crate) fn closest_name<'a>(
+    name: &str,
+    candidates: impl Iterator<Item = &'a str>,
+) -> Option<String> {
+    candidates
+        .filter(|c| *c != name)
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(dist, c)| *dist <= 2 && *dist + 1 < c.len())
+        .min_by_key(|(dist, c)| (*dist, *c))
+        .map(|(_, c)| c.to_owned())
+}
+
+/// The supported directive most similar to an unknown one, if any is close enough
+fn suggest_directive(name: &str) -> Option<String> {
+    closest_name(&name.to_ascii_lowercase(), DIRECTIVES.iter().copied())
+}
+
+/// The instruction, pseudo-instruction or macro most similar to an unknown mnemonic
+fn suggest_instruction(
+    spec: &arch::RiscVSpec,
+    state: &BinaryEmitState<'_>,
+    name: &str,
+) -> Option<String> {
+    let instructions = spec
+        .get_all_instructions()
+        .iter()
+        .filter(|i| {
+            i.extension
+                .as_ref()
+                .is_none_or(|e| spec.extension_enabled(e))
+        })
+        .map(|i| i.name.as_str());
+    let candidates = instructions
+        .chain(super::pseudo::PSEUDO_INSTRUCTIONS.iter().copied())
+        .chain(state.macros.keys().map(String::as_str))
+        .chain(spec.get_all_pseudo_instructions().map(|p| p.name.as_str()));
+    closest_name(&name.to_ascii_lowercase(), candidates)
+}
+
+/// Operands of `.insn` for each format letter, in the order used by GNU as
+const INSN_OPERANDS: &[(&str, &[&str])] = &[
+    ("r", &["opcode", "funct3", "funct7", "rd", "rs1", "rs2"]),
+    ("i", &["opcode", "funct3", "rd", "rs1", "imm"]),
+    ("s", &["opcode", "funct3", "rs2", "imm", "rs1"]),
+    ("b", &["opcode", "funct3", "rs1", "rs2", "imm"]),
+    ("u", &["opcode", "rd", "imm"]),
+    ("j", &["opcode", "rd", "imm"]),
+];
+
+/// Builds a definition taking all the fields of the named format as operands, for `.insn`
+fn insn_definition(spec: &arch::RiscVSpec, format: &str) -> Option<arch::InstructionDefinition> {
+    let (_, operands) = INSN_OPERANDS.iter().find(|(f, _)| *f == format)?;
+    let formats = spec.get_all_instruction_formats();
+    let format_idx = formats
+        .iter()
+        .rposition(|f| f.name.eq_ignore_ascii_case(format))?;
+    let fields = &formats[format_idx].fields;
+    let args = operands
+        .iter()
+        .map(|name| fields.iter().position(|f| f.name == *name))
+        .collect::<Option<Vec<_>>>()?;
+    Some(arch::InstructionDefinition {
+        name: format!(".insn {}", format),
+        format_idx,
+        args,
+        ..Default::default()
+    })
+}
+
+fn resolve_relocations(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+) -> Result<(), EmitError> {
+    for i in state.pending_relocations.take_ready() {
+        let (offset, _) = match state.pending_relocations.get(i) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        match offset.emitter_simplify(&|cname| state.find_const(cname, spec), 0) {
+            (Node::Argument(box Node::Integer(offset)), _) => {
+                if let Some((_, mut reloc)) = state.pending_relocations.remove(i) {
+                    reloc.offset = offset;
+                    state.relocations.push(reloc);
+                }
+            }
+            (_, false) => {
+                let missing = state.missing_symbol(spec, offset);
+                state.pending_relocations.wait(i, missing);
+            }
+            _ => return Err(EmitError::InvalidArgumentType(".reloc".to_owned(), 0)),
+        }
+    }
+    Ok(())
+}
+
+fn resolve_symbol_sizes(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+) -> Result<(), EmitError> {
+    for i in state.pending_sizes.take_ready() {
+        let (_, size) = match state.pending_sizes.get(i) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        match size.emitter_simplify(&|cname| state.find_const(cname, spec), 0) {
+            (Node::Argument(box Node::Integer(size)), _) => {
+                if let Some((name, _)) = state.pending_sizes.remove(i) {
+                    state.symbol_sizes.insert(name, size);
+                }
+            }
+            (_, false) => {
+                let missing = state.missing_symbol(spec, size);
+                state.pending_sizes.wait(i, missing);
+            }
+            _ => return Err(EmitError::InvalidArgumentType(".size".to_owned(), 1)),
+        }
+    }
+    Ok(())
+}
+
+/// Range of the values `li` loads with `lui`+`addi`: sign-extended 32-bit values with XLEN 64,
+/// and with XLEN 32 any value taken modulo 2^32
+pub(super) fn li_range(spec: &arch::RiscVSpec) -> (i64, i64) {
+    match spec.get_const("XLEN").unwrap_or(32) > 32 {
+        true => (i32::MIN as i64, i32::MAX as i64),
+        false => (i32::MIN as i64, u32::MAX as i64),
+    }
+}
+
+/// Checks the values of the `li` expanded before their labels were defined, which took
+/// `lui`+`addi` and so load the value modulo 2^32
+fn check_li_values(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+) -> Result<(), EmitError> {
+    let (min, max) = li_range(spec);
+    for i in state.pending_li_values.take_ready() {
+        let (value, _) = match state.pending_li_values.get(i) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        match value.emitter_simplify(&|cname| state.find_const(cname, spec), 0) {
+            (Node::Integer(v), true) => {
+                let location = state.pending_li_values.remove(i).and_then(|(_, at)| at);
+                if !(min..=max).contains(&(v as i64)) {
+                    let error = EmitError::ValueOutOfRange("li".to_owned(), 1, v as i64, min, max);
+                    return Err(match location {
+                        Some(offset) => error.at(offset),
+                        None => error,
+                    });
+                }
+            }
+            _ => {
+                let missing = state.missing_symbol(spec, value);
+                state.pending_li_values.wait(i, missing);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Emits the deferred instructions and data whose symbols got defined since the last call
+fn emit_deferred(spec: &arch::RiscVSpec, state: &mut BinaryEmitState<'_>) -> Result<(), EmitError> {
+    let mut to_emit = Vec::new();
+    for i in state.deferred.take_ready() {
+        let (pos, insn) = match state.deferred.get(i) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        // pos is the already-aligned address reserved for the instruction, so it is its final PC
+        let pc = state.address_at(*pos);
+        let simp = insn.emitter_simplify(&|cname| state.find_const(cname, spec), pc);
+        if simp.1 {
+            // the labels are gone from the simplified node, the linker needs them
+            let used = state.options.external_symbols.then(|| insn.clone());
+            to_emit.push((i, *pos, simp.0, used));
+        } else {
+            let missing = state.missing_symbol(spec, insn);
+            state.deferred.wait(i, missing);
+        }
+    }
+    for (i, _, _, _) in to_emit.iter() {
+        state.deferred.remove(*i);
+    }
+    let outer = std::mem::replace(&mut state.resolving_deferred, true);
+    for (_, pos, insn, used) in to_emit.into_iter() {
+        let saved_pos = state.out_pos;
+        state.out_pos = pos;
+        if let Some(used) = used {
+            state.record_label_uses(spec, &used);
+        }
+        let result = emit_binary_recurse(spec, state, &insn);
+        state.out_pos = saved_pos;
+        if result.is_err() {
+            state.resolving_deferred = outer;
+            return result;
+        }
+    }
+    state.resolving_deferred = outer;
+    resolve_relocations(spec, state)?;
+    resolve_symbol_sizes(spec, state)?;
+    check_li_values(spec, state)
+}
+
+fn pending_count(state: &BinaryEmitState<'_>) -> usize {
+    state.deferred.len()
+        + state.pending_relocations.len()
+        + state.pending_sizes.len()
+        + state.pending_li_values.len()
+}
+
+/// Retries everything still deferred until it's all resolved or a pass makes no progress
+fn resolve_to_fixpoint(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+) -> Result<(), EmitError> {
+    let mut passes = 0;
+    let mut pending = pending_count(state);
+    while pending != 0 {
+        if passes >= state.options.max_resolution_passes {
+            let mut symbols = Vec::new();
+            let nodes = state.deferred.iter().map(|(_, n)| n);
+            let nodes = nodes.chain(state.pending_relocations.iter().map(|(n, _)| n));
+            let nodes = nodes.chain(state.pending_sizes.iter().map(|(_, n)| n));
+            let nodes = nodes.chain(state.pending_li_values.iter().map(|(n, _)| n));
+            for sym in nodes.flat_map(|n| n.referenced_symbols()) {
+                if !symbols.contains(&sym) {
+                    symbols.push(sym);
+                }
+            }
+            let symbols = symbols.iter().map(|sym| sym.to_string()).collect();
+            return Err(EmitError::ResolutionDidNotConverge(symbols));
+        }
+        state.deferred.retry_all();
+        state.pending_relocations.retry_all();
+        state.pending_sizes.retry_all();
+        state.pending_li_values.retry_all();
+        emit_deferred(spec, state)?;
+        passes += 1;
+        let left = pending_count(state);
+        state.info(
+            2,
+            format!(
+                "Resolution pass {}: {} items still waiting for labels",
+                passes, left
+            ),
+        );
+        if left == pending {
+            break;
+        }
+        pending = left;
+    }
+    Ok(())
+}
+
+/// The relocation type filling the `%lo` part of an address into the immediate of `iname`
+fn lo12_relocation(iname: &str) -> Option<&'static str> {
+    match iname.to_ascii_lowercase().as_ref() {
+        "addi" | "addiw" | "jalr" | "lb" | "lh" | "lw" | "ld" | "lbu" | "lhu" | "lwu" => {
+            Some("R_RISCV_LO12_I")
+        }
+        "sb" | "sh" | "sw" | "sd" => Some("R_RISCV_LO12_S"),
+        _ => None,
+    }
+}
+
+/// Label at the auipc at `pc` that the relocations of the low parts of its `%pcrel_hi` refer
+/// to, defined the first time one needs it
+fn pcrel_hi_label(state: &mut BinaryEmitState<'_>, pc: u64) -> String {
+    internal_label(state, ".Lpcrel_hi", pc)
+}
+
+/// Label named `prefix` and a number at `address`, defined the first time one needs it
+fn internal_label(state: &mut BinaryEmitState<'_>, prefix: &str, address: u64) -> String {
+    if let Some((label, _)) = state
+        .label_set
+        .iter()
+        .find(|(label, at)| label.starts_with(prefix) && **at == address)
+    {
+        return label.clone();
+    }
+    let mut label = 0;
+    while state
+        .label_set
+        .contains_key(&format!("{}{}", prefix, label))
+    {
+        label += 1;
+    }
+    let label = format!("{}{}", prefix, label);
+    state.label_set.insert(label.clone(), address);
+    label
+}
+
+/// Uses of labels by a node, with the address of the label for scope-local ones
+type LabelUses = Vec<(ExternalUse, Option<u64>)>;
+
+/// A use of a symbol to relocate in one operand of an instruction or data directive, like one
+/// the source never defines: the operand, the symbol and the value of the operand with the
+/// symbol being 0
+#[derive(Clone, Debug)]
+struct ExternalUse {
+    arg: usize,
+    symbol: String,
+    value: i64,
+}
+
+/// The operands of the node at `pc` that use symbols the source never defines (or the ones
+/// `relocatable` picks), or None if one of them uses more than one or can't be computed without
+/// them
+fn external_uses(
+    spec: &arch::RiscVSpec,
+    state: &BinaryEmitState<'_>,
+    node: &Node,
+    pc: u64,
+    relocatable: &dyn Fn(&str) -> bool,
+) -> Option<Vec<ExternalUse>> {
+    let args = match node {
+        Node::Located(_, box Node::Instruction(_, args)) | Node::Instruction(_, args) => args,
+        _ => return None,
+    };
+    let mut uses = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        let mut symbols = arg.referenced_symbols();
+        symbols.retain(|sym| relocatable(sym));
+        symbols.sort();
+        symbols.dedup();
+        let symbol = match symbols.as_slice() {
+            [] => continue,
+            [symbol] => symbol.clone(),
+            _ => return None,
+        };
+        let value = match arg.emitter_simplify(
+            &|cname| {
+                (symbol == cname)
+                    .then_some(0)
+                    .or_else(|| state.find_const(cname, spec))
+            },
+            pc,
+        ) {
+            (Node::Argument(box Node::Integer(v)), _) | (Node::Integer(v), _) => v as i64,
+            _ => return None,
+        };
+        uses.push(ExternalUse {
+            arg: i,
+            symbol: symbol.to_string(),
+            value,
+        });
+    }
+    Some(uses)
+}
+
+/// Adds the relocations of the operands of the entries (an index, a position and a node with its
+/// uses of symbols) and returns the entries they cover, with the operands relocated. An auipc
+/// or lui and the instruction after it using the same symbol get relocated as a pair, like the
+/// two instructions of a call or la, and with EmitOptions.linker_relax the ones a linker may
+/// shorten get an `R_RISCV_RELAX` beside them.
+fn relocate_uses(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+    mut entries: Vec<(usize, usize, Node, Vec<ExternalUse>)>,
+) -> Vec<(usize, usize, Node, Vec<usize>)> {
+    entries.sort_by_key(|(_, pos, _, _)| *pos);
+    let name_of = |node: &Node| match node {
+        Node::Located(_, box Node::Instruction(name, _)) | Node::Instruction(name, _) => {
+            name.to_ascii_lowercase()
+        }
+        _ => String::new(),
+    };
+    let first = state.relocations.len();
+    let mut relocated: Vec<(usize, usize, Node, Vec<usize>)> = Vec::new();
+    let mut paired = HashSet::new();
+    for (n, (i, pos, node, uses)) in entries.iter().enumerate() {
+        if paired.contains(i) || uses.is_empty() {
+            continue;
+        }
+        let pc = state.address_at(*pos);
+        let iname = name_of(node);
+        let reloc = |kind: &str, symbol: &str, addend: i64| Relocation {
+            offset: pc,
+            kind: kind.to_owned(),
+            symbol: symbol.to_owned(),
+            addend,
+        };
+        let width = match iname.as_ref() {
+            ".word" | ".4byte" => Some(("R_RISCV_32", 4)),
+            ".dword" | ".8byte" => Some(("R_RISCV_64", 8)),
+            _ => None,
+        };
+        if let Some((kind, width)) = width {
+            for u in uses.iter() {
+                let mut r = reloc(kind, &u.symbol, u.value);
+                r.offset += (u.arg * width) as u64;
+                state.relocations.push(r);
+            }
+            let zeroed = uses.iter().map(|u| u.arg).collect();
+            relocated.push((*i, *pos, node.clone(), zeroed));
+            continue;
+        }
+        let u = match uses.as_slice() {
+            [u] => u,
+            _ => continue,
+        };
+        // the instruction right after, using the same symbol for the low 12 bits
+        let partner = entries.get(n + 1).and_then(|(pi, ppos, pnode, puses)| {
+            let lo = lo12_relocation(&name_of(pnode))?;
+            match puses.as_slice() {
+                [pu] if pu.symbol == u.symbol && *ppos == pos + 4 => {
+                    Some((*pi, *ppos, pnode.clone(), pu.arg, pu.value, lo))
+                }
+                _ => None,
+            }
+        });
+        let hi = u.value as u32 as i32 as i64;
+        match iname.as_ref() {
+            "jal" => state.relocations.push(reloc(
+                "R_RISCV_JAL",
+                &u.symbol,
+                u.value.wrapping_add(pc as i64),
+            )),
+            "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => state.relocations.push(reloc(
+                "R_RISCV_BRANCH",
+                &u.symbol,
+                u.value.wrapping_add(pc as i64),
+            )),
+            "c.j" | "c.jal" => state.relocations.push(reloc(
+                "R_RISCV_RVC_JUMP",
+                &u.symbol,
+                u.value.wrapping_add(pc as i64),
+            )),
+            "c.beqz" | "c.bnez" => state.relocations.push(reloc(
+                "R_RISCV_RVC_BRANCH",
+                &u.symbol,
+                u.value.wrapping_add(pc as i64),
+            )),
+            "auipc" => match partner {
+                Some((pi, ppos, pnode, parg, lo_value, lo)) => {
+                    let addend = hi.wrapping_add(lo_value).wrapping_add(pc as i64);
+                    if name_of(&pnode) == "jalr" {
+                        state
+                            .relocations
+                            .push(reloc("R_RISCV_CALL_PLT", &u.symbol, addend));
+                    } else {
+                        let label = pcrel_hi_label(state, pc);
+                        state
+                            .relocations
+                            .push(reloc("R_RISCV_PCREL_HI20", &u.symbol, addend));
+                        let lo = lo.replace("LO12", "PCREL_LO12");
+                        let mut r = reloc(&lo, &label, 0);
+                        r.offset = state.address_at(ppos);
+                        state.relocations.push(r);
+                    }
+                    paired.insert(pi);
+                    relocated.push((pi, ppos, pnode, vec![parg]));
+                }
+                None => {
+                    // the high part alone lost the low bits of the offset from the symbol
+                    let target = state.pcrel_hi_targets.get(&pc).and_then(|target| {
+                        match target.emitter_simplify(
+                            &|cname| {
+                                (cname == u.symbol)
+                                    .then_some(0)
+                                    .or_else(|| state.find_const(cname, spec))
+                            },
+                            pc,
+                        ) {
+                            (Node::Integer(v), true) => Some(v as i64),
+                            _ => None,
+                        }
+                    });
+                    let addend = target.unwrap_or(hi.wrapping_add(pc as i64));
+                    state
+                        .relocations
+                        .push(reloc("R_RISCV_PCREL_HI20", &u.symbol, addend));
+                }
+            },
+            "lui" => match partner {
+                Some((pi, ppos, pnode, parg, lo_value, lo)) => {
+                    let addend = hi.wrapping_add(lo_value);
+                    state
+                        .relocations
+                        .push(reloc("R_RISCV_HI20", &u.symbol, addend));
+                    let mut r = reloc(lo, &u.symbol, addend);
+                    r.offset = state.address_at(ppos);
+                    state.relocations.push(r);
+                    paired.insert(pi);
+                    relocated.push((pi, ppos, pnode, vec![parg]));
+                }
+                None => state.relocations.push(reloc("R_RISCV_HI20", &u.symbol, hi)),
+            },
+            _ => match (lo12_relocation(&iname), state.pcrel_lo_auipcs.get(&pc)) {
+                // a %pcrel_lo of an auipc further away
+                (Some(lo), Some(&auipc)) => {
+                    let label = pcrel_hi_label(state, auipc);
+                    let lo = lo.replace("LO12", "PCREL_LO12");
+                    state.relocations.push(reloc(&lo, &label, 0));
+                }
+                (Some(lo), None) => state.relocations.push(reloc(lo, &u.symbol, u.value)),
+                (None, _) => continue,
+            },
+        }
+        relocated.push((*i, *pos, node.clone(), vec![u.arg]));
+    }
+
+    if state.options.linker_relax {
+        for reloc in state.relocations.split_off(first) {
+            let relaxable = matches!(
+                reloc.kind.as_ref(),
+                "R_RISCV_CALL_PLT"
+                    | "R_RISCV_PCREL_HI20"
+                    | "R_RISCV_PCREL_LO12_I"
+                    | "R_RISCV_PCREL_LO12_S"
+                    | "R_RISCV_HI20"
+                    | "R_RISCV_LO12_I"
+                    | "R_RISCV_LO12_S"
+            );
+            let offset = reloc.offset;
+            state.relocations.push(reloc);
+            if relaxable {
+                state.relocations.push(Relocation {
+                    offset,
+                    kind: "R_RISCV_RELAX".to_owned(),
+                    symbol: String::new(),
+                    addend: 0,
+                });
+            }
+        }
+    }
+    relocated
+}
+
+/// With EmitOptions.external_symbols, emits what still waits for symbols the source never
+/// defines with zeroes in their place, and the relocations a linker fills them in with. What
+/// can't be relocated stays unresolved.
+fn relocate_external_symbols(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+) -> Result<(), EmitError> {
+    let entries: Vec<(usize, usize, Node, Vec<ExternalUse>)> = (0..state.deferred.entries.len())
+        .filter_map(|i| {
+            let (pos, node) = state.deferred.get(i)?;
+            // numeric labels are never left to the linker
+            let uses = external_uses(spec, state, node, state.address_at(*pos), &|sym| {
+                state.find_const(sym, spec).is_none() && !sym.contains(':')
+            })?;
+            Some((i, *pos, node.clone(), uses))
+        })
+        .collect();
+    let relocated = relocate_uses(spec, state, entries);
+
+    let outer = std::mem::replace(&mut state.resolving_deferred, true);
+    for (i, pos, node, zeroed) in relocated.into_iter() {
+        state.deferred.remove(i);
+        let zero = |args: &mut Vec<Node>| {
+            for arg in zeroed.iter() {
+                args[*arg] = Node::Argument(Box::new(Node::Integer(0)));
+            }
+        };
+        let mut node = node;
+        match &mut node {
+            Node::Located(_, box Node::Instruction(_, args)) | Node::Instruction(_, args) => {
+                zero(args)
+            }
+            _ => {}
+        }
+        let saved_pos = state.out_pos;
+        state.out_pos = pos;
+        let result = emit_binary_recurse(spec, state, &node);
+        state.out_pos = saved_pos;
+        if result.is_err() {
+            state.resolving_deferred = outer;
+            return result;
+        }
+    }
+    state.resolving_deferred = outer;
+    Ok(())
+}
+
+/// Relocates the uses of labels recorded while emitting with EmitOptions.external_symbols.
+/// With EmitOptions.linker_relax all of them, so they still hold once the linker deleted bytes
+/// between them and their labels. Otherwise only the ones a linker placing the sections breaks,
+/// absolute addresses and references into another section, into
+/// AssembledImage.section_relocations. Scope-local labels get a `.Ltmp` label at their address
+/// to relocate against. The bytes keep the values already emitted.
+fn relocate_label_uses(spec: &arch::RiscVSpec, state: &mut BinaryEmitState<'_>) {
+    let internal: HashSet<String> = state
+        .label_set
+        .keys()
+        .filter(|label| label.starts_with(".L"))
+        .cloned()
+        .collect();
+    let first = state.relocations.len();
+    let mut entries = Vec::new();
+    for (i, (pos, node, uses)) in std::mem::take(&mut state.label_uses)
+        .into_iter()
+        .enumerate()
+    {
+        let uses = uses
+            .into_iter()
+            .map(|(mut u, local)| {
+                if let Some(address) = local {
+                    u.symbol = internal_label(state, ".Ltmp", address);
+                }
+                u
+            })
+            .collect();
+        entries.push((i, pos, node, uses));
+    }
+    relocate_uses(spec, state, entries);
+    if state.options.linker_relax {
+        return;
+    }
+
+    let added = state.relocations.split_off(first);
+    let same_section = |r: &Relocation| {
+        let target = state.label_set.get(&r.symbol).copied();
+        target.is_some_and(|target| state.label_section(target) == state.section_at(r.offset))
+    };
+    // the offset between an instruction and a label in its own section stays the same
+    let pc_relative: HashSet<u64> = added
+        .iter()
+        .filter(|r| {
+            matches!(
+                r.kind.as_ref(),
+                "R_RISCV_JAL"
+                    | "R_RISCV_BRANCH"
+                    | "R_RISCV_RVC_JUMP"
+                    | "R_RISCV_RVC_BRANCH"
+                    | "R_RISCV_CALL_PLT"
+                    | "R_RISCV_PCREL_HI20"
+            ) && same_section(r)
+        })
+        .map(|r| r.offset)
+        .collect();
+    let kept: Vec<Relocation> = added
+        .into_iter()
+        // the low parts of a %pcrel_hi of a symbol the source doesn't define have theirs already
+        .filter(|r| {
+            !state
+                .relocations
+                .iter()
+                .any(|other| other.offset == r.offset && other.kind == r.kind)
+        })
+        .filter(|r| match r.kind.starts_with("R_RISCV_PCREL_LO12") {
+            // the low part goes with the auipc its label is at
+            true => !state
+                .label_set
+                .get(&r.symbol)
+                .is_some_and(|auipc| pc_relative.contains(auipc)),
+            false => !pc_relative.contains(&r.offset),
+        })
+        .collect();
+    // the labels made for the dropped relocations aren't needed
+    let referenced: HashSet<&str> = kept.iter().map(|r| r.symbol.as_str()).collect();
+    state.label_set.retain(|label, _| {
+        !label.starts_with(".L") || internal.contains(label) || referenced.contains(label.as_str())
+    });
+    state.section_relocations.extend(kept);
+}
+
+/// Resolves everything left at the end of the source, failing if anything stays unresolved
+fn finish_deferred(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+) -> Result<(), EmitError> {
+    emit_sections(spec, state)?;
+    resolve_to_fixpoint(spec, state)?;
+    if state.options.external_symbols {
+        relocate_external_symbols(spec, state)?;
+        if !state.unit {
+            relocate_label_uses(spec, state);
+        }
+    }
+    if let Some((_, defnode)) = state.deferred.iter().min_by_key(|(pos, _)| *pos) {
+        let mut deferred: Vec<&(usize, Node)> = state.deferred.iter().collect();
+        deferred.sort_by_key(|(pos, _)| *pos);
+        // every symbol left undefined, named once with its first use
+        let mut unresolved: Vec<(String, u64)> = Vec::new();
+        for (at, node) in deferred {
+            for sym in node.referenced_symbols() {
+                let name = source_symbol_name(&sym);
+                if state.find_const(&sym, spec).is_none()
+                    && !unresolved.iter().any(|(n, _)| *n == name)
+                {
+                    unresolved.push((name, *at as u64));
+                }
+            }
+        }
+        let error = match unresolved.len() {
+            0 => EmitError::UnexpectedNodeType(format!("{:?}", defnode)),
+            1 => {
+                let (name, address) = unresolved.remove(0);
+                let similar = state.suggest_symbol(spec, &name);
+                EmitError::UnresolvedSymbol(name, address, similar)
+            }
+            _ => EmitError::UnresolvedSymbols(
+                unresolved
+                    .into_iter()
+                    .map(|(name, address)| {
+                        let similar = state.suggest_symbol(spec, &name);
+                        (name, address, similar)
+                    })
+                    .collect(),
+            ),
+        };
+        return Err(match defnode {
+            Node::Located(offset, _) => error.at(*offset),
+            _ => error,
+        });
+    }
+    if !state.pending_relocations.is_empty() {
+        return Err(EmitError::InvalidArgumentType(".reloc".to_owned(), 0));
+    }
+    if !state.pending_sizes.is_empty() {
+        return Err(EmitError::InvalidArgumentType(".size".to_owned(), 1));
+    }
+    if let Some(frame) = state.conditionals.last() {
+        return Err(frame.unterminated());
+    }
+    if let Some(collecting) = state.macro_definition.as_ref() {
+        return Err(collecting.unclosed());
+    }
+    Ok(())
+}
+
+fn is_conditional_directive(name: &str) -> bool {
+    CONDITIONAL_OPENERS
+        .iter()
+        .chain([".elseif", ".else", ".endif"].iter())
+        .any(|d| d.eq_ignore_ascii_case(name))
+}
+
+/// Emits a top-level element, unless it's inside a skipped conditional block or the body of
+/// a macro being defined. Macro calls emit the expanded body instead, and so does the .endr
+/// of a .rept or .irp, once for each repetition.
+fn emit_element(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+    node: &Node,
+) -> Result<(), EmitError> {
+    let inner = match node {
+        Node::Located(_, box inner) => inner,
+        _ => node,
+    };
+    if let Some(collecting) = state.macro_definition.as_mut() {
+        let name = match inner {
+            Node::Instruction(iname, _) => iname.to_ascii_lowercase(),
+            _ => String::new(),
+        };
+        let (openers, end) = collecting.delimiters();
+        if name == end && collecting.nested == 0 {
+            let MacroDefinition {
+                name,
+                definition,
+                repetition,
+                ..
+            } = state.macro_definition.take().unwrap();
+            return match repetition {
+                Some((opener, repetitions)) => {
+                    emit_repetitions(spec, state, &name, &definition, &opener, &repetitions)
+                }
+                None => {
+                    state.macros.insert(name, definition);
+                    Ok(())
+                }
+            };
+        }
+        if openers.contains(&name.as_str()) {
+            collecting.nested += 1;
+        } else if name == end {
+            collecting.nested -= 1;
+        }
+        collecting.definition.body.push(node.clone());
+        return Ok(());
+    }
+    let conditional = match inner {
+        Node::Instruction(iname, _) => is_conditional_directive(iname),
+        _ => false,
+    };
+    if !state.emitting() && !conditional {
+        return Ok(());
+    }
+    let numbered = match state.number_numeric_labels(node) {
+        Ok(numbered) => numbered,
+        Err(e) => {
+            return Err(match node {
+                Node::Located(offset, _) => e.at(*offset),
+                _ => e,
+            })
+        }
+    };
+    let node = numbered.as_ref().unwrap_or(node);
+    let inner = match node {
+        Node::Located(_, box inner) => inner,
+        _ => node,
+    };
+    if let Some(section) = state.current_section {
+        let in_place = match inner {
+            Node::Instruction(iname, _) => applies_in_place(iname),
+            _ => false,
+        };
+        if !in_place {
+            let file = state.current_file.clone();
+            state.sections[section].elements.push((file, node.clone()));
+            return Ok(());
+        }
+    }
+    let start = state.out_pos;
+    if let Node::Instruction(iname, args) = inner {
+        // spec pseudo-instructions expand like macros, which the source can still override
+        let definition = state.macros.get(iname.as_str()).cloned().or_else(|| {
+            spec.get_pseudo_instruction(iname).map(|pseudo| Macro {
+                params: pseudo.params.clone(),
+                defaults: vec![None; pseudo.params.len()],
+                body: pseudo.expansion.clone(),
+            })
+        });
+        if let Some(definition) = definition {
+            let result = expand_macro(spec, state, iname, &definition, args);
+            match node {
+                Node::Located(offset, _) => result.map_err(|e| e.at(*offset))?,
+                _ => result?,
+            }
+            state.record_element(node, start);
+            return Ok(());
+        }
+    }
+    emit_binary_recurse(spec, state, node)?;
+    state.record_element(node, start);
+    state.report_progress();
+    Ok(())
+}
+
+/// Emits the body of a .rept or .irp at `opener` once for each of the `repetitions`, as if it
+/// were a macro called with their arguments, so its local labels are unique to each one
+fn emit_repetitions(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+    name: &str,
+    definition: &Macro,
+    opener: &Node,
+    repetitions: &Repetitions,
+) -> Result<(), EmitError> {
+    let start = state.out_pos;
+    let mut expand = |args: &[Node]| {
+        let result = expand_macro(spec, state, name, definition, args);
+        match opener {
+            Node::Located(offset, _) => result.map_err(|e| e.at(*offset)),
+            _ => result,
+        }
+    };
+    match repetitions {
+        // an empty body repeated any number of times emits nothing
+        Repetitions::Count(_) if definition.body.is_empty() => (),
+        Repetitions::Count(count) => (0..*count).try_for_each(|_| expand(&[]))?,
+        Repetitions::Values(values) => values.iter().try_for_each(|args| expand(args))?,
+    }
+    state.record_element(opener, start);
+    Ok(())
+}
+
+/// Emits the body of a macro called with `args`, its parameters replaced by them and its local
+/// labels renamed to be unique to this expansion
+fn expand_macro(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+    name: &str,
+    definition: &Macro,
+    args: &[Node],
+) -> Result<(), EmitError> {
+    if args.len() > definition.params.len() {
+        return Err(EmitError::InvalidArgumentCount(name.to_owned()));
+    }
+    if state.macro_depth == MAX_MACRO_DEPTH {
+        return Err(EmitError::MacroTooDeep(name.to_owned()));
+    }
+    let mut values: Vec<&Node> = args
+        .iter()
+        .map(|arg| match arg {
+            Node::Argument(box value) => value,
+            _ => arg,
+        })
+        .collect();
+    // the parameters left out take their default
+    for default in definition.defaults[values.len()..].iter() {
+        match default {
+            Some(value) => values.push(value),
+            None => return Err(EmitError::InvalidArgumentCount(name.to_owned())),
+        }
+    }
+    state.macro_expansions += 1;
+    let expansion = state.macro_expansions;
+    let mut body = Vec::new();
+    for node in definition.body.iter() {
+        body.push(
+            node.rewrite(&mut |n| {
+                substitute_macro_params(name, definition, &values, expansion, n)
+            })?,
+        );
+    }
+
+    let suffix = format!("\\{}", expansion);
+    let scope = state.options.local_labels;
+    let locals: Vec<Symbol> = body
+        .iter()
+        .filter_map(|node| match node {
+            Node::Located(_, box Node::Label(lname)) | Node::Label(lname) => Some(lname),
+            _ => None,
+        })
+        .filter(|lname| scope.is_local(lname))
+        .cloned()
+        .collect();
+    let mut unique = |n: Node| -> Result<Node, EmitError> {
+        Ok(match n {
+            Node::Label(lname) if locals.contains(&lname) => {
+                Node::Label(format!("{}{}", lname, suffix).into())
+            }
+            Node::Identifier(id) if locals.contains(&id) => {
+                Node::Identifier(format!("{}{}", id, suffix).into())
+            }
+            n => n,
+        })
+    };
+
+    state.macro_depth += 1;
+    let mut result = Ok(());
+    for node in body.iter() {
+        result = node
+            .rewrite(&mut unique)
+            .and_then(|node| emit_element(spec, state, &node));
+        if result.is_err() {
+            break;
+        }
+    }
+    state.macro_depth -= 1;
+    result
+}
+
+/// Replaces the macro parameters in a node of the body of macro `name`: a whole `\param`
+/// operand becomes the value passed for it, and the `\param` parts of other names the value's
+/// text, like in `loop_\name:`. A `\@` part is the number of the expansion, unique to it.
+fn substitute_macro_params(
+    name: &str,
+    definition: &Macro,
+    values: &[&Node],
+    expansion: usize,
+    node: Node,
+) -> Result<Node, EmitError> {
+    let text = |s: &str| -> Result<String, EmitError> {
+        let mut out = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('\\') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start + 1..];
+            if let Some(after) = rest.strip_prefix('@') {
+                out.push_str(&expansion.to_string());
+                rest = after;
+                continue;
+            }
+            // the longest parameter name fitting, so `\a_end` can use a parameter `a`
+            let found = definition
+                .params
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| rest.starts_with(p.as_str()))
+                .max_by_key(|(_, p)| p.len());
+            let (i, param) = match found {
+                Some(found) => found,
+                None => {
+                    let len = rest
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .unwrap_or(rest.len());
+                    return Err(EmitError::UnknownMacroParameter(
+                        name.to_owned(),
+                        format!("\\{}", &rest[..len]),
+                    ));
+                }
+            };
+            match values[i] {
+                Node::Identifier(value) => out.push_str(value),
+                Node::Integer(value) => out.push_str(&value.to_string()),
+                Node::Register(reg) => out.push_str(&format!("x{}", reg)),
+                _ => return Err(EmitError::InvalidArgumentType(name.to_owned(), i)),
+            }
+            rest = &rest[param.len()..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    };
+    Ok(match node {
+        Node::Identifier(id) if id == "\\@" => Node::Integer(expansion as u64),
+        Node::Identifier(id) if id.starts_with('\\') => {
+            match definition.params.iter().position(|p| id[1..] == *p) {
+                Some(i) => values[i].clone(),
+                None => Node::Identifier(text(&id)?.into()),
+            }
+        }
+        Node::Identifier(id) if id.contains('\\') => Node::Identifier(text(&id)?.into()),
+        Node::Label(lname) if lname.contains('\\') => Node::Label(text(&lname)?.into()),
+        Node::Instruction(iname, args) if iname.contains('\\') => {
+            Node::Instruction(text(&iname)?.into(), args)
+        }
+        n => n,
+    })
+}
+
+/// Directives that neither emit anything nor depend on the position, which take effect where
+/// they are in the source even in the sections emitted after the code
+fn applies_in_place(name: &str) -> bool {
+    is_conditional_directive(name)
+        || [
+            ".text", ".data", ".rodata", ".bss", ".section", ".equ", ".define", ".req", ".unreq",
+            ".global", ".globl", ".local", ".weak", ".entry", ".type", ".macro", ".endm", ".rept",
+            ".irp",
+        ]
+        .iter()
+        .any(|d| d.eq_ignore_ascii_case(name))
+}
+
+/// Emits the sections other than the code after it: data first and .bss last, each in the
+/// order they were first used. They start at their base from EmitOptions.section_bases or at
+/// the next 8-byte boundary after what came before, so labels in them are only known now.
+fn emit_sections(spec: &arch::RiscVSpec, state: &mut BinaryEmitState<'_>) -> Result<(), EmitError> {
+    let mut sections = std::mem::take(&mut state.sections);
+    if sections.is_empty() {
+        return Ok(());
+    }
+    sections.sort_by_key(|s| s.kind == SectionKind::Bss);
+    state.current_section = None;
+    state.code_end = Some(state.out_buf.len());
+    let conditionals = std::mem::take(&mut state.conditionals);
+    let outer_file = state.current_file.clone();
+    for section in sections.into_iter() {
+        let configured = state
+            .options
+            .section_bases
+            .iter()
+            .find(|(n, _)| *n == section.name);
+        let after = state.out_buf.len().max(state.out_pos);
+        let layout = state.options.layout.as_ref();
+        let region = layout.and_then(|layout| layout.region_of(&section.name));
+        let (base, offset) = match (layout, region) {
+            // after the sections before it in its region, and after everything emitted so far
+            // in out_buf, at the same offset from a multiple of 8 as the address
+            (Some(layout), Some(region)) => {
+                let code_end = state.code_end.unwrap_or(0) as u64;
+                let text_end = (layout.region_of(".text") == Some(region))
+                    .then(|| state.address_at(0) + code_end);
+                let base = match configured {
+                    Some((_, base)) => *base,
+                    None => state
+                        .laid_out
+                        .iter()
+                        .filter(|s| layout.region_of(&s.name) == Some(region))
+                        .map(|s| s.base + s.size as u64)
+                        .chain(text_end)
+                        .fold(region.origin, u64::max)
+                        .next_multiple_of(8),
+                };
+                let offset = after + (base as usize).wrapping_sub(after) % 8;
+                state.placements.push((offset, base));
+                (base, offset)
+            }
+            _ => {
+                let base = match configured {
+                    Some((_, base)) => *base,
+                    None => after.next_multiple_of(8) as u64,
+                };
+                (base, base as usize)
+            }
+        };
+        if offset as u64 > state.options.max_image_size {
+            return Err(EmitError::ImageTooLarge(base));
+        }
+        // what aligning the section to 8 bytes skipped, unless its base was given
+        if configured.is_none() {
+            state.padding += offset - after;
+        }
+        // local labels don't reach into another section
+        emit_deferred(spec, state)?;
+        state.local_label_set.clear();
+        state.current_mode = EmitMode::Data;
+        state.out_pos = offset;
+        state.region_start = state.written.len();
+        let mut end = state.out_pos;
+        for (file, node) in section.elements.iter() {
+            if *file != state.current_file {
+                emit_deferred(spec, state)?;
+                state.local_label_set.clear();
+                state.current_file = file.clone();
+            }
+            let result = emit_element(spec, state, node);
+            state.recover(node, result)?;
+            end = end.max(state.out_pos);
+        }
+        state.laid_out.push(ImageSection {
+            name: section.name,
+            kind: section.kind,
+            base,
+            offset,
+            size: end - offset,
+        });
     }
+    state.current_file = outer_file;
+    state.conditionals = conditionals;
+    Ok(())
 }
 
-fn emit_deferred(spec: &arch::RiscVSpec, state: &mut BinaryEmitState) -> Result<(), EmitError> {
-    let mut to_remove = Vec::new();
-    let mut to_emit = Vec::new();
-    for (i, (pos, insn)) in state.deferred.iter().enumerate() {
-        let pc = *pos as u64;
-        let simp = insn.emitter_simplify(&|cname| state.find_const(cname, spec), pc);
-        if !simp.1 {
+/// Decodes the C escapes in a string as written in the source (the parser keeps them as-is)
+pub(crate) fn unescape(iname: &str, raw: &[u8]) -> Result<Vec<u8>, EmitError> {
+    let mut out = Vec::with_capacity(raw.len());
+    let mut rest = raw.iter().copied();
+    while let Some(c) = rest.next() {
+        if c != b'\\' {
+            out.push(c);
             continue;
         }
-        to_emit.push((*pos, simp.0));
-        to_remove.push(i);
-    }
-    for i in to_remove.iter().rev() {
-        state.deferred.swap_remove(*i);
+        let escaped = match rest.next() {
+            Some(b'n') => b'\n',
+            Some(b't') => b'\t',
+            Some(b'r') => b'\r',
+            Some(b'0') => 0,
+            Some(b'\\') => b'\\',
+            Some(b'"') => b'"',
+            Some(b'\'') => b'\'',
+            Some(b'x') => {
+                let digits: Vec<u8> = rest.clone().take(2).collect();
+                let value = std::str::from_utf8(&digits)
+                    .ok()
+                    .filter(|d| d.len() == 2)
+                    .and_then(|d| u8::from_str_radix(d, 16).ok());
+                match value {
+                    Some(value) => {
+                        rest.nth(1);
+                        value
+                    }
+                    None => {
+                        let sequence = format!("\\x{}", String::from_utf8_lossy(&digits));
+                        return Err(EmitError::InvalidEscape(iname.to_owned(), sequence));
+                    }
+                }
+            }
+            other => {
+                let sequence = format!("\\{}", other.map(|c| c as char).unwrap_or_default());
+                return Err(EmitError::InvalidEscape(iname.to_owned(), sequence));
+            }
+        };
+        out.push(escaped);
     }
-    for (pos, insn) in to_emit.into_iter() {
-        let saved_pos = state.out_pos;
-        state.out_pos = pos;
-        emit_binary_recurse(&spec, state, &insn)?;
-        state.out_pos = saved_pos;
+    Ok(out)
+}
+
+/// Evaluates the condition of .if/.elseif, which has to be known at that point
+fn eval_condition(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+    iname: &str,
+    cond: &Node,
+) -> Result<bool, EmitError> {
+    eval_value(spec, state, iname, cond).map(|v| v != 0)
+}
+
+/// Value of the operand of a conditional directive, see BinaryEmitState::value_now
+fn eval_value(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+    iname: &str,
+    arg: &Node,
+) -> Result<u64, EmitError> {
+    state
+        .value_now(spec, arg)
+        .ok_or_else(|| EmitError::InvalidArgumentType(iname.to_owned(), 0))
+}
+
+/// Whether the symbol named by the argument of .ifdef/.ifndef is defined at this point: only
+/// what comes before it in the source counts, like for the conditions of .if
+fn is_defined(
+    spec: &arch::RiscVSpec,
+    state: &BinaryEmitState<'_>,
+    iname: &str,
+    arg: &Node,
+) -> Result<bool, EmitError> {
+    match arg {
+        Node::Argument(box Node::Identifier(name)) => Ok(state.find_const(name, spec).is_some()
+            || state.register_aliases.contains_key(name.as_str())),
+        _ => Err(EmitError::InvalidArgumentType(iname.to_owned(), 0)),
     }
-    Ok(())
 }
 
 fn emit_binary_recurse(
     spec: &arch::RiscVSpec,
-    state: &mut BinaryEmitState,
+    state: &mut BinaryEmitState<'_>,
     node: &Node,
 ) -> Result<(), EmitError> {
     use Node::*;
 
-    let ialign_bytes = (spec.get_const("IALIGN").unwrap_or(32) as usize + 7) / 8;
-    let max_ilen_bytes = (spec.get_const("ILEN").unwrap_or(32) as usize + 7) / 8;
-
     match node {
         Root(nodes) => {
             for node in nodes.iter() {
-                emit_binary_recurse(spec, state, node)?;
-            }
-            emit_deferred(spec, state)?;
-            if let Some(defnode) = state.deferred.first() {
-                return Err(EmitError::UnexpectedNodeType(format!("{:?}", defnode)));
+                let result = emit_element(spec, state, node);
+                state.recover(node, result)?;
             }
+            let result = finish_deferred(spec, state);
+            state.with_errors(result)
+        }
+        Located(offset, box inner) => {
+            let outer = state.location.replace(*offset);
+            let result = emit_binary_recurse(spec, state, inner);
+            state.location = outer;
+            result.map_err(|e| e.at(*offset))
+        }
+        Label(lname) if lname.contains(':') => {
+            state
+                .numeric_label_set
+                .insert(lname.to_string(), state.pc());
+            let name = lname.split(':').next().unwrap_or_default();
+            state.local_labels.push((name.to_owned(), state.pc()));
+            state.symbol_defined(lname.clone());
             Ok(())
         }
         Label(lname) => {
-            if lname.starts_with('.') {
-                // local label
+            let scope = state.options.local_labels;
+            if scope.is_local(lname) {
                 if state
                     .local_label_set
-                    .insert(lname.to_owned(), state.out_pos as u64)
+                    .insert(lname.to_string(), state.pc())
                     .is_some()
                 {
-                    return Err(EmitError::DuplicateLabel(lname.to_owned()));
+                    return Err(EmitError::DuplicateLabel(lname.to_string()));
                 }
+                state.local_labels.push((lname.to_string(), state.pc()));
+                state.symbol_defined(lname.clone());
             } else {
                 // handle all previous labels and local labels
                 emit_deferred(spec, state)?;
-                state.local_label_set.clear();
+                if scope.starts_scope(lname) {
+                    state.local_label_set.clear();
+                }
+                if let Some(file) = &state.current_file {
+                    if let Some(first) = state.label_files.insert(lname.to_string(), file.clone()) {
+                        if first != *file {
+                            return Err(EmitError::DuplicateLabelInFiles(
+                                lname.to_string(),
+                                first,
+                                file.clone(),
+                            ));
+                        }
+                    }
+                }
 
-                if state
-                    .label_set
-                    .insert(lname.to_owned(), state.out_pos as u64)
-                    .is_some()
+                if state.const_set.contains_key(lname.as_str())
+                    || state.register_aliases.contains_key(lname.as_str())
+                    || state
+                        .label_set
+                        .insert(lname.to_string(), state.pc())
+                        .is_some()
                 {
-                    return Err(EmitError::DuplicateLabel(lname.to_owned()));
+                    return Err(EmitError::DuplicateLabel(lname.to_string()));
                 }
+                state.symbol_defined(lname.clone());
             }
             Ok(())
         }
@@ -132,121 +3108,1146 @@ fn emit_binary_recurse(
                 // .org ADDRESS
                 ".org" | ".ORG" => {
                     if args.len() != 1 {
-                        return Err(EmitError::InvalidArgumentCount(iname.clone()));
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
                     }
-                    if let (Node::Argument(box Node::Integer(adr)), _) = args[0].emitter_simplify(
-                        &|cname| state.find_const(cname, spec),
-                        state.out_pos as u64,
-                    ) {
-                        let new_out_pos = adr as usize;
+                    if let Some(adr) = state.value_now(spec, &args[0]) {
+                        // a layout can place the part of the output somewhere else
+                        let new_out_pos = state
+                            .offset_in_part(state.out_pos, adr)
+                            .ok_or_else(|| EmitError::InvalidArgumentType(iname.to_string(), 0))?;
+                        if new_out_pos as u64 > state.options.max_image_size {
+                            return Err(EmitError::ImageTooLarge(adr));
+                        }
                         if new_out_pos > state.out_buf.len() {
                             state
                                 .out_buf
                                 .reserve(new_out_pos - state.out_buf.len() + 32 * 32);
                             state.out_buf.resize(new_out_pos, 0);
                         }
+                        state.notify(EmitEvent::Org {
+                            from: state.pc(),
+                            to: adr,
+                        });
                         state.out_pos = new_out_pos;
                         Ok(())
                     } else {
-                        Err(EmitError::InvalidArgumentType(iname.clone(), 0))
+                        Err(EmitError::InvalidArgumentType(iname.to_string(), 0))
+                    }
+                }
+                // .align/.p2align POWER[, FILL[, MAX]] and .balign BYTES[, FILL[, MAX]]
+                ".align" | ".ALIGN" | ".p2align" | ".P2ALIGN" | ".balign" | ".BALIGN" => {
+                    if args.is_empty() || args.len() > 3 {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let mut values = Vec::new();
+                    for (i, arg) in args.iter().enumerate() {
+                        match arg
+                            .emitter_simplify(&|cname| state.find_const(cname, spec), state.pc())
+                        {
+                            (Node::Argument(box Node::Integer(v)), _) => values.push(v),
+                            _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), i)),
+                        }
+                    }
+                    let alignment = if !iname.eq_ignore_ascii_case(".balign") {
+                        if values[0] >= 32 {
+                            return Err(EmitError::InvalidArgumentType(iname.to_string(), 0));
+                        }
+                        1u64 << values[0]
+                    } else {
+                        values[0]
+                    };
+                    if !alignment.is_power_of_two() {
+                        return Err(EmitError::InvalidArgumentType(iname.to_string(), 0));
+                    }
+                    let fill = match values.get(1) {
+                        Some(fill) if *fill > 0xff => {
+                            return Err(EmitError::InvalidArgumentType(iname.to_string(), 1))
+                        }
+                        fill => fill.map(|f| *f as u8),
+                    };
+                    // no padding at all where it would take more than MAX bytes
+                    let padding = state.pc().next_multiple_of(alignment) - state.pc();
+                    if values.get(2).is_some_and(|max| padding > *max) {
+                        return Ok(());
+                    }
+                    // the linker may delete bytes before, so code pads with the most it may
+                    // need for the linker to trim
+                    let smallest = match state.compressing(spec) {
+                        true => COMPRESSED_BYTES as u64,
+                        false => 4,
+                    };
+                    if state.options.linker_relax
+                        && state.options.external_symbols
+                        && state.current_mode == EmitMode::Text
+                        && values.len() == 1
+                        && alignment > smallest
+                    {
+                        state.relocations.push(Relocation {
+                            offset: state.pc(),
+                            kind: "R_RISCV_ALIGN".to_owned(),
+                            symbol: String::new(),
+                            addend: (alignment - smallest) as i64,
+                        });
+                        state.padding += (alignment - smallest) as usize;
+                        return state.pad_bytes(spec, (alignment - smallest) as usize);
+                    }
+                    state.align_to(spec, alignment as usize, fill)
+                }
+                // .space/.skip COUNT[, FILL], .zero COUNT or .fill REPEAT[, SIZE[, VALUE]]
+                ".space" | ".SPACE" | ".skip" | ".SKIP" | ".zero" | ".ZERO" | ".fill" | ".FILL" => {
+                    let lname = iname.to_ascii_lowercase();
+                    let max_args = match lname.as_ref() {
+                        ".zero" => 1,
+                        ".fill" => 3,
+                        _ => 2,
+                    };
+                    if args.is_empty() || args.len() > max_args {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    // the size moves everything after it, so it can't wait for labels
+                    let mut values = Vec::new();
+                    for (i, arg) in args.iter().enumerate() {
+                        match state.value_now(spec, arg) {
+                            Some(v) => values.push(v),
+                            None => {
+                                return Err(EmitError::InvalidArgumentType(iname.to_string(), i))
+                            }
+                        }
+                    }
+                    // the bytes written COUNT times: the FILL byte, or the SIZE bytes of VALUE
+                    let pattern = if lname == ".fill" {
+                        let size = values.get(1).copied().unwrap_or(1);
+                        if !(1..=8).contains(&size) {
+                            return Err(EmitError::InvalidArgumentType(iname.to_string(), 1));
+                        }
+                        let size = size as usize;
+                        let value = values.get(2).copied().unwrap_or(0);
+                        if !fits_in_bytes(value, size) {
+                            return Err(data_out_of_range(iname, 2, value, size));
+                        }
+                        let mut word = value.to_le_bytes();
+                        state.options.data_endianness.arrange(&mut word[..size]);
+                        word[..size].to_vec()
+                    } else {
+                        let fill = values.get(1).copied().unwrap_or(0);
+                        if fill > 0xff {
+                            return Err(EmitError::InvalidArgumentType(iname.to_string(), 1));
+                        }
+                        vec![fill as u8]
+                    };
+                    let size = values[0].saturating_mul(pattern.len() as u64);
+                    let end = (state.out_pos as u64).saturating_add(size);
+                    if end > state.options.max_image_size {
+                        return Err(EmitError::ImageTooLarge(end));
+                    }
+                    let bytes = state.accomodate_bytes(size as usize)?;
+                    if let [fill] = pattern[..] {
+                        bytes.fill(fill);
+                    } else {
+                        for chunk in bytes.chunks_exact_mut(pattern.len()) {
+                            chunk.copy_from_slice(&pattern);
+                        }
                     }
+                    Ok(())
                 }
                 // .equ/.define NAME VALUE
-                ".equ" | ".EQU" | ".define" | ".DEFINE" => {
+                // .equ/.define NAME, VALUE, or .set NAME, VALUE which can be given again
+                ".equ" | ".EQU" | ".define" | ".DEFINE" | ".set" | ".SET" => {
                     if args.len() != 2 {
-                        return Err(EmitError::InvalidArgumentCount(iname.clone()));
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
                     }
                     if let Node::Argument(box Node::Identifier(defname)) = &args[0] {
-                        if let (Node::Argument(box Node::Integer(val)), _) = args[1]
-                            .emitter_simplify(
-                                &|cname| state.find_const(cname, spec),
-                                state.out_pos as u64,
-                            )
+                        let defsym = state.options.defsyms.iter().any(|(n, _)| *defname == **n);
+                        if defsym && state.options.defsyms_override {
+                            state.diagnostics.push(Diagnostic::Warning(format!(
+                                "{} {} ignored, keeping the predefined value",
+                                iname, defname
+                            )));
+                            return Ok(());
+                        }
+                        let set = iname.eq_ignore_ascii_case(".set");
+                        let redefinable = set && state.set_symbols.contains(defname.as_str());
+                        if !redefinable
+                            && (state.const_set.contains_key(defname.as_str())
+                                || state.register_aliases.contains_key(defname.as_str())
+                                || state.label_set.contains_key(defname.as_str()))
                         {
-                            if state.const_set.insert(defname.to_owned(), val).is_none() {
-                                Ok(())
-                            } else {
-                                Err(EmitError::DuplicateConstant(defname.to_owned()))
+                            return Err(EmitError::DuplicateConstant(defname.to_string()));
+                        }
+                        let reg = match &args[1] {
+                            Node::Argument(box Node::Register(reg)) => Some(*reg),
+                            Node::Argument(box Node::Identifier(name)) => {
+                                state.register_aliases.get(name.as_str()).copied()
                             }
+                            _ => None,
+                        };
+                        // the value is taken now, so instructions already emitted or waiting for
+                        // labels keep the one before, and the ones that waited for the name
+                        // get the value it had first
+                        if redefinable {
+                            emit_deferred(spec, state)?;
+                        }
+                        // a first definition can't use itself, even from the pass before
+                        let circular = args[1].referenced_symbols().contains(defname)
+                            && state.find_const(defname, spec).is_none();
+                        if circular {
+                            return Err(EmitError::InvalidArgumentType(iname.to_string(), 1));
+                        }
+                        if let Some(reg) = reg {
+                            state.const_set.remove(defname.as_str());
+                            state.register_aliases.insert(defname.to_string(), reg);
+                        } else if let Some(val) = state.value_now(spec, &args[1]) {
+                            state.register_aliases.remove(defname.as_str());
+                            state.const_set.insert(defname.to_string(), val);
+                            state.notify(EmitEvent::Constant {
+                                name: defname,
+                                value: val,
+                            });
+                            state.symbol_defined(defname.clone());
                         } else {
-                            Err(EmitError::InvalidArgumentType(iname.clone(), 1))
+                            return Err(EmitError::InvalidArgumentType(iname.to_string(), 1));
                         }
+                        if set {
+                            state.set_symbols.insert(defname.to_string());
+                        }
+                        Ok(())
                     } else {
-                        Err(EmitError::InvalidArgumentType(iname.clone(), 0))
+                        Err(EmitError::InvalidArgumentType(iname.to_string(), 0))
                     }
                 }
-                // Standard RISC-V instructions
-                _ => {
-                    // check spec
-                    let specinsn = spec
-                        .get_instruction_by_name(iname)
-                        .ok_or_else(|| EmitError::InvalidInstruction(iname.clone()))?;
-                    let fmt = specinsn.get_format(&spec);
-                    if args.len() != specinsn.args.len() {
-                        return Err(EmitError::InvalidArgumentCount(iname.clone()));
+                // NAME .req REGISTER names the register, until .unreq NAME
+                ".req" | ".REQ" => {
+                    let name = match args.first() {
+                        Some(Node::Argument(box Node::Identifier(name))) if args.len() == 2 => name,
+                        _ => return Err(EmitError::InvalidArgumentCount(iname.to_string())),
+                    };
+                    let reg = match &args[1] {
+                        Node::Argument(box Node::Register(reg)) => *reg,
+                        Node::Argument(box Node::Identifier(other)) => {
+                            match state.register_aliases.get(other.as_str()) {
+                                Some(reg) => *reg,
+                                None => {
+                                    return Err(EmitError::InvalidArgumentType(
+                                        iname.to_string(),
+                                        1,
+                                    ))
+                                }
+                            }
+                        }
+                        _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), 1)),
+                    };
+                    // naming the same register again is fine, like in a file included twice
+                    let taken = match state.register_aliases.get(name.as_str()) {
+                        Some(old) => *old != reg,
+                        None => {
+                            state.const_set.contains_key(name.as_str())
+                                || state.label_set.contains_key(name.as_str())
+                        }
+                    };
+                    if taken {
+                        return Err(EmitError::DuplicateConstant(name.to_string()));
                     }
-
-                    // check length
-                    let ilen_bytes = (fmt.ilen + 7) / 8;
-                    if ilen_bytes > max_ilen_bytes {
-                        return Err(EmitError::InvalidEncoding(iname.clone()));
+                    state.register_aliases.insert(name.to_string(), reg);
+                    Ok(())
+                }
+                ".unreq" | ".UNREQ" => match args.as_slice() {
+                    [Node::Argument(box Node::Identifier(name))]
+                        if state.register_aliases.remove(name.as_str()).is_some() =>
+                    {
+                        Ok(())
                     }
-                    // check alignment
-                    let aligned_pos =
-                        (state.out_pos + ialign_bytes - 1) / ialign_bytes * ialign_bytes;
-                    if state.out_pos != aligned_pos {
-                        // pad out with zeroes
-                        // TODO: NOP alignment instead of zero alignment
-                        state.accomodate_bytes(aligned_pos - state.out_pos);
+                    [_] => Err(EmitError::InvalidArgumentType(iname.to_string(), 0)),
+                    _ => Err(EmitError::InvalidArgumentCount(iname.to_string())),
+                },
+                // .text/.data/.rodata/.bss
+                ".text" | ".TEXT" | ".data" | ".DATA" | ".rodata" | ".RODATA" | ".bss" | ".BSS" => {
+                    if !args.is_empty() {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
                     }
-
-                    // simplify and defer if necessary
-                    let simpinsn = node.emitter_simplify(
-                        &|cname| state.find_const(cname, spec),
-                        state.out_pos as u64,
-                    );
-                    if !simpinsn.1 {
-                        state.deferred.push((state.out_pos, simpinsn.0));
-                        state.accomodate_bytes(ilen_bytes);
+                    state.switch_section(&iname.to_ascii_lowercase());
+                    Ok(())
+                }
+                // .section NAME[, FLAGS...], the flags are implied by the name
+                ".section" | ".SECTION" => {
+                    let name = match args.first() {
+                        Some(Node::Argument(box Node::Identifier(name))) => name.to_string(),
+                        Some(Node::Argument(box Node::StringLiteral(raw))) => {
+                            String::from_utf8_lossy(raw).into_owned()
+                        }
+                        Some(_) => {
+                            return Err(EmitError::InvalidArgumentType(iname.to_string(), 0))
+                        }
+                        None => return Err(EmitError::InvalidArgumentCount(iname.to_string())),
+                    };
+                    state.switch_section(&name);
+                    Ok(())
+                }
+                // .reloc OFFSET, TYPE[, SYMBOL[+ADDEND]]
+                ".reloc" | ".RELOC" => {
+                    if args.len() != 2 && args.len() != 3 {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let kind = match &args[1] {
+                        Node::Argument(box Node::Identifier(kind)) => kind,
+                        _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), 1)),
+                    };
+                    if relocation_type_number(kind).is_none() {
+                        return Err(EmitError::UnknownRelocation(kind.to_string()));
+                    }
+                    // the target symbol is left for the linker to resolve
+                    let (symbol, addend) = match args.get(2) {
+                        None => (String::new(), 0),
+                        Some(Node::Argument(box Node::Identifier(sym))) => (sym.to_string(), 0),
+                        Some(Node::Argument(box Node::Plus(
+                            box Node::Identifier(sym),
+                            box Node::Integer(add),
+                        ))) => (sym.to_string(), *add as i64),
+                        Some(Node::Argument(box Node::Minus(
+                            box Node::Identifier(sym),
+                            box Node::Integer(sub),
+                        ))) => (sym.to_string(), (*sub as i64).wrapping_neg()),
+                        Some(Node::Argument(box Node::Integer(add))) => {
+                            (String::new(), *add as i64)
+                        }
+                        _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), 2)),
+                    };
+                    let reloc = Relocation {
+                        offset: 0,
+                        kind: kind.to_ascii_uppercase(),
+                        symbol,
+                        addend,
+                    };
+                    let (offset, _) = args[0]
+                        .emitter_simplify(&|cname| state.find_const(cname, spec), state.pc());
+                    // the offset can refer to a label further ahead
+                    state.pending_relocations.push_ready((offset, reloc));
+                    resolve_relocations(spec, state)
+                }
+                // .byte/.half/.word/.dword VALUE..., or .2byte/.4byte/.8byte, and .float/.double
+                // whose literals the parser gave as the values of their bits
+                ".byte" | ".BYTE" | ".half" | ".HALF" | ".word" | ".WORD" | ".dword" | ".DWORD"
+                | ".2byte" | ".4byte" | ".8byte" | ".float" | ".FLOAT" | ".double" | ".DOUBLE" => {
+                    if args.is_empty() {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let width = match iname.to_ascii_lowercase().as_ref() {
+                        ".byte" => 1,
+                        ".half" | ".2byte" => 2,
+                        ".word" | ".4byte" | ".float" => 4,
+                        _ => 8,
+                    };
+                    let simpdata =
+                        node.emitter_simplify(&|cname| state.find_const(cname, spec), state.pc());
+                    if !simpdata.1 {
+                        // wait for the labels like an instruction would
+                        let mut data = simpdata.0;
+                        if let Some(offset) = state.location {
+                            data = Node::Located(offset, Box::new(data));
+                        }
+                        state.defer(spec, data);
+                        state.accomodate_bytes(width * args.len())?;
                         return Ok(());
                     }
-                    let args;
-                    if let Node::Instruction(_, sargs) = simpinsn.0 {
-                        args = sargs;
-                    } else {
-                        panic!("Simplified instruction is now a {:?}", simpinsn.0);
+                    state.record_label_uses(spec, node);
+                    let values = match &simpdata.0 {
+                        Node::Instruction(_, sargs) => sargs
+                            .iter()
+                            .enumerate()
+                            .map(|(i, arg)| match arg {
+                                Node::Argument(box Node::Integer(v)) => Ok(*v),
+                                _ => Err(EmitError::InvalidArgumentType(iname.to_string(), i)),
+                            })
+                            .collect::<Result<Vec<u64>, EmitError>>()?,
+                        _ => return Err(EmitError::UnexpectedNodeType(format!("{:?}", node))),
+                    };
+                    let mut bytes = Vec::with_capacity(width * values.len());
+                    for (i, value) in values.into_iter().enumerate() {
+                        if !fits_in_bytes(value, width) {
+                            return Err(data_out_of_range(iname, i, value, width));
+                        }
+                        let mut word = value.to_le_bytes();
+                        state.options.data_endianness.arrange(&mut word[..width]);
+                        bytes.extend_from_slice(&word[..width]);
                     }
-
-                    // handle arguments
-                    let mut argv: SmallVec<[u64; 4]> = SmallVec::new();
+                    state.accomodate_bytes(bytes.len())?.copy_from_slice(&bytes);
+                    Ok(())
+                }
+                // .ascii/.asciz/.string "TEXT"...
+                ".ascii" | ".ASCII" | ".asciz" | ".ASCIZ" | ".string" | ".STRING" => {
+                    if args.is_empty() {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let terminated = !iname.eq_ignore_ascii_case(".ascii");
+                    let mut bytes = Vec::new();
                     for (i, arg) in args.iter().enumerate() {
-                        match fmt.fields[specinsn.args[i]].vtype {
-                            arch::FieldType::Value => {
-                                if let Node::Argument(box Node::Integer(val)) = arg {
-                                    argv.push(*val);
-                                } else {
-                                    return Err(EmitError::InvalidArgumentType(iname.clone(), i));
+                        match arg {
+                            Node::Argument(box Node::StringLiteral(raw)) => {
+                                bytes.extend(unescape(iname, raw)?);
+                                if terminated {
+                                    bytes.push(0);
                                 }
                             }
-                            arch::FieldType::Register => {
-                                if let Node::Argument(box Node::Register(rid)) = arg {
-                                    argv.push(*rid as u64);
+                            _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), i)),
+                        }
+                    }
+                    state.accomodate_bytes(bytes.len())?.copy_from_slice(&bytes);
+                    Ok(())
+                }
+                // .rvtable BASE, LABEL...
+                ".rvtable" | ".RVTABLE" => {
+                    if args.len() < 2 {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let simptable =
+                        node.emitter_simplify(&|cname| state.find_const(cname, spec), state.pc());
+                    let entry_bytes = 4;
+                    if !simptable.1 {
+                        // wait for the labels like an instruction would
+                        let mut table = simptable.0;
+                        if let Some(offset) = state.location {
+                            table = Node::Located(offset, Box::new(table));
+                        }
+                        state.defer(spec, table);
+                        state.accomodate_bytes(entry_bytes * (args.len() - 1))?;
+                        return Ok(());
+                    }
+                    let values = match &simptable.0 {
+                        Node::Instruction(_, sargs) => sargs
+                            .iter()
+                            .enumerate()
+                            .map(|(i, arg)| match arg {
+                                Node::Argument(box Node::Integer(v)) => Ok(*v),
+                                _ => Err(EmitError::InvalidArgumentType(iname.to_string(), i)),
+                            })
+                            .collect::<Result<Vec<u64>, EmitError>>()?,
+                        _ => return Err(EmitError::UnexpectedNodeType(format!("{:?}", node))),
+                    };
+                    let base = values[0];
+                    let mut entries = Vec::new();
+                    for (i, target) in values.iter().enumerate().skip(1) {
+                        let offset = target.wrapping_sub(base) as i64;
+                        if offset < i32::MIN as i64 || offset > i32::MAX as i64 {
+                            return Err(EmitError::InvalidArgumentType(iname.to_string(), i));
+                        }
+                        let mut entry = (offset as i32).to_le_bytes();
+                        state.options.data_endianness.arrange(&mut entry);
+                        entries.extend_from_slice(&entry);
+                    }
+                    state
+                        .accomodate_bytes(entries.len())?
+                        .copy_from_slice(&entries);
+                    Ok(())
+                }
+                // .checksum START, END, ALGORITHM
+                ".include" | ".INCLUDE" => Err(EmitError::UnresolvedInclude),
+                // .incbin "PATH"[, SKIP[, COUNT]]
+                ".incbin" | ".INCBIN" => {
+                    if args.is_empty() || args.len() > 3 {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let path = match &args[0] {
+                        Node::Argument(box Node::StringLiteral(raw)) => {
+                            String::from_utf8(unescape(iname, raw)?)
+                                .map_err(|_| EmitError::InvalidArgumentType(iname.to_string(), 0))?
+                        }
+                        _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), 0)),
+                    };
+                    // like .space, the size moves everything after it
+                    let mut range = Vec::new();
+                    for (i, arg) in args.iter().enumerate().skip(1) {
+                        match state.value_now(spec, arg) {
+                            Some(v) => range.push(v),
+                            None => {
+                                return Err(EmitError::InvalidArgumentType(iname.to_string(), i))
+                            }
+                        }
+                    }
+                    let contents = crate::files::read(std::path::Path::new(&path))
+                        .map_err(|e| EmitError::IncbinRead(path.clone(), e.to_string()))?;
+                    let skip = range.first().copied().unwrap_or(0);
+                    if skip > contents.len() as u64 {
+                        return Err(EmitError::InvalidArgumentType(iname.to_string(), 1));
+                    }
+                    let rest = &contents[skip as usize..];
+                    let count = range.get(1).copied().unwrap_or(rest.len() as u64);
+                    if count > rest.len() as u64 {
+                        return Err(EmitError::InvalidArgumentType(iname.to_string(), 2));
+                    }
+                    let end = (state.out_pos as u64).saturating_add(count);
+                    if end > state.options.max_image_size {
+                        return Err(EmitError::ImageTooLarge(end));
+                    }
+                    state
+                        .accomodate_bytes(count as usize)?
+                        .copy_from_slice(&rest[..count as usize]);
+                    Ok(())
+                }
+                ".checksum" | ".CHECKSUM" => {
+                    if args.len() != 3 {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let algorithm = match &args[2] {
+                        Node::Argument(box Node::Identifier(name)) => {
+                            ChecksumAlgorithm::from_name(name)
+                        }
+                        _ => None,
+                    }
+                    .ok_or_else(|| EmitError::InvalidArgumentType(iname.to_string(), 2))?;
+                    // the range can end at a label further ahead, so it's evaluated at the end
+                    let pc = state.pc();
+                    let const_provider = |cname: &str| state.find_const(cname, spec);
+                    let start = args[0].emitter_simplify(&const_provider, pc).0;
+                    let end = args[1].emitter_simplify(&const_provider, pc).0;
+                    state.pending_checksums.push(PendingChecksum {
+                        position: state.out_pos,
+                        start,
+                        end,
+                        algorithm,
+                    });
+                    state.accomodate_bytes(algorithm.size())?;
+                    Ok(())
+                }
+                // .option rvc/norvc/pic/nopic/push/pop
+                ".option" | ".OPTION" => {
+                    if args.len() != 1 {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let option = match &args[0] {
+                        Node::Argument(box Node::Identifier(option)) => option.to_ascii_lowercase(),
+                        _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), 0)),
+                    };
+                    match option.as_ref() {
+                        "rvc" => state.rvc = true,
+                        "norvc" => state.rvc = false,
+                        "pic" => state.options.pic = true,
+                        "nopic" => state.options.pic = false,
+                        "push" => state.option_stack.push((state.rvc, state.options.pic)),
+                        "pop" => {
+                            (state.rvc, state.options.pic) =
+                                state.option_stack.pop().ok_or_else(|| {
+                                    EmitError::InvalidArgumentType(iname.to_string(), 0)
+                                })?
+                        }
+                        _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), 0)),
+                    }
+                    Ok(())
+                }
+                // .type NAME, @function/@object/@notype
+                ".type" | ".TYPE" => {
+                    if args.len() != 2 {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let name = match &args[0] {
+                        Node::Argument(box Node::Identifier(name)) => name,
+                        _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), 0)),
+                    };
+                    let stype = match &args[1] {
+                        Node::Argument(box Node::Identifier(stype)) => {
+                            SymbolType::from_directive(stype)
+                        }
+                        _ => None,
+                    }
+                    .ok_or_else(|| EmitError::InvalidArgumentType(iname.to_string(), 1))?;
+                    state.symbol_types.insert(name.to_string(), stype);
+                    Ok(())
+                }
+                // .global/.globl/.local/.weak NAME[, NAME...]
+                ".global" | ".GLOBAL" | ".globl" | ".GLOBL" | ".local" | ".LOCAL" | ".weak"
+                | ".WEAK" => {
+                    if args.is_empty() {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let binding = match iname.to_ascii_lowercase().as_ref() {
+                        ".local" => SymbolBinding::Local,
+                        ".weak" => SymbolBinding::Weak,
+                        _ => SymbolBinding::Global,
+                    };
+                    for (i, arg) in args.iter().enumerate() {
+                        match arg {
+                            Node::Argument(box Node::Identifier(name)) => {
+                                state.symbol_bindings.insert(name.to_string(), binding);
+                                if binding == SymbolBinding::Local {
+                                    state.export_positions.remove(name.as_str());
                                 } else {
-                                    return Err(EmitError::InvalidArgumentType(iname.clone(), i));
+                                    state.export_positions.insert(name.to_string(), state.pc());
+                                }
+                            }
+                            _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), i)),
+                        }
+                    }
+                    Ok(())
+                }
+                // .entry NAME
+                ".entry" | ".ENTRY" => {
+                    if args.len() != 1 {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let name = match &args[0] {
+                        Node::Argument(box Node::Identifier(name)) => name,
+                        _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), 0)),
+                    };
+                    if let Some((first, _)) = &state.entry {
+                        return Err(EmitError::DuplicateEntry(first.clone(), name.to_string()));
+                    }
+                    state.entry = Some((name.to_string(), state.pc()));
+                    Ok(())
+                }
+                // .size NAME, SIZE
+                ".size" | ".SIZE" => {
+                    if args.len() != 2 {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let name = match &args[0] {
+                        Node::Argument(box Node::Identifier(name)) => name,
+                        _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), 0)),
+                    };
+                    let (size, _) = args[1]
+                        .emitter_simplify(&|cname| state.find_const(cname, spec), state.pc());
+                    // the size can refer to a label further ahead
+                    state.pending_sizes.push_ready((name.to_string(), size));
+                    resolve_symbol_sizes(spec, state)
+                }
+                // .macro NAME PARAMS..., collecting the lines up to .endm
+                ".macro" | ".MACRO" => {
+                    let mut names = Vec::new();
+                    let mut defaults = Vec::new();
+                    for (i, arg) in args.iter().enumerate() {
+                        match arg {
+                            Node::Argument(box Node::Identifier(name)) => {
+                                names.push(name.to_string());
+                                defaults.push(None);
+                            }
+                            // PARAM=DEFAULT
+                            Node::Argument(box Node::Equal(
+                                box Node::Identifier(name),
+                                default,
+                            )) if i > 0 => {
+                                names.push(name.to_string());
+                                defaults.push(Some((**default).clone()));
+                            }
+                            _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), i)),
+                        }
+                    }
+                    if names.is_empty() {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let name = names.remove(0);
+                    defaults.remove(0);
+                    if state.macros.contains_key(name.as_str()) {
+                        return Err(EmitError::DuplicateMacro(name.to_string()));
+                    }
+                    state.macro_definition = Some(MacroDefinition {
+                        name,
+                        definition: Macro {
+                            params: names,
+                            defaults,
+                            body: Vec::new(),
+                        },
+                        repetition: None,
+                        nested: 0,
+                    });
+                    Ok(())
+                }
+                // .rept COUNT and .irp PARAM, VALUES..., collecting the lines up to .endr
+                ".rept" | ".REPT" | ".irp" | ".IRP" => {
+                    let (params, repetitions) = if iname.eq_ignore_ascii_case(".rept") {
+                        if args.len() != 1 {
+                            return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                        }
+                        let count = match state.value_now(spec, &args[0]) {
+                            Some(v) if (v as i64) >= 0 => v,
+                            _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), 0)),
+                        };
+                        // each repetition emitting a byte would be over the size limit anyway
+                        if count > state.options.max_image_size {
+                            return Err(EmitError::TooManyRepetitions(count));
+                        }
+                        (Vec::new(), Repetitions::Count(count))
+                    } else {
+                        let param = match args.first() {
+                            Some(Node::Argument(box Node::Identifier(param))) => param.to_string(),
+                            Some(_) => {
+                                return Err(EmitError::InvalidArgumentType(iname.to_string(), 0))
+                            }
+                            None => return Err(EmitError::InvalidArgumentCount(iname.to_string())),
+                        };
+                        let values = args[1..].iter().map(|v| vec![v.clone()]).collect();
+                        (vec![param], Repetitions::Values(values))
+                    };
+                    let opener = match state.location {
+                        Some(offset) => Node::Located(offset, Box::new(node.clone())),
+                        None => node.clone(),
+                    };
+                    state.macro_definition = Some(MacroDefinition {
+                        name: iname.to_ascii_lowercase(),
+                        definition: Macro {
+                            defaults: vec![None; params.len()],
+                            params,
+                            body: Vec::new(),
+                        },
+                        repetition: Some((opener, repetitions)),
+                        nested: 0,
+                    });
+                    Ok(())
+                }
+                // a .endm ending a definition never gets here
+                ".endm" | ".ENDM" | ".endr" | ".ENDR" => {
+                    Err(EmitError::UnbalancedMacro(iname.to_string()))
+                }
+                // .if/.elseif CONDITION, .ifeq/.ifne/.ifgt/.ifge/.iflt/.ifle VALUE,
+                // .ifdef/.ifndef NAME, .else, .endif
+                ".if" | ".IF" | ".ifdef" | ".IFDEF" | ".ifndef" | ".IFNDEF" | ".ifeq" | ".IFEQ"
+                | ".ifne" | ".IFNE" | ".ifgt" | ".IFGT" | ".ifge" | ".IFGE" | ".iflt" | ".IFLT"
+                | ".ifle" | ".IFLE" => {
+                    if args.len() != 1 {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let opened_by = CONDITIONAL_OPENERS
+                        .iter()
+                        .find(|d| d.eq_ignore_ascii_case(iname))
+                        .unwrap();
+                    // conditions in skipped code are not evaluated
+                    let enclosing = state.emitting();
+                    let cond = enclosing
+                        && match *opened_by {
+                            ".if" => eval_condition(spec, state, iname, &args[0])?,
+                            ".ifdef" | ".ifndef" => {
+                                is_defined(spec, state, iname, &args[0])?
+                                    == (*opened_by == ".ifdef")
+                            }
+                            compare => {
+                                let value = eval_value(spec, state, iname, &args[0])? as i64;
+                                match compare {
+                                    ".ifeq" => value == 0,
+                                    ".ifne" => value != 0,
+                                    ".ifgt" => value > 0,
+                                    ".ifge" => value >= 0,
+                                    ".iflt" => value < 0,
+                                    _ => value <= 0,
                                 }
                             }
+                        };
+                    state.conditionals.push(Conditional {
+                        enclosing,
+                        taken: cond,
+                        active: cond,
+                        seen_else: false,
+                        opened_by,
+                        opened_at: state.location,
+                    });
+                    Ok(())
+                }
+                ".elseif" | ".ELSEIF" => {
+                    if args.len() != 1 {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let frame = match state.conditionals.last() {
+                        Some(frame) if !frame.seen_else => *frame,
+                        _ => return Err(EmitError::UnbalancedConditional(iname.to_string())),
+                    };
+                    let cond = frame.enclosing
+                        && !frame.taken
+                        && eval_condition(spec, state, iname, &args[0])?;
+                    if let Some(frame) = state.conditionals.last_mut() {
+                        frame.active = cond;
+                        frame.taken |= cond;
+                    }
+                    Ok(())
+                }
+                ".else" | ".ELSE" => {
+                    if !args.is_empty() {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    match state.conditionals.last_mut() {
+                        Some(frame) if !frame.seen_else => {
+                            frame.active = frame.enclosing && !frame.taken;
+                            frame.taken = true;
+                            frame.seen_else = true;
+                            Ok(())
+                        }
+                        _ => Err(EmitError::UnbalancedConditional(iname.to_string())),
+                    }
+                }
+                ".endif" | ".ENDIF" => {
+                    if !args.is_empty() {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    state
+                        .conditionals
+                        .pop()
+                        .map(|_| ())
+                        .ok_or_else(|| EmitError::UnbalancedConditional(iname.to_string()))
+                }
+                // .insn FORMAT FIELDS...
+                ".insn" | ".INSN" => {
+                    let format = match args.first() {
+                        Some(Node::Argument(box Node::StringLiteral(f))) => {
+                            String::from_utf8_lossy(f).to_ascii_lowercase()
+                        }
+                        _ => return Err(EmitError::InvalidArgumentType(iname.to_string(), 0)),
+                    };
+                    let specinsn = insn_definition(spec, &format)
+                        .ok_or_else(|| EmitError::InvalidArgumentType(iname.to_string(), 0))?;
+                    if args.len() != specinsn.args.len() + 1 {
+                        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+                    }
+                    let args = state.resolve_register_aliases(iname, args)?;
+                    let node = &Node::Instruction(iname.clone(), args);
+                    emit_instruction(spec, state, node, &specinsn, 1)
+                }
+                // Standard RISC-V instructions
+                _ => {
+                    if iname.starts_with('.') && spec.get_instruction_by_name(iname).is_none() {
+                        if state.options.lenient_directives {
+                            state.diagnostics.push(Diagnostic::Warning(format!(
+                                "unknown directive {} at 0x{:x} ignored",
+                                iname,
+                                state.pc()
+                            )));
+                            return Ok(());
+                        }
+                        return Err(EmitError::UnknownDirective(
+                            iname.to_string(),
+                            suggest_directive(iname),
+                        ));
+                    }
+                    let args = &state.resolve_register_aliases(iname, args)?;
+                    if state.options.relax && state.expanding_site.is_none() {
+                        if let Some(relaxation) = relaxation(spec, &state.options, iname, args)? {
+                            return emit_relaxation_site(spec, state, iname, args, relaxation);
+                        }
+                    }
+                    if state.options.relax_branches && state.expanding_site.is_none() {
+                        let ialign = spec.get_const("IALIGN").unwrap_or(32) as usize;
+                        let pc = state
+                            .address_at(state.out_pos.next_multiple_of(ialign.div_ceil(8).max(1)));
+                        if let Some(branch) = long_branch(spec, iname, args, pc)? {
+                            return emit_branch_site(spec, state, iname, args, branch, pc);
+                        }
+                    }
+                    let pc = state.pc();
+                    let value_of = |node: &Node| match node
+                        .emitter_simplify(&|cname| state.find_const(cname, spec), pc)
+                    {
+                        (Node::Integer(v), true) => Some(v),
+                        _ => None,
+                    };
+                    let expansion = expand_pseudo(spec, &state.options, iname, args, &value_of)?;
+                    if let (Some(_), [_, Node::Argument(box value)]) = (&expansion, &args[..]) {
+                        if iname.eq_ignore_ascii_case("li") && value_of(value).is_none() {
+                            // the value can refer to a label further ahead
+                            let (value, _) =
+                                value.emitter_simplify(&|cname| state.find_const(cname, spec), pc);
+                            let location = state.location;
+                            state.pending_li_values.push_ready((value, location));
+                        }
+                    }
+                    if let Some(expansion) = expansion {
+                        for insn in expansion.iter() {
+                            emit_binary_recurse(spec, state, insn)?;
+                        }
+                        return Ok(());
+                    }
+
+                    // check spec
+                    let specinsn = spec.get_instruction_by_name(iname).ok_or_else(|| {
+                        match arch::standard_extension_of(iname) {
+                            Some(ext) if !spec.extension_enabled(ext) => {
+                                EmitError::ExtensionRequired(iname.to_string(), ext.to_owned())
+                            }
+                            _ => EmitError::InvalidInstruction(
+                                iname.to_string(),
+                                suggest_instruction(spec, state, iname),
+                            ),
+                        }
+                    })?;
+                    if let Some(ext) = &specinsn.extension {
+                        if !spec.extension_enabled(ext) {
+                            return Err(EmitError::ExtensionRequired(
+                                iname.to_string(),
+                                ext.clone(),
+                            ));
                         }
                     }
-                    assert_eq!(argv.len(), specinsn.args.len());
+                    let fmt = specinsn.get_format(&spec);
+                    let slots = specinsn
+                        .default_arg_slots(args.len())
+                        .ok_or_else(|| EmitError::InvalidArgumentCount(iname.to_string()))?;
+                    // fill in omitted operands
+                    let mut given = args.iter();
+                    let full_args = slots
+                        .iter()
+                        .zip(specinsn.args.iter())
+                        .map(|(slot, argid)| match (slot, fmt.fields[*argid].vtype) {
+                            (None, _) => given.next().cloned(),
+                            (Some(v), arch::FieldType::Register) => {
+                                Some(Node::Argument(Box::new(Node::Register(*v as i32))))
+                            }
+                            (Some(v), arch::FieldType::Value | arch::FieldType::Csr) => {
+                                Some(Node::Argument(Box::new(Node::Integer(*v))))
+                            }
+                        })
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| EmitError::InvalidArgumentCount(iname.to_string()))?;
+                    let full_args =
+                        state.resolve_named_values(spec, iname, fmt, specinsn, full_args)?;
+                    let node = &Node::Instruction(iname.clone(), full_args);
 
-                    // emit instruction
-                    let bytes = state.accomodate_bytes(ilen_bytes);
-                    specinsn
-                        .encode_into(bytes, spec, argv.as_slice())
-                        .map_err(|_| EmitError::InvalidEncoding(iname.clone()))
+                    emit_instruction(spec, state, node, specinsn, 0)
                 }
             }
         }
         _ => Err(EmitError::UnexpectedNodeType(format!("{:?}", node))),
     }
 }
+
+/// Encodes `node` as an instruction of `specinsn`, deferring it until its operands are known.
+/// The first `skip` operands only select the definition and are not encoded.
+/// Emits a call, tail or la/lla in the form `relax` picked for it
+fn emit_relaxation_site(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+    iname: &str,
+    args: &[Node],
+    relaxation: Relaxation,
+) -> Result<(), EmitError> {
+    let key = SiteKey::Pseudo(state.pseudo_sites);
+    state.pseudo_sites += 1;
+    let ialign = spec.get_const("IALIGN").unwrap_or(32) as usize;
+    let pc = state.out_pos.next_multiple_of(ialign.div_ceil(8).max(1));
+    let short = state.relaxed.contains(&key);
+    let expansion = if short {
+        vec![relaxation.insn.clone()]
+    } else {
+        let pc = state.pc();
+        let value_of =
+            |node: &Node| match node.emitter_simplify(&|cname| state.find_const(cname, spec), pc) {
+                (Node::Integer(v), true) => Some(v),
+                _ => None,
+            };
+        expand_pseudo(spec, &state.options, iname, args, &value_of)?
+            .ok_or_else(|| EmitError::InvalidInstruction(iname.to_owned(), None))?
+    };
+    state
+        .relax_sites
+        .push((key, state.address_at(pc), RelaxSite::Pseudo(relaxation)));
+    state.expanding_site = Some(short);
+    state.instruction_sites = 0;
+    let result = expansion
+        .iter()
+        .try_for_each(|insn| emit_binary_recurse(spec, state, insn));
+    state.expanding_site = None;
+    state.instruction_sites = 0;
+    result
+}
+
+/// Emits a branch or jal as it is, or in its long form once `relax` found its target out of
+/// its reach
+fn emit_branch_site(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+    iname: &str,
+    args: &[Node],
+    branch: LongBranch,
+    pc: u64,
+) -> Result<(), EmitError> {
+    let key = SiteKey::Pseudo(state.pseudo_sites);
+    state.pseudo_sites += 1;
+    let long = state.lengthened.contains(&key);
+    let expansion = if long {
+        branch.long.clone()
+    } else {
+        vec![Node::Instruction(iname.into(), args.to_vec())]
+    };
+    state.relax_sites.push((key, pc, RelaxSite::Branch(branch)));
+    state.expanding_site = Some(!long);
+    state.instruction_sites = 0;
+    // the inverted branch skips exactly the 4 bytes of the jal
+    let rvc = state.rvc;
+    state.rvc &= !long;
+    let result = expansion
+        .iter()
+        .try_for_each(|insn| emit_binary_recurse(spec, state, insn));
+    state.rvc = rvc;
+    state.expanding_site = None;
+    state.instruction_sites = 0;
+    result
+}
+
+fn emit_instruction(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState<'_>,
+    node: &Node,
+    specinsn: &arch::InstructionDefinition,
+    skip: usize,
+) -> Result<(), EmitError> {
+    let (iname, args) = match node {
+        Node::Instruction(iname, args) => (iname, args),
+        _ => return Err(EmitError::UnexpectedNodeType(format!("{:?}", node))),
+    };
+    let fmt = specinsn.get_format(spec);
+    let ialign = spec
+        .get_const("IALIGN")
+        .unwrap_or(32)
+        .max(specinsn.align.unwrap_or(0));
+    let ialign_bytes = (ialign as usize).div_ceil(8).max(1);
+    let max_ilen_bytes = (spec.get_const("ILEN").unwrap_or(32) as usize + 7) / 8;
+
+    // check length
+    let ilen_bytes = (fmt.ilen + 7) / 8;
+    if ilen_bytes == 0 || ilen_bytes > max_ilen_bytes {
+        return Err(EmitError::InvalidEncoding(iname.to_string()));
+    }
+    // check alignment
+    state.align_to(spec, ialign_bytes, None)?;
+    let node = &Node::Instruction(iname.clone(), state.pair_pcrel_parts(spec, iname, args)?);
+
+    // simplify and defer if necessary
+    let simpinsn = node.emitter_simplify(&|cname| state.find_const(cname, spec), state.pc());
+    if !simpinsn.1 {
+        // keep the location for when it gets emitted
+        let mut insn = simpinsn.0;
+        // with relaxation, one that may fit a compressed form gets a site to only take its 16
+        // bits in the next pass if it did fit
+        let mut size = ilen_bytes;
+        let relaxing = state.options.relax || state.options.relax_compressed;
+        if skip == 0 && relaxing && state.compressing(spec) && compressible(iname) {
+            let key = SiteKey::Instruction(
+                state.pseudo_sites,
+                state.expanding_site,
+                state.instruction_sites,
+            );
+            state.instruction_sites += 1;
+            if state.relaxed.contains(&key) {
+                size = COMPRESSED_BYTES;
+                state.compressed_deferred.insert(state.out_pos);
+            }
+            let site = RelaxSite::Compress(insn.clone());
+            state.relax_sites.push((key, state.pc(), site));
+        }
+        if let Some(offset) = state.location {
+            insn = Node::Located(offset, Box::new(insn));
+        }
+        state.defer(spec, insn);
+        state.accomodate_bytes(size)?;
+        return Ok(());
+    }
+    state.record_label_uses(spec, node);
+    let args = match simpinsn.0 {
+        Node::Instruction(_, sargs) => sargs,
+        other => return Err(EmitError::UnexpectedNodeType(format!("{:?}", other))),
+    };
+    if args.len() != specinsn.args.len() + skip {
+        return Err(EmitError::InvalidArgumentCount(iname.to_string()));
+    }
+
+    // handle arguments
+    let mut argv: SmallVec<[u64; 4]> = SmallVec::new();
+    let mut operands = Vec::new();
+    for (i, arg) in args.iter().enumerate().skip(skip) {
+        match fmt.fields[specinsn.args[i - skip]].vtype {
+            arch::FieldType::Value | arch::FieldType::Csr => {
+                if let Node::Argument(box Node::Integer(val)) = arg {
+                    argv.push(*val);
+                    operands.push(Operand::Value(*val));
+                } else {
+                    return Err(EmitError::InvalidArgumentType(iname.to_string(), i));
+                }
+            }
+            arch::FieldType::Register => {
+                if let Node::Argument(box Node::Register(rid)) = arg {
+                    if !fmt.fields[specinsn.args[i - skip]].register_allowed(*rid) {
+                        return Err(EmitError::RegisterNotAllowed(iname.to_string(), i));
+                    }
+                    argv.push(*rid as u64);
+                    operands.push(Operand::Register(*rid));
+                } else {
+                    return Err(EmitError::InvalidArgumentType(iname.to_string(), i));
+                }
+            }
+        }
+    }
+    if state.options.lint_x0_writes && writes_x0_needlessly(fmt, specinsn, &argv) {
+        state.diagnostics.push(Diagnostic::Warning(format!(
+            "{} at 0x{:x} writes its result to x0",
+            iname,
+            state.pc()
+        )));
+    }
+
+    // pick the compressed form, unless this was deferred into the space of the full one
+    let pos = state.out_pos;
+    let reserved_compressed = state.resolving_deferred && state.compressed_deferred.remove(&pos);
+    let mut specinsn = specinsn;
+    let mut iname: &str = iname;
+    let mut ilen_bytes = ilen_bytes;
+    if skip == 0 && (reserved_compressed || !state.resolving_deferred && state.compressing(spec)) {
+        match compress(spec, iname, &argv) {
+            Some((cinsn, cargs)) => {
+                specinsn = cinsn;
+                iname = &cinsn.name;
+                ilen_bytes = COMPRESSED_BYTES;
+                operands = cinsn
+                    .args
+                    .iter()
+                    .zip(cargs.iter())
+                    .map(|(fi, v)| match cinsn.get_format(spec).fields[*fi].vtype {
+                        arch::FieldType::Register => Operand::Register(*v as i32),
+                        arch::FieldType::Value | arch::FieldType::Csr => Operand::Value(*v),
+                    })
+                    .collect();
+                argv = cargs.into_iter().collect();
+            }
+            // the operands changed since the pass that found them fitting
+            None if reserved_compressed => {
+                return Err(EmitError::InvalidEncoding(iname.to_string()));
+            }
+            None => {}
+        }
+    }
+
+    // emit instruction
+    let order = state.options.instruction_endianness;
+    let verify = state.options.verify_encodings;
+    let bytes = state.accomodate_bytes(ilen_bytes)?;
+    specinsn
+        .encode_into(bytes, spec, argv.as_slice())
+        .map_err(|e| match e {
+            arch::EncodeError::OutOfRange(i, value, min, max) => {
+                EmitError::ValueOutOfRange(iname.to_string(), i + skip, value, min, max)
+            }
+            arch::EncodeError::Misaligned(i, value, align) => {
+                EmitError::ValueMisaligned(iname.to_string(), i + skip, value, align)
+            }
+            arch::EncodeError::ArgumentCount => EmitError::InvalidArgumentCount(iname.to_string()),
+        })?;
+    if verify {
+        if let Err(decoded) = disasm::check_round_trip(spec, specinsn, bytes, &operands) {
+            let written = disasm::instruction_text(spec, specinsn, &operands);
+            return Err(EmitError::EncodingMismatch(
+                iname.to_string(),
+                written,
+                decoded,
+            ));
+        }
+    }
+    order.arrange(bytes);
+    let address = state.address_at(pos);
+    state.instructions.push(EmittedInstruction {
+        address,
+        mnemonic: iname.to_string(),
+        operands,
+        bytes: state.out_buf[pos..pos + ilen_bytes].to_vec(),
+        source_offset: state.location,
+        deferred: state.resolving_deferred,
+    });
+    if let Some(hook) = &mut state.on_event {
+        hook(&EmitEvent::Instruction {
+            address,
+            mnemonic: iname,
+            argv: &argv,
+            bytes: &state.out_buf[pos..pos + ilen_bytes],
+        });
+    }
+    Ok(())
+}
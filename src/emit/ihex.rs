@@ -0,0 +1,92 @@
+//! Intel HEX output, for bootloaders and programmers that only accept it
+
+use super::flatbin::emit_image;
+use super::{AssembledImage, EmitError, EmitOptions, OutputBackend};
+use crate::arch;
+use crate::parser::Node;
+use std::fmt::Write;
+
+/// Most data bytes in a record, the usual choice of other tools
+const RECORD_DATA: usize = 16;
+
+const RECORD_DATA_TYPE: u8 = 0x00;
+const RECORD_EOF: u8 = 0x01;
+const RECORD_EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const RECORD_START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Intel HEX text: only the ranges the source emitted into get data records, the gaps
+/// skipped by .org are left out
+pub struct IntelHex;
+
+impl OutputBackend for IntelHex {
+    fn serialize(&self, image: &AssembledImage) -> Result<Vec<u8>, EmitError> {
+        to_ihex(image).map(String::into_bytes)
+    }
+}
+
+pub fn emit_ihex(spec: &arch::RiscVSpec, ast: &Node) -> Result<String, EmitError> {
+    let image = emit_image(spec, ast, &EmitOptions::default())?;
+    to_ihex(&image)
+}
+
+fn write_record(out: &mut String, kind: u8, address: u16, data: &[u8]) {
+    let mut checksum = (data.len() as u8)
+        .wrapping_add((address >> 8) as u8)
+        .wrapping_add(address as u8)
+        .wrapping_add(kind);
+    write!(out, ":{:02X}{:04X}{:02X}", data.len(), address, kind).unwrap();
+    for b in data.iter() {
+        write!(out, "{:02X}", b).unwrap();
+        checksum = checksum.wrapping_add(*b);
+    }
+    writeln!(out, "{:02X}", checksum.wrapping_neg()).unwrap();
+}
+
+/// Formats the written ranges of the image, starting extended linear address records wherever
+/// the upper 16 bits of the address change, and the entry point of the image if it has one
+/// as a start linear address record. Addresses have to fit in 32 bits.
+pub fn to_ihex(image: &AssembledImage) -> Result<String, EmitError> {
+    let mut out = String::new();
+    let mut upper = 0;
+    for (start, bytes) in image.written_blocks() {
+        let range_end = start + bytes.len() as u64;
+        if range_end > 1 << 32 {
+            return Err(EmitError::ImageTooLarge(range_end));
+        }
+        let mut address = start;
+        while address < range_end {
+            if address >> 16 != upper {
+                upper = address >> 16;
+                write_record(
+                    &mut out,
+                    RECORD_EXTENDED_LINEAR_ADDRESS,
+                    0,
+                    &(upper as u16).to_be_bytes(),
+                );
+            }
+            // records don't cross into the next 64K block
+            let block_end = (upper + 1) << 16;
+            let end = range_end.min(address + RECORD_DATA as u64).min(block_end);
+            write_record(
+                &mut out,
+                RECORD_DATA_TYPE,
+                address as u16,
+                &bytes[(address - start) as usize..(end - start) as usize],
+            );
+            address = end;
+        }
+    }
+    if let Some(entry) = image.entry_address() {
+        if entry >= 1 << 32 {
+            return Err(EmitError::ImageTooLarge(entry + 1));
+        }
+        write_record(
+            &mut out,
+            RECORD_START_LINEAR_ADDRESS,
+            0,
+            &(entry as u32).to_be_bytes(),
+        );
+    }
+    write_record(&mut out, RECORD_EOF, 0, &[]);
+    Ok(out)
+}
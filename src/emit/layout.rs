@@ -0,0 +1,178 @@
+//! Memory layouts: the regions of a target's address space and the sections placed in each,
+//! like the MEMORY and SECTIONS commands of a linker script
+
+use super::{AssembledImage, EmitError, SectionKind};
+use crate::arch::LoadError;
+use std::convert::TryFrom;
+
+/// A range of the address space, like the flash or the RAM of a microcontroller
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub origin: u64,
+    /// Size in bytes
+    pub length: u64,
+}
+
+impl MemoryRegion {
+    /// Address after the last byte of the region
+    pub fn end(&self) -> u64 {
+        self.origin.saturating_add(self.length)
+    }
+}
+
+/// Regions sorted by their origin, and the region of each section
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryLayout {
+    pub regions: Vec<MemoryRegion>,
+    pub sections: Vec<(String, String)>,
+}
+
+/// Size of a region, a number of bytes or a string of one with a `K`, `M` or `G` suffix
+fn parse_length(key: &str, value: &toml::Value) -> Result<u64, LoadError> {
+    let bad = || LoadError::BadType(key.to_owned());
+    if let Some(i) = value.as_integer() {
+        return u64::try_from(i).map_err(|_| bad());
+    }
+    let text = value.as_str().ok_or_else(bad)?.trim();
+    let (digits, unit) = match text.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&text[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&text[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&text[..i], 1 << 30),
+        _ => (text, 1),
+    };
+    let digits = digits.replace('_', "");
+    let number = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .map_err(|_| bad())?;
+    number.checked_mul(unit).ok_or_else(bad)
+}
+
+impl MemoryLayout {
+    /// Reads a layout from TOML like
+    ///
+    /// ```text
+    /// [memory]
+    /// flash = { origin = 0x0000_0000, length = "512K" }
+    /// ram = { origin = 0x8000_0000, length = "64K" }
+    ///
+    /// [sections]
+    /// ".text" = "flash"
+    /// ".data" = "ram"
+    /// ```
+    ///
+    /// `.text` has to be placed, the code goes at the start of its region.
+    pub fn from_toml(content: &str) -> Result<Self, LoadError> {
+        let doc = content
+            .parse::<toml::Value>()
+            .map_err(|_| LoadError::MalformedTOML)?;
+        let memory = doc
+            .get("memory")
+            .ok_or_else(|| LoadError::MissingNode("memory".to_owned()))?
+            .as_table()
+            .ok_or_else(|| LoadError::BadType("memory".to_owned()))?;
+        let mut regions = Vec::new();
+        for (name, region) in memory.iter() {
+            let key = |field: &str| format!("memory.{}.{}", name, field);
+            let field = |field: &str| {
+                region
+                    .get(field)
+                    .ok_or_else(|| LoadError::MissingNode(key(field)))
+            };
+            let origin = field("origin")?
+                .as_integer()
+                .and_then(|i| u64::try_from(i).ok())
+                .ok_or_else(|| LoadError::BadType(key("origin")))?;
+            regions.push(MemoryRegion {
+                name: name.clone(),
+                origin,
+                length: parse_length(&key("length"), field("length")?)?,
+            });
+        }
+        regions.sort_by_key(|r| r.origin);
+        let placed = doc
+            .get("sections")
+            .ok_or_else(|| LoadError::MissingNode("sections".to_owned()))?
+            .as_table()
+            .ok_or_else(|| LoadError::BadType("sections".to_owned()))?;
+        let mut sections = Vec::new();
+        for (section, region) in placed.iter() {
+            let region = region
+                .as_str()
+                .ok_or_else(|| LoadError::BadType(format!("sections.{}", section)))?;
+            if !regions.iter().any(|r| r.name == region) {
+                return Err(LoadError::MissingNode(format!("memory.{}", region)));
+            }
+            sections.push((section.clone(), region.to_owned()));
+        }
+        if !sections.iter().any(|(s, _)| s == ".text") {
+            return Err(LoadError::MissingNode("sections.\".text\"".to_owned()));
+        }
+        Ok(MemoryLayout { regions, sections })
+    }
+
+    pub fn region(&self, name: &str) -> Option<&MemoryRegion> {
+        self.regions.iter().find(|r| r.name == name)
+    }
+
+    /// Region of the section: the one it's placed in, or else the one of `.data` or `.bss`
+    /// (by the kind of the section), or else the one of `.text`
+    pub fn region_of(&self, section: &str) -> Option<&MemoryRegion> {
+        let placed = |name: &str| {
+            self.sections
+                .iter()
+                .find(|(s, _)| s == name)
+                .and_then(|(_, r)| self.region(r))
+        };
+        let like = match SectionKind::from_name(section) {
+            SectionKind::Text => ".text",
+            SectionKind::Data => ".data",
+            SectionKind::Bss => ".bss",
+        };
+        placed(section)
+            .or_else(|| placed(like))
+            .or_else(|| placed(".text"))
+    }
+
+    /// Checks the sections of the image end within their regions and don't start before them
+    pub fn check(&self, image: &AssembledImage) -> Result<(), EmitError> {
+        for section in image.sections.iter() {
+            let region = match self.region_of(&section.name) {
+                Some(region) => region,
+                None => continue,
+            };
+            let end = section.base + section.size as u64;
+            if section.base < region.origin || end > region.end() {
+                return Err(EmitError::RegionOverflow(
+                    section.name.clone(),
+                    region.name.clone(),
+                    section.base,
+                    end,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Contents of the region from its origin to the last byte written into it, for
+    /// programming it on its own. Gaps and .bss are zeroes, empty if nothing was written.
+    pub fn region_bytes(&self, image: &AssembledImage, region: &MemoryRegion) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (address, bytes) in image.written_blocks() {
+            if address < region.origin || address >= region.end() {
+                continue;
+            }
+            let at = (address - region.origin) as usize;
+            if out.len() < at + bytes.len() {
+                out.resize(at + bytes.len(), 0);
+            }
+            out[at..at + bytes.len()].copy_from_slice(bytes);
+        }
+        out
+    }
+}
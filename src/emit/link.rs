@@ -0,0 +1,372 @@
+//! Emitting the files of flatbin::emit_image_files each on its own, on EmitOptions.jobs threads,
+//! and linking the images: they're placed one after the other, their symbol tables merged and
+//! the uses of the labels of the other files, left as relocations, patched in.
+
+use super::flatbin::{emit_unit, li_range};
+use super::{
+    AssembledImage, EmitOptions, Endianness, ImageSection, Relocation, SectionKind, SymbolBinding,
+};
+use crate::arch;
+use crate::disasm;
+use crate::intern::{ascii_lowercase, Symbol};
+use crate::parser::Node;
+use std::collections::{HashMap, HashSet};
+
+/// Directives whose effect doesn't end with the file or that place bytes by their address, so
+/// files using them are emitted one after the other
+const SEQUENTIAL_DIRECTIVES: &[&str] = &[
+    ".org",
+    ".section",
+    ".data",
+    ".rodata",
+    ".bss",
+    ".option",
+    ".reloc",
+    ".checksum",
+];
+
+/// Directives that can use the labels of other files as relocations
+const RELOCATED_DIRECTIVES: &[&str] = &[
+    ".word", ".4byte", ".dword", ".8byte", ".global", ".globl", ".weak", ".local", ".type",
+];
+
+/// What a file defines and uses, to tell whether it can be emitted without the others
+#[derive(Default)]
+struct FileSymbols {
+    labels: HashSet<Symbol>,
+    /// Constants, register aliases and macros
+    definitions: HashSet<Symbol>,
+    /// Symbols used by instructions and RELOCATED_DIRECTIVES, and the mnemonics
+    uses: HashSet<Symbol>,
+    /// Symbols whose values decide what gets emitted, like in a conditional or the value of a
+    /// `li` or a macro argument
+    immediate_uses: HashSet<Symbol>,
+    /// Whether it has one of SEQUENTIAL_DIRECTIVES
+    sequential: bool,
+}
+
+fn file_symbols(ast: &Node, options: &EmitOptions) -> FileSymbols {
+    let elements = match ast {
+        Node::Root(nodes) => nodes.as_slice(),
+        node => std::slice::from_ref(node),
+    };
+    let mut file = FileSymbols::default();
+    let used = |args: &[Node], into: &mut HashSet<Symbol>| {
+        for arg in args.iter() {
+            into.extend(arg.referenced_symbols());
+        }
+    };
+    for element in elements.iter() {
+        match element {
+            Node::Located(_, box Node::Label(name)) | Node::Label(name) => {
+                let numeric = name.starts_with(|c: char| c.is_ascii_digit());
+                if !numeric && !options.local_labels.is_local(name) {
+                    file.labels.insert(name.clone());
+                }
+            }
+            Node::Located(_, box Node::Instruction(iname, args))
+            | Node::Instruction(iname, args) => {
+                let name = ascii_lowercase(iname);
+                file.sequential |= SEQUENTIAL_DIRECTIVES.contains(&name.as_ref());
+                match name.as_ref() {
+                    ".equ" | ".define" | ".set" | ".req" | ".unreq" | ".macro" => {
+                        if let Some(Node::Argument(box Node::Identifier(defined))) = args.first() {
+                            file.definitions.insert(defined.clone());
+                        }
+                        // the parameters of a macro are its own
+                        if name != ".macro" {
+                            used(args.get(1..).unwrap_or_default(), &mut file.immediate_uses);
+                        }
+                    }
+                    name if RELOCATED_DIRECTIVES.contains(&name) => used(args, &mut file.uses),
+                    name if name.starts_with('.')
+                        || name == "li"
+                        || file.definitions.contains(iname) =>
+                    {
+                        used(args, &mut file.immediate_uses)
+                    }
+                    _ => used(args, &mut file.uses),
+                }
+                if !name.starts_with('.') {
+                    file.uses.insert(iname.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    file
+}
+
+/// Whether every file can be emitted on its own: none uses the constants or macros of another
+/// or needs the value of another's label at once. With the C extension a file using a label of
+/// a file before can't either, as the instruction using it may have been compressed.
+fn separable(spec: &arch::RiscVSpec, files: &[FileSymbols]) -> bool {
+    let compressed = spec.extension_enabled("C");
+    files.iter().enumerate().all(|(i, file)| {
+        !file.sequential
+            && files.iter().enumerate().all(|(j, other)| {
+                let uses = |names: &HashSet<Symbol>| {
+                    file.uses
+                        .iter()
+                        .chain(file.immediate_uses.iter())
+                        .any(|name| names.contains(name))
+                };
+                i == j
+                    || (file.definitions.is_disjoint(&other.definitions)
+                        && !uses(&other.definitions)
+                        && file.immediate_uses.is_disjoint(&other.labels)
+                        && !(compressed && j < i && uses(&other.labels)))
+            })
+    })
+}
+
+/// Emits the files each on its own and links them into the image emitting them one after the
+/// other gives, or returns None if they have to be emitted that way: with the options placing
+/// or relaxing code, files flatbin::emit_image_files can't tell apart, any error (to be
+/// reported by emitting them one after the other) or a relocation it doesn't link. Every file
+/// is emitted at address 0 first, then again where the files before end until none moves, as
+/// the files use their own labels as they are.
+pub(super) fn emit_separately(
+    spec: &arch::RiscVSpec,
+    files: &[(String, Node)],
+    options: &EmitOptions,
+) -> Option<AssembledImage> {
+    let placing = options.relax
+        || options.relax_compressed
+        || options.relax_branches
+        || options.linker_relax
+        || options.external_symbols
+        || options.end_symbols
+        || options.layout.is_some()
+        || !options.section_bases.is_empty()
+        || options.verbosity > 0
+        || options.instruction_endianness != Endianness::Little;
+    if files.len() < 2 || placing {
+        return None;
+    }
+    let symbols = crate::parallel::map(options.jobs, files.iter().collect(), |(_, ast)| {
+        file_symbols(ast, options)
+    });
+    if !separable(spec, &symbols) {
+        return None;
+    }
+    let mut units: Vec<Option<(u64, AssembledImage)>> = files.iter().map(|_| None).collect();
+    let mut bases = vec![0; files.len()];
+    for _ in 0..options.max_resolution_passes {
+        let moved: Vec<usize> = (0..files.len())
+            .filter(|i| units[*i].as_ref().map(|(base, _)| *base) != Some(bases[*i]))
+            .collect();
+        if moved.is_empty() {
+            let units = units.into_iter().map(|unit| unit.map(|(_, image)| image));
+            return link(spec, units.collect::<Option<_>>()?, options);
+        }
+        let emitted = crate::parallel::map(options.jobs, moved, |i| {
+            let (name, ast) = &files[i];
+            (i, emit_unit(spec, name, ast, bases[i], options))
+        });
+        for (i, image) in emitted.into_iter() {
+            let image = image.ok().filter(|image| image.sections.len() == 1)?;
+            units[i] = Some((bases[i], image));
+        }
+        let mut end = 0;
+        for (base, unit) in bases.iter_mut().zip(units.iter()) {
+            *base = end;
+            end += unit.as_ref()?.1.bytes.len() as u64;
+        }
+    }
+    None
+}
+
+/// Puts the emitted files together and patches in the relocations between them
+fn link(
+    spec: &arch::RiscVSpec,
+    mut units: Vec<AssembledImage>,
+    options: &EmitOptions,
+) -> Option<AssembledImage> {
+    // the labels made for the low parts of a %pcrel_hi stay with their file
+    let internal: Vec<HashMap<String, u64>> = units
+        .iter_mut()
+        .map(|unit| {
+            let names: HashSet<&str> = unit
+                .relocations
+                .iter()
+                .filter(|r| r.kind.starts_with("R_RISCV_PCREL_LO12"))
+                .map(|r| r.symbol.as_str())
+                .filter(|name| name.starts_with(".Lpcrel_hi"))
+                .collect();
+            let (internal, labels) = std::mem::take(&mut unit.symbols)
+                .into_iter()
+                .partition(|(name, _)| names.contains(name.as_str()));
+            unit.symbols = labels;
+            internal
+        })
+        .collect();
+    let mut owners: HashMap<&str, (usize, u64)> = HashMap::new();
+    for (i, unit) in units.iter().enumerate() {
+        for (name, address) in unit.symbols.iter() {
+            if owners.insert(name.as_str(), (i, *address)).is_some() {
+                return None;
+            }
+        }
+    }
+
+    let (min, max) = li_range(spec);
+    let mut patches = Vec::new();
+    for (i, unit) in units.iter().enumerate() {
+        let base = unit.sections[0].base;
+        let auipcs: HashMap<u64, &Relocation> = unit
+            .relocations
+            .iter()
+            .filter(|r| r.kind == "R_RISCV_PCREL_HI20")
+            .map(|r| (r.offset, r))
+            .collect();
+        for r in unit.relocations.iter() {
+            let (kind, (owner, value)) = match r.kind.strip_prefix("R_RISCV_PCREL_LO12_") {
+                // the low part of the offset its auipc got
+                Some(format) => {
+                    let auipc = *internal[i].get(&r.symbol)?;
+                    let hi = auipcs.get(&auipc)?;
+                    let (owner, target) = *owners.get(hi.symbol.as_str())?;
+                    let offset = (target as i64 + hi.addend).wrapping_sub(auipc as i64);
+                    (format!("R_RISCV_LO12_{}", format), (owner, offset))
+                }
+                None => {
+                    let (owner, target) = *owners.get(r.symbol.as_str())?;
+                    let value = target as i64 + r.addend;
+                    let value = match r.kind.as_ref() {
+                        "R_RISCV_32" | "R_RISCV_64" | "R_RISCV_HI20" | "R_RISCV_LO12_I"
+                        | "R_RISCV_LO12_S" => value,
+                        _ => value.wrapping_sub(r.offset as i64),
+                    };
+                    (r.kind.clone(), (owner, value))
+                }
+            };
+            if !(min..=max).contains(&value) {
+                return None;
+            }
+            patches.push((i, (r.offset - base) as usize, kind, value, owner));
+        }
+    }
+    let mut patched = HashSet::new();
+    for (i, at, kind, value, owner) in patches.into_iter() {
+        let unit = &mut units[i];
+        patch(&mut unit.bytes, at, &kind, value, options.data_endianness)?;
+        if !kind.starts_with("R_RISCV_32") && !kind.starts_with("R_RISCV_64") {
+            patched.insert((i, unit.sections[0].base + at as u64, owner));
+            if kind == "R_RISCV_CALL_PLT" {
+                patched.insert((i, unit.sections[0].base + at as u64 + 4, owner));
+            }
+        }
+    }
+    for (i, address, owner) in patched.into_iter() {
+        let unit = &mut units[i];
+        let at = (address - unit.sections[0].base) as usize;
+        let n = unit
+            .instructions
+            .binary_search_by_key(&address, |insn| insn.address)
+            .ok()?;
+        let insn = &mut unit.instructions[n];
+        insn.bytes = unit.bytes.get(at..at + insn.bytes.len())?.to_vec();
+        let (specinsn, operands) = disasm::decode(spec, &insn.bytes)?;
+        if !specinsn.name.eq_ignore_ascii_case(&insn.mnemonic) {
+            return None;
+        }
+        insn.operands = operands;
+        // emitting the files one after the other only waits for the labels further ahead
+        insn.deferred = owner > i;
+    }
+
+    let mut image = AssembledImage::default();
+    let mut entries = 0;
+    for unit in units.into_iter() {
+        let start = image.bytes.len();
+        image.bytes.extend(unit.bytes);
+        for range in unit.written.into_iter() {
+            let range = start + range.start..start + range.end;
+            match image.written.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => image.written.push(range),
+            }
+        }
+        image.symbols.extend(unit.symbols);
+        image.local_labels.extend(unit.local_labels);
+        image.constants.extend(unit.constants);
+        image.symbol_types.extend(unit.symbol_types);
+        image.symbol_sizes.extend(unit.symbol_sizes);
+        for (name, binding) in unit.symbol_bindings.into_iter() {
+            if binding == SymbolBinding::Local {
+                image.export_positions.remove(&name);
+            }
+            image.symbol_bindings.insert(name, binding);
+        }
+        image.export_positions.extend(unit.export_positions);
+        if unit.entry.is_some() {
+            image.entry = unit.entry;
+            entries += 1;
+        }
+        image.instructions.extend(unit.instructions);
+        image.elements.extend(unit.elements);
+        image.padding += unit.padding;
+        image.diagnostics.extend(unit.diagnostics);
+    }
+    if entries > 1 {
+        return None;
+    }
+    image
+        .local_labels
+        .sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+    image.sections = vec![ImageSection {
+        name: ".text".to_owned(),
+        kind: SectionKind::Text,
+        base: 0,
+        offset: 0,
+        size: image.bytes.len(),
+    }];
+    if options.strip_local {
+        image.strip_local_symbols();
+    }
+    Some(image)
+}
+
+/// Writes the value of a relocation of the kind at `at`, None for kinds it doesn't patch and
+/// values out of their range. The low part of a %pcrel_hi is patched as the low part of the
+/// offset its auipc got.
+fn patch(bytes: &mut [u8], at: usize, kind: &str, value: i64, order: Endianness) -> Option<()> {
+    let mut field = |at: usize, mask: u32, bits: u32| -> Option<()> {
+        let slot = bytes.get_mut(at..at + 4)?;
+        let insn = u32::from_le_bytes([slot[0], slot[1], slot[2], slot[3]]);
+        slot.copy_from_slice(&((insn & !mask) | (bits & mask)).to_le_bytes());
+        Some(())
+    };
+    let fits = |bits: u32| value % 2 == 0 && (-(1 << (bits - 1))..1 << (bits - 1)).contains(&value);
+    let v = value as u32;
+    let hi = value.wrapping_add(0x800) as u32 & 0xffff_f000;
+    let lo = v.wrapping_sub(hi);
+    let i_imm = lo << 20;
+    let s_imm = (lo >> 5 & 0x7f) << 25 | (lo & 0x1f) << 7;
+    match kind {
+        "R_RISCV_32" | "R_RISCV_64" => {
+            let width = if kind == "R_RISCV_32" { 4 } else { 8 };
+            let mut value = value.to_le_bytes()[..width].to_vec();
+            order.arrange(&mut value);
+            bytes.get_mut(at..at + width)?.copy_from_slice(&value);
+            Some(())
+        }
+        "R_RISCV_JAL" if fits(21) => {
+            let imm = (v >> 20 & 1) << 31 | (v >> 1 & 0x3ff) << 21 | (v >> 11 & 1) << 20;
+            field(at, 0xffff_f000, imm | (v & 0xff000))
+        }
+        "R_RISCV_BRANCH" if fits(13) => {
+            let imm = (v >> 12 & 1) << 31 | (v >> 5 & 0x3f) << 25 | (v >> 1 & 0xf) << 8;
+            field(at, 0xfe00_0f80, imm | (v >> 11 & 1) << 7)
+        }
+        "R_RISCV_CALL_PLT" => {
+            field(at, 0xffff_f000, hi)?;
+            field(at + 4, 0xfff0_0000, i_imm)
+        }
+        "R_RISCV_PCREL_HI20" | "R_RISCV_HI20" => field(at, 0xffff_f000, hi),
+        "R_RISCV_LO12_I" => field(at, 0xfff0_0000, i_imm),
+        "R_RISCV_LO12_S" => field(at, 0xfe00_0f80, s_imm),
+        _ => None,
+    }
+}
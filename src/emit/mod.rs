@@ -1 +1,965 @@
+pub mod array;
+pub mod checksum;
+pub mod compress;
+pub mod dwarf;
+pub mod elf;
 pub mod flatbin;
+pub mod ihex;
+pub mod layout;
+mod link;
+pub mod pseudo;
+pub mod srec;
+pub mod verilog;
+
+use crate::intern::Symbol;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub enum EmitError {
+    UnexpectedNodeType(String),
+    /// Mnemonic that no instruction, pseudo-instruction or macro has, and the most similar one
+    InvalidInstruction(String, Option<String>),
+    /// Instruction and the extension it needs, which isn't enabled
+    ExtensionRequired(String, String),
+    /// Name of a directive that isn't supported, with the closest supported one
+    UnknownDirective(String, Option<String>),
+    InvalidArgumentCount(String),
+    InvalidArgumentType(String, usize),
+    /// Instruction and index of a register operand outside the set its field allows
+    RegisterNotAllowed(String, usize),
+    InvalidEncoding(String),
+    /// Instruction whose bytes don't decode back into it (see EmitOptions.verify_encodings),
+    /// the way it was written and what its bytes decode as
+    EncodingMismatch(String, String, String),
+    /// Instruction, index of the operand, its value and the range [min, max] its field allows
+    ValueOutOfRange(String, usize, i64, i64, i64),
+    /// Instruction, index of the operand, its value and the multiple it has to be
+    ValueMisaligned(String, usize, i64, u64),
+    /// Directive and the escape sequence of its string it doesn't know, like `\q`
+    InvalidEscape(String, String),
+    DuplicateLabel(String),
+    /// Label defined in two files: the label, the file defining it first and the one redefining it
+    DuplicateLabelInFiles(String, String, String),
+    DuplicateConstant(String),
+    /// Entry point set by a .entry before and the one another .entry sets
+    DuplicateEntry(String, String),
+    /// Symbol that is never defined, the address of the instruction or data using it and the
+    /// most similar label, constant or register name
+    UnresolvedSymbol(String, u64, Option<String>),
+    /// Symbols never defined when there are several, each with the address of its first use and
+    /// the most similar name, like UnresolvedSymbol
+    UnresolvedSymbols(Vec<(String, u64, Option<String>)>),
+    PicUnsupported(String),
+    UnknownRelocation(String),
+    /// Two sections' names and the address range [start, end) they both occupy
+    SectionOverlap(String, String, u64, u64),
+    /// Address range [start, end) already emitted that the bytes after a backward .org would
+    /// overwrite
+    OrgOverlap(u64, u64),
+    /// Section that can only hold zeroes, like .bss, and the address of a byte that isn't zero
+    NonZeroBss(String, u64),
+    /// Section, the memory region of the layout it's placed in and the address range [start,
+    /// end) it takes, which doesn't fit in the region
+    RegionOverflow(String, String, u64, u64),
+    /// Conditional directive without a matching .if, or a .if left open at the end
+    UnbalancedConditional(String),
+    /// .macro without a matching .endm, .rept or .irp without a matching .endr, or the other
+    /// way around
+    UnbalancedMacro(String),
+    DuplicateMacro(String),
+    /// Macro and the `\name` in its body that isn't one of its parameters
+    UnknownMacroParameter(String, String),
+    /// Macro whose expansion would nest more than MAX_MACRO_DEPTH expansions, like one calling itself
+    MacroTooDeep(String),
+    /// Count of a .rept over EmitOptions.max_image_size
+    TooManyRepetitions(u64),
+    /// .include left in the tree, parser::resolve_includes has to splice the files in first
+    UnresolvedInclude,
+    /// File of an .incbin that couldn't be read and the reason
+    IncbinRead(String, String),
+    /// Symbols used by whatever was still deferred when the resolution pass limit was reached
+    ResolutionDidNotConverge(Vec<String>),
+    /// Size the image would have grown to, over EmitOptions.max_image_size
+    ImageTooLarge(u64),
+    /// Start of emitted bytes that isn't at a boundary of the output's words of the given size
+    UnalignedWord(u64, usize),
+    /// Name given to a source array that isn't an identifier of its language
+    InvalidArrayName(String),
+    /// Empty image written as a C array, which can't have no elements
+    EmptyArray,
+    /// Errors of several elements, in the order they were found (see EmitOptions.max_errors)
+    Multiple(Vec<EmitError>),
+    /// Error caused by the top-level element starting at the given byte offset of the source.
+    /// Deferred instructions keep the offset of where they were written.
+    Located(usize, Box<EmitError>),
+}
+
+impl EmitError {
+    /// Attributes the error to the element at `offset`, unless it already has a location
+    pub fn at(self, offset: usize) -> Self {
+        match self {
+            EmitError::Located(..) => self,
+            e => EmitError::Located(offset, Box::new(e)),
+        }
+    }
+
+    /// Byte offset in the source of the element causing the error, if known
+    pub fn location(&self) -> Option<usize> {
+        match self {
+            EmitError::Located(offset, _) => Some(*offset),
+            _ => None,
+        }
+    }
+
+    /// The error without its location
+    pub fn kind(&self) -> &EmitError {
+        match self {
+            EmitError::Located(_, e) => e.kind(),
+            e => e,
+        }
+    }
+
+    /// Name of the kind of the error, like `ValueOutOfRange`, for tools to tell errors apart
+    pub fn code(&self) -> String {
+        let debug = format!("{:?}", self.kind());
+        debug
+            .split(|c: char| !c.is_alphanumeric())
+            .next()
+            .unwrap_or_default()
+            .to_owned()
+    }
+
+    /// Each of the errors, just this one unless it's `Multiple`
+    pub fn all(&self) -> &[EmitError] {
+        match self {
+            EmitError::Multiple(errors) => errors,
+            e => std::slice::from_ref(e),
+        }
+    }
+
+    pub fn into_kind(self) -> EmitError {
+        match self {
+            EmitError::Located(_, e) => e.into_kind(),
+            e => e,
+        }
+    }
+}
+
+impl std::fmt::Display for EmitError {
+    /// The message without the location, which needs the source to be shown as a line
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use EmitError::*;
+        match self {
+            UnexpectedNodeType(node) => write!(f, "unexpected element {}", node),
+            InvalidInstruction(insn, Some(similar)) => write!(
+                f,
+                "unknown instruction or macro '{}', did you mean '{}'?",
+                insn, similar
+            ),
+            InvalidInstruction(insn, None) => write!(f, "unknown instruction or macro '{}'", insn),
+            ExtensionRequired(insn, ext) => write!(
+                f,
+                "instruction '{}' needs the {} extension, which isn't enabled",
+                insn, ext
+            ),
+            UnknownDirective(name, Some(similar)) => write!(
+                f,
+                "unknown directive '{}', did you mean '{}'?",
+                name, similar
+            ),
+            UnknownDirective(name, None) => write!(f, "unknown directive '{}'", name),
+            InvalidArgumentCount(insn) => write!(f, "wrong number of operands for '{}'", insn),
+            InvalidArgumentType(insn, i) => write!(f, "invalid operand {} of '{}'", i + 1, insn),
+            RegisterNotAllowed(insn, i) => write!(
+                f,
+                "register of operand {} isn't one '{}' allows",
+                i + 1,
+                insn
+            ),
+            InvalidEncoding(insn) => write!(f, "operands of '{}' don't fit its encoding", insn),
+            EncodingMismatch(insn, written, decoded) => write!(
+                f,
+                "'{}' encoded as `{}` decodes as `{}`, the spec's encoding of it is wrong",
+                insn, written, decoded
+            ),
+            ValueOutOfRange(insn, i, value, min, max) => write!(
+                f,
+                "operand {} of '{}' is {}, out of range [{}, {}]",
+                i + 1,
+                insn,
+                value,
+                min,
+                max
+            ),
+            ValueMisaligned(insn, i, value, align) => write!(
+                f,
+                "operand {} of '{}' is {}, which isn't a multiple of {}",
+                i + 1,
+                insn,
+                value,
+                align
+            ),
+            InvalidEscape(directive, sequence) => write!(
+                f,
+                "unknown escape sequence '{}' in '{}'",
+                sequence, directive
+            ),
+            DuplicateLabel(name) => write!(f, "'{}' is already defined", name),
+            DuplicateLabelInFiles(name, first, second) => write!(
+                f,
+                "label '{}' defined in {} is defined again in {}",
+                name, first, second
+            ),
+            DuplicateConstant(name) => write!(f, "constant '{}' is already defined", name),
+            DuplicateEntry(first, second) => write!(
+                f,
+                "the entry point is already '{}', it can't be set to '{}' too",
+                first, second
+            ),
+            UnresolvedSymbol(name, address, Some(similar)) => write!(
+                f,
+                "'{}' is never defined (used at address 0x{:x}), did you mean '{}'?",
+                name, address, similar
+            ),
+            UnresolvedSymbol(name, address, None) => write!(
+                f,
+                "'{}' is never defined (used at address 0x{:x})",
+                name, address
+            ),
+            UnresolvedSymbols(symbols) => {
+                let each: Vec<String> = symbols
+                    .iter()
+                    .map(|(name, address, similar)| match similar {
+                        Some(similar) => format!(
+                            "'{}' (used at address 0x{:x}, did you mean '{}'?)",
+                            name, address, similar
+                        ),
+                        None => format!("'{}' (used at address 0x{:x})", name, address),
+                    })
+                    .collect();
+                write!(f, "symbols are never defined: {}", each.join(", "))
+            }
+            PicUnsupported(insn) => write!(
+                f,
+                "'{}' needs a GOT in position-independent code, which the output can't have",
+                insn
+            ),
+            UnknownRelocation(kind) => write!(f, "unknown relocation type '{}'", kind),
+            SectionOverlap(a, b, start, end) => write!(
+                f,
+                "sections {} and {} overlap at 0x{:x}..0x{:x}",
+                a, b, start, end
+            ),
+            OrgOverlap(start, end) => write!(
+                f,
+                "0x{:x}..0x{:x} was already emitted, a backward .org can only fill gaps",
+                start, end
+            ),
+            NonZeroBss(section, address) => write!(
+                f,
+                "section {} can only hold zeroes, but the byte at 0x{:x} isn't",
+                section, address
+            ),
+            RegionOverflow(section, region, start, end) => write!(
+                f,
+                "section {} (0x{:x}..0x{:x}) doesn't fit in memory region {}",
+                section, start, end, region
+            ),
+            UnbalancedConditional(directive) => {
+                write!(f, "'{}' without a matching .if/.endif", directive)
+            }
+            UnbalancedMacro(directive) => {
+                let pair = match directive.to_ascii_lowercase().as_str() {
+                    ".rept" | ".irp" | ".endr" => ".rept/.endr",
+                    _ => ".macro/.endm",
+                };
+                write!(f, "'{}' without a matching {}", directive, pair)
+            }
+            DuplicateMacro(name) => write!(f, "macro '{}' is already defined", name),
+            UnknownMacroParameter(name, param) => {
+                write!(f, "macro '{}' has no parameter '{}'", name, param)
+            }
+            MacroTooDeep(name) => write!(
+                f,
+                "expanding macro '{}' nests too many macro expansions",
+                name
+            ),
+            TooManyRepetitions(count) => {
+                write!(f, ".rept of {} repetitions is over the size limit", count)
+            }
+            UnresolvedInclude => write!(f, ".include can only be used when assembling files"),
+            IncbinRead(path, reason) => write!(f, "can't read '{}' for .incbin: {}", path, reason),
+            ResolutionDidNotConverge(symbols) => write!(
+                f,
+                "addresses didn't settle within the resolution pass limit, depending on {}",
+                symbols.join(", ")
+            ),
+            ImageTooLarge(size) => write!(
+                f,
+                "output would grow to {} bytes, over the size limit",
+                size
+            ),
+            UnalignedWord(address, size) => write!(
+                f,
+                "output at 0x{:x} doesn't start at a boundary of {}-byte words",
+                address, size
+            ),
+            InvalidArrayName(name) => write!(f, "array name '{}' isn't an identifier", name),
+            EmptyArray => write!(
+                f,
+                "the image is empty, and a C array can't have no elements"
+            ),
+            Multiple(errors) => {
+                let each: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{} errors: {}", errors.len(), each.join("; "))
+            }
+            Located(_, e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for EmitError {}
+
+/// Messages produced while assembling
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Diagnostic {
+    /// Reason the assembly failed, only reported by crate::assemble
+    Error(String),
+    Warning(String),
+    Info(String),
+}
+
+/// Byte order of multi-byte values in the output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl Endianness {
+    /// Puts the little endian bytes of a value in this order
+    pub fn arrange(self, bytes: &mut [u8]) {
+        if self == Endianness::Big {
+            bytes.reverse();
+        }
+    }
+}
+
+/// How `li` picks its instruction sequence
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LiExpansion {
+    /// Fewest instructions for the value (`addi`, `lui` or `lui`+`addi`, and with XLEN 64 the
+    /// longer sequences of wider values), values referring to labels further ahead always take
+    /// `lui`+`addi` and must fit in it once they resolve
+    Shortest,
+    /// Always `lui`+`addi`, so tools patching the immediate can rely on the layout. With XLEN 64
+    /// values that aren't sign-extended 32-bit ones are an error.
+    Fixed,
+}
+
+/// Which labels are local, and how long they stay visible
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocalLabelScope {
+    /// Labels starting with a dot are visible until the next global label
+    PerGlobalLabel,
+    /// Labels starting with a dot are visible in the whole file
+    PerFile,
+    /// Only labels made of a dot and digits (like `.1`) are local, until the next global label.
+    /// Other labels starting with a dot are global, but don't start a new scope.
+    NumericOnly,
+}
+
+impl LocalLabelScope {
+    /// Whether the label goes into the local label set
+    pub fn is_local(self, label: &str) -> bool {
+        match self {
+            LocalLabelScope::PerGlobalLabel | LocalLabelScope::PerFile => label.starts_with('.'),
+            LocalLabelScope::NumericOnly => {
+                // macro expansions make their local labels unique with a \N suffix
+                let label = label.split('\\').next().unwrap_or(label);
+                label.len() > 1
+                    && label.starts_with('.')
+                    && label[1..].bytes().all(|c| c.is_ascii_digit())
+            }
+        }
+    }
+
+    /// Whether defining the (global) label forgets the local labels defined so far
+    pub fn starts_scope(self, label: &str) -> bool {
+        match self {
+            LocalLabelScope::PerGlobalLabel => true,
+            LocalLabelScope::PerFile => false,
+            LocalLabelScope::NumericOnly => !label.starts_with('.'),
+        }
+    }
+}
+
+/// Settings affecting how the source is assembled
+#[derive(Clone, Debug)]
+pub struct EmitOptions {
+    /// Generate position-independent code, `la` then loads addresses through the GOT. This is
+    /// the setting at the start of the source, `.option pic`/`.option nopic` switch it.
+    pub pic: bool,
+    /// Warn about arithmetic instructions discarding their result by writing to x0
+    pub lint_x0_writes: bool,
+    /// Decode every emitted instruction back through the spec (`--verify`), reporting
+    /// `EmitError::EncodingMismatch` if it doesn't give back the instruction and its operands,
+    /// to find mistakes in a spec's formats when assembling instead of on the target
+    pub verify_encodings: bool,
+    pub local_labels: LocalLabelScope,
+    /// Warn about and skip unknown directives instead of failing
+    pub lenient_directives: bool,
+    /// Define `_etext` (end of the code), `_edata` (end of the initialized data) and `_end` (end
+    /// of the image, including .bss) for startup code, unless the source defines them
+    pub end_symbols: bool,
+    /// Leave symbols not declared .global or .weak out of the exported symbol information
+    pub strip_local: bool,
+    /// Leave the symbols the source never defines to a linker, for object files: instructions
+    /// and data using them get a relocation against them and zeroes in place of the value,
+    /// instead of failing with `EmitError::UnresolvedSymbol`
+    pub external_symbols: bool,
+    /// How many times the deferred instructions get retried at the end before giving up
+    pub max_resolution_passes: usize,
+    /// Errors to collect before stopping, going on past a failing element (whose bytes are left
+    /// out) to report the ones after it as `EmitError::Multiple`; 1 stops at the first
+    pub max_errors: usize,
+    /// Largest output `.org` may grow the image to, in bytes
+    pub max_image_size: u64,
+    /// Let what follows a backward .org overwrite bytes emitted before, like patching a header,
+    /// instead of reporting `EmitError::OrgOverlap`
+    pub allow_overwrite: bool,
+    /// Shorten call and tail to a single `jal` wherever the target is within ±1MiB, and la/lla
+    /// to a single `addi` from zero where the address fits in 12 bits, once the addresses are
+    /// known. With the C extension, instructions using labels further ahead also get their
+    /// compressed form where the operands fit. Only emit_image and emit_image_files do this,
+    /// it takes a few passes over the source.
+    pub relax: bool,
+    /// Only the compressed forms of `relax`: instructions using labels further ahead get their
+    /// compressed form where their final operands fit, while call, tail and la/lla keep their
+    /// sequences (`-Oc`)
+    pub relax_compressed: bool,
+    /// Rewrite branches whose target is out of their ±4KiB reach into the inverted branch over
+    /// a `jal zero`, and jal out of its ±1MiB reach into `auipc`+`jalr`, instead of failing
+    /// (`--relax-branches`). Like `relax`, only emit_image and emit_image_files do this.
+    pub relax_branches: bool,
+    /// Let the linker relax the object (`--linker-relax`): calls and address materializations
+    /// get an `R_RISCV_RELAX` beside their relocations, so a linker may shorten them. As that
+    /// deletes bytes, the instructions and data using labels of the source get relocated as well
+    /// instead of keeping the offsets worked out here, and `.align` in code pads with the most
+    /// it may take, marked `R_RISCV_ALIGN` for the linker to trim. Only with `external_symbols`.
+    pub linker_relax: bool,
+    /// Constants defined before the source is assembled, like `--defsym NAME=VALUE`
+    pub defsyms: Vec<(String, u64)>,
+    /// Keep the defsym value when the source defines the same constant with .equ (with a
+    /// warning), instead of reporting the redefinition as an error
+    pub defsyms_override: bool,
+    pub li_expansion: LiExpansion,
+    /// Extra `Diagnostic::Info` events about the assembly itself: 0 for none, 1 for progress
+    /// every `progress_interval` bytes and a summary at the end, 2 to also report each
+    /// resolution pass over the instructions waiting for labels
+    pub verbosity: u8,
+    /// Bytes of output between progress reports
+    pub progress_interval: usize,
+    /// Byte order of the values of .half, .word, .dword, .rvtable and .checksum
+    pub data_endianness: Endianness,
+    /// Byte order of instructions (and the NOPs padding them). RISC-V defines instructions as
+    /// little endian even on big-endian cores, so `Big` is only for non-standard ones.
+    pub instruction_endianness: Endianness,
+    /// Addresses of sections placed somewhere else than after the code and the sections before
+    /// them, like `--section-start .data=0x8000`
+    pub section_bases: Vec<(String, u64)>,
+    /// Directories searched for the files of .include and .incbin that aren't next to the
+    /// including file, like `-I DIR`. Only assemble::assemble_files splices the includes in,
+    /// the emitter gets the tree with them resolved.
+    pub include_dirs: Vec<std::path::PathBuf>,
+    /// With several files (flatbin::emit_image_files), only the labels a file declares .global
+    /// or .weak are seen by the other files, like separately assembled objects linked together.
+    /// The others can be defined again in another file, and are in AssembledImage.private_labels.
+    pub file_private_labels: bool,
+    /// Threads reading, parsing and emitting the files of assemble::assemble_files and renaming
+    /// their private labels, 0 for one per CPU (`-j N`). Each file's addresses follow the one
+    /// before, see flatbin::emit_image_files.
+    pub jobs: usize,
+    /// Memory regions to place the code and the sections in (`--layout FILE`), which they have
+    /// to fit in. The sections of a region follow each other from its origin, they take no room
+    /// in between in AssembledImage.bytes (see AssembledImage::address_of).
+    pub layout: Option<layout::MemoryLayout>,
+}
+
+impl Default for EmitOptions {
+    fn default() -> Self {
+        EmitOptions {
+            pic: false,
+            lint_x0_writes: false,
+            verify_encodings: false,
+            local_labels: LocalLabelScope::PerGlobalLabel,
+            end_symbols: false,
+            lenient_directives: false,
+            strip_local: false,
+            external_symbols: false,
+            max_resolution_passes: 64,
+            max_errors: 1,
+            max_image_size: 1 << 28,
+            allow_overwrite: false,
+            relax: false,
+            relax_compressed: false,
+            relax_branches: false,
+            linker_relax: false,
+            defsyms: Vec::new(),
+            defsyms_override: false,
+            li_expansion: LiExpansion::Shortest,
+            verbosity: 0,
+            progress_interval: 64 * 1024,
+            data_endianness: Endianness::Little,
+            instruction_endianness: Endianness::Little,
+            section_bases: Vec::new(),
+            include_dirs: Vec::new(),
+            file_private_labels: false,
+            jobs: 0,
+            layout: None,
+        }
+    }
+}
+
+/// What a section holds, telling where it goes and how object files describe it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SectionKind {
+    /// Code, padded with NOPs and laid out first
+    Text,
+    /// Initialized data, after the code
+    Data,
+    /// Zero-initialized data, after everything else and left out of the image bytes
+    Bss,
+}
+
+impl SectionKind {
+    /// The kind of a section by its name, like GNU as does for sections it knows
+    pub fn from_name(name: &str) -> Self {
+        if name.starts_with(".text") {
+            SectionKind::Text
+        } else if name.starts_with(".bss") || name.starts_with(".sbss") {
+            SectionKind::Bss
+        } else {
+            SectionKind::Data
+        }
+    }
+}
+
+/// A contiguous region of the assembled image
+#[derive(Clone, Debug)]
+pub struct ImageSection {
+    pub name: String,
+    pub kind: SectionKind,
+    /// Address of the first byte of the section
+    pub base: u64,
+    /// Offset of the section's contents in AssembledImage.bytes, where .bss sections have none
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// A reference to a symbol that has to be patched in by a linker
+#[derive(Clone, Debug)]
+pub struct Relocation {
+    /// Address of the patched location
+    pub offset: u64,
+    /// Relocation type name, like R_RISCV_32
+    pub kind: String,
+    pub symbol: String,
+    pub addend: i64,
+}
+
+/// ELF relocation types defined by the RISC-V psABI, with their numbers
+pub const RELOCATION_TYPES: &[(&str, u32)] = &[
+    ("R_RISCV_NONE", 0),
+    ("R_RISCV_32", 1),
+    ("R_RISCV_64", 2),
+    ("R_RISCV_RELATIVE", 3),
+    ("R_RISCV_COPY", 4),
+    ("R_RISCV_JUMP_SLOT", 5),
+    ("R_RISCV_TLS_DTPMOD32", 6),
+    ("R_RISCV_TLS_DTPMOD64", 7),
+    ("R_RISCV_TLS_DTPREL32", 8),
+    ("R_RISCV_TLS_DTPREL64", 9),
+    ("R_RISCV_TLS_TPREL32", 10),
+    ("R_RISCV_TLS_TPREL64", 11),
+    ("R_RISCV_BRANCH", 16),
+    ("R_RISCV_JAL", 17),
+    ("R_RISCV_CALL", 18),
+    ("R_RISCV_CALL_PLT", 19),
+    ("R_RISCV_GOT_HI20", 20),
+    ("R_RISCV_TLS_GOT_HI20", 21),
+    ("R_RISCV_TLS_GD_HI20", 22),
+    ("R_RISCV_PCREL_HI20", 23),
+    ("R_RISCV_PCREL_LO12_I", 24),
+    ("R_RISCV_PCREL_LO12_S", 25),
+    ("R_RISCV_HI20", 26),
+    ("R_RISCV_LO12_I", 27),
+    ("R_RISCV_LO12_S", 28),
+    ("R_RISCV_TPREL_HI20", 29),
+    ("R_RISCV_TPREL_LO12_I", 30),
+    ("R_RISCV_TPREL_LO12_S", 31),
+    ("R_RISCV_TPREL_ADD", 32),
+    ("R_RISCV_ADD8", 33),
+    ("R_RISCV_ADD16", 34),
+    ("R_RISCV_ADD32", 35),
+    ("R_RISCV_ADD64", 36),
+    ("R_RISCV_SUB8", 37),
+    ("R_RISCV_SUB16", 38),
+    ("R_RISCV_SUB32", 39),
+    ("R_RISCV_SUB64", 40),
+    ("R_RISCV_ALIGN", 43),
+    ("R_RISCV_RVC_BRANCH", 44),
+    ("R_RISCV_RVC_JUMP", 45),
+    ("R_RISCV_RELAX", 51),
+    ("R_RISCV_SUB6", 52),
+    ("R_RISCV_SET6", 53),
+    ("R_RISCV_SET8", 54),
+    ("R_RISCV_SET16", 55),
+    ("R_RISCV_SET32", 56),
+    ("R_RISCV_32_PCREL", 57),
+];
+
+/// Looks up the number of a relocation type by its name, case-insensitively
+pub fn relocation_type_number(name: &str) -> Option<u32> {
+    RELOCATION_TYPES
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| *v)
+}
+
+/// Value an instruction operand was encoded with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Register(i32),
+    Value(u64),
+}
+
+/// An instruction as it was finally encoded
+#[derive(Clone, Debug)]
+pub struct EmittedInstruction {
+    pub address: u64,
+    pub mnemonic: String,
+    pub operands: Vec<Operand>,
+    pub bytes: Vec<u8>,
+    /// Offset of the top-level source element it came from
+    pub source_offset: Option<usize>,
+    /// Whether it had to wait for labels defined further ahead
+    pub deferred: bool,
+}
+
+/// A top-level source element as it was emitted, for listings
+#[derive(Clone, Debug)]
+pub struct EmittedElement {
+    pub source_offset: usize,
+    /// Where its bytes start and how many there are, a macro call counting its whole expansion
+    pub address: u64,
+    pub size: usize,
+    /// Value a .equ defined
+    pub value: Option<u64>,
+}
+
+/// ELF symbol type given by a .type directive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolType {
+    NoType,
+    Object,
+    Function,
+}
+
+impl SymbolType {
+    /// Parses the type operand of .type, like @function
+    pub fn from_directive(name: &str) -> Option<Self> {
+        match name {
+            "@notype" => Some(SymbolType::NoType),
+            "@object" => Some(SymbolType::Object),
+            "@function" => Some(SymbolType::Function),
+            _ => None,
+        }
+    }
+
+    /// The STT_* value stored in the low bits of st_info
+    pub fn elf_type(self) -> u8 {
+        match self {
+            SymbolType::NoType => 0,
+            SymbolType::Object => 1,
+            SymbolType::Function => 2,
+        }
+    }
+}
+
+/// ELF symbol binding given by .global/.local/.weak, labels are local by default
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolBinding {
+    Local,
+    Global,
+    Weak,
+}
+
+impl SymbolBinding {
+    /// The STB_* value stored in the high bits of st_info
+    pub fn elf_binding(self) -> u8 {
+        match self {
+            SymbolBinding::Local => 0,
+            SymbolBinding::Global => 1,
+            SymbolBinding::Weak => 2,
+        }
+    }
+}
+
+/// Names and addresses of symbols, sorted by address
+pub type SymbolList = Vec<(String, u64)>;
+
+/// What a name in the symbol map stands for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MapSymbolKind {
+    Label,
+    LocalLabel,
+    /// Defined with .equ, .set or a defsym
+    Constant,
+}
+
+/// A name of the source with its final value, for debuggers and emulators
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MapSymbol {
+    pub name: String,
+    pub value: u64,
+    pub kind: MapSymbolKind,
+    /// Section of a label, none for constants
+    pub section: Option<String>,
+    pub binding: SymbolBinding,
+}
+
+/// Everything produced by the emitter core, consumed by the output backends
+#[derive(Clone, Debug, Default)]
+pub struct AssembledImage {
+    pub bytes: Vec<u8>,
+    /// Sorted, non-overlapping ranges of `bytes` the source emitted into. The rest are zeroes
+    /// skipped over by .org, which sparse formats like Intel HEX leave out.
+    pub written: Vec<std::ops::Range<usize>>,
+    pub sections: Vec<ImageSection>,
+    pub symbols: HashMap<String, u64>,
+    /// Labels kept to the file defining them with EmitOptions.file_private_labels, apart from
+    /// `symbols`. They're named `NAME@FILE` here so equal names of two files don't clash, and
+    /// `label_name` gives the name written out for them.
+    pub private_labels: HashMap<String, u64>,
+    /// Local labels of every scope, a name appearing once for each scope defining it
+    pub local_labels: SymbolList,
+    /// Final values of the constants, the last one for names redefined with .set
+    pub constants: HashMap<String, u64>,
+    /// Types set with .type, for object file symbol tables
+    pub symbol_types: HashMap<String, SymbolType>,
+    /// Sizes set with .size, for object file symbol tables
+    pub symbol_sizes: HashMap<String, u64>,
+    /// Bindings set with .global/.local/.weak, for object file symbol tables
+    pub symbol_bindings: HashMap<String, SymbolBinding>,
+    /// Addresses of the .global/.weak directives exporting each symbol
+    pub export_positions: HashMap<String, u64>,
+    /// Address of the label named by .entry
+    pub entry: Option<u64>,
+    pub relocations: Vec<Relocation>,
+    /// With EmitOptions.external_symbols, relocations of the uses of the source's own labels
+    /// that don't hold once a linker places the sections: absolute addresses and references
+    /// into another section. Object files need them, executables are at their final addresses.
+    pub section_relocations: Vec<Relocation>,
+    /// Every emitted instruction, sorted by address
+    pub instructions: Vec<EmittedInstruction>,
+    /// Every emitted top-level element that has a source offset, in the order they were emitted
+    pub elements: Vec<EmittedElement>,
+    /// Bytes of the gaps filled to align the position: by .align, by instructions moved to
+    /// their alignment, and before sections starting at an aligned address
+    pub padding: usize,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl AssembledImage {
+    pub fn symbol_binding(&self, name: &str) -> SymbolBinding {
+        self.symbol_bindings
+            .get(name)
+            .copied()
+            .unwrap_or(SymbolBinding::Local)
+    }
+
+    /// Where execution starts: the label named by .entry, or `_start` by convention
+    pub fn entry_address(&self) -> Option<u64> {
+        self.entry.or_else(|| self.symbols.get("_start").copied())
+    }
+
+    /// Names and addresses of the symbols declared .global or .weak, sorted by address, for
+    /// outputs without a symbol table to import them from elsewhere. A declared symbol that's
+    /// never defined is reported at its declaration.
+    pub fn exported_symbols(&self) -> Result<SymbolList, EmitError> {
+        let undefined = self
+            .export_positions
+            .iter()
+            .filter(|(name, _)| !self.symbols.contains_key(*name))
+            .min_by_key(|(name, position)| (**position, name.as_str()));
+        if let Some((name, position)) = undefined {
+            let similar = flatbin::closest_name(name, self.symbols.keys().map(String::as_str));
+            return Err(EmitError::UnresolvedSymbol(
+                name.clone(),
+                *position,
+                similar,
+            ));
+        }
+        let mut exported: SymbolList = self
+            .export_positions
+            .keys()
+            .map(|name| (name.clone(), self.symbols[name]))
+            .collect();
+        exported.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(exported)
+    }
+
+    /// Every label, local label and constant with its value, sorted by value
+    pub fn symbol_map(&self) -> Vec<MapSymbol> {
+        let labels = self
+            .symbols
+            .iter()
+            .chain(self.private_labels.iter())
+            .map(|s| (s, MapSymbolKind::Label));
+        let local_labels = self
+            .local_labels
+            .iter()
+            .map(|(name, value)| ((name, value), MapSymbolKind::LocalLabel));
+        let constants = self.constants.iter().map(|s| (s, MapSymbolKind::Constant));
+        let mut map: Vec<MapSymbol> = labels
+            .chain(local_labels)
+            .chain(constants)
+            .map(|((name, value), kind)| self.map_symbol(name, *value, kind))
+            .collect();
+        map.sort_by(|a, b| (a.value, a.kind, &a.name).cmp(&(b.value, b.kind, &b.name)));
+        map
+    }
+
+    /// The values of the labels and constants by the interned symbols naming them in the AST,
+    /// for tools looking nodes up without comparing names
+    pub fn interned_symbols(&self) -> HashMap<Symbol, u64> {
+        self.symbols
+            .iter()
+            .chain(self.constants.iter())
+            .map(|(name, value)| (Symbol::intern(name), *value))
+            .collect()
+    }
+
+    /// Index of the section holding a label at `address`. A label at the end of a section
+    /// belongs to the one starting there, if any.
+    pub fn label_section(&self, address: u64) -> Option<usize> {
+        let end = |s: &ImageSection| s.base + s.size as u64;
+        self.sections
+            .iter()
+            .position(|s| address >= s.base && address < end(s))
+            .or_else(|| self.sections.iter().position(|s| address == end(s)))
+    }
+
+    /// The name of a label as the source writes it, without the file a private label is kept to
+    pub fn label_name<'a>(&self, name: &'a str) -> &'a str {
+        match self.private_labels.contains_key(name) {
+            true => name.split_once('@').map_or(name, |(plain, _)| plain),
+            false => name,
+        }
+    }
+
+    /// The symbol map entry of a name, with the section holding its address if it's a label and
+    /// the binding of the name (local labels are always local)
+    pub fn map_symbol(&self, name: &str, value: u64, kind: MapSymbolKind) -> MapSymbol {
+        let section = match kind {
+            MapSymbolKind::Constant => None,
+            _ => self
+                .label_section(value)
+                .map(|i| self.sections[i].name.clone()),
+        };
+        MapSymbol {
+            name: self.label_name(name).to_owned(),
+            value,
+            kind,
+            section,
+            binding: match kind {
+                MapSymbolKind::LocalLabel => SymbolBinding::Local,
+                _ => self.symbol_binding(name),
+            },
+        }
+    }
+
+    /// Drops the information about symbols that aren't exported, like `ld --strip-local`
+    pub fn strip_local_symbols(&mut self) {
+        self.local_labels.clear();
+        self.private_labels.clear();
+        self.constants.clear();
+        let bindings = &self.symbol_bindings;
+        let exported = |name: &String| {
+            bindings
+                .get(name)
+                .is_some_and(|b| *b != SymbolBinding::Local)
+        };
+        self.symbols.retain(|name, _| exported(name));
+        self.symbol_types.retain(|name, _| exported(name));
+        self.symbol_sizes.retain(|name, _| exported(name));
+        self.symbol_bindings
+            .retain(|_, binding| *binding != SymbolBinding::Local);
+    }
+
+    /// Checks that no two sections claim the same addresses
+    /// Address of the byte at `offset` in `bytes`, which is the offset itself unless a layout
+    /// placed its section somewhere else
+    pub fn address_of(&self, offset: usize) -> u64 {
+        self.sections
+            .iter()
+            .rev()
+            .find(|s| {
+                s.kind != SectionKind::Bss && s.offset <= offset && offset <= s.offset + s.size
+            })
+            .map_or(offset as u64, |s| s.base + (offset - s.offset) as u64)
+    }
+
+    /// Offset in `bytes` of the byte at `address`, if it's in the image
+    pub fn offset_of(&self, address: u64) -> Option<usize> {
+        match self
+            .sections
+            .iter()
+            .filter(|s| s.kind != SectionKind::Bss)
+            .find(|s| s.base <= address && address < s.base + s.size as u64)
+        {
+            Some(s) => Some(s.offset + (address - s.base) as usize),
+            // outside the sections only without a layout, where offsets are addresses
+            None if self.sections.iter().all(|s| s.base == s.offset as u64) => {
+                ((address as usize) < self.bytes.len()).then_some(address as usize)
+            }
+            None => None,
+        }
+    }
+
+    /// The written ranges by their addresses and bytes, sorted by address
+    pub fn written_blocks(&self) -> Vec<(u64, &[u8])> {
+        let mut blocks: Vec<(u64, &[u8])> = self
+            .written
+            .iter()
+            .map(|r| (self.address_of(r.start), &self.bytes[r.clone()]))
+            .collect();
+        blocks.sort_by_key(|(address, _)| *address);
+        blocks
+    }
+
+    pub fn check_section_overlaps(&self) -> Result<(), EmitError> {
+        for (i, a) in self.sections.iter().enumerate() {
+            for b in self.sections.iter().skip(i + 1) {
+                let start = a.base.max(b.base);
+                let end = (a.base + a.size as u64).min(b.base + b.size as u64);
+                if start < end {
+                    return Err(EmitError::SectionOverlap(
+                        a.name.clone(),
+                        b.name.clone(),
+                        start,
+                        end,
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An output file format, turning an assembled image into the bytes of a file
+pub trait OutputBackend {
+    fn serialize(&self, image: &AssembledImage) -> Result<Vec<u8>, EmitError>;
+}
@@ -0,0 +1,743 @@
+//! Pseudo-instructions, expanded into sequences of real instructions before encoding
+
+use super::{EmitError, EmitOptions, LiExpansion};
+use crate::arch;
+use crate::intern::ascii_lowercase;
+use crate::parser::Node;
+
+/// Names of the pseudo-instructions expand_pseudo knows
+pub const PSEUDO_INSTRUCTIONS: &[&str] = &[
+    "la",
+    "lla",
+    "li",
+    "mv",
+    "nop",
+    "j",
+    "ret",
+    "jr",
+    "not",
+    "neg",
+    "seqz",
+    "snez",
+    "sltz",
+    "sgtz",
+    "beqz",
+    "bnez",
+    "blez",
+    "bgez",
+    "bltz",
+    "bgtz",
+    "bgt",
+    "ble",
+    "bgtu",
+    "bleu",
+    "csrr",
+    "csrw",
+    "csrs",
+    "csrc",
+    "csrwi",
+    "csrsi",
+    "csrci",
+    "rdcycle",
+    "rdtime",
+    "rdinstret",
+    "rdcycleh",
+    "rdtimeh",
+    "rdinstreth",
+    "call",
+    "tail",
+    "push",
+    "pop",
+];
+
+/// Returns the expansion of `iname` if it is a known pseudo-instruction.
+/// `value_of` gives the value of an operand expression if it's already known.
+pub fn expand_pseudo(
+    spec: &arch::RiscVSpec,
+    options: &EmitOptions,
+    iname: &str,
+    args: &[Node],
+    value_of: &dyn Fn(&Node) -> Option<u64>,
+) -> Result<Option<Vec<Node>>, EmitError> {
+    // instructions defined by the spec always win over built-in pseudo-instructions
+    if spec.get_instruction_by_name(iname).is_some() {
+        return Ok(None);
+    }
+    let lname = ascii_lowercase(iname);
+    match lname.as_ref() {
+        // la rd, symbol
+        "la" => {
+            if options.pic {
+                // GOT-relative loads need a GOT, which only a linker can provide
+                return Err(EmitError::PicUnsupported(iname.to_owned()));
+            }
+            expand_lla(iname, args).map(Some)
+        }
+        // lla rd, symbol
+        "lla" => expand_lla(iname, args).map(Some),
+        // li rd, imm
+        "li" => expand_li(spec, options.li_expansion, iname, args, value_of).map(Some),
+        // mv rd, rs
+        "mv" => {
+            if args.len() != 2 {
+                return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+            }
+            let rd = register_arg(iname, args, 0)?;
+            let rs = register_arg(iname, args, 1)?;
+            Ok(Some(vec![instruction("addi", vec![rd, rs, integer(0)])]))
+        }
+        // nop
+        "nop" => {
+            if !args.is_empty() {
+                return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+            }
+            Ok(Some(vec![instruction(
+                "addi",
+                vec![register(0), register(0), integer(0)],
+            )]))
+        }
+        // j offset, relative to the jump like for jal
+        "j" => {
+            if args.len() != 1 {
+                return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+            }
+            let offset = Node::Argument(Box::new(value_arg(iname, args, 0)?));
+            Ok(Some(vec![instruction("jal", vec![register(0), offset])]))
+        }
+        // ret
+        "ret" => {
+            if !args.is_empty() {
+                return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+            }
+            let ra = named_register(spec, iname, "ra")?;
+            Ok(Some(vec![instruction(
+                "jalr",
+                vec![register(0), ra, integer(0)],
+            )]))
+        }
+        // jr rs
+        "jr" => {
+            if args.len() != 1 {
+                return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+            }
+            let rs = register_arg(iname, args, 0)?;
+            Ok(Some(vec![instruction(
+                "jalr",
+                vec![register(0), rs, integer(0)],
+            )]))
+        }
+        // not/neg/seqz/snez/sltz/sgtz rd, rs
+        "not" | "neg" | "seqz" | "snez" | "sltz" | "sgtz" => {
+            if args.len() != 2 {
+                return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+            }
+            let rd = register_arg(iname, args, 0)?;
+            let rs = register_arg(iname, args, 1)?;
+            let zero = register(0);
+            Ok(Some(vec![match lname.as_ref() {
+                "not" => instruction("xori", vec![rd, rs, integer(u64::MAX)]),
+                "neg" => instruction("sub", vec![rd, zero, rs]),
+                "seqz" => instruction("sltiu", vec![rd, rs, integer(1)]),
+                "snez" => instruction("sltu", vec![rd, zero, rs]),
+                "sltz" => instruction("slt", vec![rd, rs, zero]),
+                _ => instruction("slt", vec![rd, zero, rs]),
+            }]))
+        }
+        // beqz/bnez/blez/bgez/bltz/bgtz rs, offset, comparing with zero
+        "beqz" | "bnez" | "blez" | "bgez" | "bltz" | "bgtz" => {
+            if args.len() != 2 {
+                return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+            }
+            let rs = register_arg(iname, args, 0)?;
+            let offset = Node::Argument(Box::new(value_arg(iname, args, 1)?));
+            let zero = register(0);
+            let (name, rs1, rs2) = match lname.as_ref() {
+                "beqz" => ("beq", rs, zero),
+                "bnez" => ("bne", rs, zero),
+                "blez" => ("bge", zero, rs),
+                "bgez" => ("bge", rs, zero),
+                "bltz" => ("blt", rs, zero),
+                _ => ("blt", zero, rs),
+            };
+            Ok(Some(vec![instruction(name, vec![rs1, rs2, offset])]))
+        }
+        // bgt/ble/bgtu/bleu rs, rt, offset, the branches the other way around
+        "bgt" | "ble" | "bgtu" | "bleu" => {
+            if args.len() != 3 {
+                return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+            }
+            let rs = register_arg(iname, args, 0)?;
+            let rt = register_arg(iname, args, 1)?;
+            let offset = Node::Argument(Box::new(value_arg(iname, args, 2)?));
+            let name = match lname.as_ref() {
+                "bgt" => "blt",
+                "ble" => "bge",
+                "bgtu" => "bltu",
+                _ => "bgeu",
+            };
+            Ok(Some(vec![instruction(name, vec![rt, rs, offset])]))
+        }
+        // csrr rd, csr
+        "csrr" => {
+            if args.len() != 2 {
+                return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+            }
+            let rd = register_arg(iname, args, 0)?;
+            let csr = Node::Argument(Box::new(value_arg(iname, args, 1)?));
+            Ok(Some(vec![instruction("csrrs", vec![rd, csr, register(0)])]))
+        }
+        // csrw/csrs/csrc csr, rs and csrwi/csrsi/csrci csr, imm, discarding the old value
+        "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" => {
+            if args.len() != 2 {
+                return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+            }
+            let csr = Node::Argument(Box::new(value_arg(iname, args, 0)?));
+            let source = if lname.ends_with('i') {
+                Node::Argument(Box::new(value_arg(iname, args, 1)?))
+            } else {
+                register_arg(iname, args, 1)?
+            };
+            let name = format!("csrr{}", &lname[3..]);
+            Ok(Some(vec![instruction(
+                &name,
+                vec![register(0), csr, source],
+            )]))
+        }
+        // rdcycle/rdtime/rdinstret rd, and their h forms reading the upper halves on RV32
+        "rdcycle" | "rdtime" | "rdinstret" | "rdcycleh" | "rdtimeh" | "rdinstreth" => {
+            if args.len() != 1 {
+                return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+            }
+            let rd = register_arg(iname, args, 0)?;
+            let counter = match lname.trim_end_matches('h') {
+                "rdcycle" => 0xc00,
+                "rdtime" => 0xc01,
+                _ => 0xc02,
+            };
+            let csr = integer(if lname.ends_with('h') {
+                counter + 0x80
+            } else {
+                counter
+            });
+            Ok(Some(vec![instruction("csrrs", vec![rd, csr, register(0)])]))
+        }
+        // call symbol / call rd, symbol
+        "call" => expand_call(spec, iname, args).map(Some),
+        // tail symbol
+        "tail" => expand_tail(spec, iname, args).map(Some),
+        // push {reglist} / pop {reglist}
+        "push" => expand_push_pop(spec, iname, args, true).map(Some),
+        "pop" => expand_push_pop(spec, iname, args, false).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// auipc rd, %pcrel_hi(symbol); addi rd, rd, %pcrel_lo(symbol)
+fn expand_lla(iname: &str, args: &[Node]) -> Result<Vec<Node>, EmitError> {
+    if args.len() != 2 {
+        return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+    }
+    let rd = register_arg(iname, args, 0)?;
+    let symbol = value_arg(iname, args, 1)?;
+    // the addi is one instruction after the auipc the offset is relative to
+    let lo_offset = Node::Minus(
+        Box::new(symbol.clone()),
+        Box::new(Node::Minus(
+            Box::new(Node::PcValue),
+            Box::new(Node::Integer(4)),
+        )),
+    );
+    Ok(vec![
+        Node::Instruction(
+            "auipc".into(),
+            vec![
+                rd.clone(),
+                Node::Argument(Box::new(zext32(pcrel_hi(Node::Minus(
+                    Box::new(symbol),
+                    Box::new(Node::PcValue),
+                ))))),
+            ],
+        ),
+        Node::Instruction(
+            "addi".into(),
+            vec![
+                rd.clone(),
+                rd,
+                Node::Argument(Box::new(pcrel_lo(lo_offset))),
+            ],
+        ),
+    ])
+}
+
+/// auipc rd, %pcrel_hi(symbol); jalr rd, rd, %pcrel_lo(symbol), with rd being ra by default
+fn expand_call(spec: &arch::RiscVSpec, iname: &str, args: &[Node]) -> Result<Vec<Node>, EmitError> {
+    let (rd, symbol) = match args.len() {
+        1 => (
+            named_register(spec, iname, "ra")?,
+            value_arg(iname, args, 0)?,
+        ),
+        2 => (register_arg(iname, args, 0)?, value_arg(iname, args, 1)?),
+        _ => return Err(EmitError::InvalidArgumentCount(iname.to_owned())),
+    };
+    // the jalr is one instruction after the auipc the offset is relative to
+    let lo_offset = Node::Minus(
+        Box::new(symbol.clone()),
+        Box::new(Node::Minus(
+            Box::new(Node::PcValue),
+            Box::new(Node::Integer(4)),
+        )),
+    );
+    let hi = zext32(pcrel_hi(Node::Minus(
+        Box::new(symbol),
+        Box::new(Node::PcValue),
+    )));
+    Ok(vec![
+        instruction("auipc", vec![rd.clone(), Node::Argument(Box::new(hi))]),
+        instruction(
+            "jalr",
+            vec![
+                rd.clone(),
+                rd,
+                Node::Argument(Box::new(pcrel_lo(lo_offset))),
+            ],
+        ),
+    ])
+}
+
+/// auipc t1, %pcrel_hi(symbol); jalr zero, t1, %pcrel_lo(symbol), a call that doesn't return
+fn expand_tail(spec: &arch::RiscVSpec, iname: &str, args: &[Node]) -> Result<Vec<Node>, EmitError> {
+    if args.len() != 1 {
+        return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+    }
+    let t1 = named_register(spec, iname, "t1")?;
+    let mut insns = expand_call(spec, iname, &[t1, args[0].clone()])?;
+    if let Some(Node::Instruction(_, jalr_args)) = insns.last_mut() {
+        jalr_args[0] = register(0);
+    }
+    Ok(insns)
+}
+
+/// Single instruction a call, tail or la/lla can be shortened to once its target turns out to
+/// be close enough
+pub struct Relaxation {
+    pub target: Node,
+    /// The target is reached relative to the instruction's address, not as an absolute address
+    pub pc_relative: bool,
+    pub range: std::ops::Range<i64>,
+    pub insn: Node,
+}
+
+impl Relaxation {
+    /// Whether the single instruction at `pc` reaches a target with the given value
+    pub fn reaches(&self, target: u64, pc: u64) -> bool {
+        if self.pc_relative {
+            let offset = target.wrapping_sub(pc) as i64;
+            self.range.contains(&offset) && offset % 2 == 0
+        } else {
+            self.range.contains(&(target as i64))
+        }
+    }
+}
+
+/// The relaxed form of `iname` if it is a pseudo-instruction that has one: `jal` for call and
+/// tail, `addi rd, zero, symbol` for la/lla. The latter loads the address as an absolute value,
+/// so it isn't used for position-independent code.
+pub fn relaxation(
+    spec: &arch::RiscVSpec,
+    options: &EmitOptions,
+    iname: &str,
+    args: &[Node],
+) -> Result<Option<Relaxation>, EmitError> {
+    if spec.get_instruction_by_name(iname).is_some() {
+        return Ok(None);
+    }
+    let jal = |rd: Node, symbol: Node| Relaxation {
+        target: symbol.clone(),
+        pc_relative: true,
+        range: -(1 << 20)..(1 << 20),
+        insn: instruction(
+            "jal",
+            vec![
+                rd,
+                Node::Argument(Box::new(Node::Minus(
+                    Box::new(symbol),
+                    Box::new(Node::PcValue),
+                ))),
+            ],
+        ),
+    };
+    let lname = ascii_lowercase(iname);
+    Ok(match lname.as_ref() {
+        "call" => match args.len() {
+            1 => Some(jal(
+                named_register(spec, iname, "ra")?,
+                value_arg(iname, args, 0)?,
+            )),
+            2 => Some(jal(
+                register_arg(iname, args, 0)?,
+                value_arg(iname, args, 1)?,
+            )),
+            _ => return Err(EmitError::InvalidArgumentCount(iname.to_owned())),
+        },
+        "tail" if args.len() == 1 => Some(jal(register(0), value_arg(iname, args, 0)?)),
+        "la" | "lla" if args.len() == 2 && !options.pic => {
+            let symbol = value_arg(iname, args, 1)?;
+            Some(Relaxation {
+                target: symbol.clone(),
+                pc_relative: false,
+                range: -2048..2048,
+                insn: instruction(
+                    "addi",
+                    vec![
+                        register_arg(iname, args, 0)?,
+                        register(0),
+                        Node::Argument(Box::new(symbol)),
+                    ],
+                ),
+            })
+        }
+        _ => None,
+    })
+}
+
+/// Sequence reaching the target of a branch or jal that turns out to be out of its range
+pub struct LongBranch {
+    /// Offset of the target from the branch, with the address of the branch in place of `.`
+    pub offset: Node,
+    pub range: std::ops::Range<i64>,
+    pub long: Vec<Node>,
+}
+
+impl LongBranch {
+    /// Whether the branch itself reaches the offset
+    pub fn reaches(&self, offset: u64) -> bool {
+        self.range.contains(&(offset as i64))
+    }
+}
+
+/// The long form of the branch or jal `iname` at `pc` if its offset uses labels: the inverted
+/// branch over a `jal zero` for branches, `auipc`+`jalr` for jal (through t1 when it doesn't
+/// link).
+pub fn long_branch(
+    spec: &arch::RiscVSpec,
+    iname: &str,
+    args: &[Node],
+    pc: u64,
+) -> Result<Option<LongBranch>, EmitError> {
+    if spec.get_instruction_by_name(iname).is_none() {
+        return Ok(None);
+    }
+    let lname = ascii_lowercase(iname);
+    let inverse = match lname.as_ref() {
+        "beq" => "bne",
+        "bne" => "beq",
+        "blt" => "bge",
+        "bge" => "blt",
+        "bltu" => "bgeu",
+        "bgeu" => "bltu",
+        "jal" => "jal",
+        _ => return Ok(None),
+    };
+    let (rd, offset) = match args.len() {
+        1 if lname == "jal" => (
+            named_register(spec, iname, "ra")?,
+            value_arg(iname, args, 0)?,
+        ),
+        2 if lname == "jal" => (register_arg(iname, args, 0)?, value_arg(iname, args, 1)?),
+        3 if lname != "jal" => (register(0), value_arg(iname, args, 2)?),
+        _ => return Ok(None),
+    };
+    if offset.referenced_symbols().is_empty() {
+        return Ok(None);
+    }
+    // the instructions of the long form are at other addresses
+    let offset = offset
+        .rewrite(&mut |node| {
+            Ok::<_, ()>(match node {
+                Node::PcValue => Node::Integer(pc),
+                node => node,
+            })
+        })
+        .unwrap();
+    let (range, long) = if lname == "jal" {
+        let via = match rd {
+            Node::Argument(box Node::Register(0)) => named_register(spec, iname, "t1")?,
+            _ => rd.clone(),
+        };
+        (
+            -(1 << 20)..(1 << 20),
+            vec![
+                instruction(
+                    "auipc",
+                    vec![
+                        via.clone(),
+                        Node::Argument(Box::new(Node::Hi(Box::new(offset.clone())))),
+                    ],
+                ),
+                instruction(
+                    "jalr",
+                    vec![
+                        rd,
+                        via,
+                        Node::Argument(Box::new(Node::Lo(Box::new(offset.clone())))),
+                    ],
+                ),
+            ],
+        )
+    } else {
+        (
+            -4096..4096,
+            vec![
+                instruction(inverse, vec![args[0].clone(), args[1].clone(), integer(8)]),
+                instruction(
+                    "jal",
+                    vec![
+                        rd,
+                        Node::Argument(Box::new(Node::Minus(
+                            Box::new(offset.clone()),
+                            Box::new(Node::Integer(4)),
+                        ))),
+                    ],
+                ),
+            ],
+        )
+    };
+    Ok(Some(LongBranch {
+        offset,
+        range,
+        long,
+    }))
+}
+
+/// lui rd, hi; addi rd, rd, lo, leaving out what isn't needed for the known value if allowed.
+/// With XLEN 32 only values that fit in 32 bits (sign-extended) can be loaded. Wider cores add
+/// the low part with addiw, so the sum gets sign-extended from 32 bits like the one of lui, and
+/// build the known values that don't fit in 32 bits with li_wide.
+fn expand_li(
+    spec: &arch::RiscVSpec,
+    policy: LiExpansion,
+    iname: &str,
+    args: &[Node],
+    value_of: &dyn Fn(&Node) -> Option<u64>,
+) -> Result<Vec<Node>, EmitError> {
+    if args.len() != 2 {
+        return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+    }
+    let rd = register_arg(iname, args, 0)?;
+    let value = value_arg(iname, args, 1)?;
+    let known = value_of(&value);
+    let wide = spec.get_const("XLEN").unwrap_or(32) > 32;
+    if let Some(v) = known {
+        let fits = (v as i64) >= i32::MIN as i64 && (v as i64) <= u32::MAX as i64;
+        if wide && !(fits && (v as i64) <= i32::MAX as i64) {
+            if policy == LiExpansion::Fixed {
+                // the layout of the longer sequences depends on the value
+                let (min, max) = (i32::MIN as i64, i32::MAX as i64);
+                return Err(EmitError::ValueOutOfRange(
+                    iname.to_owned(),
+                    1,
+                    v as i64,
+                    min,
+                    max,
+                ));
+            }
+            return Ok(li_wide(&rd, v as i64));
+        }
+        if !fits {
+            return Err(EmitError::InvalidArgumentType(iname.to_owned(), 1));
+        }
+    }
+    // with XLEN 32 values are taken modulo 2^32, so 0xffffffff is -1
+    let signed = known.map(|v| v as u32 as i32 as i64);
+
+    let zero = Node::Argument(Box::new(Node::Register(0)));
+    let lui = Node::Instruction(
+        "lui".into(),
+        vec![
+            rd.clone(),
+            Node::Argument(Box::new(zext32(pcrel_hi(value.clone())))),
+        ],
+    );
+    let addi = |rs1| {
+        Node::Instruction(
+            if wide { "addiw" } else { "addi" }.into(),
+            vec![
+                rd.clone(),
+                rs1,
+                Node::Argument(Box::new(pcrel_lo(value.clone()))),
+            ],
+        )
+    };
+    let small = signed.filter(|v| (-2048..2048).contains(v));
+    Ok(match (policy, small, signed) {
+        (LiExpansion::Shortest, Some(v), _) => {
+            let imm = Node::Argument(Box::new(Node::Integer(v as u64)));
+            vec![Node::Instruction("addi".into(), vec![rd, zero, imm])]
+        }
+        (LiExpansion::Shortest, None, Some(v)) if v & 0xfff == 0 => vec![lui],
+        _ => vec![lui, addi(rd.clone())],
+    })
+}
+
+/// Loads a value of up to 64 bits like GNU as does: values fitting in 32 bits with lui and addiw
+/// (or a lone addi), the others by loading the value without its low 12 bits and the zeroes
+/// after them, then shifting it into place and adding the low 12 bits
+fn li_wide(rd: &Node, value: i64) -> Vec<Node> {
+    let lo = (value << 52) >> 52;
+    if (i32::MIN as i64..=i32::MAX as i64).contains(&value) {
+        let hi = ((value + 0x800) >> 12) & 0xfffff;
+        let mut out = Vec::new();
+        if hi != 0 {
+            out.push(instruction(
+                "lui",
+                vec![rd.clone(), integer((hi << 12) as u64)],
+            ));
+        }
+        if hi == 0 {
+            out.push(instruction(
+                "addi",
+                vec![rd.clone(), register(0), integer(lo as u64)],
+            ));
+        } else if lo != 0 {
+            out.push(instruction(
+                "addiw",
+                vec![rd.clone(), rd.clone(), integer(lo as u64)],
+            ));
+        }
+        return out;
+    }
+    let upper = value.wrapping_sub(lo) >> 12;
+    let shift = 12 + upper.trailing_zeros();
+    let mut out = li_wide(rd, upper >> (shift - 12));
+    out.push(instruction(
+        "slli",
+        vec![rd.clone(), rd.clone(), integer(shift as u64)],
+    ));
+    if lo != 0 {
+        out.push(instruction(
+            "addi",
+            vec![rd.clone(), rd.clone(), integer(lo as u64)],
+        ));
+    }
+    out
+}
+
+/// Stores (push) or loads (pop) the registers in a stack frame of XLEN-sized slots, keeping sp
+/// aligned to 16 bytes. The first register of the list is at the highest address.
+fn expand_push_pop(
+    spec: &arch::RiscVSpec,
+    iname: &str,
+    args: &[Node],
+    push: bool,
+) -> Result<Vec<Node>, EmitError> {
+    if args.len() != 1 {
+        return Err(EmitError::InvalidArgumentCount(iname.to_owned()));
+    }
+    let regs = match &args[0] {
+        Node::Argument(box Node::RegisterSet(regs)) => regs.clone(),
+        Node::Argument(box Node::Register(reg)) => vec![*reg],
+        _ => return Err(EmitError::InvalidArgumentType(iname.to_owned(), 0)),
+    };
+    let sp = spec
+        .get_register_by_name("sp")
+        .map(|r| r.index)
+        .ok_or_else(|| EmitError::InvalidInstruction(iname.to_owned(), None))?;
+    let slot = spec.get_const("XLEN").unwrap_or(32) / 8;
+    let (store, load) = if slot == 8 {
+        ("sd", "ld")
+    } else {
+        ("sw", "lw")
+    };
+    let frame = (regs.len() as u64 * slot).div_ceil(16) * 16;
+
+    let reg = |r: i32| Node::Argument(Box::new(Node::Register(r)));
+    let adjust = |by: u64| {
+        Node::Instruction(
+            "addi".into(),
+            vec![
+                reg(sp),
+                reg(sp),
+                Node::Argument(Box::new(Node::Integer(by))),
+            ],
+        )
+    };
+    let mut out = Vec::new();
+    if push {
+        out.push(adjust(frame.wrapping_neg()));
+    }
+    for (i, r) in regs.iter().enumerate() {
+        let offset = frame - (i as u64 + 1) * slot;
+        out.push(Node::Instruction(
+            if push { store } else { load }.into(),
+            vec![
+                reg(*r),
+                Node::Argument(Box::new(Node::Integer(offset))),
+                reg(sp),
+            ],
+        ));
+    }
+    if !push {
+        out.push(adjust(frame));
+    }
+    Ok(out)
+}
+
+/// Upper 20 bits of an offset, rounded so that adding the sign-extended lower 12 bits restores it
+pub fn pcrel_hi(offset: Node) -> Node {
+    Node::Shl(
+        Box::new(Node::Ashr(
+            Box::new(Node::Plus(Box::new(offset), Box::new(Node::Integer(0x800)))),
+            Box::new(Node::Integer(12)),
+        )),
+        Box::new(Node::Integer(12)),
+    )
+}
+
+/// Lower 12 bits of an offset, as a signed value complementing `pcrel_hi`
+pub fn pcrel_lo(offset: Node) -> Node {
+    Node::Minus(Box::new(offset.clone()), Box::new(pcrel_hi(offset)))
+}
+
+/// Truncates to the low 32 bits, as expected by U-type immediates
+fn zext32(value: Node) -> Node {
+    Node::Shr(
+        Box::new(Node::Shl(Box::new(value), Box::new(Node::Integer(32)))),
+        Box::new(Node::Integer(32)),
+    )
+}
+
+fn instruction(name: &str, args: Vec<Node>) -> Node {
+    Node::Instruction(name.into(), args)
+}
+
+fn register(index: i32) -> Node {
+    Node::Argument(Box::new(Node::Register(index)))
+}
+
+fn integer(value: u64) -> Node {
+    Node::Argument(Box::new(Node::Integer(value)))
+}
+
+/// A register the expansion needs by its ABI name, like ra
+fn named_register(spec: &arch::RiscVSpec, iname: &str, name: &str) -> Result<Node, EmitError> {
+    spec.get_register_by_name(name)
+        .map(|r| register(r.index))
+        .ok_or_else(|| EmitError::InvalidInstruction(iname.to_owned(), None))
+}
+
+fn register_arg(iname: &str, args: &[Node], i: usize) -> Result<Node, EmitError> {
+    match &args[i] {
+        Node::Argument(box Node::Register(_)) => Ok(args[i].clone()),
+        _ => Err(EmitError::InvalidArgumentType(iname.to_owned(), i)),
+    }
+}
+
+fn value_arg(iname: &str, args: &[Node], i: usize) -> Result<Node, EmitError> {
+    match &args[i] {
+        Node::Argument(box Node::Register(_)) => {
+            Err(EmitError::InvalidArgumentType(iname.to_owned(), i))
+        }
+        Node::Argument(box value) => Ok(value.clone()),
+        _ => Err(EmitError::InvalidArgumentType(iname.to_owned(), i)),
+    }
+}
@@ -0,0 +1,148 @@
+//! Motorola S-record output, for flashing tools that take it instead of Intel HEX
+
+use super::flatbin::emit_image;
+use super::{AssembledImage, EmitError, EmitOptions, OutputBackend};
+use crate::arch;
+use crate::parser::Node;
+use std::fmt::Write;
+
+/// Most data bytes in a record, the usual choice of other tools
+const RECORD_DATA: usize = 16;
+
+/// Module name carried by the S0 header record
+const HEADER: &[u8] = b"rvasm";
+
+/// Size of the addresses in the records, which picks the S1/S9, S2/S8 or S3/S7 records
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SrecAddrWidth {
+    Bits16,
+    Bits24,
+    Bits32,
+}
+
+impl SrecAddrWidth {
+    /// Narrowest width that fits every written address and the entry of the image
+    pub fn for_image(image: &AssembledImage) -> Self {
+        let end = written_end(image).max(image.entry_address().unwrap_or(0) + 1);
+        if end <= 1 << 16 {
+            SrecAddrWidth::Bits16
+        } else if end <= 1 << 24 {
+            SrecAddrWidth::Bits24
+        } else {
+            SrecAddrWidth::Bits32
+        }
+    }
+
+    fn bytes(self) -> usize {
+        match self {
+            SrecAddrWidth::Bits16 => 2,
+            SrecAddrWidth::Bits24 => 3,
+            SrecAddrWidth::Bits32 => 4,
+        }
+    }
+
+    fn data_record(self) -> u8 {
+        match self {
+            SrecAddrWidth::Bits16 => 1,
+            SrecAddrWidth::Bits24 => 2,
+            SrecAddrWidth::Bits32 => 3,
+        }
+    }
+
+    fn termination_record(self) -> u8 {
+        match self {
+            SrecAddrWidth::Bits16 => 9,
+            SrecAddrWidth::Bits24 => 8,
+            SrecAddrWidth::Bits32 => 7,
+        }
+    }
+}
+
+/// S-record text: only the ranges the source emitted into get data records, the gaps
+/// skipped by .org are left out
+pub struct Srec {
+    pub addr_width: SrecAddrWidth,
+}
+
+impl OutputBackend for Srec {
+    fn serialize(&self, image: &AssembledImage) -> Result<Vec<u8>, EmitError> {
+        to_srec(image, self.addr_width).map(String::into_bytes)
+    }
+}
+
+pub fn emit_srec(
+    spec: &arch::RiscVSpec,
+    ast: &Node,
+    addr_width: SrecAddrWidth,
+) -> Result<String, EmitError> {
+    let image = emit_image(spec, ast, &EmitOptions::default())?;
+    to_srec(&image, addr_width)
+}
+
+fn write_record(out: &mut String, kind: u8, address: u64, address_bytes: usize, data: &[u8]) {
+    // the count covers the address, the data and the checksum
+    let count = (address_bytes + data.len() + 1) as u8;
+    let address = &address.to_be_bytes()[8 - address_bytes..];
+    write!(out, "S{}{:02X}", kind, count).unwrap();
+    let mut sum = count;
+    for b in address.iter().chain(data.iter()) {
+        write!(out, "{:02X}", b).unwrap();
+        sum = sum.wrapping_add(*b);
+    }
+    writeln!(out, "{:02X}", !sum).unwrap();
+}
+
+/// Address after the last written byte
+fn written_end(image: &AssembledImage) -> u64 {
+    image
+        .written_blocks()
+        .iter()
+        .map(|(address, bytes)| address + bytes.len() as u64)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Formats the written ranges of the image after an S0 header, then the S5 (or S6, over
+/// 0xffff) record counting the data records and the termination record holding the entry point
+/// (0 without one). The addresses have to fit in `addr_width`.
+pub fn to_srec(image: &AssembledImage, addr_width: SrecAddrWidth) -> Result<String, EmitError> {
+    let address_bytes = addr_width.bytes();
+    let limit = 1u64 << (8 * address_bytes);
+    let end = written_end(image);
+    if end > limit {
+        return Err(EmitError::ImageTooLarge(end));
+    }
+    let entry = image.entry_address().unwrap_or(0);
+    if entry >= limit {
+        return Err(EmitError::ImageTooLarge(entry + 1));
+    }
+    let mut out = String::new();
+    write_record(&mut out, 0, 0, 2, HEADER);
+    let mut count = 0u64;
+    for (address, bytes) in image.written_blocks() {
+        for (i, chunk) in bytes.chunks(RECORD_DATA).enumerate() {
+            count += 1;
+            write_record(
+                &mut out,
+                addr_width.data_record(),
+                address + (i * RECORD_DATA) as u64,
+                address_bytes,
+                chunk,
+            );
+        }
+    }
+    match count {
+        0..=0xffff => write_record(&mut out, 5, count, 2, &[]),
+        0x10000..=0xff_ffff => write_record(&mut out, 6, count, 3, &[]),
+        // too many to count
+        _ => {}
+    }
+    write_record(
+        &mut out,
+        addr_width.termination_record(),
+        entry,
+        address_bytes,
+        &[],
+    );
+    Ok(out)
+}
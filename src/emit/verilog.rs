@@ -0,0 +1,103 @@
+//! Memory initialization files for Verilog's `$readmemh` and `$readmemb`, for simulating a core
+//! with the program
+
+use super::flatbin::emit_image;
+use super::{AssembledImage, EmitError, EmitOptions, OutputBackend};
+use crate::arch;
+use crate::parser::Node;
+use std::fmt::Write;
+
+/// `$readmemh` text with one memory word of `word_bytes` bytes per line
+pub struct VerilogHex {
+    pub word_bytes: usize,
+}
+
+impl OutputBackend for VerilogHex {
+    fn serialize(&self, image: &AssembledImage) -> Result<Vec<u8>, EmitError> {
+        to_verilog_hex(image, self.word_bytes).map(String::into_bytes)
+    }
+}
+
+/// `$readmemb` text with one memory word of `word_bytes` bytes per line
+pub struct VerilogBin {
+    pub word_bytes: usize,
+}
+
+impl OutputBackend for VerilogBin {
+    fn serialize(&self, image: &AssembledImage) -> Result<Vec<u8>, EmitError> {
+        to_verilog_bin(image, self.word_bytes).map(String::into_bytes)
+    }
+}
+
+pub fn emit_verilog_hex(
+    spec: &arch::RiscVSpec,
+    ast: &Node,
+    word_bytes: usize,
+) -> Result<String, EmitError> {
+    let image = emit_image(spec, ast, &EmitOptions::default())?;
+    to_verilog_hex(&image, word_bytes)
+}
+
+/// Formats the written ranges of the image as words, each written as one little endian
+/// number (so the byte at the lowest address gives the last two digits). Every range has to
+/// start at a word boundary. A range ending inside a word is padded with zeroes at the
+/// higher addresses, which are the leading digits. Wherever the words don't follow each other,
+/// an `@ADDRESS` line moves the loader to the next one, counting in words like the memory
+/// array does. A `word_bytes` of 0 is taken as 1.
+pub fn to_verilog_hex(image: &AssembledImage, word_bytes: usize) -> Result<String, EmitError> {
+    to_verilog(image, word_bytes, false)
+}
+
+/// Same as to_verilog_hex with the words in binary, for `$readmemb` (the `@ADDRESS` lines stay
+/// hexadecimal, like the format wants)
+pub fn to_verilog_bin(image: &AssembledImage, word_bytes: usize) -> Result<String, EmitError> {
+    to_verilog(image, word_bytes, true)
+}
+
+fn to_verilog(
+    image: &AssembledImage,
+    word_bytes: usize,
+    binary: bool,
+) -> Result<String, EmitError> {
+    let word_bytes = word_bytes.max(1) as u64;
+    // whole words covering the written ranges, by address
+    let mut words: Vec<(u64, Vec<u8>)> = Vec::new();
+    for (address, bytes) in image.written_blocks() {
+        if address % word_bytes != 0 {
+            return Err(EmitError::UnalignedWord(address, word_bytes as usize));
+        }
+        let end = (address + bytes.len() as u64).div_ceil(word_bytes) * word_bytes;
+        let (start, data) = match words.last_mut() {
+            Some((start, data)) if address <= *start + data.len() as u64 => (*start, data),
+            _ => {
+                words.push((address, Vec::new()));
+                let (start, data) = words.last_mut().unwrap();
+                (*start, data)
+            }
+        };
+        let len = data.len().max((end - start) as usize);
+        data.resize(len, 0);
+        let at = (address - start) as usize;
+        data[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    let mut out = String::new();
+    let mut next = 0;
+    for (start, data) in words.iter() {
+        if *start != next {
+            writeln!(out, "@{:x}", start / word_bytes).unwrap();
+        }
+        for word in data.chunks(word_bytes as usize) {
+            for b in word.iter().rev() {
+                if binary {
+                    write!(out, "{:08b}", b).unwrap();
+                } else {
+                    write!(out, "{:02x}", b).unwrap();
+                }
+            }
+            out.push('\n');
+        }
+        next = start + data.len() as u64;
+    }
+    Ok(out)
+}
@@ -0,0 +1,53 @@
+//! The file access of the library. Without the `fs` feature nothing is read from disk, so
+//! `.include` and `.incbin` fail as unsupported.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "fs")]
+pub(crate) fn read_to_string(path: &Path) -> io::Result<String> {
+    std::fs::read_to_string(path)
+}
+
+#[cfg(feature = "fs")]
+pub(crate) fn read(path: &Path) -> io::Result<Vec<u8>> {
+    std::fs::read(path)
+}
+
+#[cfg(feature = "fs")]
+pub(crate) fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    std::fs::canonicalize(path)
+}
+
+#[cfg(feature = "fs")]
+pub(crate) fn exists(path: &Path) -> bool {
+    path.exists()
+}
+
+#[cfg(not(feature = "fs"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "file access is disabled (the fs feature is off)",
+    )
+}
+
+#[cfg(not(feature = "fs"))]
+pub(crate) fn read_to_string(_path: &Path) -> io::Result<String> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "fs"))]
+pub(crate) fn read(_path: &Path) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "fs"))]
+pub(crate) fn canonicalize(_path: &Path) -> io::Result<PathBuf> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "fs"))]
+pub(crate) fn exists(_path: &Path) -> bool {
+    false
+}
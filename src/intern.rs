@@ -0,0 +1,203 @@
+//! Interned names of the symbols and instructions in the AST, so copying and simplifying nodes
+//! doesn't allocate for each name
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, OnceLock, RwLock, Weak};
+
+/// A name interned with an ID that's the same for equal names. The text is shared by the symbols
+/// naming it and freed with the last of them, so the names of assemblies done with (like the
+/// labels an editor session made while typing) don't pile up; an ID is kept while a symbol has it.
+#[derive(Clone)]
+pub struct Symbol {
+    id: u32,
+    name: Arc<str>,
+}
+
+/// The names by ID, holding them weakly so the symbols own the text
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<Box<str>, u32>,
+    names: Vec<Option<Weak<str>>>,
+    /// IDs of names swept after their symbols were all dropped, for reuse
+    free: Vec<u32>,
+    /// Count of names to sweep the dropped ones at, twice the names kept by the sweep before
+    sweep_at: usize,
+}
+
+fn interner() -> &'static RwLock<Interner> {
+    static INTERNER: OnceLock<RwLock<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(Default::default)
+}
+
+fn reading() -> std::sync::RwLockReadGuard<'static, Interner> {
+    // the interner is consistent between its updates, so a panic elsewhere can't break it
+    interner().read().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Interner {
+    fn get(&self, name: &str) -> Option<Symbol> {
+        self.symbol(*self.ids.get(name)?)
+    }
+
+    fn symbol(&self, id: u32) -> Option<Symbol> {
+        let name = self.names.get(id as usize)?.as_ref()?.upgrade()?;
+        Some(Symbol { id, name })
+    }
+
+    /// Forget the names no symbol has anymore, freeing their IDs
+    fn sweep(&mut self) {
+        let (names, free) = (&mut self.names, &mut self.free);
+        self.ids.retain(|_, id| {
+            let live = names[*id as usize]
+                .as_ref()
+                .is_some_and(|n| n.strong_count() > 0);
+            if !live {
+                names[*id as usize] = None;
+                free.push(*id);
+            }
+            live
+        });
+        self.sweep_at = (self.ids.len() * 2).max(1024);
+    }
+}
+
+impl Symbol {
+    /// The symbol for `name`, interning it if no symbol has it
+    pub fn intern(name: &str) -> Self {
+        // most names were seen before, which threads parsing at once can look up together
+        if let Some(symbol) = reading().get(name) {
+            return symbol;
+        }
+        let mut interner = interner().write().unwrap_or_else(|e| e.into_inner());
+        if let Some(symbol) = interner.get(name) {
+            return symbol;
+        }
+        if interner.ids.len() >= interner.sweep_at {
+            interner.sweep();
+        }
+        let text: Arc<str> = name.into();
+        // a name whose symbols were dropped since the last sweep gets its old ID back
+        let id = match interner.ids.get(name) {
+            Some(&id) => id,
+            None => {
+                let id = match interner.free.pop() {
+                    Some(id) => id,
+                    None => {
+                        interner.names.push(None);
+                        (interner.names.len() - 1) as u32
+                    }
+                };
+                interner.ids.insert(name.into(), id);
+                id
+            }
+        };
+        interner.names[id as usize] = Some(Arc::downgrade(&text));
+        Symbol { id, name: text }
+    }
+
+    /// The symbol for `name` if a symbol has it already
+    pub fn lookup(name: &str) -> Option<Self> {
+        reading().get(name)
+    }
+
+    /// The symbol with the ID, if a symbol has it
+    pub fn from_id(id: u32) -> Option<Self> {
+        reading().symbol(id)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+}
+
+/// The name in ASCII lowercase, borrowed when it already is, like mnemonics mostly are
+pub fn ascii_lowercase(name: &str) -> Cow<'_, str> {
+    match name.bytes().any(|c| c.is_ascii_uppercase()) {
+        true => Cow::Owned(name.to_ascii_lowercase()),
+        false => Cow::Borrowed(name),
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+/// Symbols sort by their names, not the order they were interned in
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.name.cmp(&other.name)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        &*self.name == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.name == *other
+    }
+}
+
+impl std::ops::Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl AsRef<str> for Symbol {
+    fn as_ref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Symbol::intern(&name)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.name, f)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
@@ -0,0 +1,22 @@
+#![feature(box_patterns)]
+#![warn(clippy::all)]
+#![allow(dead_code)]
+pub mod arch;
+pub mod assemble;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod disasm;
+pub mod emit;
+mod files;
+pub mod intern;
+mod parallel;
+pub mod parser;
+pub mod report;
+pub mod session;
+#[cfg(feature = "fs")]
+mod test;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+mod yaml;
+
+pub use assemble::{Artifact, Assembler, Diagnostics};
@@ -1,32 +1,301 @@
-#![feature(box_syntax)]
-#![feature(box_patterns)]
 #![warn(clippy::all)]
-#![allow(dead_code)]
-mod arch;
-mod emit;
-mod parser;
-mod test;
-
-use emit::flatbin;
+use rvasm::assemble::{
+    diagnostic_json, emit_error_json, include_error_json, line_map, render_emit_error,
+    render_include_error,
+};
+use rvasm::emit::array::{ArrayLanguage, SourceArray};
+use rvasm::emit::dwarf::{DebugInfo, LineRow};
+use rvasm::emit::elf::{Elf, ElfKind};
+use rvasm::emit::ihex::IntelHex;
+use rvasm::emit::layout::MemoryLayout;
+use rvasm::emit::srec::{Srec, SrecAddrWidth};
+use rvasm::emit::verilog::{VerilogBin, VerilogHex};
+use rvasm::emit::{
+    flatbin, AssembledImage, Diagnostic, EmitOptions, Endianness, LiExpansion, LocalLabelScope,
+    MapSymbolKind, OutputBackend,
+};
+use rvasm::report::{AssemblyStats, MapFormat, StatsFormat};
+use rvasm::{arch, disasm, parser, report};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 #[derive(Debug, Copy, Clone, StructOpt)]
 enum OutputFormat {
     Flat,
+    Elf,
+    IntelHex,
+    /// Motorola S-records with the narrowest addresses that fit, or 16/24/32-bit ones
+    Srec,
+    S19,
+    S28,
+    S37,
+    VerilogHex,
+    VerilogBin,
+    /// Source code declaring the image as an array
+    CArray,
+    RustArray,
 }
 impl std::str::FromStr for OutputFormat {
     type Err = &'static str;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_ascii_lowercase().as_ref() {
-            "flat" => Ok(OutputFormat::Flat),
+            "flat" | "bin" => Ok(OutputFormat::Flat),
+            "elf" => Ok(OutputFormat::Elf),
+            "ihex" | "hex" => Ok(OutputFormat::IntelHex),
+            "srec" => Ok(OutputFormat::Srec),
+            "s19" => Ok(OutputFormat::S19),
+            "s28" => Ok(OutputFormat::S28),
+            "s37" => Ok(OutputFormat::S37),
+            "verilog" | "memh" => Ok(OutputFormat::VerilogHex),
+            "verilog-bin" => Ok(OutputFormat::VerilogBin),
+            "c" => Ok(OutputFormat::CArray),
+            "rust" => Ok(OutputFormat::RustArray),
             _ => Err("Invalid output format specified"),
         }
     }
 }
 
+impl OutputFormat {
+    /// The backend writing the format as the options ask
+    fn backend(
+        self,
+        spec: &arch::RiscVSpec,
+        image: &AssembledImage,
+        opt: &Opt,
+        debug: Option<DebugInfo>,
+    ) -> Box<dyn OutputBackend> {
+        // memory words are XLEN wide unless set
+        let word_bytes = opt
+            .word_width
+            .map_or(spec.get_const("XLEN").unwrap_or(32) as usize / 8, |w| w.0);
+        let array = |language| {
+            let mut array = SourceArray::new(language);
+            if let Some(name) = &opt.array_name {
+                array.name = name.clone();
+            }
+            // arrays are of bytes unless set
+            array.word_bytes = opt.word_width.map_or(1, |w| w.0);
+            array.per_line = opt.array_per_line;
+            array.address_comments = opt.array_addresses;
+            Box::new(array)
+        };
+        match self {
+            OutputFormat::Flat => Box::new(flatbin::FlatBinary),
+            OutputFormat::Elf => Box::new(Elf {
+                debug,
+                ..Elf::for_spec(spec, ElfKind::for_image(image))
+            }),
+            OutputFormat::IntelHex => Box::new(IntelHex),
+            OutputFormat::Srec => Box::new(Srec {
+                addr_width: SrecAddrWidth::for_image(image),
+            }),
+            OutputFormat::S19 => Box::new(Srec {
+                addr_width: SrecAddrWidth::Bits16,
+            }),
+            OutputFormat::S28 => Box::new(Srec {
+                addr_width: SrecAddrWidth::Bits24,
+            }),
+            OutputFormat::S37 => Box::new(Srec {
+                addr_width: SrecAddrWidth::Bits32,
+            }),
+            OutputFormat::VerilogHex => Box::new(VerilogHex { word_bytes }),
+            OutputFormat::VerilogBin => Box::new(VerilogBin { word_bytes }),
+            OutputFormat::CArray => array(ArrayLanguage::C),
+            OutputFormat::RustArray => array(ArrayLanguage::Rust),
+        }
+    }
+}
+
+/// Memory word size in bits, for the Verilog and source array outputs
+#[derive(Debug, Copy, Clone)]
+struct WordWidth(usize);
+impl std::str::FromStr for WordWidth {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" | "16" | "32" | "64" => Ok(WordWidth(s.parse::<usize>().unwrap() / 8)),
+            _ => Err("Expected a word width of 8, 16, 32 or 64 bits"),
+        }
+    }
+}
+
+/// How errors and warnings are printed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum DiagnosticsFormat {
+    Text,
+    Json,
+}
+impl std::str::FromStr for DiagnosticsFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "text" => Ok(DiagnosticsFormat::Text),
+            "json" => Ok(DiagnosticsFormat::Json),
+            _ => Err("Expected a diagnostics format of text or json"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct SymbolMapFormat(MapFormat);
+impl std::str::FromStr for SymbolMapFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "text" => Ok(SymbolMapFormat(MapFormat::Text)),
+            "json" => Ok(SymbolMapFormat(MapFormat::Json)),
+            "nm" => Ok(SymbolMapFormat(MapFormat::Nm)),
+            _ => Err("Invalid map format specified"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct AssemblyStatsFormat(StatsFormat);
+impl std::str::FromStr for AssemblyStatsFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "text" => Ok(AssemblyStatsFormat(StatsFormat::Text)),
+            "json" => Ok(AssemblyStatsFormat(StatsFormat::Json)),
+            _ => Err("Invalid stats format specified"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct LocalLabels(LocalLabelScope);
+impl std::str::FromStr for LocalLabels {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "label" => Ok(LocalLabels(LocalLabelScope::PerGlobalLabel)),
+            "file" => Ok(LocalLabels(LocalLabelScope::PerFile)),
+            "numeric" => Ok(LocalLabels(LocalLabelScope::NumericOnly)),
+            _ => Err("Invalid local label scope specified"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct LiPolicy(LiExpansion);
+impl std::str::FromStr for LiPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "shortest" => Ok(LiPolicy(LiExpansion::Shortest)),
+            "fixed" => Ok(LiPolicy(LiExpansion::Fixed)),
+            _ => Err("Invalid li expansion specified"),
+        }
+    }
+}
+
+/// Optimizations of -O, only `c` for now
+#[derive(Debug, Copy, Clone)]
+struct Optimization {
+    compress: bool,
+}
+impl std::str::FromStr for Optimization {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "c" => Ok(Optimization { compress: true }),
+            _ => Err("Invalid optimization specified"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct ByteOrder(Endianness);
+impl std::str::FromStr for ByteOrder {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "little" => Ok(ByteOrder(Endianness::Little)),
+            "big" => Ok(ByteOrder(Endianness::Big)),
+            _ => Err("Invalid endianness specified"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Defsym(String, u64);
+impl std::str::FromStr for Defsym {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a bare name is a flag, like `-D DEBUG`
+        let (name, value) = s.split_once('=').unwrap_or((s, "1"));
+        let (negative, value) = match value.strip_prefix('-') {
+            Some(value) => (true, value),
+            None => (false, value),
+        };
+        let value = match value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+        {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => value.parse(),
+        }
+        .map_err(|_| "Invalid defsym value")?;
+        if name.is_empty() {
+            return Err("Expected NAME=VALUE");
+        }
+        let value = if negative {
+            value.wrapping_neg()
+        } else {
+            value
+        };
+        Ok(Defsym(name.to_owned(), value))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SectionStart(String, u64);
+impl std::str::FromStr for SectionStart {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Defsym(name, address) = s.parse().map_err(|_| "Expected NAME=ADDRESS")?;
+        Ok(SectionStart(name, address))
+    }
+}
+
+/// `-` in place of a file name, for stdin or stdout
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+fn read_input(path: &Path) -> std::io::Result<Vec<u8>> {
+    if is_stdio(path) {
+        let mut bytes = Vec::new();
+        std::io::stdin().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    } else {
+        std::fs::read(path)
+    }
+}
+
+fn write_output(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    if is_stdio(path) {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(bytes)?;
+        stdout.flush()
+    } else {
+        std::fs::File::create(path)?.write_all(bytes)
+    }
+}
+
 #[derive(Debug, Clone, StructOpt)]
 #[structopt(
     name = "rvasm",
@@ -34,8 +303,10 @@ impl std::str::FromStr for OutputFormat {
     raw(setting = "structopt::clap::AppSettings::ColoredHelp")
 )]
 struct Opt {
-    #[structopt(help = "Input file path")]
-    input_file: Option<PathBuf>,
+    #[structopt(
+        help = "Input file paths, `-` for stdin. Several files are assembled into one image, seeing each other's labels that they declare .global or .weak"
+    )]
+    input_files: Vec<PathBuf>,
 
     #[structopt(
         short = "s",
@@ -44,36 +315,156 @@ struct Opt {
     )]
     input_string: Option<String>,
 
+    #[structopt(
+        short = "I",
+        long = "include-dir",
+        raw(number_of_values = "1"),
+        help = "Directory to look for .include and .incbin files in when they aren't next to the including file (can be repeated, searched in order)"
+    )]
+    include_dirs: Vec<PathBuf>,
+
+    #[structopt(
+        long = "disassemble",
+        help = "Decode the input file as a flat binary and write its instructions as assembly instead of assembling it"
+    )]
+    disassemble: bool,
+
     #[structopt(
         short = "o",
         long = "output-file",
-        help = "Output (assembled) file path"
+        help = "Output (assembled) file path, `-` for stdout"
     )]
     output_file: Option<PathBuf>,
 
-    #[structopt(short = "v", long = "verbose", help = "Enable additional output")]
-    verbose: bool,
+    #[structopt(
+        short = "v",
+        long = "verbose",
+        parse(from_occurrences),
+        help = "Enable additional output, repeat to also report progress of the assembly (-vv) and each resolution pass (-vvv)"
+    )]
+    verbose: u8,
+
+    #[structopt(
+        short = "q",
+        long = "quiet",
+        help = "Only print errors, not warnings or informational messages"
+    )]
+    quiet: bool,
+
+    #[structopt(
+        long = "diagnostics",
+        default_value = "text",
+        help = "Format of errors and warnings on stderr: `text`, or `json` (one object per line with the code, severity, message, file, line, column and length of each)"
+    )]
+    diagnostics: DiagnosticsFormat,
+
+    #[structopt(
+        short = "j",
+        long = "jobs",
+        default_value = "0",
+        help = "Threads parsing and emitting the input files (0 for one per CPU), each one's addresses following the one before"
+    )]
+    jobs: usize,
+
+    #[structopt(
+        long = "max-errors",
+        default_value = "20",
+        help = "Report up to this many errors before stopping, going on past the lines that fail (1 stops at the first)"
+    )]
+    max_errors: usize,
 
     #[structopt(
         short = "f",
         long = "format",
         default_value = "flat",
-        help = "Output file format (only `flat` binary is supported)"
+        help = "Output file format: `flat` (or `bin`) binary, `elf` (an executable if the source sets an entry point with `.entry` or `_start` and needs no relocations, an object file otherwise), `ihex` (Intel HEX), `srec` (Motorola S-records, with the narrowest addresses that fit, or `s19`/`s28`/`s37` for 16/24/32-bit ones), `verilog` (or `memh`, a `$readmemh` file of XLEN-sized words), `verilog-bin` (the same for `$readmemb`), `c` (a header declaring the image as a `const uint8_t program[]` array) or `rust` (a `pub static PROGRAM: [u8; N]` array)"
     )]
     output_format: OutputFormat,
 
+    #[structopt(
+        long = "word-width",
+        help = "Memory word size in bits for the verilog formats (XLEN by default) and the array elements of the c and rust formats (8 by default): 8, 16, 32 or 64"
+    )]
+    word_width: Option<WordWidth>,
+
+    #[structopt(
+        long = "array-name",
+        help = "Identifier of the array of the c and rust formats (`program` and `PROGRAM` by default)"
+    )]
+    array_name: Option<String>,
+
+    #[structopt(
+        long = "array-per-line",
+        default_value = "16",
+        help = "Array elements on each line of the c and rust formats"
+    )]
+    array_per_line: usize,
+
+    #[structopt(
+        long = "array-addresses",
+        help = "Start each line of the c and rust formats with a comment giving the address of its first element"
+    )]
+    array_addresses: bool,
+
     #[structopt(
         short = "c",
         long = "cfg",
-        help = "Additional config file paths to parse"
+        visible_alias = "spec",
+        raw(number_of_values = "1"),
+        help = "Load a spec file with custom instructions, instruction formats, CSRs and constants on top of the architecture, as TOML or (ending in .yaml or .yml) YAML (can be repeated)"
     )]
     cfg: Vec<PathBuf>,
 
+    #[structopt(
+        long = "cfg-override",
+        help = "Let the --cfg files redefine loaded instructions and instruction formats instead of failing"
+    )]
+    cfg_override: bool,
+
+    #[structopt(
+        long = "map",
+        help = "Also write the addresses of the symbols declared .global or .weak to this file"
+    )]
+    map_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "map-all",
+        help = "Write every label, local label and constant to the --map file, not only the exported symbols"
+    )]
+    map_all: bool,
+
+    #[structopt(
+        long = "map-format",
+        default_value = "text",
+        help = "Format of the --map file: `text` (`0xVALUE NAME` lines), `json` (objects with the name, value and kind of each symbol) or `nm` (`VALUE<TAB>TYPE<TAB>NAME` lines with the symbol type letters of nm)"
+    )]
+    map_format: SymbolMapFormat,
+
+    #[structopt(
+        long = "stats",
+        help = "Also write the size of the image and each section, the padding, and the instructions counted by mnemonic, by extension and as compressed or full-size to this file, `-` for stdout"
+    )]
+    stats_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "stats-format",
+        default_value = "text",
+        help = "Format of the --stats file: `text` or `json`"
+    )]
+    stats_format: AssemblyStatsFormat,
+
+    #[structopt(
+        long = "listing",
+        help = "Also write a listing of the source lines next to their addresses and encoded bytes to this file"
+    )]
+    listing_file: Option<PathBuf>,
+
     #[structopt(
         short = "a",
         long = "arch",
+        visible_alias = "march",
         default_value = "RV32I",
-        help = "RISC-V variant to assemble for, like RV32IMZamZifencei (finds config files in standard path)"
+        help = "RISC-V variant to assemble for, like RV32IMZamZifencei or the ISA string rv32imac_zicsr (finds config files in standard path)"
     )]
     arch: String,
 
@@ -83,16 +474,141 @@ struct Opt {
         help = "In addition to writing a file, print the assembly in binary to the terminal"
     )]
     print_binary: bool,
+
+    #[structopt(
+        short = "g",
+        long = "debug-info",
+        help = "With `-f elf`, add DWARF line number information (`.debug_line` and a compile unit in `.debug_info`) so debuggers and addr2line can step through the source"
+    )]
+    debug_info: bool,
+
+    #[structopt(
+        long = "pic",
+        help = "Generate position-independent code (`la` loads through the GOT, use `lla` for local addresses)"
+    )]
+    pic: bool,
+
+    #[structopt(
+        long = "end-symbols",
+        help = "Define _etext, _edata and _end at the end of the code and of the image, unless already defined"
+    )]
+    end_symbols: bool,
+
+    #[structopt(
+        long = "lenient-directives",
+        help = "Warn about unknown directives and skip them instead of failing"
+    )]
+    lenient_directives: bool,
+
+    #[structopt(
+        long = "allow-overwrite",
+        help = "Let code and data after a backward .org overwrite what was emitted there instead of failing"
+    )]
+    allow_overwrite: bool,
+
+    #[structopt(
+        long = "relax",
+        help = "Shorten call/tail to jal, la/lla to addi and instructions using labels further ahead to compressed forms wherever they fit"
+    )]
+    relax: bool,
+
+    #[structopt(
+        long = "relax-branches",
+        help = "Rewrite branches out of their ±4KiB reach into the inverted branch over a jump, and jal out of ±1MiB into auipc+jalr"
+    )]
+    relax_branches: bool,
+
+    #[structopt(
+        long = "linker-relax",
+        help = "Mark calls and address materializations with R_RISCV_RELAX for the linker to shorten them (-f elf), relocating every use of a label"
+    )]
+    linker_relax: bool,
+
+    #[structopt(
+        short = "O",
+        long = "optimize",
+        raw(number_of_values = "1"),
+        help = "Optimization to apply: `c` (-Oc) also compresses instructions using labels further ahead wherever their final operands fit, like --relax without shortening call/tail/la"
+    )]
+    optimizations: Vec<Optimization>,
+
+    #[structopt(
+        long = "local-labels",
+        default_value = "label",
+        help = "Scope of local labels: `label` (dot labels until the next global label), `file` (dot labels in the whole file) or `numeric` (only labels like .1, until the next global label)"
+    )]
+    local_labels: LocalLabels,
+
+    #[structopt(
+        long = "li",
+        default_value = "shortest",
+        help = "Expansion of `li`: `shortest` (as few instructions as the value allows) or `fixed` (always lui+addi, for patching the immediate later)"
+    )]
+    li_expansion: LiPolicy,
+
+    #[structopt(
+        long = "endianness",
+        default_value = "little",
+        help = "Byte order of data (`.half`, `.word`, `.dword`, `.rvtable` and `.checksum` values): `little` or `big`"
+    )]
+    data_endianness: ByteOrder,
+
+    #[structopt(
+        long = "big-endian-instructions",
+        help = "Also write instructions big endian, for non-standard cores (RISC-V instructions are always little endian)"
+    )]
+    big_endian_instructions: bool,
+
+    #[structopt(
+        short = "D",
+        long = "defsym",
+        visible_alias = "define",
+        raw(number_of_values = "1"),
+        help = "Define a constant like `.equ NAME, VALUE` before the source, as NAME=VALUE or NAME for 1 (can be repeated)"
+    )]
+    defsyms: Vec<Defsym>,
+
+    #[structopt(
+        long = "defsym-override",
+        help = "Let --defsym values win over .equ definitions of the same name in the source instead of failing"
+    )]
+    defsym_override: bool,
+
+    #[structopt(
+        long = "section-start",
+        raw(number_of_values = "1"),
+        help = "Place the section NAME at ADDRESS instead of after the ones before it, as NAME=ADDRESS (can be repeated)"
+    )]
+    section_starts: Vec<SectionStart>,
+
+    #[structopt(
+        long = "layout",
+        parse(from_os_str),
+        help = "TOML file of the memory regions and the region of each section, like a linker script (flat output is split into one file per region, named OUTPUT.REGION.EXT)"
+    )]
+    layout: Option<PathBuf>,
+
+    #[structopt(
+        long = "warn-x0",
+        help = "Warn about arithmetic instructions writing their result to x0"
+    )]
+    warn_x0: bool,
+
+    #[structopt(
+        long = "verify",
+        help = "Decode every emitted instruction back through the spec and fail where it doesn't give back the instruction as written, to catch mistakes in the spec's encodings"
+    )]
+    verify: bool,
 }
 
 fn main() {
     let opt = Opt::from_args();
-    if opt.input_string.is_none() && opt.input_file.is_none() {
+    if opt.input_string.is_none() && opt.input_files.is_empty() {
         Opt::clap().print_long_help().unwrap();
         eprintln!("A source file or string is required");
         return;
     }
-    if opt.input_string.is_some() && opt.input_file.is_some() {
+    if opt.input_string.is_some() && !opt.input_files.is_empty() {
         Opt::clap().print_long_help().unwrap();
         eprintln!("Only one source allowed: either a file or a string");
         return;
@@ -101,71 +617,248 @@ fn main() {
     let mut std_path = Vec::new();
     std_path.push(PathBuf::from("./cfg/"));
 
-    let mut rv = crate::arch::RiscVSpec::new();
-    if let Err(e) = rv.load_arch_cfg(&std_path, &opt.arch, opt.verbose) {
-        eprintln!("Error loading arch-defined configuration: {:?}", e);
+    let mut rv = arch::RiscVSpec::new();
+    if let Err(e) = rv.load_arch_cfg(&std_path, &opt.arch, opt.verbose > 0) {
+        eprintln!("Error loading arch-defined configuration: {}", e);
         std::process::exit(1);
     }
-    for cfg in opt.cfg {
-        if let Err(e) = rv.load_single_cfg_file(&cfg) {
+    for cfg in opt.cfg.iter() {
+        if let Err(e) = rv.load_extension_file(cfg, opt.cfg_override) {
             let pstr = cfg.as_os_str().to_string_lossy();
             eprintln!(
-                "Error loading additional configuration from {}: {:?}",
+                "Error loading additional configuration from {}: {}",
                 pstr, e
             );
             std::process::exit(1);
         }
     }
 
-    if opt.verbose {
+    if opt.verbose > 0 {
         for abi in rv.get_loaded_abis() {
-            println!(
+            // stdout may be the output
+            eprintln!(
                 "Loaded ABI: {} - '{}' based on spec '{}'",
                 abi.code, abi.name, abi.spec
             );
         }
     }
 
-    let ast;
-    if let Some(ref istr) = opt.input_string {
-        ast = parser::ast_from_str(&istr.replace(";", "\n"), &rv);
-    } else {
-        ast = parser::ast_from_file(
-            opt.input_file
-                .as_ref()
-                .unwrap()
-                .to_str()
-                .expect("Invalid Unicode in specified file path"),
-            &rv,
-        );
-    }
-    if let Err(e) = ast {
-        eprintln!("Parse error: {:?}", e);
-        std::process::exit(1);
+    if opt.disassemble {
+        let path = match (opt.input_files.as_slice(), &opt.input_string) {
+            ([path], None) => path,
+            _ => {
+                eprintln!("Disassembling needs an input file");
+                std::process::exit(1);
+            }
+        };
+        let bytes = match read_input(path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Could not read input file {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+        let text = disasm::render_disassembly(&rv, &bytes, 0);
+        match opt.output_file {
+            Some(output_file) => {
+                write_output(&output_file, text.as_bytes()).expect("Could not write to output file")
+            }
+            None => print!("{}", text),
+        }
+        return;
     }
-    let ast = ast.unwrap();
 
-    use std::convert::TryInto;
-    let bin: Vec<u8>;
+    let inputs = if let Some(ref istr) = opt.input_string {
+        vec![(String::new(), istr.replace(";", "\n"))]
+    } else {
+        let mut inputs = Vec::new();
+        for path in opt.input_files.iter() {
+            let source = read_input(path).and_then(|bytes| {
+                String::from_utf8(bytes)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            });
+            match source {
+                // includes of stdin are looked for in the working directory
+                Ok(source) if is_stdio(path) => inputs.push(("<stdin>".to_owned(), source)),
+                Ok(source) => inputs.push((path.to_string_lossy().into_owned(), source)),
+                Err(e) => {
+                    eprintln!("Could not read source file {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        inputs
+    };
+    let names: Vec<String> = inputs.iter().map(|(name, _)| name.clone()).collect();
+    let mut sources = Vec::new();
+    let trees =
+        parser::resolve_includes_of_files(&rv, inputs, &opt.include_dirs, &mut sources, opt.jobs);
+    let files: Vec<(String, parser::Node)> = match trees {
+        Ok(trees) => names.into_iter().zip(trees).collect(),
+        Err(e) => {
+            match opt.diagnostics {
+                DiagnosticsFormat::Text => eprintln!("{}", render_include_error(&sources, &e)),
+                DiagnosticsFormat::Json => eprintln!("{}", include_error_json(&e)),
+            }
+            std::process::exit(1);
+        }
+    };
 
-    match opt.output_format {
-        OutputFormat::Flat => {
-            let ebin = flatbin::emit_flat_binary(&rv, &ast);
-            if let Err(e) = ebin {
-                eprintln!("Binary emission error: {:?}", e);
+    let layout = opt.layout.as_ref().map(|path| {
+        let layout = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| MemoryLayout::from_toml(&content).map_err(|e| e.to_string()));
+        match layout {
+            Ok(layout) => layout,
+            Err(e) => {
+                eprintln!("Error loading memory layout from {}: {}", path.display(), e);
                 std::process::exit(1);
-            } else {
-                bin = ebin.unwrap();
             }
         }
+    });
+
+    let options = EmitOptions {
+        pic: opt.pic,
+        lint_x0_writes: opt.warn_x0,
+        verify_encodings: opt.verify,
+        local_labels: opt.local_labels.0,
+        end_symbols: opt.end_symbols,
+        lenient_directives: opt.lenient_directives,
+        max_errors: opt.max_errors.max(1),
+        // only object files can leave symbols to the linker
+        external_symbols: matches!(opt.output_format, OutputFormat::Elf),
+        allow_overwrite: opt.allow_overwrite,
+        relax: opt.relax,
+        relax_branches: opt.relax_branches,
+        linker_relax: opt.linker_relax && matches!(opt.output_format, OutputFormat::Elf),
+        relax_compressed: opt.optimizations.iter().any(|o| o.compress),
+        defsyms: opt.defsyms.iter().map(|d| (d.0.clone(), d.1)).collect(),
+        defsyms_override: opt.defsym_override,
+        section_bases: opt
+            .section_starts
+            .iter()
+            .map(|s| (s.0.clone(), s.1))
+            .collect(),
+        li_expansion: opt.li_expansion.0,
+        verbosity: opt.verbose.saturating_sub(1),
+        data_endianness: opt.data_endianness.0,
+        instruction_endianness: if opt.big_endian_instructions {
+            Endianness::Big
+        } else {
+            Endianness::Little
+        },
+        file_private_labels: true,
+        jobs: opt.jobs,
+        layout: layout.clone(),
+        ..EmitOptions::default()
+    };
+    let image = match files.as_slice() {
+        [(_, ast)] => flatbin::emit_image(&rv, ast, &options),
+        _ => flatbin::emit_image_files(&rv, &files, &options),
+    };
+    if let Err(e) = image {
+        let files: Vec<(&str, &str)> = sources
+            .iter()
+            .map(|(name, source)| (name.as_str(), source.as_str()))
+            .collect();
+        for error in e.all() {
+            match opt.diagnostics {
+                DiagnosticsFormat::Text => eprintln!("{}", render_emit_error(&files, error)),
+                DiagnosticsFormat::Json => eprintln!("{}", emit_error_json(&files, error)),
+            }
+        }
+        std::process::exit(1);
+    }
+    let image = image.unwrap();
+    for diag in image.diagnostics.iter() {
+        match diag {
+            Diagnostic::Warning(_) | Diagnostic::Info(_) if opt.quiet => {}
+            _ if opt.diagnostics == DiagnosticsFormat::Json => {
+                eprintln!("{}", diagnostic_json(None, diag))
+            }
+            Diagnostic::Error(msg) => eprintln!("Error: {}", msg),
+            Diagnostic::Warning(msg) => eprintln!("Warning: {}", msg),
+            Diagnostic::Info(msg) => eprintln!("{}", msg),
+        }
+    }
+    // only object files can import the exported symbols the source doesn't define
+    let exported = image.exported_symbols();
+    let imports = matches!(opt.output_format, OutputFormat::Elf) && opt.map_file.is_none();
+    if let (Err(e), false) = (&exported, imports) {
+        match opt.diagnostics {
+            DiagnosticsFormat::Text => eprintln!("Error: {}", e),
+            DiagnosticsFormat::Json => eprintln!("{}", emit_error_json(&[], e)),
+        }
+        std::process::exit(1);
     }
+    if let (Ok(exported), Some(map_file)) = (&exported, &opt.map_file) {
+        let symbols = if opt.map_all {
+            image.symbol_map()
+        } else {
+            exported
+                .iter()
+                .map(|(name, address)| image.map_symbol(name, *address, MapSymbolKind::Label))
+                .collect()
+        };
+        std::fs::write(
+            map_file,
+            report::render_symbol_map(&symbols, opt.map_format.0),
+        )
+        .expect("Could not write to map file");
+    }
+    if let Some(stats_file) = &opt.stats_file {
+        let stats = AssemblyStats::new(&rv, &image);
+        let text = report::render_stats(&stats, opt.stats_format.0);
+        if let Err(e) = write_output(stats_file, text.as_bytes()) {
+            eprintln!("Could not write stats file {}: {}", stats_file.display(), e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(listing_file) = &opt.listing_file {
+        // the offsets of the tree count the included files as if they followed each other
+        let text: String = sources.iter().map(|(_, source)| source.as_str()).collect();
+        std::fs::write(listing_file, report::render_listing(&text, &image))
+            .expect("Could not write to listing file");
+    }
+    let debug = opt.debug_info.then(|| {
+        let texts: Vec<&str> = sources.iter().map(|(_, source)| source.as_str()).collect();
+        DebugInfo {
+            files: sources.iter().map(|(name, _)| name.clone()).collect(),
+            directory: std::env::current_dir()
+                .map(|dir| dir.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            producer: format!("rvasm {}", env!("CARGO_PKG_VERSION")),
+            rows: line_map(&texts, &image.instructions)
+                .iter()
+                .map(|entry| LineRow {
+                    address: entry.address,
+                    file: entry.file,
+                    line: entry.line as u64,
+                })
+                .collect(),
+        }
+    });
+    let bin = opt
+        .output_format
+        .backend(&rv, &image, &opt, debug)
+        .serialize(&image);
+    if let Err(e) = &bin {
+        match opt.diagnostics {
+            DiagnosticsFormat::Text => eprintln!("Error: {}", e),
+            DiagnosticsFormat::Json => eprintln!("{}", emit_error_json(&[], e)),
+        }
+        std::process::exit(1);
+    }
+    let bin = bin.unwrap();
 
     if opt.print_binary {
         println!("Binary assembly:");
         let mut cnt = 0;
-        for word in bin.chunks(4) {
-            let word: [u8; 4] = word.try_into().unwrap();
-            print!("{:032b} ", u32::from_le_bytes(word));
+        for word in image.bytes.chunks(4) {
+            // data can leave a partial word at the end
+            let mut padded = [0u8; 4];
+            padded[..word.len()].copy_from_slice(word);
+            print!("{:032b} ", u32::from_le_bytes(padded));
             if cnt == 1 {
                 println!();
                 cnt = 0;
@@ -176,12 +869,30 @@ fn main() {
         println!();
     }
 
-    if let Some(output_file) = opt.output_file {
-        std::fs::File::create(output_file)
-            .expect("Could not open output file for writing")
-            .write_all(&bin)
-            .expect("Could not write to output file");
-    } else {
+    if let (Some(layout), Some(output_file), OutputFormat::Flat) =
+        (&layout, &opt.output_file, &opt.output_format)
+    {
+        if is_stdio(output_file) {
+            eprintln!("A memory layout splits flat output into files, it can't go to stdout");
+            std::process::exit(1);
+        }
+        for region in layout.regions.iter() {
+            let bytes = layout.region_bytes(&image, region);
+            if bytes.is_empty() {
+                continue;
+            }
+            let mut name = output_file.file_stem().unwrap_or_default().to_os_string();
+            name.push(format!(".{}", region.name));
+            if let Some(ext) = output_file.extension() {
+                name.push(".");
+                name.push(ext);
+            }
+            std::fs::write(output_file.with_file_name(name), bytes)
+                .expect("Could not write to output file");
+        }
+    } else if let Some(output_file) = opt.output_file {
+        write_output(&output_file, &bin).expect("Could not write to output file");
+    } else if !opt.quiet {
         eprintln!("Warning: no output file specified so none was created.");
     }
 }
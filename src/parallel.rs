@@ -0,0 +1,52 @@
+//! Running the parts of assembling several files that don't depend on each other, like parsing
+//! them, on several threads
+
+use std::sync::Mutex;
+
+/// Threads to use for `jobs`, 0 meaning one per CPU
+pub fn threads(jobs: usize) -> usize {
+    match jobs {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        jobs => jobs,
+    }
+}
+
+/// `f` of each item, in their order, computed on up to `jobs` threads (see `threads`). Where
+/// threads can't be started, like on wasm32-unknown-unknown, the items are done on this one.
+pub fn map<T, R, F>(jobs: usize, items: Vec<T>, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let count = items.len();
+    let workers = threads(jobs).min(count);
+    if workers <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let next = || queue.lock().unwrap_or_else(|e| e.into_inner()).next();
+    let work = || {
+        let mut done = Vec::new();
+        while let Some((i, item)) = next() {
+            done.push((i, f(item)));
+        }
+        done
+    };
+    let mut results: Vec<(usize, R)> = std::thread::scope(|scope| {
+        let spawned: Vec<_> = (1..workers)
+            .filter_map(|_| std::thread::Builder::new().spawn_scoped(scope, work).ok())
+            .collect();
+        // this thread takes items too, and all of them if none could be started
+        let mut results = work();
+        for handle in spawned {
+            match handle.join() {
+                Ok(done) => results.extend(done),
+                Err(panic) => std::panic::resume_unwind(panic),
+            }
+        }
+        results
+    });
+    results.sort_unstable_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
@@ -1,4 +1,5 @@
 use crate::arch;
+use crate::intern::Symbol;
 
 #[allow(ellipsis_inclusive_range_patterns)]
 #[allow(clippy::all)]
@@ -6,33 +7,60 @@ mod grammar {
     include!(concat!(env!("OUT_DIR"), "/grammar.rs"));
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Node {
-    Identifier(String),
+    Identifier(Symbol),
     Integer(u64),
     StringLiteral(Vec<u8>),
     Register(i32),
+    /// Register list like {ra, s0-s2}, in the order given
+    RegisterSet(Vec<i32>),
     PcValue,
 
     Negation(Box<Self>),
+    /// Bitwise complement, `~x`
+    BitNot(Box<Self>),
     Plus(Box<Self>, Box<Self>),
     Minus(Box<Self>, Box<Self>),
     Times(Box<Self>, Box<Self>),
     Divide(Box<Self>, Box<Self>),
+    Remainder(Box<Self>, Box<Self>),
     Shl(Box<Self>, Box<Self>),
     Shr(Box<Self>, Box<Self>),
     Ashr(Box<Self>, Box<Self>),
+    BitAnd(Box<Self>, Box<Self>),
+    BitOr(Box<Self>, Box<Self>),
+    BitXor(Box<Self>, Box<Self>),
+    /// Comparisons (signed) and logical operators, evaluating to 1 if true and 0 if false
+    Equal(Box<Self>, Box<Self>),
+    NotEqual(Box<Self>, Box<Self>),
+    Less(Box<Self>, Box<Self>),
+    Greater(Box<Self>, Box<Self>),
+    LessEqual(Box<Self>, Box<Self>),
+    GreaterEqual(Box<Self>, Box<Self>),
+    LogicalAnd(Box<Self>, Box<Self>),
+    LogicalOr(Box<Self>, Box<Self>),
+    /// Operator functions splitting an address for lui/auipc and a 12-bit immediate, like `%hi(sym)`
+    Hi(Box<Self>),
+    Lo(Box<Self>),
+    PcrelHi(Box<Self>),
+    /// Low part of the `%pcrel_hi` of the auipc at the given label, paired with it by the emitter
+    PcrelLo(Box<Self>),
 
-    Label(String),
+    Label(Symbol),
     Argument(Box<Node>),
-    Instruction(String, Vec<Node>),
+    Instruction(Symbol, Vec<Node>),
 
+    /// Top-level element starting at the given byte offset of the source
+    Located(usize, Box<Node>),
     Root(Vec<Node>),
 }
 
 impl Node {
-    pub fn parse_u64(s: &str, radix: u32) -> Self {
-        Node::Integer(u64::from_str_radix(&s.replace("_", ""), radix).unwrap())
+    pub fn parse_u64(s: &str, radix: u32) -> Result<Self, &'static str> {
+        u64::from_str_radix(&s.replace("_", ""), radix)
+            .map(Node::Integer)
+            .map_err(|_| "integer that fits in 64 bits")
     }
 
     pub fn parse_register(spec: &arch::RiscVSpec, name: &str) -> Result<Self, &'static str> {
@@ -40,21 +68,221 @@ impl Node {
             .map_or(Err("invalid register"), |i| Ok(Node::Register(i.index)))
     }
 
+    /// Registers from `first` to `last` by their common name prefix, like s0-s2 or x5-x7
+    pub fn parse_register_range(
+        spec: &arch::RiscVSpec,
+        first: &str,
+        last: &str,
+    ) -> Result<Vec<i32>, &'static str> {
+        let lookup = |name: &str| {
+            spec.get_register_by_name(name)
+                .map(|r| r.index)
+                .ok_or("invalid register")
+        };
+        if first == last {
+            return Ok(vec![lookup(first)?]);
+        }
+        let split = |name: &'_ str| {
+            let pos = name.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+            let (prefix, num) = name.split_at(pos);
+            num.parse::<u32>().map(|n| (prefix.to_owned(), n))
+        };
+        match (split(first), split(last)) {
+            (Ok((pfirst, nfirst)), Ok((plast, nlast))) if pfirst == plast && nfirst <= nlast => {
+                (nfirst..=nlast)
+                    .map(|n| lookup(&format!("{}{}", pfirst, n)))
+                    .collect()
+            }
+            _ => Err("invalid register range"),
+        }
+    }
+
+    pub fn parse_register_list(ranges: Vec<Vec<i32>>) -> Result<Self, &'static str> {
+        let mut regs: Vec<i32> = Vec::new();
+        for r in ranges.into_iter().flatten() {
+            if regs.contains(&r) {
+                return Err("duplicate register in list");
+            }
+            regs.push(r);
+        }
+        Ok(Node::RegisterSet(regs))
+    }
+
+    pub fn parse_operator_function(name: &str, arg: Self) -> Result<Self, &'static str> {
+        let arg = Box::new(arg);
+        match name {
+            "hi" => Ok(Node::Hi(arg).simplify()),
+            "lo" => Ok(Node::Lo(arg).simplify()),
+            "pcrel_hi" => Ok(Node::PcrelHi(arg)),
+            "pcrel_lo" => Ok(Node::PcrelLo(arg)),
+            _ => Err("unknown operator function"),
+        }
+    }
+
+    /// The upper part of `value` for lui/auipc, which take the value of the bits they set, rounded
+    /// so that adding the sign-extended `lo` of the value gives it back
+    pub fn hi_part(value: u64) -> u64 {
+        value.wrapping_add(0x800) & 0xffff_f000
+    }
+
+    /// The low 12 bits of `value`, sign-extended like the immediates of addi and loads
+    pub fn lo_part(value: u64) -> u64 {
+        ((value & 0xfff) ^ 0x800).wrapping_sub(0x800)
+    }
+
     pub fn simplify(self) -> Self {
         use Node::*;
         match self {
             Negation(box Integer(i)) => Integer(i.wrapping_neg()),
+            BitNot(box Integer(i)) => Integer(!i),
             Plus(box Integer(a), box Integer(b)) => Integer(a.wrapping_add(b)),
             Minus(box Integer(a), box Integer(b)) => Integer(a.wrapping_sub(b)),
             Times(box Integer(a), box Integer(b)) => Integer(a.wrapping_mul(b)),
-            Divide(box Integer(a), box Integer(b)) => Integer(a.wrapping_div(b)),
-            Shl(box Integer(a), box Integer(b)) => Integer(a << b),
-            Shr(box Integer(a), box Integer(b)) => Integer(a >> b),
-            Ashr(box Integer(a), box Integer(b)) => Integer((a as i64 >> b as i64) as u64),
+            // division by zero stays unsimplified, so it's reported as an invalid argument
+            Divide(box Integer(a), box Integer(b)) if b != 0 => Integer(a.wrapping_div(b)),
+            Remainder(box Integer(a), box Integer(b)) if b != 0 => Integer(a.wrapping_rem(b)),
+            Shl(box Integer(a), box Integer(b)) => Integer(a.checked_shl(b as u32).unwrap_or(0)),
+            Shr(box Integer(a), box Integer(b)) => Integer(a.checked_shr(b as u32).unwrap_or(0)),
+            Ashr(box Integer(a), box Integer(b)) => Integer((a as i64 >> b.min(63)) as u64),
+            BitAnd(box Integer(a), box Integer(b)) => Integer(a & b),
+            BitOr(box Integer(a), box Integer(b)) => Integer(a | b),
+            BitXor(box Integer(a), box Integer(b)) => Integer(a ^ b),
+            Equal(box Integer(a), box Integer(b)) => Integer((a == b) as u64),
+            NotEqual(box Integer(a), box Integer(b)) => Integer((a != b) as u64),
+            Less(box Integer(a), box Integer(b)) => Integer(((a as i64) < (b as i64)) as u64),
+            Greater(box Integer(a), box Integer(b)) => Integer(((a as i64) > (b as i64)) as u64),
+            LessEqual(box Integer(a), box Integer(b)) => Integer(((a as i64) <= (b as i64)) as u64),
+            GreaterEqual(box Integer(a), box Integer(b)) => {
+                Integer(((a as i64) >= (b as i64)) as u64)
+            }
+            // short-circuit, the other side doesn't have to be known
+            LogicalAnd(box Integer(0), _) => Integer(0),
+            LogicalOr(box Integer(a), _) if a != 0 => Integer(1),
+            LogicalAnd(box Integer(_), box Integer(b)) => Integer((b != 0) as u64),
+            LogicalOr(box Integer(_), box Integer(b)) => Integer((b != 0) as u64),
+            Hi(box Integer(v)) => Integer(Self::hi_part(v)),
+            Lo(box Integer(v)) => Integer(Self::lo_part(v)),
             _ => self,
         }
     }
 
+    /// Names of all the identifiers and labels used in the node, in order of appearance
+    pub fn referenced_symbols(&self) -> Vec<Symbol> {
+        use Node::*;
+        match self {
+            Identifier(name) | Label(name) => vec![name.clone()],
+            Integer(_) | StringLiteral(_) | Register(_) | RegisterSet(_) | PcValue => vec![],
+            Negation(box a)
+            | BitNot(box a)
+            | Hi(box a)
+            | Lo(box a)
+            | PcrelHi(box a)
+            | PcrelLo(box a)
+            | Argument(box a)
+            | Located(_, box a) => a.referenced_symbols(),
+            Plus(box a, box b)
+            | Minus(box a, box b)
+            | Times(box a, box b)
+            | Divide(box a, box b)
+            | Remainder(box a, box b)
+            | Shl(box a, box b)
+            | Shr(box a, box b)
+            | Ashr(box a, box b)
+            | BitAnd(box a, box b)
+            | BitOr(box a, box b)
+            | BitXor(box a, box b)
+            | Equal(box a, box b)
+            | NotEqual(box a, box b)
+            | Less(box a, box b)
+            | Greater(box a, box b)
+            | LessEqual(box a, box b)
+            | GreaterEqual(box a, box b)
+            | LogicalAnd(box a, box b)
+            | LogicalOr(box a, box b) => {
+                let mut syms = a.referenced_symbols();
+                syms.extend(b.referenced_symbols());
+                syms
+            }
+            Instruction(_, nodes) | Root(nodes) => {
+                nodes.iter().flat_map(|n| n.referenced_symbols()).collect()
+            }
+        }
+    }
+
+    /// Rebuilds the node bottom up, passing every node to `f` once its children are rewritten and
+    /// simplifying the result, so a constant put in by `f` folds into the expression around it
+    pub fn rewrite<E>(&self, f: &mut dyn FnMut(Self) -> Result<Self, E>) -> Result<Self, E> {
+        use Node::*;
+        let node = match self {
+            Identifier(_) | Integer(_) | StringLiteral(_) | Register(_) | RegisterSet(_)
+            | PcValue | Label(_) => self.clone(),
+            Negation(a) => Negation(Box::new(a.rewrite(f)?)),
+            BitNot(a) => BitNot(Box::new(a.rewrite(f)?)),
+            Hi(a) => Hi(Box::new(a.rewrite(f)?)),
+            Lo(a) => Lo(Box::new(a.rewrite(f)?)),
+            PcrelHi(a) => PcrelHi(Box::new(a.rewrite(f)?)),
+            PcrelLo(a) => PcrelLo(Box::new(a.rewrite(f)?)),
+            Argument(a) => Argument(Box::new(a.rewrite(f)?)),
+            Located(offset, a) => Located(*offset, Box::new(a.rewrite(f)?)),
+            Plus(a, b)
+            | Minus(a, b)
+            | Times(a, b)
+            | Divide(a, b)
+            | Remainder(a, b)
+            | Shl(a, b)
+            | Shr(a, b)
+            | Ashr(a, b)
+            | BitAnd(a, b)
+            | BitOr(a, b)
+            | BitXor(a, b)
+            | Equal(a, b)
+            | NotEqual(a, b)
+            | Less(a, b)
+            | Greater(a, b)
+            | LessEqual(a, b)
+            | GreaterEqual(a, b)
+            | LogicalAnd(a, b)
+            | LogicalOr(a, b) => {
+                let (a, b) = (Box::new(a.rewrite(f)?), Box::new(b.rewrite(f)?));
+                match self {
+                    Plus(..) => Plus(a, b),
+                    Minus(..) => Minus(a, b),
+                    Times(..) => Times(a, b),
+                    Divide(..) => Divide(a, b),
+                    Remainder(..) => Remainder(a, b),
+                    Shl(..) => Shl(a, b),
+                    Shr(..) => Shr(a, b),
+                    Ashr(..) => Ashr(a, b),
+                    BitAnd(..) => BitAnd(a, b),
+                    BitOr(..) => BitOr(a, b),
+                    BitXor(..) => BitXor(a, b),
+                    Equal(..) => Equal(a, b),
+                    NotEqual(..) => NotEqual(a, b),
+                    Less(..) => Less(a, b),
+                    Greater(..) => Greater(a, b),
+                    LessEqual(..) => LessEqual(a, b),
+                    GreaterEqual(..) => GreaterEqual(a, b),
+                    LogicalAnd(..) => LogicalAnd(a, b),
+                    _ => LogicalOr(a, b),
+                }
+            }
+            Instruction(name, nodes) => Instruction(
+                name.clone(),
+                nodes
+                    .iter()
+                    .map(|n| n.rewrite(f))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Root(nodes) => Root(
+                nodes
+                    .iter()
+                    .map(|n| n.rewrite(f))
+                    .collect::<Result<_, _>>()?,
+            ),
+        };
+        Ok(f(node)?.simplify())
+    }
+
     /// Returns: the simplified node and whether all the constants were reduced to integers.
     pub fn emitter_simplify<F: Fn(&str) -> Option<u64>>(
         &self,
@@ -75,51 +303,185 @@ impl Node {
             Integer(v) => (Integer(*v), true),
             StringLiteral(_) => cloned_t(),
             Register(_) => cloned_t(),
+            RegisterSet(_) => cloned_t(),
             PcValue => (Integer(pc), true),
 
             Negation(box a) => {
                 let sa = a.emitter_simplify(const_provider, pc);
-                (Negation(box sa.0).simplify(), sa.1)
+                (Negation(Box::new(sa.0)).simplify(), sa.1)
+            }
+            BitNot(box a) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                (BitNot(Box::new(sa.0)).simplify(), sa.1)
             }
             Plus(box a, box b) => {
                 let sa = a.emitter_simplify(const_provider, pc);
                 let sb = b.emitter_simplify(const_provider, pc);
-                (Plus(box sa.0, box sb.0).simplify(), sa.1 && sb.1)
+                (
+                    Plus(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
             }
             Minus(box a, box b) => {
                 let sa = a.emitter_simplify(const_provider, pc);
                 let sb = b.emitter_simplify(const_provider, pc);
-                (Minus(box sa.0, box sb.0).simplify(), sa.1 && sb.1)
+                (
+                    Minus(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
             }
             Times(box a, box b) => {
                 let sa = a.emitter_simplify(const_provider, pc);
                 let sb = b.emitter_simplify(const_provider, pc);
-                (Times(box sa.0, box sb.0).simplify(), sa.1 && sb.1)
+                (
+                    Times(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
             }
             Divide(box a, box b) => {
                 let sa = a.emitter_simplify(const_provider, pc);
                 let sb = b.emitter_simplify(const_provider, pc);
-                (Divide(box sa.0, box sb.0).simplify(), sa.1 && sb.1)
+                (
+                    Divide(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
+            }
+            Remainder(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                let sb = b.emitter_simplify(const_provider, pc);
+                (
+                    Remainder(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
             }
             Shl(box a, box b) => {
                 let sa = a.emitter_simplify(const_provider, pc);
                 let sb = b.emitter_simplify(const_provider, pc);
-                (Shl(box sa.0, box sb.0).simplify(), sa.1 && sb.1)
+                (Shl(Box::new(sa.0), Box::new(sb.0)).simplify(), sa.1 && sb.1)
             }
             Shr(box a, box b) => {
                 let sa = a.emitter_simplify(const_provider, pc);
                 let sb = b.emitter_simplify(const_provider, pc);
-                (Shr(box sa.0, box sb.0).simplify(), sa.1 && sb.1)
+                (Shr(Box::new(sa.0), Box::new(sb.0)).simplify(), sa.1 && sb.1)
             }
             Ashr(box a, box b) => {
                 let sa = a.emitter_simplify(const_provider, pc);
                 let sb = b.emitter_simplify(const_provider, pc);
-                (Ashr(box sa.0, box sb.0).simplify(), sa.1 && sb.1)
+                (
+                    Ashr(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
+            }
+            BitAnd(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                let sb = b.emitter_simplify(const_provider, pc);
+                (
+                    BitAnd(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
+            }
+            BitOr(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                let sb = b.emitter_simplify(const_provider, pc);
+                (
+                    BitOr(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
+            }
+            BitXor(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                let sb = b.emitter_simplify(const_provider, pc);
+                (
+                    BitXor(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
+            }
+            Equal(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                let sb = b.emitter_simplify(const_provider, pc);
+                (
+                    Equal(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
+            }
+            NotEqual(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                let sb = b.emitter_simplify(const_provider, pc);
+                (
+                    NotEqual(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
+            }
+            Less(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                let sb = b.emitter_simplify(const_provider, pc);
+                (
+                    Less(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
+            }
+            Greater(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                let sb = b.emitter_simplify(const_provider, pc);
+                (
+                    Greater(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
+            }
+            LessEqual(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                let sb = b.emitter_simplify(const_provider, pc);
+                (
+                    LessEqual(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
+            }
+            GreaterEqual(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                let sb = b.emitter_simplify(const_provider, pc);
+                (
+                    GreaterEqual(Box::new(sa.0), Box::new(sb.0)).simplify(),
+                    sa.1 && sb.1,
+                )
+            }
+            LogicalAnd(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                let sb = b.emitter_simplify(const_provider, pc);
+                let s = LogicalAnd(Box::new(sa.0), Box::new(sb.0)).simplify();
+                let resolved = matches!(s, Integer(_)) || (sa.1 && sb.1);
+                (s, resolved)
+            }
+            LogicalOr(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                let sb = b.emitter_simplify(const_provider, pc);
+                let s = LogicalOr(Box::new(sa.0), Box::new(sb.0)).simplify();
+                let resolved = matches!(s, Integer(_)) || (sa.1 && sb.1);
+                (s, resolved)
             }
 
+            Hi(box a) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                (Hi(Box::new(sa.0)).simplify(), sa.1)
+            }
+            Lo(box a) => {
+                let sa = a.emitter_simplify(const_provider, pc);
+                (Lo(Box::new(sa.0)).simplify(), sa.1)
+            }
+            // relative to the address of the instruction using it
+            PcrelHi(box a) => match a.emitter_simplify(const_provider, pc) {
+                (Integer(v), true) => (Integer(Self::hi_part(v.wrapping_sub(pc))), true),
+                (sa, _) => (PcrelHi(Box::new(sa)), false),
+            },
+            // only the emitter knows the auipc it refers to, it replaces this by a %lo
+            PcrelLo(_) => cloned_f(),
+
             Argument(box node) => {
                 let s = node.emitter_simplify(const_provider, pc);
-                (Argument(box s.0), s.1)
+                (Argument(Box::new(s.0)), s.1)
+            }
+            Located(offset, box node) => {
+                let s = node.emitter_simplify(const_provider, pc);
+                (Located(*offset, Box::new(s.0)), s.1)
             }
             Instruction(iname, args) => {
                 let mut succ = true;
@@ -129,7 +491,7 @@ impl Node {
                     sargs.push(s.0);
                     succ &= s.1;
                 }
-                (Instruction(iname.to_owned(), sargs), succ)
+                (Instruction(iname.clone(), sargs), succ)
             }
 
             Root(nodes) => {
@@ -146,18 +508,352 @@ impl Node {
     }
 }
 
+/// Most files that can be open in a chain of includes
+pub const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Why resolve_includes failed. Errors of a .include name where it is, like `main.s:3:1`.
+#[derive(Debug)]
+pub enum IncludeError {
+    /// Name of the file and where in it parsing failed, for each line that doesn't parse
+    Parse(String, Vec<ParseError>),
+    /// .include without a string operand naming the file
+    InvalidInclude(String),
+    /// Path of the file that couldn't be read and why
+    Read(String, String, String),
+    /// Path of a file included again while it's being included
+    Cycle(String, String),
+    /// Path of the file that would go over MAX_INCLUDE_DEPTH
+    TooDeep(String, String),
+}
+
+impl IncludeError {
+    /// Name of the kind of error, like `Parse`
+    pub fn code(&self) -> &'static str {
+        match self {
+            IncludeError::Parse(..) => "Parse",
+            IncludeError::InvalidInclude(_) => "InvalidInclude",
+            IncludeError::Read(..) => "Read",
+            IncludeError::Cycle(..) => "Cycle",
+            IncludeError::TooDeep(..) => "TooDeep",
+        }
+    }
+}
+
+impl std::fmt::Display for IncludeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use IncludeError::*;
+        match self {
+            Parse(name, errors) => {
+                for (i, e) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    if !name.is_empty() {
+                        write!(f, "{}:", name)?;
+                    }
+                    write!(
+                        f,
+                        "{}:{}: Parse error: {}",
+                        e.line,
+                        e.column,
+                        parse_error_message(e)
+                    )?;
+                }
+                Ok(())
+            }
+            InvalidInclude(at) => write!(f, "{}: error: .include needs a file name string", at),
+            Read(at, path, e) => write!(f, "{}: error: could not read {}: {}", at, path, e),
+            Cycle(at, path) => write!(f, "{}: error: {} includes itself", at, path),
+            TooDeep(at, path) => write!(
+                f,
+                "{}: error: including {} goes over {} nested files",
+                at, path, MAX_INCLUDE_DEPTH
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IncludeError {}
+
+/// Parses `source` of the file `name` and splices the files it includes with `.include "path"`
+/// in place of the directives, so the includer sees their labels and constants. Relative paths
+/// start from the directory of the including file (the working directory for an empty name).
+/// The texts of the files are added to `sources` in the order the offsets of the tree count
+/// them, as if they were concatenated, which is what assemble::format_emit_error expects. A file
+/// that doesn't parse is added last, for assemble::render_include_error to show its lines.
+/// Files are included even where a false .if skips the directive.
+pub fn resolve_includes(
+    spec: &arch::RiscVSpec,
+    name: &str,
+    source: String,
+    sources: &mut Vec<(String, String)>,
+) -> Result<Node, IncludeError> {
+    resolve_includes_searching(spec, name, source, &[], sources)
+}
+
+/// resolve_includes also looking for the files of .include and .incbin in `include_dirs`, in
+/// their order, when they aren't found next to the including file (like `-I DIR`)
+pub fn resolve_includes_searching(
+    spec: &arch::RiscVSpec,
+    name: &str,
+    source: String,
+    include_dirs: &[std::path::PathBuf],
+    sources: &mut Vec<(String, String)>,
+) -> Result<Node, IncludeError> {
+    let mut stack = Vec::new();
+    if !name.is_empty() {
+        let path = std::path::Path::new(name);
+        stack.push(crate::files::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+    }
+    resolve_includes_in(spec, name, source, include_dirs, sources, &mut stack)
+}
+
+/// resolve_includes_searching of each of the files (names and sources), parsing them on up to
+/// `jobs` threads (0 for one per CPU). The trees and `sources` are the same as resolving the
+/// files one after the other: the offsets count from the start of the first file. The error is
+/// the one of the first file failing.
+pub fn resolve_includes_of_files(
+    spec: &arch::RiscVSpec,
+    files: Vec<(String, String)>,
+    include_dirs: &[std::path::PathBuf],
+    sources: &mut Vec<(String, String)>,
+    jobs: usize,
+) -> Result<Vec<Node>, IncludeError> {
+    let resolved = crate::parallel::map(jobs, files, |(name, source)| {
+        let mut own = Vec::new();
+        let result = resolve_includes_searching(spec, &name, source, include_dirs, &mut own);
+        (result, own)
+    });
+    let mut trees = Vec::new();
+    for (result, own) in resolved.into_iter() {
+        let ast = match result {
+            Ok(ast) => ast,
+            Err(e) => {
+                sources.extend(own);
+                return Err(e);
+            }
+        };
+        let base: usize = sources.iter().map(|(_, s)| s.len()).sum();
+        let nodes = match ast {
+            Node::Root(nodes) => nodes,
+            node => vec![node],
+        };
+        let nodes = nodes
+            .into_iter()
+            .map(|node| match node {
+                Node::Located(offset, inner) => Node::Located(base + offset, inner),
+                node => node,
+            })
+            .collect();
+        trees.push(Node::Root(nodes));
+        sources.extend(own);
+    }
+    Ok(trees)
+}
+
+/// Path of the file an .include or .incbin of `relative` in a file of `dir` names: the one next
+/// to the file if it exists, or the first of the include directories having it. Absolute paths
+/// and files found nowhere are left as they are, for reading them to report the error.
+fn find_included(
+    dir: &std::path::Path,
+    include_dirs: &[std::path::PathBuf],
+    relative: &str,
+) -> std::path::PathBuf {
+    let path = dir.join(relative);
+    if crate::files::exists(&path) || std::path::Path::new(relative).is_absolute() {
+        return path;
+    }
+    include_dirs
+        .iter()
+        .map(|d| d.join(relative))
+        .find(|p| crate::files::exists(p))
+        .unwrap_or(path)
+}
+
+fn resolve_includes_in(
+    spec: &arch::RiscVSpec,
+    name: &str,
+    source: String,
+    include_dirs: &[std::path::PathBuf],
+    sources: &mut Vec<(String, String)>,
+    stack: &mut Vec<std::path::PathBuf>,
+) -> Result<Node, IncludeError> {
+    let base: usize = sources.iter().map(|(_, s)| s.len()).sum();
+    let index = sources.len();
+    sources.push((name.to_owned(), source));
+    let nodes = match ast_from_str_all(&sources[index].1, spec) {
+        Ok(Node::Root(nodes)) => nodes,
+        Ok(node) => vec![node],
+        Err(e) => return Err(IncludeError::Parse(name.to_owned(), e)),
+    };
+    let dir = std::path::Path::new(name)
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_default();
+
+    let mut spliced = Vec::new();
+    for node in nodes.into_iter() {
+        let (offset, inner) = match node {
+            Node::Located(offset, inner) => (offset, inner),
+            node => {
+                spliced.push(node);
+                continue;
+            }
+        };
+        let args = match inner.as_ref() {
+            Node::Instruction(iname, args) if iname.eq_ignore_ascii_case(".include") => args,
+            Node::Instruction(iname, args) if iname.eq_ignore_ascii_case(".incbin") => {
+                let args = incbin_relative_to(&dir, include_dirs, args.clone());
+                let node = Node::Instruction(iname.clone(), args);
+                spliced.push(Node::Located(base + offset, Box::new(node)));
+                continue;
+            }
+            _ => {
+                spliced.push(Node::Located(base + offset, inner));
+                continue;
+            }
+        };
+        let (line, column) = crate::assemble::line_column(&sources[index].1, offset);
+        let at = if name.is_empty() {
+            format!("{}:{}", line, column)
+        } else {
+            format!("{}:{}:{}", name, line, column)
+        };
+        let raw = match args.as_slice() {
+            [Node::Argument(box Node::StringLiteral(raw))] => raw,
+            _ => return Err(IncludeError::InvalidInclude(at)),
+        };
+        let relative = crate::emit::flatbin::unescape(".include", raw)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| IncludeError::InvalidInclude(at.clone()))?;
+        let path = find_included(&dir, include_dirs, &relative);
+        let path_name = path.to_string_lossy().into_owned();
+        let canonical = crate::files::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if stack.contains(&canonical) {
+            return Err(IncludeError::Cycle(at, path_name));
+        }
+        if stack.len() >= MAX_INCLUDE_DEPTH {
+            return Err(IncludeError::TooDeep(at, path_name));
+        }
+        let included = crate::files::read_to_string(&path)
+            .map_err(|e| IncludeError::Read(at, path_name.clone(), e.to_string()))?;
+        stack.push(canonical);
+        match resolve_includes_in(spec, &path_name, included, include_dirs, sources, stack)? {
+            Node::Root(nodes) => spliced.extend(nodes),
+            node => spliced.push(node),
+        }
+        stack.pop();
+    }
+    Ok(Node::Root(spliced))
+}
+
+/// The operands of an .incbin with its path starting from `dir`, or found in the include
+/// directories, like the paths of .include. Paths that aren't valid strings are left to the
+/// emitter to report.
+fn incbin_relative_to(
+    dir: &std::path::Path,
+    include_dirs: &[std::path::PathBuf],
+    mut args: Vec<Node>,
+) -> Vec<Node> {
+    if let Some(Node::Argument(box Node::StringLiteral(raw))) = args.first_mut() {
+        let relative = crate::emit::flatbin::unescape(".incbin", raw)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        if let Some(relative) = relative {
+            let path = find_included(dir, include_dirs, &relative)
+                .to_string_lossy()
+                .into_owned();
+            // the emitter decodes the escapes again
+            *raw = path.replace('\\', "\\\\").replace('"', "\\\"").into_bytes();
+        }
+    }
+    args
+}
+
+pub use grammar::ParseError;
+
+/// Lengths of the logical lines of `text`, each ending past a newline no `\` continues (the
+/// last one at the end of the text). No element of the source goes on past one, so they parse
+/// on their own.
+pub(crate) fn chunk_lengths(text: &str) -> Vec<usize> {
+    let bytes = text.as_bytes();
+    let mut lengths = Vec::new();
+    let mut start = 0;
+    for (i, _) in text.match_indices('\n') {
+        if i > 0 && bytes[i - 1] == b'\\' {
+            continue;
+        }
+        lengths.push(i + 1 - start);
+        start = i + 1;
+    }
+    if start < text.len() {
+        lengths.push(text.len() - start);
+    }
+    lengths
+}
+
+/// Parses the source like ast_from_str, but when it doesn't parse reports every line that
+/// doesn't, each parsed on its own, in the order of the source
+pub fn ast_from_str_all(s: &str, spec: &arch::RiscVSpec) -> Result<Node, Vec<ParseError>> {
+    let first = match ast_from_str(s, spec) {
+        Ok(ast) => return Ok(ast),
+        Err(e) => e,
+    };
+    let mut errors = Vec::new();
+    let (mut start, mut line) = (0, 1);
+    for len in chunk_lengths(s) {
+        let chunk = &s[start..start + len];
+        if let Err(e) = ast_from_str(chunk, spec) {
+            errors.push(ParseError {
+                line: line + e.line - 1,
+                offset: start + e.offset,
+                ..e
+            });
+        }
+        start += len;
+        line += chunk.matches('\n').count();
+    }
+    // the lines each parsing doesn't mean the source does
+    if errors.is_empty() {
+        errors.push(first);
+    }
+    Err(errors)
+}
+
+/// What the parser expected where it failed, its alternatives sorted so the same error reads
+/// the same on every run
+pub fn parse_error_message(e: &ParseError) -> String {
+    let mut expected: Vec<String> = e
+        .expected
+        .iter()
+        .map(|token| {
+            let token: String = token
+                .chars()
+                .map(|c| match c.is_control() {
+                    true => c.escape_default().to_string(),
+                    false => c.to_string(),
+                })
+                .collect();
+            format!("`{}`", token)
+        })
+        .collect();
+    expected.sort_unstable();
+    match expected.as_slice() {
+        [] => "expected the end of the input".to_owned(),
+        [token] => format!("expected {}", token),
+        tokens => format!("expected one of {}", tokens.join(", ")),
+    }
+}
+
 pub fn ast_from_str(s: &str, spec: &arch::RiscVSpec) -> Result<Node, grammar::ParseError> {
     grammar::top_level(s, spec)
 }
 
-pub fn ast_from_file(path: &str, spec: &arch::RiscVSpec) -> Result<Node, grammar::ParseError> {
-    use std::fs::File;
-    use std::io::prelude::*;
-    use std::io::BufReader;
-    let f = File::open(path).unwrap_or_else(|_| panic!("Could not open source file {}", path));
-    let mut rd = BufReader::new(f);
-    let mut buf = String::new();
-    rd.read_to_string(&mut buf)
-        .unwrap_or_else(|_| panic!("Could not read from source file {}", path));
-    ast_from_str(&buf, spec)
+/// Parses the file at `path`, without splicing in the files it includes (see resolve_includes)
+#[cfg(feature = "fs")]
+pub fn ast_from_file(path: &str, spec: &arch::RiscVSpec) -> Result<Node, IncludeError> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| IncludeError::Read(path.to_owned(), path.to_owned(), e.to_string()))?;
+    ast_from_str_all(&source, spec).map_err(|e| IncludeError::Parse(path.to_owned(), e))
 }
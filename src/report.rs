@@ -0,0 +1,522 @@
+//! Printable reports interleaving the source with what it was assembled into, for teaching
+
+use crate::arch;
+use crate::emit::elf::read_only;
+use crate::emit::flatbin::emit_image;
+use crate::emit::{
+    AssembledImage, EmitError, EmitOptions, EmittedInstruction, MapSymbol, MapSymbolKind, Operand,
+    SectionKind, SymbolBinding,
+};
+use crate::parser::Node;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Text,
+    /// A standalone HTML fragment with its own stylesheet
+    Html,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MapFormat {
+    /// `0xVALUE NAME` lines
+    Text,
+    /// An array of `{"name", "value", "kind"}` objects
+    Json,
+    /// `VALUE\tTYPE\tNAME` lines like the output of `nm`, with the value in hex and the type
+    /// letter of the section (`T` code, `R` read-only data, `D` data, `B` .bss, `A` constants),
+    /// lowercase for local symbols and `W` for weak ones
+    Nm,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StatsFormat {
+    Text,
+    /// An object with the totals and objects counting the instructions by name
+    Json,
+}
+
+/// Sizes and instruction counts of an assembled image, to follow the code size of a program as
+/// it changes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssemblyStats {
+    /// Bytes of the image, without .bss
+    pub size: usize,
+    /// Name and size of each section, in the order of the image
+    pub sections: Vec<(String, usize)>,
+    pub instructions: usize,
+    pub mnemonics: BTreeMap<String, usize>,
+    /// Instructions by the code of the spec defining them, like "RV32I" or "C", and "other" for
+    /// ones written with .insn
+    pub extensions: BTreeMap<String, usize>,
+    /// Instructions taking 2 bytes
+    pub compressed: usize,
+    pub full_size: usize,
+    /// AssembledImage.padding
+    pub padding: usize,
+}
+
+impl AssemblyStats {
+    pub fn new(spec: &arch::RiscVSpec, image: &AssembledImage) -> Self {
+        let mut stats = AssemblyStats {
+            size: image.bytes.len(),
+            sections: image
+                .sections
+                .iter()
+                .map(|section| (section.name.clone(), section.size))
+                .collect(),
+            instructions: image.instructions.len(),
+            mnemonics: BTreeMap::new(),
+            extensions: BTreeMap::new(),
+            compressed: 0,
+            full_size: 0,
+            padding: image.padding,
+        };
+        for insn in image.instructions.iter() {
+            let mnemonic = insn.mnemonic.to_ascii_lowercase();
+            let extension = match spec.get_instruction_by_name(&mnemonic) {
+                Some(definition) if !definition.spec_code.is_empty() => {
+                    definition.spec_code.clone()
+                }
+                _ => "other".to_owned(),
+            };
+            *stats.mnemonics.entry(mnemonic).or_default() += 1;
+            *stats.extensions.entry(extension).or_default() += 1;
+            match insn.bytes.len() {
+                2 => stats.compressed += 1,
+                _ => stats.full_size += 1,
+            }
+        }
+        stats
+    }
+}
+
+/// The counts from the largest, names with the same count in order
+fn by_count(counts: &BTreeMap<String, usize>) -> Vec<(&String, &usize)> {
+    let mut counts: Vec<_> = counts.iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(a.1));
+    counts
+}
+
+fn json_counts(counts: &BTreeMap<String, usize>) -> String {
+    let counts: Vec<String> = counts
+        .iter()
+        .map(|(name, count)| format!("\"{}\": {}", json_escape(name), count))
+        .collect();
+    format!("{{{}}}", counts.join(", "))
+}
+
+pub fn render_stats(stats: &AssemblyStats, format: StatsFormat) -> String {
+    let mut out = String::new();
+    match format {
+        StatsFormat::Text => {
+            writeln!(out, "size: {} bytes", stats.size).unwrap();
+            for (name, size) in stats.sections.iter() {
+                writeln!(out, "  {:<16} {:>8}", name, size).unwrap();
+            }
+            writeln!(out, "padding: {} bytes", stats.padding).unwrap();
+            writeln!(out, "instructions: {}", stats.instructions).unwrap();
+            writeln!(out, "  {:<16} {:>8}", "compressed", stats.compressed).unwrap();
+            writeln!(out, "  {:<16} {:>8}", "full-size", stats.full_size).unwrap();
+            writeln!(out, "by extension:").unwrap();
+            for (name, count) in by_count(&stats.extensions) {
+                writeln!(out, "  {:<16} {:>8}", name, count).unwrap();
+            }
+            writeln!(out, "by mnemonic:").unwrap();
+            for (name, count) in by_count(&stats.mnemonics) {
+                writeln!(out, "  {:<16} {:>8}", name, count).unwrap();
+            }
+        }
+        StatsFormat::Json => {
+            let sections: Vec<String> = stats
+                .sections
+                .iter()
+                .map(|(name, size)| {
+                    format!(
+                        "{{\"name\": \"{}\", \"size\": {}}}",
+                        json_escape(name),
+                        size
+                    )
+                })
+                .collect();
+            writeln!(out, "{{").unwrap();
+            writeln!(out, "  \"size\": {},", stats.size).unwrap();
+            writeln!(out, "  \"sections\": [{}],", sections.join(", ")).unwrap();
+            writeln!(out, "  \"padding\": {},", stats.padding).unwrap();
+            writeln!(out, "  \"instructions\": {},", stats.instructions).unwrap();
+            writeln!(out, "  \"compressed\": {},", stats.compressed).unwrap();
+            writeln!(out, "  \"full_size\": {},", stats.full_size).unwrap();
+            writeln!(out, "  \"extensions\": {},", json_counts(&stats.extensions)).unwrap();
+            writeln!(out, "  \"mnemonics\": {}", json_counts(&stats.mnemonics)).unwrap();
+            writeln!(out, "}}").unwrap();
+        }
+    }
+    out
+}
+
+/// One row of the report, a source line or an additional instruction it expanded to
+struct Row<'a> {
+    line: Option<usize>,
+    source: &'a str,
+    insn: Option<&'a EmittedInstruction>,
+}
+
+const HTML_STYLE: &str = "<style>
+.rvasm-report { border-collapse: collapse; font-family: monospace; }
+.rvasm-report td, .rvasm-report th { padding: 0 0.8em; text-align: left; white-space: pre; }
+.rvasm-report .line { color: #999; text-align: right; }
+.rvasm-report .address { color: #7a4ea3; }
+.rvasm-report .encoding { color: #1f5fa8; }
+.rvasm-report .resolved { color: #2d7d3a; }
+.rvasm-report tr.deferred { background: #fff4d6; }
+</style>
+";
+
+pub(crate) fn register_name(spec: &arch::RiscVSpec, index: i32) -> String {
+    spec.get_register(index)
+        .and_then(|r| r.get_abi_name())
+        .map(|n| n.to_owned())
+        .unwrap_or_else(|| format!("x{}", index))
+}
+
+/// The instruction with its operands resolved, like `jal zero, 8`
+fn resolved_text(spec: &arch::RiscVSpec, insn: &EmittedInstruction) -> String {
+    let operands: Vec<String> = insn
+        .operands
+        .iter()
+        .map(|op| match *op {
+            Operand::Register(r) => register_name(spec, r),
+            Operand::Value(v) if (-4096..4096).contains(&(v as i64)) => format!("{}", v as i64),
+            Operand::Value(v) => format!("0x{:x}", v),
+        })
+        .collect();
+    if operands.is_empty() {
+        insn.mnemonic.clone()
+    } else {
+        format!("{} {}", insn.mnemonic, operands.join(", "))
+    }
+}
+
+/// Bytes of the instruction as a little endian number, the way it's usually written down
+fn encoding_text(bytes: &[u8]) -> String {
+    bytes.iter().rev().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn json_escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Writes the symbols for debuggers and emulators to read, like those of
+/// `AssembledImage::symbol_map`
+pub fn render_symbol_map(symbols: &[MapSymbol], format: MapFormat) -> String {
+    let mut out = String::new();
+    match format {
+        MapFormat::Text => {
+            for symbol in symbols.iter() {
+                writeln!(out, "0x{:08x} {}", symbol.value, symbol.name).unwrap();
+            }
+        }
+        MapFormat::Json => {
+            out.push('[');
+            for (i, symbol) in symbols.iter().enumerate() {
+                let kind = match symbol.kind {
+                    MapSymbolKind::Label => "label",
+                    MapSymbolKind::LocalLabel => "local",
+                    MapSymbolKind::Constant => "constant",
+                };
+                write!(
+                    out,
+                    "{}\n  {{\"name\": \"{}\", \"value\": {}, \"kind\": \"{}\"}}",
+                    if i == 0 { "" } else { "," },
+                    json_escape(&symbol.name),
+                    symbol.value,
+                    kind
+                )
+                .unwrap();
+            }
+            out.push_str("\n]\n");
+        }
+        MapFormat::Nm => {
+            for symbol in symbols.iter() {
+                writeln!(
+                    out,
+                    "{:08x}\t{}\t{}",
+                    symbol.value,
+                    nm_type(symbol),
+                    symbol.name
+                )
+                .unwrap();
+            }
+        }
+    }
+    out
+}
+
+/// The letter `nm` shows for the type of the symbol
+fn nm_type(symbol: &MapSymbol) -> char {
+    let letter = match symbol.section.as_deref() {
+        None => 'a',
+        Some(name) => match SectionKind::from_name(name) {
+            SectionKind::Text => 't',
+            SectionKind::Data if read_only(name) => 'r',
+            SectionKind::Data => 'd',
+            SectionKind::Bss => 'b',
+        },
+    };
+    match symbol.binding {
+        SymbolBinding::Local => letter,
+        SymbolBinding::Global => letter.to_ascii_uppercase(),
+        SymbolBinding::Weak => 'W',
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn rows<'a>(source: &'a str, image: &'a AssembledImage) -> Vec<Row<'a>> {
+    let line_starts: Vec<usize> = source.match_indices('\n').map(|(i, _)| i + 1).collect();
+    let mut by_line: Vec<Vec<&EmittedInstruction>> = vec![Vec::new(); line_starts.len() + 1];
+    let mut unplaced = Vec::new();
+    for insn in image.instructions.iter() {
+        match insn.source_offset {
+            Some(offset) => by_line[line_starts.partition_point(|s| *s <= offset)].push(insn),
+            None => unplaced.push(insn),
+        }
+    }
+
+    let mut rows = Vec::new();
+    for (i, (text, insns)) in source.split('\n').zip(by_line.iter()).enumerate() {
+        let text = text.trim_end();
+        if insns.is_empty() {
+            rows.push(Row {
+                line: Some(i + 1),
+                source: text,
+                insn: None,
+            });
+        }
+        for (j, insn) in insns.iter().enumerate() {
+            rows.push(Row {
+                line: if j == 0 { Some(i + 1) } else { None },
+                source: if j == 0 { text } else { "" },
+                insn: Some(insn),
+            });
+        }
+    }
+    // a trailing newline doesn't make another line
+    if rows
+        .last()
+        .is_some_and(|r| r.insn.is_none() && r.source.is_empty())
+    {
+        rows.pop();
+    }
+    rows.extend(unplaced.into_iter().map(|insn| Row {
+        line: None,
+        source: "",
+        insn: Some(insn),
+    }));
+    rows
+}
+
+/// Renders the source line by line with the address, encoding and resolved operands of the
+/// instructions it produced, followed by the symbol table. Instructions that had to wait for
+/// labels defined further ahead are marked.
+pub fn render_report(
+    spec: &arch::RiscVSpec,
+    source: &str,
+    image: &AssembledImage,
+    format: ReportFormat,
+) -> String {
+    let rows = rows(source, image);
+    let mut symbols: Vec<(&String, &u64)> = image.symbols.iter().collect();
+    symbols.sort_by_key(|(name, addr)| (**addr, (*name).clone()));
+    let any_deferred = image.instructions.iter().any(|i| i.deferred);
+
+    let mut out = String::new();
+    match format {
+        ReportFormat::Text => {
+            for row in rows.iter() {
+                let line = row.line.map(|l| l.to_string()).unwrap_or_default();
+                let (address, encoding, resolved) = match row.insn {
+                    Some(insn) => (
+                        format!("{:08x}", insn.address),
+                        format!(
+                            "{}{}",
+                            encoding_text(&insn.bytes),
+                            if insn.deferred { " *" } else { "" }
+                        ),
+                        resolved_text(spec, insn),
+                    ),
+                    None => Default::default(),
+                };
+                let text = format!(
+                    "{:>5}  {:8}  {:<18}  {:<28} | {}",
+                    line, address, encoding, resolved, row.source
+                );
+                writeln!(out, "{}", text.trim_end()).unwrap();
+            }
+            if any_deferred {
+                writeln!(out, "\n* encoded once the labels it refers to were defined").unwrap();
+            }
+            if !symbols.is_empty() {
+                writeln!(out, "\nSymbols:").unwrap();
+                for (name, addr) in symbols.iter() {
+                    writeln!(out, "{:08x}  {}", addr, name).unwrap();
+                }
+            }
+        }
+        ReportFormat::Html => {
+            out.push_str(HTML_STYLE);
+            out.push_str("<table class=\"rvasm-report\">\n");
+            out.push_str("<tr><th>Line</th><th>Address</th><th>Encoding</th><th>Instruction</th><th>Source</th></tr>\n");
+            for row in rows.iter() {
+                let deferred = row.insn.is_some_and(|i| i.deferred);
+                let (address, encoding, resolved) = match row.insn {
+                    Some(insn) => (
+                        format!("{:08x}", insn.address),
+                        encoding_text(&insn.bytes),
+                        html_escape(&resolved_text(spec, insn)),
+                    ),
+                    None => Default::default(),
+                };
+                writeln!(
+                    out,
+                    "<tr{}><td class=\"line\">{}</td><td class=\"address\">{}</td><td class=\"encoding\"{}>{}</td><td class=\"resolved\">{}</td><td class=\"source\">{}</td></tr>",
+                    if deferred { " class=\"deferred\"" } else { "" },
+                    row.line.map(|l| l.to_string()).unwrap_or_default(),
+                    address,
+                    if deferred {
+                        " title=\"encoded once the labels it refers to were defined\""
+                    } else {
+                        ""
+                    },
+                    encoding,
+                    resolved,
+                    html_escape(row.source),
+                )
+                .unwrap();
+            }
+            out.push_str("</table>\n");
+            if !symbols.is_empty() {
+                out.push_str("<table class=\"rvasm-report rvasm-symbols\">\n");
+                out.push_str("<tr><th>Address</th><th>Symbol</th></tr>\n");
+                for (name, addr) in symbols.iter() {
+                    writeln!(
+                        out,
+                        "<tr><td class=\"address\">{:08x}</td><td>{}</td></tr>",
+                        addr,
+                        html_escape(name)
+                    )
+                    .unwrap();
+                }
+                out.push_str("</table>\n");
+            }
+        }
+    }
+    out
+}
+
+/// Most data bytes on one line of a listing
+const LISTING_DATA_BYTES: usize = 8;
+
+/// Assembles `ast`, parsed from `source`, into a listing like objdump's: each source line after
+/// the address and bytes it was assembled into. Instructions waiting for labels further ahead
+/// show their final encoding.
+pub fn emit_listing(
+    spec: &arch::RiscVSpec,
+    ast: &Node,
+    source: &str,
+    options: &EmitOptions,
+) -> Result<String, EmitError> {
+    let image = emit_image(spec, ast, options)?;
+    Ok(render_listing(source, &image))
+}
+
+/// The bytes emitted at [`start`, `end`) on listing lines: each instruction on its own, written
+/// as a little endian number, and data in the order of the memory. Bytes of .bss are left out.
+fn listing_chunks(image: &AssembledImage, start: usize, end: usize) -> Vec<(usize, String)> {
+    let mut chunks = Vec::new();
+    let mut pos = start;
+    while pos < end {
+        let insn = image
+            .instructions
+            .binary_search_by_key(&(pos as u64), |i| i.address)
+            .ok()
+            .map(|i| &image.instructions[i])
+            .filter(|i| !i.bytes.is_empty());
+        if let Some(insn) = insn {
+            chunks.push((pos, encoding_text(&insn.bytes)));
+            pos += insn.bytes.len();
+            continue;
+        }
+        let after = image
+            .instructions
+            .partition_point(|i| i.address as usize <= pos);
+        let next_insn = image
+            .instructions
+            .get(after)
+            .map_or(end, |i| i.address as usize);
+        let chunk_end = end.min(next_insn).min(pos + LISTING_DATA_BYTES);
+        let bytes = match image
+            .offset_of(pos as u64)
+            .and_then(|offset| image.bytes.get(offset..offset + chunk_end - pos))
+        {
+            Some(bytes) => bytes,
+            None => break,
+        };
+        let text: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        chunks.push((pos, text.join(" ")));
+        pos = chunk_end;
+    }
+    chunks
+}
+
+/// Lists every line of `source` with what its elements were assembled into. Lines making more
+/// than fits on one get the rest on lines of their own below, and constants defined with .equ
+/// show their value.
+pub fn render_listing(source: &str, image: &AssembledImage) -> String {
+    let line_starts: Vec<usize> = source.match_indices('\n').map(|(i, _)| i + 1).collect();
+    let mut by_line: Vec<Vec<(Option<usize>, String)>> = vec![Vec::new(); line_starts.len() + 1];
+    for element in image.elements.iter() {
+        let line = &mut by_line[line_starts.partition_point(|s| *s <= element.source_offset)];
+        if let Some(value) = element.value {
+            line.push((None, format!("= 0x{:x}", value)));
+        }
+        let start = element.address as usize;
+        for (address, text) in listing_chunks(image, start, start + element.size) {
+            line.push((Some(address), text));
+        }
+    }
+
+    let mut out = String::new();
+    let texts = source.trim_end_matches('\n').split('\n');
+    for (text, chunks) in texts.zip(by_line.iter()) {
+        let text = text.trim_end();
+        let mut chunks = chunks.iter();
+        let (address, bytes) = match chunks.next() {
+            Some((address, bytes)) => (*address, bytes.as_str()),
+            None => (None, ""),
+        };
+        let address = address.map(|a| format!("{:08x}:", a)).unwrap_or_default();
+        let line = format!("{:9} {:<24} {}", address, bytes, text);
+        writeln!(out, "{}", line.trim_end()).unwrap();
+        for (address, bytes) in chunks {
+            let address = address.map(|a| format!("{:08x}:", a)).unwrap_or_default();
+            writeln!(out, "{:9} {}", address, bytes).unwrap();
+        }
+    }
+    out
+}
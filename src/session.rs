@@ -0,0 +1,302 @@
+//! A source buffer kept assembled while it's edited, for editors and language servers: an edit
+//! parses only the lines it touches again, and the symbols and encodings are looked up in the
+//! last assembly
+
+use crate::arch;
+use crate::assemble::{emit_artifact, parse_error_diagnostic, Artifact, Diagnostics};
+use crate::emit::{Diagnostic, EmitOptions, EmittedInstruction};
+use crate::intern::Symbol;
+use crate::parser::{self, Node, ParseError};
+use std::ops::Range;
+
+/// Lines of the buffer parsed on their own: a line and the ones its `\` at the end continues
+/// into. No element of the source goes on past such a run.
+#[derive(Clone, Debug)]
+struct Chunk {
+    len: usize,
+    /// The elements, located from the start of the chunk, or why it doesn't parse
+    parsed: Result<Vec<Node>, ParseError>,
+}
+
+impl Chunk {
+    fn parse(spec: &arch::RiscVSpec, text: &str) -> Self {
+        let parsed = parser::ast_from_str(text, spec).map(|ast| match ast {
+            Node::Root(nodes) => nodes,
+            node => vec![node],
+        });
+        Chunk {
+            len: text.len(),
+            parsed,
+        }
+    }
+}
+
+/// Offsets (the chunks starting at `start`) and nodes of the elements of the chunks, if they
+/// all parse
+fn located(chunks: &[Chunk], mut start: usize) -> Option<Vec<(usize, &Node)>> {
+    let mut elements = Vec::new();
+    for chunk in chunks.iter() {
+        for node in chunk.parsed.as_ref().ok()?.iter() {
+            elements.push(match node {
+                Node::Located(offset, inner) => (start + offset, &**inner),
+                node => (start, node),
+            });
+        }
+        start += chunk.len;
+    }
+    Some(elements)
+}
+
+/// The source being edited and what assembling it gave, like assemble (.include isn't spliced
+/// in). A change to the elements of a line emits the whole source again, since it can move
+/// every address after it, but edits to comments and spacing only move the source offsets.
+///
+/// ```ignore
+/// let mut session = Assembler::new(&spec).session("start:\n    j start\n");
+/// session.edit(0..5, "begin");
+/// session.edit(13..18, "begin");
+/// assert_eq!(session.symbol_address("begin"), Some(0));
+/// ```
+pub struct Session<'spec> {
+    spec: &'spec arch::RiscVSpec,
+    options: EmitOptions,
+    source: String,
+    chunks: Vec<Chunk>,
+    /// Offset of each chunk in the source
+    starts: Vec<usize>,
+    /// Offset of each line in the source
+    line_starts: Vec<usize>,
+    assembled: Result<Artifact, Diagnostics>,
+    /// Indices of the instructions, sorted by their source offsets
+    by_offset: Vec<usize>,
+    reparsed: usize,
+    reassembled: bool,
+}
+
+impl<'spec> Session<'spec> {
+    pub fn new(spec: &'spec arch::RiscVSpec, source: &str, options: EmitOptions) -> Self {
+        let mut session = Session {
+            spec,
+            options,
+            source: String::new(),
+            chunks: Vec::new(),
+            starts: Vec::new(),
+            line_starts: vec![0],
+            assembled: Err(Diagnostics(Vec::new())),
+            by_offset: Vec::new(),
+            reparsed: 0,
+            reassembled: false,
+        };
+        session.edit(0..0, source);
+        session
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Replaces the bytes of the source in `range` by `text` and assembles it again, parsing
+    /// only the lines the edit touches. Panics like String::replace_range when the range is out
+    /// of the source or not on character boundaries.
+    pub fn edit(&mut self, range: Range<usize>, text: &str) {
+        self.source.replace_range(range.clone(), text);
+        let delta = text.len() as isize - range.len() as isize;
+        let chunk_at = |offset: usize| self.starts.partition_point(|s| *s <= offset);
+        // the chunk holding the end of the edit is parsed again too, since the newline ending
+        // the one before can be gone
+        let first = chunk_at(range.start).saturating_sub(1);
+        let mut end = chunk_at(range.end).min(self.chunks.len());
+        let region_start = self.starts.get(first).copied().unwrap_or(0);
+        let old_end = |end: usize| match end {
+            0 => 0,
+            end => self.starts[end - 1] + self.chunks[end - 1].len,
+        };
+        let mut region_end = (old_end(end) as isize + delta) as usize;
+        // a `\` the edit put before the last newline continues the line into the next chunk
+        while end < self.chunks.len() && self.source[..region_end].ends_with("\\\n") {
+            end += 1;
+            region_end = (old_end(end) as isize + delta) as usize;
+        }
+
+        let mut chunks = Vec::new();
+        let mut start = region_start;
+        for len in parser::chunk_lengths(&self.source[region_start..region_end]) {
+            chunks.push(Chunk::parse(self.spec, &self.source[start..start + len]));
+            start += len;
+        }
+        let old_region_end = old_end(end);
+        self.reparsed = chunks.len();
+        // where the elements of the region moved, if the edit kept them all
+        let moved: Option<Vec<(usize, usize)>> = match (
+            located(&self.chunks[first..end], region_start),
+            located(&chunks, region_start),
+        ) {
+            (Some(old), Some(new))
+                if old.len() == new.len() && old.iter().zip(&new).all(|(a, b)| a.1 == b.1) =>
+            {
+                Some(old.iter().zip(&new).map(|(a, b)| (a.0, b.0)).collect())
+            }
+            _ => None,
+        };
+        self.chunks.splice(first..end, chunks);
+        let mut start = 0;
+        self.starts = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                start += chunk.len;
+                start - chunk.len
+            })
+            .collect();
+        self.line_starts = std::iter::once(0)
+            .chain(self.source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+
+        let assembled = std::mem::replace(&mut self.assembled, Err(Diagnostics(Vec::new())));
+        self.reassembled = moved.is_none() || assembled.is_err();
+        self.assembled = match (assembled, moved) {
+            (Ok(artifact), Some(moved)) => {
+                let offset = |offset: usize| match offset {
+                    o if o < region_start => o,
+                    o if o >= old_region_end => (o as isize + delta) as usize,
+                    o => match moved.binary_search_by_key(&o, |(old, _)| *old) {
+                        Ok(i) => moved[i].1,
+                        Err(_) => o,
+                    },
+                };
+                let mut image = artifact.into_image();
+                for insn in image.instructions.iter_mut() {
+                    insn.source_offset = insn.source_offset.map(offset);
+                }
+                for element in image.elements.iter_mut() {
+                    element.source_offset = offset(element.source_offset);
+                }
+                Ok(Artifact::new(&[self.source.as_str()], image))
+            }
+            _ => self.assemble(),
+        };
+        let mut by_offset = Vec::new();
+        if let Ok(artifact) = &self.assembled {
+            by_offset = (0..artifact.instructions().len()).collect();
+            by_offset.sort_by_key(|i| artifact.instructions()[*i].source_offset);
+        }
+        self.by_offset = by_offset;
+    }
+
+    /// Replaces the whole source
+    pub fn set_source(&mut self, source: &str) {
+        self.edit(0..self.source.len(), source);
+    }
+
+    fn assemble(&self) -> Result<Artifact, Diagnostics> {
+        let mut nodes = Vec::new();
+        let mut errors = Vec::new();
+        for (chunk, start) in self.chunks.iter().zip(&self.starts) {
+            match &chunk.parsed {
+                Ok(elements) => nodes.extend(elements.iter().map(|node| match node {
+                    Node::Located(offset, inner) => Node::Located(start + offset, inner.clone()),
+                    node => node.clone(),
+                })),
+                Err(e) => {
+                    let e = ParseError {
+                        line: self.line_of(*start) + e.line - 1,
+                        offset: start + e.offset,
+                        ..e.clone()
+                    };
+                    errors.push(parse_error_diagnostic(&e));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(Diagnostics(errors));
+        }
+        emit_artifact(self.spec, &Node::Root(nodes), &self.source, &self.options)
+    }
+
+    /// Line (counting from 1) holding the offset
+    fn line_of(&self, offset: usize) -> usize {
+        self.line_starts.partition_point(|s| *s <= offset)
+    }
+
+    /// What the last edit assembled, or why it failed: the parse errors of all the lines that
+    /// don't parse, or else the errors emitting
+    pub fn artifact(&self) -> Result<&Artifact, &Diagnostics> {
+        self.assembled.as_ref()
+    }
+
+    /// Errors, or the warnings and notes if it assembled
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match &self.assembled {
+            Ok(artifact) => artifact.diagnostics(),
+            Err(Diagnostics(errors)) => errors,
+        }
+    }
+
+    pub fn symbol_address(&self, name: &str) -> Option<u64> {
+        self.assembled.as_ref().ok()?.symbol(name)
+    }
+
+    /// The label or identifier the source has at the offset, with its range, like the one
+    /// under an editor's cursor. Mnemonics and registers aren't symbols.
+    pub fn symbol_at(&self, offset: usize) -> Option<(Symbol, Range<usize>)> {
+        let bytes = self.source.as_bytes();
+        if offset > bytes.len() {
+            return None;
+        }
+        let is_name = |c: &u8| c.is_ascii_alphanumeric() || *c == b'_' || *c == b'.';
+        let start = bytes[..offset]
+            .iter()
+            .rposition(|c| !is_name(c))
+            .map_or(0, |i| i + 1);
+        let end = bytes[offset..]
+            .iter()
+            .position(|c| !is_name(c))
+            .map_or(bytes.len(), |i| offset + i);
+        let symbol = Symbol::lookup(self.source.get(start..end)?)?;
+        let chunk = self
+            .starts
+            .partition_point(|s| *s <= start)
+            .checked_sub(1)?;
+        let elements = self.chunks[chunk].parsed.as_ref().ok()?;
+        elements
+            .iter()
+            .any(|node| node.referenced_symbols().contains(&symbol))
+            .then_some((symbol, start..end))
+    }
+
+    /// The instructions the elements of the line (counting from 1) emitted, in the order of
+    /// their addresses, with their encodings
+    pub fn instructions_at_line(&self, line: usize) -> Vec<&EmittedInstruction> {
+        let (artifact, range) = match (&self.assembled, line.checked_sub(1)) {
+            (Ok(artifact), Some(i)) if i < self.line_starts.len() => {
+                let end = self.line_starts.get(i + 1).copied();
+                (
+                    artifact,
+                    self.line_starts[i]..end.unwrap_or(self.source.len()),
+                )
+            }
+            _ => return Vec::new(),
+        };
+        let instructions = artifact.instructions();
+        let offset = |i: &usize| instructions[*i].source_offset;
+        let from = self
+            .by_offset
+            .partition_point(|i| offset(i) < Some(range.start));
+        let to = self
+            .by_offset
+            .partition_point(|i| offset(i) < Some(range.end));
+        let mut found: Vec<usize> = self.by_offset[from..to].to_vec();
+        found.sort_unstable();
+        found.into_iter().map(|i| &instructions[i]).collect()
+    }
+
+    /// Number of lines (with the ones they continue into) the last edit parsed again
+    pub fn reparsed_lines(&self) -> usize {
+        self.reparsed
+    }
+
+    /// Whether the last edit emitted the source again, instead of only moving source offsets
+    pub fn reassembled(&self) -> bool {
+        self.reassembled
+    }
+}
@@ -32,3 +32,5184 @@ fn test_parser_on_all_inputs() {
         }
     }
 }
+
+fn load_rv32i() -> crate::arch::RiscVSpec {
+    let mut rv = crate::arch::RiscVSpec::new();
+    rv.load_single_cfg_file(std::path::Path::new("./cfg/rv32i.toml"))
+        .expect("Parse error");
+    rv
+}
+
+fn assemble_with(
+    rv: &crate::arch::RiscVSpec,
+    src: &str,
+    options: &crate::emit::EmitOptions,
+) -> Result<Vec<u8>, crate::emit::EmitError> {
+    use crate::emit::flatbin::emit_image;
+    use crate::parser::ast_from_str;
+
+    let ast = ast_from_str(src, rv).expect("Testcase parsing failed");
+    // what went wrong, where is checked by test_error_locations
+    emit_image(rv, &ast, options)
+        .map(|image| image.bytes)
+        .map_err(crate::emit::EmitError::into_kind)
+}
+
+fn assemble_words(rv: &crate::arch::RiscVSpec, src: &str) -> Vec<u32> {
+    use std::convert::TryInto;
+
+    let bin = assemble_with(rv, src, &Default::default()).expect("Testcase emission failed");
+    bin.chunks(4)
+        .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+        .collect()
+}
+
+#[test]
+fn test_deferred_branch_uses_own_address() {
+    let rv = load_rv32i();
+    // beq x0, x0, +8 / +4
+    let words = assemble_words(
+        &rv,
+        "start:\nbeq x0, x0, target - $\nbeq x0, x0, target - $\ntarget:\n",
+    );
+    assert_eq!(words, vec![0x0000_0463, 0x0000_0263]);
+}
+
+#[test]
+fn test_deferred_branch_after_alignment_padding() {
+    let rv = load_rv32i();
+    // the branch is discovered at offset 2, but lands at 4 after IALIGN padding
+    let words = assemble_words(&rv, ".org 2\nbeq x0, x0, target - $\ntarget:\n");
+    assert_eq!(words, vec![0x0000_0000, 0x0000_0263]);
+}
+
+#[test]
+fn test_deferred_local_branch_backward_and_forward() {
+    let rv = load_rv32i();
+    let words = assemble_words(
+        &rv,
+        "func:\n.loop:\nbeq x0, x0, .end - $\nbeq x0, x0, .loop - $\n.end:\nnext:\n",
+    );
+    // +8, then -4
+    assert_eq!(words, vec![0x0000_0463, 0xfe00_0ee3]);
+}
+
+#[test]
+fn test_lla_expands_to_pcrel_pair() {
+    let rv = load_rv32i();
+    // auipc a0, 0x1; addi a0, a0, -0x7fc
+    let words = assemble_words(&rv, "start:\nlla a0, target\n.org 0x804\ntarget:\n");
+    assert_eq!(&words[..2], &[0x0000_1517, 0x8045_0513]);
+    // backward reference, negative offset
+    let words = assemble_words(&rv, "target:\naddi x0, x0, 0\nlla a0, target\n");
+    assert_eq!(&words[1..], &[0x0000_0517, 0xffc5_0513]);
+    let words = assemble_words(&rv, "target:\n.org 0x2000\nlla a0, target\n");
+    assert_eq!(&words[0x800..], &[0xffff_e517, 0x0005_0513]);
+}
+
+#[test]
+fn test_la_honors_pic_option() {
+    use crate::emit::{EmitError, EmitOptions};
+    let rv = load_rv32i();
+    let src = "start:\nla a0, start\n";
+    assert_eq!(
+        assemble_words(&rv, src),
+        assemble_words(&rv, "start:\nlla a0, start\n")
+    );
+    let pic = EmitOptions {
+        pic: true,
+        ..Default::default()
+    };
+    match assemble_with(&rv, src, &pic) {
+        Err(EmitError::PicUnsupported(_)) => {}
+        r => panic!("Unexpected result {:?}", r),
+    }
+    assert!(assemble_with(&rv, "start:\nlla a0, start\n", &pic).is_ok());
+
+    // .option pic/nopic switch it for what follows, push/pop save and restore it with rvc
+    assert!(matches!(
+        assemble_with(
+            &rv,
+            ".option pic\nstart:\nla a0, start\n",
+            &Default::default()
+        ),
+        Err(EmitError::PicUnsupported(_))
+    ));
+    assert_eq!(
+        assemble_with(&rv, ".option nopic\nstart:\nla a0, start\n", &pic).unwrap(),
+        assemble_with(&rv, src, &Default::default()).unwrap()
+    );
+    let scoped = ".option push\n.option nopic\nstart:\nla a0, start\n.option pop\nla a0, start\n";
+    assert!(matches!(
+        assemble_with(&rv, scoped, &pic),
+        Err(EmitError::PicUnsupported(_))
+    ));
+    assert!(assemble_with(&rv, scoped, &Default::default()).is_ok());
+}
+
+#[test]
+fn test_section_overlap_detection() {
+    use crate::emit::{AssembledImage, EmitError, ImageSection, SectionKind};
+    let section = |name: &str, base: u64, size: usize| ImageSection {
+        name: name.to_owned(),
+        kind: SectionKind::from_name(name),
+        base,
+        offset: 0,
+        size,
+    };
+    let mut image = AssembledImage {
+        sections: vec![section(".text", 0, 0x100), section(".data", 0x100, 0x20)],
+        ..Default::default()
+    };
+    assert!(image.check_section_overlaps().is_ok());
+    image.sections.push(section(".rodata", 0x110, 0x40));
+    match image.check_section_overlaps() {
+        Err(EmitError::SectionOverlap(a, b, 0x110, 0x120)) => {
+            assert_eq!((a.as_ref(), b.as_ref()), (".data", ".rodata"));
+        }
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_immediate_signedness_range() {
+    use crate::assemble::assemble_instruction;
+    let rv = load_rv32i();
+    let ok = |src: &str| assemble_instruction(&rv, src).is_ok();
+    assert_eq!(
+        assemble_instruction(&rv, "addi a0, a0, -1").unwrap(),
+        0xfff5_0513u32.to_le_bytes()
+    );
+    assert!(ok("addi a0, a0, 2047"));
+    assert!(ok("addi a0, a0, -2048"));
+    assert!(!ok("addi a0, a0, 2048"));
+    assert!(!ok("addi a0, a0, 0xfff"));
+    assert!(ok("slli a0, a0, 31"));
+    assert!(!ok("slli a0, a0, 32"));
+    assert!(!ok("slli a0, a0, -1"));
+    assert!(ok("lui a0, 0xfffff000"));
+    assert!(!ok("lui a0, -4096"));
+}
+
+#[test]
+fn test_default_operands() {
+    let rv = load_rv32i();
+    assert_eq!(
+        assemble_words(&rv, "jal 16\njalr a0\njalr t0, a0\nfence"),
+        vec![0x0100_00ef, 0x0005_00e7, 0x0005_02e7, 0x0ff0_000f]
+    );
+    assert_eq!(
+        assemble_words(&rv, "jal 16"),
+        assemble_words(&rv, "jal ra, 16")
+    );
+    assert!(assemble_with(&rv, "addi a0, a0", &Default::default()).is_err());
+    assert!(assemble_with(&rv, "jal", &Default::default()).is_err());
+}
+
+#[test]
+fn test_dwarf_line_program_roundtrip() {
+    use crate::emit::dwarf::{debug_line, LineRow};
+    use std::convert::TryInto;
+
+    let files = vec!["main.s".to_owned(), "inc.s".to_owned()];
+    let row = |address, file, line| LineRow {
+        address,
+        file,
+        line,
+    };
+    // lines going back further than LINE_BASE take DW_LNS_advance_line
+    let rows = vec![
+        row(0, 0, 3),
+        row(4, 0, 4),
+        row(8, 1, 1),
+        row(0x400, 0, 200),
+        row(0x404, 0, 20),
+        row(0x408, 1, 2),
+    ];
+    let sec = debug_line(&files, &rows, 0x40c, 4);
+
+    let unit_length = u32::from_le_bytes(sec[0..4].try_into().unwrap()) as usize;
+    assert_eq!(unit_length + 4, sec.len());
+    assert_eq!(u16::from_le_bytes(sec[4..6].try_into().unwrap()), 4);
+    let header_length = u32::from_le_bytes(sec[6..10].try_into().unwrap()) as usize;
+    let (line_base, line_range, opcode_base) = (sec[13] as i8 as i64, sec[14] as u64, sec[15]);
+
+    // run the line number state machine
+    let uleb = |pos: &mut usize| {
+        let (mut v, mut shift) = (0u64, 0);
+        loop {
+            let b = sec[*pos];
+            *pos += 1;
+            v |= ((b & 0x7f) as u64) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                return (v, b, shift);
+            }
+        }
+    };
+    let mut pos = 10 + header_length;
+    let (mut address, mut file, mut line) = (0u64, 1u64, 1i64);
+    let mut decoded = Vec::new();
+    loop {
+        let op = sec[pos];
+        pos += 1;
+        match op {
+            0 => {
+                let len = uleb(&mut pos).0 as usize;
+                match sec[pos] {
+                    1 => {
+                        pos += len;
+                        break;
+                    }
+                    2 => {
+                        address =
+                            u32::from_le_bytes(sec[pos + 1..pos + 5].try_into().unwrap()) as u64
+                    }
+                    e => panic!("unexpected extended opcode {}", e),
+                }
+                pos += len;
+            }
+            1 => decoded.push(row(address, file as usize - 1, line as u64)),
+            2 => address += uleb(&mut pos).0,
+            3 => {
+                let (v, last, shift) = uleb(&mut pos);
+                let sign = if shift < 64 && last & 0x40 != 0 {
+                    !0 << shift
+                } else {
+                    0
+                };
+                line += (v | sign) as i64;
+            }
+            4 => file = uleb(&mut pos).0,
+            op if op >= opcode_base => {
+                let adj = (op - opcode_base) as u64;
+                address += adj / line_range;
+                line += line_base + (adj % line_range) as i64;
+                decoded.push(row(address, file as usize - 1, line as u64));
+            }
+            op => panic!("unexpected opcode {}", op),
+        }
+    }
+    assert_eq!(decoded, rows);
+    assert_eq!(address, 0x40c);
+    assert_eq!(pos, sec.len());
+}
+
+#[test]
+fn test_reloc_directive() {
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::EmitError;
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let image = |src: &str| emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default());
+
+    let img =
+        image("start:\n.reloc $, R_RISCV_JAL, func\njal 0\n.reloc end, r_riscv_32, data+8\nend:\n")
+            .unwrap();
+    assert_eq!(img.relocations.len(), 2);
+    assert_eq!(
+        (img.relocations[0].offset, img.relocations[0].kind.as_ref()),
+        (0, "R_RISCV_JAL")
+    );
+    assert_eq!(img.relocations[0].symbol, "func");
+    let r = &img.relocations[1];
+    assert_eq!((r.offset, r.kind.as_ref(), r.addend), (4, "R_RISCV_32", 8));
+    assert_eq!(r.symbol, "data");
+
+    match image(".reloc 0, R_RISCV_BOGUS, func").map_err(EmitError::into_kind) {
+        Err(EmitError::UnknownRelocation(_)) => {}
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_push_pop_register_lists() {
+    let rv = load_rv32i();
+    // addi sp, sp, -16; sw ra, 12(sp); sw s0, 8(sp); sw s1, 4(sp); sw s2, 0(sp)
+    assert_eq!(
+        assemble_words(&rv, "push {ra, s0-s2}"),
+        vec![
+            0xff01_0113,
+            0x0011_2623,
+            0x0081_2423,
+            0x0091_2223,
+            0x0121_2023
+        ]
+    );
+    // lw ra, 12(sp); lw s0, 8(sp); addi sp, sp, 16
+    assert_eq!(
+        assemble_words(&rv, "pop { ra,s0 }"),
+        vec![0x00c1_2083, 0x0081_2403, 0x0101_0113]
+    );
+    // frames stay 16-byte aligned
+    assert_eq!(assemble_words(&rv, "push {a0-a4}")[0], 0xfe01_0113);
+    assert_eq!(assemble_words(&rv, "push a0").len(), 2);
+
+    use crate::parser::ast_from_str;
+    assert!(ast_from_str("push {s2-s0}", &rv).is_err());
+    assert!(ast_from_str("push {s0, s0}", &rv).is_err());
+}
+
+#[test]
+fn test_x0_write_lint() {
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::EmitOptions;
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let lint = EmitOptions {
+        lint_x0_writes: true,
+        ..Default::default()
+    };
+    let warnings = |src: &str, options: &EmitOptions| {
+        let ast = ast_from_str(src, &rv).unwrap();
+        emit_image(&rv, &ast, options).unwrap().diagnostics.len()
+    };
+    assert_eq!(warnings("add x0, a0, a1\nlui zero, 0x1000", &lint), 2);
+    assert_eq!(warnings("add x0, a0, a1", &Default::default()), 0);
+    assert_eq!(
+        warnings("addi x0, x0, 0\njal x0, 0\njalr x0, ra, 0", &lint),
+        0
+    );
+    assert_eq!(warnings("lw x0, 0, sp\nadd a0, a0, a1", &lint), 0);
+}
+
+#[test]
+fn test_padding_follows_text_data_mode() {
+    let mut rv = load_rv32i();
+    rv.load_single_cfg_string(
+        "[meta]\nname = \"Wide alignment\"\ncode = \"Xwide\"\nspec = \"test\"\n[consts]\nIALIGN = 64\n",
+    )
+    .unwrap();
+    assert_eq!(
+        assemble_words(&rv, "add a0, a0, a1\nadd a0, a0, a1"),
+        vec![0x00b5_0533, 0x0000_0013, 0x00b5_0533]
+    );
+    assert_eq!(
+        assemble_words(
+            &rv,
+            ".data\nadd a0, a0, a1\nadd a0, a0, a1\n.text\nadd a0, a0, a1\nadd a0, a0, a1"
+        ),
+        // the data follows the code at the next 8-byte boundary
+        vec![
+            0x00b5_0533,
+            0x0000_0013,
+            0x00b5_0533,
+            0,
+            0x00b5_0533,
+            0,
+            0x00b5_0533
+        ]
+    );
+}
+
+#[test]
+fn test_instruction_hook_sees_final_bytes() {
+    use crate::emit::flatbin::emit_image_with_hook;
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let ast = ast_from_str("start:\njal x0, end - $\nlla a0, start\nend:\n", &rv).unwrap();
+    let mut seen = Vec::new();
+    let mut hook = |addr: u64, name: &str, bytes: &[u8]| {
+        seen.push((addr, name.to_owned(), bytes.to_vec()));
+    };
+    let image = emit_image_with_hook(&rv, &ast, &Default::default(), Some(&mut hook)).unwrap();
+    // the forward jump is only reported once resolved
+    let names: Vec<_> = seen.iter().map(|(a, n, _)| (*a, n.as_ref())).collect();
+    assert_eq!(names, vec![(4, "auipc"), (8, "addi"), (0, "jal")]);
+    for (addr, _, bytes) in seen.iter() {
+        let addr = *addr as usize;
+        assert_eq!(&image.bytes[addr..addr + 4], &bytes[..]);
+    }
+}
+
+#[test]
+fn test_emit_events() {
+    use crate::emit::flatbin::{emit_image_with_events, EmitEvent};
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let src = ".equ N, 3\nbeq a0, a1, end - $\naddi a0, a0, N\n.org 0x10\nend:\nsw a0, 4(sp)\n";
+    let ast = ast_from_str(src, &rv).unwrap();
+    let mut seen = Vec::new();
+    let mut hook = |event: &EmitEvent| {
+        seen.push(match *event {
+            EmitEvent::Instruction {
+                address,
+                mnemonic,
+                argv,
+                bytes,
+            } => format!("{:x} {} {:?} {:02x?}", address, mnemonic, argv, bytes),
+            EmitEvent::Org { from, to } => format!(".org {:x} -> {:x}", from, to),
+            EmitEvent::Constant { name, value } => format!("{} = {}", name, value),
+        });
+    };
+    let image = emit_image_with_events(&rv, &ast, &Default::default(), Some(&mut hook)).unwrap();
+    // the branch waits for end and is only reported with its final bytes
+    assert_eq!(
+        seen,
+        vec![
+            "N = 3",
+            "4 addi [10, 10, 3] [13, 05, 35, 00]",
+            ".org 8 -> 10",
+            "10 sw [10, 4, 2] [23, 22, a1, 00]",
+            "0 beq [10, 11, 16] [63, 08, b5, 00]",
+        ]
+    );
+    assert_eq!(&image.bytes[0..4], &[0x63, 0x08, 0xb5, 0x00]);
+    // the same without the hook
+    assert_eq!(
+        emit_image_with_events(&rv, &ast, &Default::default(), None)
+            .unwrap()
+            .bytes,
+        image.bytes
+    );
+}
+
+#[test]
+fn test_symbol_type_and_size() {
+    use crate::emit::{flatbin::emit_image, SymbolType};
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let src = "\
+.type main, @function
+.size main, main_end - main
+.type table, @object
+main:
+addi a0, zero, 1
+jalr zero, ra
+main_end:
+.data
+table:
+.size table, $ - table
+";
+    let ast = ast_from_str(src, &rv).unwrap();
+    let image = emit_image(&rv, &ast, &Default::default()).unwrap();
+    assert_eq!(image.symbol_types.get("main"), Some(&SymbolType::Function));
+    assert_eq!(image.symbol_types.get("table"), Some(&SymbolType::Object));
+    assert_eq!(image.symbol_sizes.get("main"), Some(&8));
+    assert_eq!(image.symbol_sizes.get("table"), Some(&0));
+    // the bytes themselves are unaffected
+    assert_eq!(image.bytes.len(), 8);
+
+    let ast = ast_from_str(".type main, @section\n", &rv).unwrap();
+    assert!(emit_image(&rv, &ast, &Default::default()).is_err());
+}
+
+#[test]
+fn test_current_location_counter() {
+    let rv = load_rv32i();
+    // `.` is the address of the instruction using it, like `$`
+    let dot = assemble_words(
+        &rv,
+        "addi a0, zero, 1\naddi a1, zero, . + 4\njal zero, . - 8\n",
+    );
+    let dollar = assemble_words(
+        &rv,
+        "addi a0, zero, 1\naddi a1, zero, $ + 4\njal zero, $ - 8\n",
+    );
+    assert_eq!(dot, dollar);
+    assert_eq!(dot[1] >> 20, 8);
+
+    use crate::emit::flatbin::emit_image;
+    use crate::parser::ast_from_str;
+    let src = "f:\naddi a0, zero, 1\n.f_local:\njalr zero, ra\n.size f, . - f\n.equ at, .\naddi a2, zero, at\n";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap();
+    assert_eq!(image.symbol_sizes.get("f"), Some(&8));
+    let word = u32::from_le_bytes([
+        image.bytes[8],
+        image.bytes[9],
+        image.bytes[10],
+        image.bytes[11],
+    ]);
+    assert_eq!(word >> 20, 8);
+}
+
+#[test]
+fn test_resolution_pass_limit() {
+    use crate::emit::{EmitError, EmitOptions};
+    let rv = load_rv32i();
+    // the local label is only known once the whole file is read
+    let src = "start:\njal zero, .end - $\naddi a0, zero, 1\n.end:\n";
+    let limited = EmitOptions {
+        max_resolution_passes: 0,
+        ..Default::default()
+    };
+    match assemble_with(&rv, src, &limited) {
+        Err(EmitError::ResolutionDidNotConverge(syms)) => assert_eq!(syms, vec![".end"]),
+        r => panic!("expected a resolution error, got {:?}", r),
+    }
+    assert!(assemble_with(&rv, src, &Default::default()).is_ok());
+}
+
+#[test]
+fn test_forward_constant_references() {
+    use crate::emit::EmitError;
+    let rv = load_rv32i();
+    // the size of the buffer is only known once the table after it is
+    let src = ".word SIZE\n.space PAD\ntable:\n.word 1, 2, 3\nend:\n.equ SIZE, end - table\n.equ PAD, SIZE / 2\n";
+    let bytes = assemble_with(&rv, src, &Default::default()).unwrap();
+    assert_eq!(bytes.len(), 4 + 6 + 12);
+    assert_eq!(bytes[..4], [12, 0, 0, 0]);
+    assert_eq!(bytes[10..14], [1, 0, 0, 0]);
+
+    let src = ".rept COUNT\n.byte 7\n.endr\n.if COUNT > 1\n.byte 8\n.endif\n.set COUNT, 2\n";
+    assert_eq!(
+        assemble_with(&rv, src, &Default::default()).unwrap(),
+        vec![7, 7, 8]
+    );
+
+    // defining a constant with itself still needs an earlier definition
+    assert!(matches!(
+        assemble_with(&rv, ".set idx, idx + 1\n", &Default::default()),
+        Err(EmitError::InvalidArgumentType(_, 1))
+    ));
+}
+
+#[test]
+fn test_assemble_result() {
+    use crate::assemble::{assemble, LineMapEntry};
+    let rv = load_rv32i();
+    let src = "start:\n  jal zero, end - $\n\n  lla a0, start\nend: addi a0, a0, 1\n";
+    let result = assemble(&rv, src, &Default::default());
+    assert!(result.succeeded());
+    assert_eq!(result.bytes.len(), 16);
+    assert_eq!(result.symbols.get("end"), Some(&12));
+    // the deferred jump and both halves of the lla are attributed to their lines
+    let entry = |address, line| LineMapEntry {
+        address,
+        file: 0,
+        line,
+    };
+    assert_eq!(
+        result.line_map,
+        vec![entry(0, 2), entry(4, 4), entry(8, 4), entry(12, 5)]
+    );
+
+    let result = assemble(&rv, "addii a0, a0, 1\n", &Default::default());
+    assert!(!result.succeeded());
+    assert!(result.bytes.is_empty());
+}
+
+#[test]
+fn test_assembler_api() {
+    use crate::emit::Operand;
+    use crate::Assembler;
+    let rv = load_rv32i();
+    let assembler = Assembler::new(&rv).with_defines([("OFFSET", 8)]);
+    let artifact = assembler
+        .assemble_str(
+            "start: addi a0, a0, OFFSET
+  jal zero, start
+",
+        )
+        .unwrap();
+    assert_eq!(artifact.bytes().len(), 8);
+    assert_eq!(artifact.symbol("start"), Some(0));
+    let jal = artifact.instruction_at(4).unwrap();
+    assert_eq!(jal.mnemonic, "jal");
+    assert_eq!(jal.operands[1], Operand::Value(0));
+    assert!(artifact.instruction_at(2).is_none());
+    assert_eq!(artifact.line_map()[1].line, 2);
+    // the same setup assembles any number of sources
+    let errors = assembler
+        .assemble_str(
+            "addi a0, a0, 1
+addii a0, a0, 1
+",
+        )
+        .unwrap_err();
+    assert_eq!(errors.0.len(), 1);
+    assert!(errors.to_string().starts_with("2:1: error:"), "{}", errors);
+    assert!(assembler.assemble_file("/nonexistent.s").is_err());
+}
+
+#[test]
+fn test_session() {
+    use crate::session::Session;
+    use crate::Assembler;
+    let rv = load_rv32i();
+    let assembler = Assembler::new(&rv);
+    let same_as_anew = |session: &Session| {
+        let artifact = assembler.assemble_str(session.source()).unwrap();
+        let edited = session.artifact().unwrap();
+        assert_eq!(edited.bytes(), artifact.bytes());
+        assert_eq!(edited.symbols(), artifact.symbols());
+        assert_eq!(edited.line_map(), artifact.line_map());
+    };
+    let mut session = assembler.session("start: addi a0, a0, 1\n  jal zero, start ; back\n");
+    same_as_anew(&session);
+    // a line put in between moves the jump to the next line and address
+    let jump = session.source().find("  jal").unwrap();
+    session.edit(jump..jump, "loop: addi a1, a1, 2\n");
+    assert_eq!(session.reparsed_lines(), 2);
+    assert!(session.reassembled());
+    same_as_anew(&session);
+    assert_eq!(session.symbol_address("loop"), Some(4));
+    let at_line = session.instructions_at_line(3);
+    assert_eq!(at_line.len(), 1);
+    assert_eq!(
+        (at_line[0].address, at_line[0].mnemonic.as_str()),
+        (8, "jal")
+    );
+    let jump = session.source().find("start ;").unwrap();
+    let (symbol, range) = session.symbol_at(jump + 2).unwrap();
+    assert_eq!((symbol.as_str(), range), ("start", jump..jump + 5));
+    assert!(session.symbol_at(jump - 5).is_none());
+
+    // comments, blank lines and spacing only move the offsets
+    session.edit(0..0, "; header\n\n");
+    session.edit(
+        session.source().len() - 5..session.source().len() - 1,
+        "the start",
+    );
+    let comma = session.source().find(", a0").unwrap();
+    session.edit(comma..comma + 2, ",");
+    assert!(!session.reassembled());
+    assert_eq!(session.reparsed_lines(), 1);
+    same_as_anew(&session);
+    assert_eq!(session.instructions_at_line(5)[0].mnemonic, "jal");
+
+    // a `\` continues the line into the next one, parsing them together
+    let end = session.source().find(", 1\n").unwrap() + 3;
+    session.edit(end..end, " \\");
+    assert_eq!(session.reparsed_lines(), 1);
+    same_as_anew(&session);
+    let two = session.source().find("2\n").unwrap();
+    session.edit(two..two + 1, "\\");
+    assert!(session.reassembled());
+    assert!(session.artifact().is_err());
+    session.edit(two..two + 1, "2");
+    same_as_anew(&session);
+
+    // every line that doesn't parse is reported, at its line
+    session.set_source("addi a0, a0, (\nnop\naddi a1, a1, (\n");
+    let errors = session.artifact().unwrap_err();
+    assert_eq!(errors.0.len(), 2);
+    assert!(errors.to_string().contains("Parse error: 3:"), "{}", errors);
+    session.edit(14..14, "1)");
+    assert_eq!(session.diagnostics().len(), 1);
+    session.set_source("");
+    assert!(session.artifact().unwrap().bytes().is_empty());
+}
+
+#[test]
+fn test_operand_whitespace_and_trailing_comma() {
+    let rv = load_rv32i();
+    let expected = assemble_words(&rv, "addi a0, a0, 1\njal ra, 8\n");
+    for src in [
+        "addi\ta0,a0,1\njal ra,8\n",
+        "  addi  a0 ,\ta0 ,  1\t\njal\tra , 8 ; comment\n",
+        "addi a0, a0, 1,\njal ra, 8,\n",
+        "addi a0, a0, 1, ; comment\njal ra, 8 ,\n",
+        "addi a0, a0, 1\njal 8,\n",
+    ]
+    .iter()
+    {
+        assert_eq!(assemble_words(&rv, src), expected, "{:?}", src);
+    }
+    // only a single trailing comma is accepted
+    assert!(crate::parser::ast_from_str("addi a0, a0, 1,,\n", &rv).is_err());
+}
+
+#[test]
+fn test_exported_symbols() {
+    use crate::emit::flatbin::emit_flat_binary_with_symbols;
+    use crate::emit::EmitError;
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let src = "\
+.global main, end
+.globl helper
+.weak handler
+.local helper
+main:
+jal ra, helper - $
+helper:
+addi a0, a0, 1
+handler:
+end:
+jalr zero, ra
+";
+    let binary = emit_flat_binary_with_symbols(&rv, &ast_from_str(src, &rv).unwrap()).unwrap();
+    assert_eq!(binary.bytes.len(), 12);
+    assert_eq!(binary.entry, None);
+    assert_eq!(
+        binary.symbols,
+        vec![
+            ("main".to_owned(), 0),
+            ("end".to_owned(), 8),
+            ("handler".to_owned(), 8)
+        ]
+    );
+
+    let undefined = "nop\n.global start\n";
+    assert!(matches!(
+        emit_flat_binary_with_symbols(&rv, &ast_from_str(undefined, &rv).unwrap()),
+        Err(EmitError::UnresolvedSymbol(name, 4, _)) if name == "start"
+    ));
+}
+
+#[test]
+fn test_symbol_map() {
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::MapSymbolKind::*;
+    use crate::parser::ast_from_str;
+    use crate::report::{render_symbol_map, MapFormat};
+    let rv = load_rv32i();
+    let src = "\
+.equ SIZE, 0x20
+.set step, 1
+first:
+.loop:
+addi a0, a0, step
+beq a0, zero, .loop - $
+.set step, 4
+second:
+.loop:
+jal zero, .loop - $
+";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap();
+    let map: Vec<_> = image
+        .symbol_map()
+        .into_iter()
+        .map(|s| (s.name, s.value, s.kind))
+        .collect();
+    let expected = [
+        ("first", 0, Label),
+        (".loop", 0, LocalLabel),
+        ("step", 4, Constant),
+        ("second", 8, Label),
+        (".loop", 8, LocalLabel),
+        ("SIZE", 0x20, Constant),
+    ];
+    let expected: Vec<_> = expected
+        .iter()
+        .map(|(name, value, kind)| (name.to_string(), *value, *kind))
+        .collect();
+    assert_eq!(map, expected);
+    let symbols = image.symbol_map();
+    assert_eq!(
+        render_symbol_map(&symbols[..2], MapFormat::Text),
+        "0x00000000 first\n0x00000000 .loop\n"
+    );
+    assert_eq!(
+        render_symbol_map(&symbols[..2], MapFormat::Json),
+        "[\n  {\"name\": \"first\", \"value\": 0, \"kind\": \"label\"},\n  \
+         {\"name\": \".loop\", \"value\": 0, \"kind\": \"local\"}\n]\n"
+    );
+    // nm style, with the type letters of the sections and bindings
+    let src = "\
+.global main
+main: nop
+.weak hook
+hook: nop
+.rodata
+msg: .byte 1
+.bss
+.global buf
+buf: .space 4
+.equ LIMIT, 9
+";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap();
+    assert_eq!(
+        render_symbol_map(&image.symbol_map(), MapFormat::Nm),
+        "00000000\tT\tmain\n00000004\tW\thook\n00000008\tr\tmsg\n00000009\ta\tLIMIT\n\
+         00000010\tB\tbuf\n"
+    );
+}
+
+#[test]
+fn test_interned_symbols() {
+    use crate::emit::flatbin::emit_image;
+    use crate::intern::Symbol;
+    use crate::parser::{ast_from_str, Node};
+    let rv = load_rv32i();
+    let a = Symbol::intern("interned_name");
+    assert_eq!(a, Symbol::intern(&String::from("interned_name")));
+    assert_eq!(Symbol::lookup("interned_name"), Some(a.clone()));
+    assert_eq!(Symbol::from_id(a.id()), Some(a.clone()));
+    assert_eq!(a.as_str(), "interned_name");
+    assert_ne!(a, Symbol::intern("interned_other"));
+    // the text goes with the last symbol naming it
+    drop(Symbol::intern("interned_dropped"));
+    assert_eq!(Symbol::lookup("interned_dropped"), None);
+
+    let src = "\
+.equ SIZE, 0x20
+start:
+addi a0, a0, SIZE
+jal zero, start - $
+";
+    let ast = ast_from_str(src, &rv).unwrap();
+    let image = emit_image(&rv, &ast, &Default::default()).unwrap();
+    let symbols = image.interned_symbols();
+    // the nodes name the symbols with the same IDs the image gives their values by
+    let mut used = Vec::new();
+    ast.rewrite::<()>(&mut |node| {
+        if let Node::Identifier(sym) | Node::Label(sym) = &node {
+            used.push((sym.to_string(), symbols.get(sym).copied()));
+        }
+        Ok(node)
+    })
+    .unwrap();
+    assert!(used.contains(&("SIZE".to_owned(), Some(0x20))));
+    assert!(used.contains(&("start".to_owned(), Some(0))));
+}
+
+#[test]
+fn test_strip_local_symbols() {
+    use crate::emit::{flatbin::emit_image, EmitOptions, SymbolBinding};
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let src = "\
+.globl main
+.weak handler
+.type helper, @function
+main:
+jal ra, helper - $
+.loop:
+jal zero, .loop - $
+handler:
+helper:
+jalr zero, ra
+";
+    let ast = ast_from_str(src, &rv).unwrap();
+    let full = emit_image(&rv, &ast, &Default::default()).unwrap();
+    assert_eq!(full.symbols.len(), 3);
+    assert_eq!(full.symbol_binding("helper"), SymbolBinding::Local);
+    assert_eq!(full.symbol_binding("handler"), SymbolBinding::Weak);
+
+    let strip = EmitOptions {
+        strip_local: true,
+        ..Default::default()
+    };
+    let stripped = emit_image(&rv, &ast, &strip).unwrap();
+    let mut names: Vec<_> = stripped.symbols.keys().cloned().collect();
+    names.sort();
+    assert_eq!(names, vec!["handler", "main"]);
+    assert!(stripped.symbol_types.is_empty());
+    assert_eq!(stripped.bytes, full.bytes);
+}
+
+#[test]
+fn test_insn_directive() {
+    let rv = load_rv32i();
+    let expected = assemble_words(
+        &rv,
+        "add a0, a1, a2\naddi a0, a1, -1\nsw a0, 8, sp\nbeq a0, a1, 8\nlui a0, 0x12345000\njal ra, 16\n",
+    );
+    let insn = assemble_words(
+        &rv,
+        "\
+.insn r 0x33, 0, 0, a0, a1, a2
+.insn i 0x13, 0, a0, a1, -1
+.insn s 0x23, 2, a0, 8, sp
+.insn B 0x63, 0, a0, a1, 8
+.insn u 0x37, a0, 0x12345000
+.insn j 0x6f, ra, 16
+",
+    );
+    assert_eq!(insn, expected);
+    // forward references are deferred like for any other instruction
+    assert_eq!(
+        assemble_words(&rv, ".insn j 0x6f, zero, end - $\nend:\n"),
+        assemble_words(&rv, "jal zero, 4\n")
+    );
+
+    let opts = Default::default();
+    assert!(assemble_with(&rv, ".insn r 0x80, 0, 0, a0, a1, a2\n", &opts).is_err());
+    assert!(assemble_with(&rv, ".insn i 0x13, 0, a0, a1, 2048\n", &opts).is_err());
+    assert!(assemble_with(&rv, ".insn i 0x13, 0, a0, a1\n", &opts).is_err());
+    assert!(assemble_with(&rv, ".insn q 0x13, 0, a0, a1, 1\n", &opts).is_err());
+}
+
+#[test]
+fn test_local_label_scopes() {
+    use crate::emit::{EmitOptions, LocalLabelScope};
+    let rv = load_rv32i();
+    let with_scope = |local_labels| EmitOptions {
+        local_labels,
+        ..Default::default()
+    };
+    let reused = "f:\n.loop:\njal zero, .loop - $\ng:\n.loop:\njal zero, .loop - $\n";
+    let per_label = with_scope(LocalLabelScope::PerGlobalLabel);
+    assert!(assemble_with(&rv, reused, &Default::default()).is_ok());
+    assert!(assemble_with(&rv, reused, &per_label).is_ok());
+    let per_file = with_scope(LocalLabelScope::PerFile);
+    assert!(assemble_with(&rv, reused, &per_file).is_err());
+
+    let visible = "f:\n.done:\njal zero, 0\ng:\njal zero, .done - $\n";
+    assert!(assemble_with(&rv, visible, &per_label).is_err());
+    assert_eq!(
+        assemble_with(&rv, visible, &per_file).unwrap(),
+        assemble_with(&rv, "jal zero, 0\njal zero, -4\n", &per_file).unwrap()
+    );
+
+    // compiler-style .L labels neither are local nor end the scope of .1
+    let numeric = with_scope(LocalLabelScope::NumericOnly);
+    let src = "f:\n.1:\n.Lskip:\njal zero, .1 - $\ng:\njal zero, .Lskip - $\n";
+    assert!(assemble_with(&rv, src, &numeric).is_ok());
+    assert!(assemble_with(&rv, src, &per_label).is_err());
+    assert!(assemble_with(&rv, reused, &numeric).is_err());
+}
+
+#[test]
+fn test_numeric_labels() {
+    use crate::emit::{flatbin::emit_image, EmitError};
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let src = "\
+1: addi a0, a0, -1
+bne a0, zero, 1b - $
+jal zero, 1f - $
+f:
+1: jal zero, 1b - $
+.macro spin
+1: jal zero, 1b - $
+.endm
+spin
+spin
+.word 1b, 1f
+1:
+";
+    let expected = "\
+addi a0, a0, -1
+bne a0, zero, -4
+jal zero, 4
+jal zero, 0
+jal zero, 0
+jal zero, 0
+.word 0x14, 0x20
+";
+    assert_eq!(
+        assemble_with(&rv, src, &Default::default()).unwrap(),
+        assemble_with(&rv, expected, &Default::default()).unwrap()
+    );
+    // they stay out of the labels of the image, unlike in the symbol map
+    let ast = ast_from_str(src, &rv).unwrap();
+    let image = emit_image(&rv, &ast, &Default::default()).unwrap();
+    assert!(image.symbols.iter().all(|(name, _)| name == "f"));
+    assert_eq!(
+        image.symbol_map().iter().filter(|s| s.name == "1").count(),
+        5
+    );
+
+    for (src, name) in [
+        ("jal zero, 1b - $\n", "1b"),
+        ("2:\njal zero, 2f - $\n", "2f"),
+    ] {
+        assert!(matches!(
+            assemble_with(&rv, src, &Default::default()),
+            Err(EmitError::UnresolvedSymbol(n, _, _)) if n == name
+        ));
+    }
+}
+
+#[test]
+fn test_incremental_emitter() {
+    use crate::emit::flatbin::IncrementalEmitter;
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let mut emitter = IncrementalEmitter::new(&Default::default());
+    let mut line = |src: &str| {
+        let ast = ast_from_str(src, &rv).unwrap();
+        emitter.emit_line(&rv, &ast)
+    };
+    assert_eq!(line("start:").unwrap(), vec![]);
+    // waits for `end`, leaving a placeholder
+    assert_eq!(line("jal zero, end - $").unwrap(), vec![0; 4]);
+    assert_eq!(line(".equ ONE, 1").unwrap(), vec![]);
+    let addi = line("addi a0, zero, ONE").unwrap();
+    assert!(line("addii a0, zero, 1").is_err());
+    assert_eq!(line("end:").unwrap(), vec![]);
+    assert_eq!(emitter.pending(), 0);
+
+    let expected =
+        assemble_with(&rv, "jal zero, 8\naddi a0, zero, 1\n", &Default::default()).unwrap();
+    assert_eq!(addi, &expected[4..8]);
+    assert_eq!(emitter.bytes(), &expected[..]);
+    let image = emitter.finish(&rv).unwrap();
+    assert_eq!(image.symbols.get("end"), Some(&8));
+}
+
+#[test]
+fn test_zero_length_formats_are_rejected() {
+    use crate::arch::LoadError;
+    let load = |formats: &str| {
+        let mut rv = load_rv32i();
+        let cfg = format!(
+            "[meta]\nname = \"Broken\"\ncode = \"Xbroken\"\nspec = \"test\"\n[instruction_formats]\n{}",
+            formats
+        );
+        rv.load_single_cfg_string(&cfg)
+    };
+    match load("[instruction_formats.Empty]\n") {
+        Err(LoadError::BadInstructionFormat(f)) => assert_eq!(f, "instruction_formats.Empty"),
+        r => panic!("expected a format error, got {:?}", r),
+    }
+    let unencoded =
+        "[instruction_formats.Unencoded]\nimm = { type = \"value\", length = 4, encoding = [] }\n";
+    assert!(load(unencoded).is_err());
+    let reversed = "[instruction_formats.Reversed]\nimm = { type = \"value\", length = 4, encoding = [[0,3,0]] }\n";
+    assert!(matches!(load(reversed), Err(LoadError::BadType(_))));
+    let fine = "[instruction_formats.Byte]\nimm = { type = \"value\", length = 8, encoding = [[7,0,0]] }\n";
+    assert!(load(fine).is_ok());
+}
+
+#[test]
+fn test_conditional_assembly() {
+    use crate::emit::EmitError;
+    let rv = load_rv32i();
+    let variant = |x: i32| {
+        let src = format!(
+            "\
+.equ X, {}
+.if X > 0 && X != 2
+addi a0, zero, 1
+.elseif X == 2 || X == 3
+addi a0, zero, 2
+.elseif X < -5
+.if 1
+addi a0, zero, 3
+.endif
+.else
+.if 1
+addi a0, zero, 4
+.else
+addi a0, zero, 5
+.endif
+.endif
+",
+            x
+        );
+        assemble_words(&rv, &src)
+    };
+    let addi = |imm| assemble_words(&rv, &format!("addi a0, zero, {}\n", imm));
+    assert_eq!(variant(1), addi(1));
+    assert_eq!(variant(2), addi(2));
+    assert_eq!(variant(-6), addi(3));
+    assert_eq!(variant(0), addi(4));
+
+    // the other operand doesn't matter once the result is known
+    assert_eq!(
+        assemble_words(&rv, ".if 1 || UNDEFINED\naddi a0, zero, 1\n.endif\n"),
+        addi(1)
+    );
+    assert_eq!(
+        assemble_words(&rv, ".if 0 && UNDEFINED\naddi a0, zero, 1\n.endif\n"),
+        vec![]
+    );
+    // skipped labels aren't defined
+    assert_eq!(assemble_words(&rv, ".if 0\nf:\n.endif\nf:\n"), vec![]);
+    assert_eq!(
+        assemble_words(&rv, "addi a0, zero, (3 <= 3) + (1 >= 2) + (2 << 1 < 5)\n"),
+        addi(2)
+    );
+
+    // comparisons of a value with zero
+    let compare = |directive: &str, value: i32| {
+        let src = format!("{} {} - 1\naddi a0, zero, 1\n.endif\n", directive, value);
+        !assemble_words(&rv, &src).is_empty()
+    };
+    let outcomes: Vec<(bool, bool, bool)> = [".ifeq", ".ifne", ".ifgt", ".ifge", ".iflt", ".IFLE"]
+        .iter()
+        .map(|d| (compare(d, 0), compare(d, 1), compare(d, 2)))
+        .collect();
+    assert_eq!(
+        outcomes,
+        [
+            (false, true, false),
+            (true, false, true),
+            (false, false, true),
+            (false, true, true),
+            (true, false, false),
+            (true, true, false),
+        ]
+    );
+
+    let opts = Default::default();
+    assert!(assemble_with(&rv, ".if 1\n", &opts).is_err());
+    assert!(assemble_with(&rv, ".endif\n", &opts).is_err());
+    assert!(assemble_with(&rv, ".if 1\n.else\n.else\n.endif\n", &opts).is_err());
+    assert!(assemble_with(&rv, ".if 1\n.else\n.elseif 1\n.endif\n", &opts).is_err());
+    // labels further ahead take their place in the layout, unless no layout settles
+    assert!(assemble_with(&rv, ".if later\n.endif\nlater:\n", &opts).is_ok());
+    assert!(matches!(
+        assemble_with(&rv, ".if later == 0\nnop\n.endif\nlater:\n", &opts),
+        Err(EmitError::ResolutionDidNotConverge(syms)) if syms == ["later"]
+    ));
+    // each named once, however many uses of it are unsettled
+    let src = ".if b == 0\nnop\n.endif\n.if a == 0\nnop\n.endif\n.if b == 0\nnop\n.endif\na:\nb:\n";
+    assert!(matches!(
+        assemble_with(&rv, src, &opts),
+        Err(EmitError::ResolutionDidNotConverge(syms)) if syms == ["a", "b"]
+    ));
+}
+
+#[test]
+fn test_end_symbols() {
+    use crate::emit::{flatbin::emit_image, EmitOptions};
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let options = EmitOptions {
+        end_symbols: true,
+        ..Default::default()
+    };
+    let ast = ast_from_str("addi a0, zero, 1\n.data\naddi a0, zero, 2\n", &rv).unwrap();
+    let image = emit_image(&rv, &ast, &options).unwrap();
+    assert_eq!(image.symbols.get("_etext"), Some(&4));
+    assert_eq!(image.symbols.get("_edata"), Some(&12));
+    assert_eq!(image.symbols.get("_end"), Some(&12));
+    assert!(emit_image(&rv, &ast, &Default::default())
+        .unwrap()
+        .symbols
+        .is_empty());
+
+    // user definitions win
+    let ast = ast_from_str("_end:\naddi a0, zero, 1\n.equ _edata, 0x100\n", &rv).unwrap();
+    let image = emit_image(&rv, &ast, &options).unwrap();
+    assert_eq!(image.symbols.get("_end"), Some(&0));
+    assert_eq!(image.symbols.get("_edata"), None);
+    assert_eq!(image.symbols.get("_etext"), Some(&4));
+}
+
+#[test]
+fn test_instruction_alignment_from_spec() {
+    let with_align = |align: &str| {
+        let mut rv = load_rv32i();
+        let cfg = format!(
+            "[meta]\nname = \"Aligned loads\"\ncode = \"Xalign\"\nspec = \"test\"\n\
+             [instructions.lwa]\nformat = \"I\"\nargs = [\"rd\", \"imm\", \"rs1\"]\n\
+             fields = {{ opcode = 0b0000011, funct3 = 0b010 }}\nalign = {}\n",
+            align
+        );
+        rv.load_single_cfg_string(&cfg).map(|_| rv)
+    };
+    let rv = with_align("64").unwrap();
+    let lw = assemble_words(&rv, "lw a0, 0, sp\n")[0];
+    let add = assemble_words(&rv, "add a0, a0, a1\n")[0];
+    assert_eq!(
+        assemble_words(&rv, "add a0, a0, a1\nlwa a0, 0, sp\nlwa a0, 0, sp\n"),
+        vec![add, 0x0000_0013, lw, 0x0000_0013, lw]
+    );
+    // padding is zeroes in data mode
+    assert_eq!(
+        assemble_words(&rv, ".data\nadd a0, a0, a1\nlwa a0, 0, sp\n"),
+        vec![add, 0, lw]
+    );
+    // weaker than IALIGN changes nothing
+    let rv = with_align("16").unwrap();
+    assert_eq!(
+        assemble_words(&rv, "add a0, a0, a1\nlwa a0, 0, sp\n").len(),
+        2
+    );
+    assert!(with_align("12").is_err());
+    assert!(with_align("24").is_err());
+}
+
+#[test]
+fn test_name_suggestions() {
+    use crate::emit::EmitError;
+    let rv = load_rv32i();
+    let opts = Default::default();
+    let hint = |src: &str| match assemble_with(&rv, src, &opts).map_err(|e| e.into_kind()) {
+        Err(EmitError::InvalidInstruction(_, hint)) => hint,
+        Err(EmitError::UnresolvedSymbol(_, _, hint)) => hint,
+        r => panic!("{}: {:?}", src, r),
+    };
+    // mnemonics, pseudo-instructions and macros
+    assert_eq!(hint("adddi a0, a0, 1\n").as_deref(), Some("addi"));
+    assert_eq!(hint("BEQZZ a0, 0\n").as_deref(), Some("beqz"));
+    assert_eq!(
+        hint(".macro push_all\n.endm\npushall\n").as_deref(),
+        Some("push_all")
+    );
+    // labels, constants and registers
+    assert_eq!(
+        hint("j loopend - $\nloop_end:\n").as_deref(),
+        Some("loop_end")
+    );
+    assert_eq!(
+        hint(".equ COUNT, 3\nli a0, COUNTT\n").as_deref(),
+        Some("COUNT")
+    );
+    assert_eq!(hint("addi a0, zer, 1\n").as_deref(), Some("zero"));
+    assert_eq!(hint("frobnicate a0\n"), None);
+    assert_eq!(
+        EmitError::UnresolvedSymbol("prinf".to_owned(), 4, Some("printf".to_owned())).to_string(),
+        "'prinf' is never defined (used at address 0x4), did you mean 'printf'?"
+    );
+}
+
+#[test]
+fn test_unknown_directives() {
+    use crate::emit::{flatbin::emit_image, Diagnostic, EmitError, EmitOptions};
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let opts = Default::default();
+    match assemble_with(&rv, ".eqv X, 1\n", &opts) {
+        Err(EmitError::UnknownDirective(name, hint)) => {
+            assert_eq!(name, ".eqv");
+            assert_eq!(hint.as_deref(), Some(".equ"));
+        }
+        r => panic!("expected an unknown directive error, got {:?}", r),
+    }
+    match assemble_with(&rv, ".GLOBL2 main\n", &opts) {
+        Err(EmitError::UnknownDirective(_, hint)) => assert_eq!(hint.as_deref(), Some(".globl")),
+        r => panic!("expected an unknown directive error, got {:?}", r),
+    }
+    match assemble_with(&rv, ".cfi_startproc\n", &opts) {
+        Err(EmitError::UnknownDirective(_, hint)) => assert_eq!(hint, None),
+        r => panic!("expected an unknown directive error, got {:?}", r),
+    }
+    assert!(matches!(
+        assemble_with(&rv, "addii a0, a0, 1\n", &opts),
+        Err(EmitError::InvalidInstruction(..))
+    ));
+
+    let lenient = EmitOptions {
+        lenient_directives: true,
+        ..Default::default()
+    };
+    let ast = ast_from_str(".cfi_startproc\naddi a0, a0, 1\n", &rv).unwrap();
+    let image = emit_image(&rv, &ast, &lenient).unwrap();
+    assert_eq!(image.bytes.len(), 4);
+    assert!(matches!(&image.diagnostics[..], [Diagnostic::Warning(_)]));
+}
+
+#[test]
+fn test_report() {
+    use crate::emit::flatbin::emit_image;
+    use crate::parser::ast_from_str;
+    use crate::report::{render_report, ReportFormat};
+    let rv = load_rv32i();
+    let src = "start:\njal zero, end - $\nlla a0, start\nend:\n";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap();
+
+    let text = render_report(&rv, src, &image, ReportFormat::Text);
+    let lines: Vec<&str> = text.lines().collect();
+    let columns = |l: &str| -> Vec<String> {
+        let (left, source) = l.split_once(" |").unwrap();
+        let mut v: Vec<String> = left
+            .split("  ")
+            .map(|c| c.trim().to_owned())
+            .filter(|c| !c.is_empty())
+            .collect();
+        v.push(source.trim().to_owned());
+        v
+    };
+    assert_eq!(columns(lines[0]), vec!["1", "start:"]);
+    assert_eq!(
+        columns(lines[1]),
+        vec![
+            "2",
+            "00000000",
+            "00c0006f *",
+            "jal zero, 12",
+            "jal zero, end - $"
+        ]
+    );
+    assert_eq!(
+        columns(lines[2]),
+        vec!["3", "00000004", "00000517", "auipc a0, 0", "lla a0, start"]
+    );
+    assert_eq!(
+        columns(lines[3]),
+        vec!["00000008", "ffc50513", "addi a0, a0, -4", ""]
+    );
+    assert!(text.contains("\n* encoded once"));
+    assert!(text.ends_with("Symbols:\n00000000  start\n0000000c  end\n"));
+
+    let html = render_report(&rv, "addi a0, a0, 1 ; a < b\n", &image, ReportFormat::Html);
+    assert!(html.contains("<tr class=\"deferred\">"));
+    assert!(html.contains("a &lt; b"));
+}
+
+#[test]
+fn test_register_aliases() {
+    use crate::emit::EmitError;
+    let rv = load_rv32i();
+    assert_eq!(
+        assemble_words(
+            &rv,
+            ".equ TMP, t0\n.equ SCRATCH, TMP\naddi a0, TMP, 1\npush SCRATCH\n"
+        ),
+        assemble_words(&rv, "addi a0, t0, 1\npush t0\n")
+    );
+    assert!(matches!(
+        assemble_with(&rv, ".equ TMP, t0\naddi a0, a0, TMP\n", &Default::default()),
+        Err(EmitError::InvalidArgumentType(name, 2)) if name == "addi"
+    ));
+    assert!(matches!(
+        assemble_with(
+            &rv,
+            ".equ TMP, t0\naddi a0, a0, TMP + 1\n",
+            &Default::default()
+        ),
+        Err(EmitError::InvalidArgumentType(name, 2)) if name == "addi"
+    ));
+    assert!(matches!(
+        assemble_with(&rv, ".equ TMP, t0\n.equ TMP, 4\n", &Default::default()),
+        Err(EmitError::DuplicateConstant(name)) if name == "TMP"
+    ));
+}
+
+#[test]
+fn test_req_directive() {
+    use crate::emit::EmitError;
+    let mut rv = load_rv32i();
+    let src = "counter .req s3\nbase .REQ counter\n\
+               addi counter, counter, -1\nlw a0, 4(base)\nsw counter, 0(base)\n\
+               .unreq counter\ncounter:\n.word counter\n";
+    assert_eq!(
+        assemble_words(&rv, src),
+        assemble_words(
+            &rv,
+            "addi s3, s3, -1\nlw a0, 4(s3)\nsw s3, 0(s3)\n.word 12\n"
+        )
+    );
+    let error = |src: &str| assemble_with(&rv, src, &Default::default()).unwrap_err();
+    assert!(matches!(
+        error("counter .req s3\ncounter .req s4\n"),
+        EmitError::DuplicateConstant(name) if name == "counter"
+    ));
+    assert!(matches!(
+        error("counter .req missing\n"),
+        EmitError::InvalidArgumentType(name, 1) if name == ".req"
+    ));
+    assert!(matches!(
+        error(".unreq counter\n"),
+        EmitError::InvalidArgumentType(name, 0) if name == ".unreq"
+    ));
+    // the alias only lasts until .unreq
+    assert!(matches!(
+        error("counter .req s3\n.unreq counter\naddi counter, counter, 1\n"),
+        EmitError::UnresolvedSymbol(name, 0, _) if name == "counter"
+    ));
+
+    // specs can give registers more names
+    rv.load_single_cfg_string(
+        "[meta]\nname = \"Aliases\"\ncode = \"Xalias\"\nspec = \"none\"\n\
+         [registers.aliases]\nacc = \"s2\"\nlink = 1\n",
+    )
+    .unwrap();
+    assert_eq!(
+        assemble_words(&rv, "add acc, acc, a0\nlw a0, 0(link)\n"),
+        assemble_words(&rv, "add s2, s2, a0\nlw a0, 0(ra)\n")
+    );
+    assert_eq!(
+        rv.get_register_by_name("s2").unwrap().get_abi_name(),
+        Some("s2")
+    );
+}
+
+/// Small xorshift generator so the random trees are the same on every run
+struct TreeGen(u64);
+
+impl TreeGen {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.next() as usize % items.len()]
+    }
+
+    fn expression(&mut self, depth: u32) -> crate::parser::Node {
+        use crate::parser::Node::*;
+        let leaf = depth == 0 || self.next().is_multiple_of(3);
+        let b = |g: &mut Self| Box::new(g.expression(depth.saturating_sub(1)));
+        match self.next() % if leaf { 6 } else { 31 } {
+            0 => Identifier(
+                (*self.pick(&[
+                    "start",
+                    ".l",
+                    "TMP",
+                    "_end",
+                    "missing",
+                    "@function",
+                    "crc32",
+                ]))
+                .into(),
+            ),
+            1 => Integer(*self.pick(&[0, 1, 4, 12, 0x800, 0xfff, u64::MAX, 1 << 40, 64])),
+            2 => StringLiteral(self.pick(&["r", "I", "b", "", "x"]).as_bytes().to_vec()),
+            3 => Register(*self.pick(&[0, 1, 2, 10, 31, 32, -1])),
+            4 => RegisterSet(vec![1, 8, 9, 40]),
+            5 => PcValue,
+            6 => Negation(b(self)),
+            7 => Plus(b(self), b(self)),
+            8 => Minus(b(self), b(self)),
+            9 => Times(b(self), b(self)),
+            10 => Divide(b(self), b(self)),
+            11 => Shl(b(self), b(self)),
+            12 => Shr(b(self), b(self)),
+            13 => Ashr(b(self), b(self)),
+            14 => Equal(b(self), b(self)),
+            15 => Less(b(self), b(self)),
+            16 => LogicalAnd(b(self), b(self)),
+            17 => LogicalOr(b(self), b(self)),
+            18 => Label("start".into()),
+            19 => Argument(b(self)),
+            20 => Located(self.next() as usize % 100, b(self)),
+            21 => Hi(b(self)),
+            22 => Lo(b(self)),
+            23 => PcrelHi(b(self)),
+            24 => PcrelLo(b(self)),
+            25 => Remainder(b(self), b(self)),
+            26 => BitAnd(b(self), b(self)),
+            27 => BitOr(b(self), b(self)),
+            28 => BitXor(b(self), b(self)),
+            29 => BitNot(b(self)),
+            _ => Root(vec![self.expression(0)]),
+        }
+    }
+
+    fn element(&mut self, depth: u32) -> crate::parser::Node {
+        use crate::parser::Node::*;
+        const NAMES: &[&str] = &[
+            "addi",
+            "add",
+            "jal",
+            "beq",
+            "lui",
+            "lw",
+            "sw",
+            "ecall",
+            "la",
+            "lla",
+            "push",
+            "pop",
+            "call",
+            "bogus",
+            ".org",
+            ".equ",
+            ".text",
+            ".data",
+            ".reloc",
+            ".type",
+            ".size",
+            ".global",
+            ".local",
+            ".weak",
+            ".if",
+            ".ifeq",
+            ".elseif",
+            ".else",
+            ".endif",
+            ".insn",
+            ".checksum",
+            ".rvtable",
+            ".word",
+            ".byte",
+            ".asciz",
+            ".bogus",
+        ];
+        match self.next() % 8 {
+            0 => Label((*self.pick(&["start", ".l", "_end", "end"])).into()),
+            1 => Located(self.next() as usize % 100, Box::new(self.element(depth))),
+            2 if depth > 0 => Root(
+                (0..self.next() % 3)
+                    .map(|_| self.element(depth - 1))
+                    .collect(),
+            ),
+            3 => self.expression(2),
+            _ => {
+                let name = (*self.pick(NAMES)).into();
+                let args = (0..self.next() % 5)
+                    .map(|_| {
+                        let arg = self.expression(2);
+                        if self.next().is_multiple_of(4) {
+                            arg
+                        } else {
+                            Argument(Box::new(arg))
+                        }
+                    })
+                    .collect();
+                Instruction(name, args)
+            }
+        }
+    }
+}
+
+#[test]
+fn test_emitter_never_panics() {
+    use crate::emit::flatbin::{emit_image, IncrementalEmitter};
+    use crate::emit::EmitOptions;
+    use crate::parser::Node;
+    let rv = load_rv32i();
+    let mut gen = TreeGen(0x9e37_79b9_7f4a_7c15);
+    for i in 0..3000 {
+        let elements: Vec<Node> = (0..1 + gen.next() % 12).map(|_| gen.element(2)).collect();
+        let options = EmitOptions {
+            pic: i % 2 == 0,
+            end_symbols: i % 3 == 0,
+            lenient_directives: i % 5 == 0,
+            max_resolution_passes: 4,
+            max_image_size: 1 << 16,
+            ..EmitOptions::default()
+        };
+        let _ = emit_image(&rv, &Node::Root(elements.clone()), &options);
+        let _ = emit_image(&rv, &gen.element(2), &options);
+        let mut incremental = IncrementalEmitter::new(&options);
+        for element in elements.iter() {
+            let _ = incremental.emit_line(&rv, element);
+        }
+        let _ = incremental.finish(&rv);
+    }
+}
+
+#[test]
+fn test_parser_never_panics() {
+    use crate::assemble::assemble;
+    use crate::emit::EmitOptions;
+    const PIECES: &[&str] = &[
+        "addi",
+        "a0",
+        "zero",
+        ",",
+        " ",
+        "\n",
+        "(",
+        ")",
+        "-",
+        "~",
+        "1",
+        "0x",
+        "ffffffffffffffffffff",
+        "99999999999999999999",
+        "'",
+        "\\x4",
+        "\"",
+        ".word",
+        ".float",
+        "1e999",
+        ".if",
+        ".endif",
+        ".macro",
+        ".endm",
+        "\\",
+        "@",
+        "1b",
+        "1:",
+        "end",
+        ":",
+        "%hi",
+        "{",
+        "}",
+        "$",
+        ".",
+        ";",
+        "<<",
+    ];
+    let rv = load_rv32i();
+    let mut gen = TreeGen(0x2545_f491_4f6c_dd1d);
+    let options = EmitOptions {
+        max_errors: 8,
+        max_resolution_passes: 4,
+        max_image_size: 1 << 16,
+        ..EmitOptions::default()
+    };
+    for _ in 0..3000 {
+        let source: String = (0..1 + gen.next() % 24)
+            .map(|_| *gen.pick(PIECES))
+            .collect();
+        let _ = assemble(&rv, &source, &options);
+    }
+    // too large for 64 bits
+    for literal in ["0x10000000000000000", "18446744073709551616", "0x1g"] {
+        let result = assemble(&rv, &format!("addi a0, a0, {}\n", literal), &options);
+        assert!(
+            format!("{:?}", result.diagnostics).contains("Parse error"),
+            "{}",
+            literal
+        );
+    }
+    assert_eq!(
+        assemble_words(&rv, "addi a0, a0, 0xffff_ffff_ffff_f800\n"),
+        assemble_words(&rv, "addi a0, a0, -2048\n")
+    );
+}
+
+#[test]
+fn test_assemble_files() {
+    use crate::assemble::{assemble_files, LineMapEntry};
+    use crate::emit::{EmitOptions, LocalLabelScope};
+    let rv = load_rv32i();
+    let dir = std::env::temp_dir().join(format!("rvasm-test-files-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, src: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, src).unwrap();
+        path
+    };
+    let a = write("a.s", "start:\njal zero, other\n.l:\nbeq x0, x0, .l\n");
+    let b = write("b.s", ".l:\nother:\nbeq x0, x0, .l\n");
+    let dup = write("dup.s", "addi a0, a0, 1\nstart:\n");
+
+    let options = EmitOptions {
+        local_labels: LocalLabelScope::PerFile,
+        ..EmitOptions::default()
+    };
+    let result = assemble_files(&rv, &[a.clone(), b.clone()], &options);
+    assert!(result.succeeded(), "{:?}", result.diagnostics);
+    assert_eq!(
+        result.bytes,
+        assemble_with(
+            &rv,
+            "start:\njal zero, other\n.l:\nbeq x0, x0, .l\n.m:\nother:\nbeq x0, x0, .m\n",
+            &options
+        )
+        .unwrap()
+    );
+    assert_eq!(result.symbols.get("other"), Some(&8));
+    assert_eq!(
+        result.line_map[2],
+        LineMapEntry {
+            address: 8,
+            file: 1,
+            line: 3,
+        }
+    );
+
+    let result = assemble_files(&rv, &[a.clone(), dup.clone()], &options);
+    let message = format!("{:?}", result.diagnostics);
+    assert!(!result.succeeded());
+    assert!(message.contains("label 'start' defined in"));
+    assert!(message.contains("a.s") && message.contains("dup.s:"));
+
+    let result = assemble_files(&rv, &[a, dir.join("missing.s")], &options);
+    assert!(format!("{:?}", result.diagnostics).contains("missing.s"));
+
+    // parsing on several threads gives what parsing one file after the other does
+    write("inc.s", "addi a1, a1, 1\n");
+    let mut units: Vec<_> = (0..24)
+        .map(|i| {
+            let src = format!("f{}:\n.include \"inc.s\"\njal zero, f{}\n", i, (i + 1) % 24);
+            write(&format!("unit{}.s", i), &src)
+        })
+        .collect();
+    let jobs = |jobs| EmitOptions {
+        jobs,
+        ..options.clone()
+    };
+    let one = assemble_files(&rv, &units, &jobs(1));
+    let several = assemble_files(&rv, &units, &jobs(4));
+    assert!(several.succeeded(), "{:?}", several.diagnostics);
+    assert_eq!(several.bytes, one.bytes);
+    assert_eq!(several.symbols, one.symbols);
+    assert_eq!(several.line_map, one.line_map);
+    units.insert(20, write("bad.s", "addi a0, a0, 1\naddii a0, a0, 1\n"));
+    let one = assemble_files(&rv, &units, &jobs(1));
+    let several = assemble_files(&rv, &units, &jobs(4));
+    assert_eq!(
+        format!("{:?}", several.diagnostics),
+        format!("{:?}", one.diagnostics)
+    );
+    assert!(format!("{:?}", several.diagnostics).contains("bad.s:2"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_memory_layout() {
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::layout::MemoryLayout;
+    use crate::emit::{EmitError, EmitOptions};
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let layout = MemoryLayout::from_toml(
+        "[memory]\nram = { origin = 0x2000_0000, length = \"1K\" }\n\
+         flash = { origin = 0x0800_0000, length = 16 }\n\
+         [sections]\n\".text\" = \"flash\"\n\".data\" = \"ram\"\n",
+    )
+    .unwrap();
+    assert_eq!(layout.regions[0].name, "flash");
+    assert_eq!(layout.regions[1].end(), 0x2000_0400);
+    assert_eq!(layout.region_of(".rodata").unwrap().name, "ram");
+    assert_eq!(layout.region_of(".text.startup").unwrap().name, "flash");
+    assert!(MemoryLayout::from_toml("[memory]\n[sections]\n\".data\" = \"ram\"\n").is_err());
+
+    let options = EmitOptions {
+        layout: Some(layout.clone()),
+        ..EmitOptions::default()
+    };
+    let src = "start:\nla t0, value\njal zero, start - $\n.data\nvalue:\n.word 0x11223344\n";
+    let ast = ast_from_str(src, &rv).unwrap();
+    let image = emit_image(&rv, &ast, &options).unwrap();
+    assert_eq!(image.symbols.get("start"), Some(&0x0800_0000));
+    assert_eq!(image.symbols.get("value"), Some(&0x2000_0000));
+    assert_eq!(image.instructions[2].address, 0x0800_0008);
+    assert_eq!(
+        image.written_blocks()[1],
+        (0x2000_0000, &[0x44, 0x33, 0x22, 0x11][..])
+    );
+    let flash = layout.region_bytes(&image, &layout.regions[0]);
+    assert_eq!(flash.len(), 12);
+    assert_eq!(flash, image.bytes[..12]);
+    assert_eq!(
+        layout.region_bytes(&image, &layout.regions[1]),
+        vec![0x44, 0x33, 0x22, 0x11]
+    );
+    let hex = crate::emit::ihex::to_ihex(&image).unwrap();
+    assert!(hex.contains(":020000040800F2\n") && hex.contains(":020000042000DA\n"));
+
+    // 20 bytes of code don't fit in the 16 of flash
+    let ast = ast_from_str("nop\nnop\nnop\nnop\nnop\n", &rv).unwrap();
+    match emit_image(&rv, &ast, &options).map_err(EmitError::into_kind) {
+        Err(EmitError::RegionOverflow(section, region, 0x0800_0000, 0x0800_0014)) => {
+            assert_eq!((section.as_str(), region.as_str()), (".text", "flash"))
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn test_file_private_labels() {
+    use crate::assemble::assemble_files;
+    use crate::emit::elf::{Elf, ElfKind};
+    use crate::emit::{flatbin::emit_image_files, EmitOptions, OutputBackend, SymbolBinding};
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let dir = std::env::temp_dir().join(format!("rvasm-test-private-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, src: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, src).unwrap();
+        path
+    };
+    // each file has its own helper, only main and the helper of b are exported
+    let (a_src, b_src) = (
+        ".globl main\nmain:\ncall helper\nhelper:\nret\n",
+        ".global helper\nhelper:\ncall main\n",
+    );
+    let (a, b) = (write("a.s", a_src), write("b.s", b_src));
+    let c = write("c.s", "jal ra, other\n");
+    let d = write("d.s", "other:\nret\n");
+    let options = EmitOptions {
+        file_private_labels: true,
+        ..EmitOptions::default()
+    };
+
+    let result = assemble_files(&rv, &[a.clone(), b.clone()], &options);
+    assert!(result.succeeded(), "{:?}", result.diagnostics);
+    assert_eq!(
+        result.bytes,
+        assemble_with(
+            &rv,
+            "main:\ncall helper_a\nhelper_a:\nret\nhelper:\ncall main\n",
+            &Default::default()
+        )
+        .unwrap()
+    );
+    assert_eq!(result.symbols.get("helper"), Some(&12));
+    assert_eq!(result.symbols.len(), 2, "{:?}", result.symbols);
+
+    // the private helper is written out by its own name, as a local symbol
+    let parsed = |name: &str, src: &str| (name.to_owned(), ast_from_str(src, &rv).unwrap());
+    let files = [parsed("a.s", a_src), parsed("b.s", b_src)];
+    let image = emit_image_files(&rv, &files, &options).unwrap();
+    let helpers: Vec<_> = image
+        .symbol_map()
+        .into_iter()
+        .filter(|s| s.name == "helper")
+        .map(|s| (s.value, s.binding))
+        .collect();
+    assert_eq!(
+        helpers,
+        vec![(8, SymbolBinding::Local), (12, SymbolBinding::Global)]
+    );
+    let elf = Elf::for_spec(&rv, ElfKind::Relocatable)
+        .serialize(&image)
+        .unwrap();
+    assert!(elf.windows(8).any(|w| w == b"\0helper\0"));
+    assert!(!elf.windows(7).any(|w| w == b"helper@"));
+
+    // a label nobody exports stays in its file
+    let result = assemble_files(&rv, &[c.clone(), d.clone()], &options);
+    assert!(!result.succeeded());
+    assert!(format!("{:?}", result.diagnostics).contains("'other' is never defined"));
+    let result = assemble_files(&rv, &[c, d], &Default::default());
+    assert!(result.succeeded(), "{:?}", result.diagnostics);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_emit_files_linked() {
+    use crate::arch::RiscVSpec;
+    use crate::emit::flatbin::{emit_image, emit_image_files};
+    use crate::emit::EmitOptions;
+    use crate::parser::ast_from_str;
+    // emitted each on its own and linked, or one after the other, it's one source's image
+    let a = "start:\ncall f\nla a0, data\nj start\nbeq a0, a1, f - .\n.word data, f + 3\n\
+             lui a0, %hi(data)\naddi a0, a0, %lo(data)\n.equ N, 3\n.if N > 2\nli a0, N\n.endif\n";
+    let b = ".global f\nf:\nret\n.align 4\ndata:\n.word start, data\njal ra, start - .\n\
+             la a0, start\ntail start\n.half data - f\n";
+    let c = ".byte 1\n.p2align 3\nx:\nj f\n.word . - x\nlw a0, %lo(start)(a0)\n";
+    let uses_constant = "addi a0, a0, N\n";
+    let compressed = "c.addi a0, 1\nj start\n";
+    for (arch, files) in [
+        ("rv32i", vec![a, b]),
+        ("rv32i", vec![b, a, c]),
+        ("rv64i", vec![c, a, b, c.replace("x", "y").as_str()]),
+        ("rv32i", vec![a, uses_constant, b]),
+        ("rv32ic", vec![a, b, compressed]),
+    ] {
+        let mut rv = RiscVSpec::new();
+        rv.load_arch_builtin(arch).unwrap();
+        let parsed: Vec<_> = (files.iter().enumerate())
+            .map(|(i, src)| (format!("{}.s", i), ast_from_str(src, &rv).unwrap()))
+            .collect();
+        let options = EmitOptions {
+            jobs: 4,
+            ..EmitOptions::default()
+        };
+        let linked = emit_image_files(&rv, &parsed, &options).unwrap();
+        let whole = ast_from_str(&files.concat(), &rv).unwrap();
+        let image = emit_image(&rv, &whole, &options).unwrap();
+        assert_eq!(linked.bytes, image.bytes, "{} {:?}", arch, files);
+        assert_eq!(linked.symbols, image.symbols);
+        assert_eq!(linked.written, image.written);
+        let listed = |image: &crate::emit::AssembledImage| -> Vec<String> {
+            (image.instructions.iter())
+                .map(|i| format!("{:x} {:?} {:?}", i.address, i.operands, i.deferred))
+                .collect()
+        };
+        assert_eq!(listed(&linked), listed(&image));
+    }
+}
+
+#[test]
+fn test_defsyms() {
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::{Diagnostic, EmitError, EmitOptions};
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let mut options = EmitOptions {
+        defsyms: vec![("STACK".to_owned(), 0x100)],
+        ..EmitOptions::default()
+    };
+    assert_eq!(
+        assemble_with(&rv, "addi sp, zero, STACK\n", &options).unwrap(),
+        assemble_with(&rv, "addi sp, zero, 0x100\n", &Default::default()).unwrap()
+    );
+    // redefinitions are reported instead of one of the definitions silently winning
+    assert!(matches!(
+        assemble_with(&rv, ".equ STACK, 0x200\n", &options),
+        Err(EmitError::DuplicateConstant(name)) if name == "STACK"
+    ));
+    assert!(matches!(
+        assemble_with(&rv, "STACK:\n", &options),
+        Err(EmitError::DuplicateLabel(name)) if name == "STACK"
+    ));
+    assert!(matches!(
+        assemble_with(&rv, "top:\n.equ top, 4\n", &Default::default()),
+        Err(EmitError::DuplicateConstant(name)) if name == "top"
+    ));
+
+    options.defsyms_override = true;
+    let src = ".equ STACK, 0x200\naddi sp, zero, STACK\n";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &options).unwrap();
+    assert_eq!(
+        image.bytes,
+        assemble_with(&rv, "addi sp, zero, 0x100\n", &Default::default()).unwrap()
+    );
+    assert!(matches!(&image.diagnostics[..], [Diagnostic::Warning(_)]));
+}
+
+#[test]
+fn test_checksum_directive() {
+    use crate::emit::checksum::ChecksumAlgorithm;
+    use crate::emit::EmitError;
+    assert_eq!(ChecksumAlgorithm::Crc32.compute(b"123456789"), 0xcbf4_3926);
+    assert_eq!(
+        ChecksumAlgorithm::Sum16.compute(&[0xff; 300]),
+        (300 * 0xff) & 0xffff
+    );
+
+    let rv = load_rv32i();
+    let body = "start:\naddi a0, a0, 1\naddi a1, a1, -1\nend:\n";
+    let code = assemble_with(&rv, body, &Default::default()).unwrap();
+    // the checksum can come before the range and refer to labels further ahead
+    let bin = assemble_with(
+        &rv,
+        &format!(
+            ".checksum start, end, crc32\n.checksum 0, 4, sum32\n{}",
+            body
+        ),
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(&bin[8..], &code[..]);
+    let crc = ChecksumAlgorithm::Crc32.compute(&code) as u32;
+    assert_eq!(&bin[..4], &crc.to_le_bytes());
+    let sum = ChecksumAlgorithm::Sum32.compute(&bin[..4]) as u32;
+    assert_eq!(&bin[4..8], &sum.to_le_bytes());
+
+    assert!(matches!(
+        assemble_with(&rv, ".checksum 0, 0x100, sum32\n", &Default::default()),
+        Err(EmitError::InvalidArgumentType(_, 1))
+    ));
+    assert!(matches!(
+        assemble_with(&rv, ".checksum 0, 4, md5\n", &Default::default()),
+        Err(EmitError::InvalidArgumentType(_, 2))
+    ));
+}
+
+#[test]
+fn test_assemble_instruction() {
+    use crate::assemble::{assemble_instruction, AsmError};
+    let rv = load_rv32i();
+    assert_eq!(
+        assemble_instruction(&rv, "addi a0, a1, 4").unwrap(),
+        assemble_with(&rv, "addi a0, a1, 4\n", &Default::default()).unwrap()
+    );
+    assert_eq!(assemble_instruction(&rv, "  lla a0, 8  ").unwrap().len(), 8);
+    assert_eq!(
+        assemble_instruction(&rv, ".insn i 0x13, 0, a0, a1, 4").unwrap(),
+        assemble_instruction(&rv, "addi a0, a1, 4").unwrap()
+    );
+    assert!(matches!(
+        assemble_instruction(&rv, "addi a0, a1, 4\naddi a0, a1, 4"),
+        Err(AsmError::NotOneInstruction(2))
+    ));
+    assert!(matches!(
+        assemble_instruction(&rv, "start:"),
+        Err(AsmError::NotOneInstruction(1))
+    ));
+    assert!(matches!(
+        assemble_instruction(&rv, ".org 4"),
+        Err(AsmError::NotOneInstruction(1))
+    ));
+    assert!(matches!(
+        assemble_instruction(&rv, "jal zero, end"),
+        Err(AsmError::UnknownSymbol(name)) if name == "end"
+    ));
+    assert!(matches!(
+        assemble_instruction(&rv, "addi a0, a1, 4096"),
+        Err(AsmError::Emit(_))
+    ));
+    assert!(matches!(
+        assemble_instruction(&rv, "addi a0,, a1"),
+        Err(AsmError::Parse(_))
+    ));
+}
+
+#[test]
+fn test_backward_org_patches_header() {
+    let rv = load_rv32i();
+    // the header at 0 is written after the body whose size it depends on
+    let src = "\
+.org 0x10
+main:
+addi a0, a0, -1
+addi a1, a1, 1
+end:
+.org 0
+jal zero, main
+.checksum main, end, sum32
+.checksum 8, 12, sum32
+";
+    let bin = assemble_with(&rv, src, &Default::default()).unwrap();
+    let body = assemble_with(
+        &rv,
+        "addi a0, a0, -1\naddi a1, a1, 1\n",
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(bin.len(), 0x18);
+    assert_eq!(&bin[0x10..], &body[..]);
+    assert_eq!(
+        &bin[..4],
+        &assemble_words(&rv, "jal zero, 16")[0].to_le_bytes()
+    );
+    let sum: u32 = body.iter().map(|b| *b as u32).sum();
+    assert_eq!(&bin[4..8], &sum.to_le_bytes());
+    // a checksum over its own space sees zeroes
+    assert_eq!(&bin[8..12], &[0; 4]);
+
+    // overwritten instructions don't keep any of their old bits
+    let overwrite = crate::emit::EmitOptions {
+        allow_overwrite: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        assemble_with(
+            &rv,
+            "addi a0, a0, -1\nadd a1, a1, a1\n.org 0\naddi zero, zero, 0\n",
+            &overwrite
+        )
+        .unwrap(),
+        assemble_with(
+            &rv,
+            "addi zero, zero, 0\nadd a1, a1, a1\n",
+            &Default::default()
+        )
+        .unwrap()
+    );
+    assert_eq!(
+        assemble_with(
+            &rv,
+            "addi a0, a0, -1\n.org 0\n.checksum 0, 4, sum32\n",
+            &overwrite
+        )
+        .unwrap(),
+        vec![0; 4]
+    );
+}
+
+#[test]
+fn test_backward_org_overlap() {
+    use crate::emit::EmitError;
+    let rv = load_rv32i();
+    let strict = Default::default();
+    // filling the gap left by a forward .org is fine, even right up to the code after it
+    let bin = assemble_with(&rv, ".org 8\n.word 2\n.org 0\n.word 0, 1\n", &strict).unwrap();
+    assert_eq!(bin, [0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+    // reaching into it isn't, the error gives the bytes that would be overwritten
+    assert!(matches!(
+        assemble_with(&rv, ".org 8\n.word 2\n.org 4\n.word 0, 1\n", &strict),
+        Err(EmitError::OrgOverlap(8, 12))
+    ));
+    assert!(matches!(
+        assemble_with(&rv, "addi a0, a0, 1\nnop\n.org 2\n.byte 1\n", &strict),
+        Err(EmitError::OrgOverlap(2, 3))
+    ));
+    // an instruction waiting for a label defined after the .org goes into its own space
+    let bin = assemble_with(&rv, ".org 4\njal zero, end\n.org 0\nnop\nend:\n", &strict).unwrap();
+    assert_eq!(
+        &bin[4..],
+        &assemble_words(&rv, "jal zero, 4")[0].to_le_bytes()
+    );
+}
+
+#[test]
+fn test_extension_gating() {
+    use crate::emit::EmitError;
+    let mut rv = load_rv32i();
+    assert!(rv.extension_enabled("I") && !rv.extension_enabled("M"));
+    assert!(matches!(
+        assemble_with(&rv, "mul a0, a1, a2\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(insn, ext)) if insn == "mul" && ext == "M"
+    ));
+    assert!(matches!(
+        assemble_with(&rv, "csrrw a0, 0x300, a1\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(_, ext)) if ext == "Zicsr"
+    ));
+    assert!(matches!(
+        assemble_with(&rv, "frobnicate a0\n", &Default::default()),
+        Err(EmitError::InvalidInstruction(..))
+    ));
+
+    // instructions tagged in the spec need their extension too
+    rv.load_single_cfg_string(
+        r#"
+[meta]
+name = "Custom instructions"
+code = "Xcustom"
+spec = "none"
+[instructions.cmul]
+format = "R"
+args = ["rd", "rs1", "rs2"]
+fields = { opcode = 0b0001011, funct3 = 0, funct7 = 1 }
+extension = "M"
+"#,
+    )
+    .unwrap();
+    assert!(rv.extension_enabled("Xcustom"));
+    assert!(matches!(
+        assemble_with(&rv, "cmul a0, a1, a2\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(insn, ext)) if insn == "cmul" && ext == "M"
+    ));
+}
+
+#[test]
+fn test_register_subset_fields() {
+    use crate::assemble::{assemble_instruction, AsmError};
+    use crate::emit::EmitError;
+    let mut rv = load_rv32i();
+    // c.addi4spn rd', nzuimm: only x8-x15 fit in the 3 bit register field
+    rv.load_single_cfg_string(
+        r#"
+[meta]
+name = "Compressed subset"
+code = "C"
+spec = "none"
+[instruction_formats.CIW]
+op = { type = "value", length = 2, encoding = [[1,0,0]] }
+funct3 = { type = "value", length = 3, encoding = [[2,0,13]] }
+rd = { type = "register", length = 3, registers = ["s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5"], encoding = [[2,0,2]] }
+imm = { type = "value", length = 10, encoding = [[5,4,11], [9,6,7], [2,2,6], [3,3,5]] }
+[instructions."c.addi4spn"]
+format = "CIW"
+args = ["rd", "imm"]
+fields = { op = 0, funct3 = 0 }
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        assemble_instruction(&rv, "c.addi4spn a0, 16").unwrap(),
+        0x0808u16.to_le_bytes()
+    );
+    assert!(matches!(
+        assemble_instruction(&rv, "c.addi4spn t0, 16"),
+        Err(AsmError::Emit(EmitError::RegisterNotAllowed(insn, 0))) if insn == "c.addi4spn"
+    ));
+}
+
+#[test]
+fn test_load_yaml_spec() {
+    use crate::arch::LoadError;
+    use crate::assemble::assemble_instruction;
+    let mut rv = load_rv32i();
+    rv.load_extension_yaml_string(
+        r#"
+meta:
+  name: My core   # comments go anywhere
+  code: Xmycore
+  spec: "none"
+consts:
+  CUSTOM0: 0b000_1011
+csrs:
+  mycsr: 0x7c0
+instructions:
+  "mycore.foo":
+    format: R
+    args: [rd, rs1, rs2]
+    fields: { opcode: CUSTOM0, funct3: 0, funct7: 0 }
+  mycore.bar:
+    format: I
+    args:
+      - rd
+      - rs1
+    fields: {opcode: CUSTOM0, funct3: 1, imm: -1}
+"#,
+        false,
+    )
+    .unwrap();
+    assert_eq!(
+        assemble_instruction(&rv, "mycore.foo a0, a1, a2").unwrap(),
+        0x00c5850bu32.to_le_bytes()
+    );
+    assert_eq!(
+        assemble_instruction(&rv, "mycore.bar a0, a1").unwrap(),
+        0xfff5950bu32.to_le_bytes()
+    );
+    assert_eq!(rv.get_csr("mycsr"), Some(0x7c0));
+    assert!(matches!(
+        rv.load_extension_yaml_string("meta:\n  name: x\n code: y\n", false),
+        Err(LoadError::MalformedYAML(3))
+    ));
+
+    // fields that don't add up are rejected with what's wrong with them
+    let load = |format: &str, fields: &str| {
+        let mut rv = load_rv32i();
+        let cfg = format!(
+            "[meta]\nname = \"Broken\"\ncode = \"Xbroken\"\nspec = \"test\"\n\
+             [instruction_formats.Custom]\n{}\n[instructions.\"broken.op\"]\nformat = \"Custom\"\nargs = []\nfields = {{ {} }}\n",
+            format, fields
+        );
+        rv.load_single_cfg_string(&cfg).map_err(|e| e.to_string())
+    };
+    let op = "op = { type = \"value\", length = 7, encoding = [[6,0,0]] }\n";
+    let with_imm = |imm: &str| format!("{}imm = {{ type = \"value\", {} }}", op, imm);
+    assert_eq!(
+        load(&with_imm("length = 4, encoding = [[4,0,7]]"), "op = 1"),
+        Err("'instruction_formats.Custom.imm' encodes bit 4 of a 4-bit value".to_owned())
+    );
+    // the fields are checked in the order of their names
+    assert_eq!(
+        load(&with_imm("length = 8, encoding = [[7,0,6]]"), "op = 1"),
+        Err("'instruction_formats.Custom.op' overlaps field 'imm' at instruction bit 6".to_owned())
+    );
+    assert_eq!(
+        load(op, "op = 0x80"),
+        Err("'instructions.broken.op.fields[op]' value 128 doesn't fit in 7 bits".to_owned())
+    );
+    assert!(load(op, "op = 0x7f").is_ok());
+    // bits past ILEN, before allocating anything for them
+    assert_eq!(
+        load(
+            &with_imm("length = 7, encoding = [[6,0,2000000000]]"),
+            "op = 1"
+        ),
+        Err(
+            "'instruction_formats.Custom.imm' encodes bit 2000000006 of a 32-bit instruction"
+                .to_owned()
+        )
+    );
+    assert_eq!(
+        load(&with_imm("length = 4, encoding = [[3,0,40]]"), "op = 1"),
+        Err("'instruction_formats.Custom.imm' encodes bit 43 of a 32-bit instruction".to_owned())
+    );
+    assert!(load(
+        &with_imm("length = 4, encoding = [[3,0,9223372036854775807]]"),
+        "op = 1"
+    )
+    .is_err());
+}
+
+#[test]
+fn test_spec_pseudo_instructions() {
+    use crate::arch::LoadError;
+    use crate::emit::EmitError;
+    let ext = |body: &str| {
+        format!(
+            "[meta]\nname = \"My accelerator\"\ncode = \"Xacc\"\nspec = \"none\"\n{}",
+            body
+        )
+    };
+    let mut rv = load_rv32i();
+    rv.load_single_cfg_string(&ext(r#"
+[pseudo_instructions.bitset]
+args = ["rd", "imm"]
+expansion = ["ori rd, rd, imm"]
+
+[pseudo_instructions.swap]
+args = ["a", "b"]
+expansion = ["xor a, a, b", "xor b, a, b", "xor a, a, b"]
+
+[pseudo_instructions."acc.load"]
+args = ["rd", "sym"]
+expansion = ["lui rd, %hi(sym)", "lw rd, %lo(sym)(rd)"]
+"#))
+        .unwrap();
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "bitset a0, 0x10
+swap t0, t1
+acc.load a1, 0x12345
+"
+        ),
+        assemble_words(
+            &rv,
+            "ori a0, a0, 0x10
+xor t0, t0, t1
+xor t1, t0, t1
+xor t0, t0, t1
+\
+             lui a1, %hi(0x12345)
+lw a1, %lo(0x12345)(a1)
+"
+        )
+    );
+    // a macro of the source wins over the spec
+    assert_eq!(
+        assemble_words(
+            &rv,
+            ".macro bitset rd, imm\nxori \\rd, \\rd, \\imm\n.endm\nbitset a0, 1\n"
+        ),
+        assemble_words(&rv, "xori a0, a0, 1\n")
+    );
+    assert!(matches!(
+        assemble_with(&rv, "bitset a0\n", &Default::default()),
+        Err(EmitError::InvalidArgumentCount(i)) if i == "bitset"
+    ));
+
+    assert!(matches!(
+        rv.load_single_cfg_string(&ext(
+            "[pseudo_instructions.add]\nargs = []\nexpansion = [\"nop\"]\n"
+        )),
+        Err(LoadError::DuplicateInstruction(i)) if i == "add"
+    ));
+    let broken = ext(
+        "[pseudo_instructions.broken]\nargs = [\"rd\"]\nexpansion = [\"nop\", \"addi rd, rd, )\"]\n",
+    );
+    assert_eq!(
+        load_rv32i()
+            .load_single_cfg_string(&broken)
+            .unwrap_err()
+            .to_string(),
+        "'pseudo_instructions.broken.expansion[1]' doesn't parse as assembly"
+    );
+}
+
+#[test]
+fn test_load_extension() {
+    use crate::arch::LoadError;
+    use crate::assemble::assemble_instruction;
+    let ext = |body: &str| {
+        format!(
+            "[meta]\nname = \"My extension\"\ncode = \"Xmyext\"\nspec = \"none\"\n{}",
+            body
+        )
+    };
+    let mut rv = load_rv32i();
+    // custom-0 opcode, with the R format of the base
+    rv.load_single_cfg_string(&ext(
+        "[instructions.\"myext.foo\"]\nformat = \"R\"\nargs = [\"rd\", \"rs1\", \"rs2\"]\nfields = { opcode = 0b0001011, funct3 = 0, funct7 = 0 }\n",
+    ))
+    .unwrap();
+    assert_eq!(
+        assemble_instruction(&rv, "myext.foo a0, a1, a2").unwrap(),
+        0x00c5850bu32.to_le_bytes()
+    );
+
+    let add = ext("[instructions.add]\nformat = \"R\"\nargs = [\"rd\", \"rs1\", \"rs2\"]\nfields = { opcode = 0b0001011, funct3 = 1, funct7 = 0 }\n");
+    assert!(matches!(
+        rv.load_single_cfg_string(&add),
+        Err(LoadError::DuplicateInstruction(i)) if i == "add"
+    ));
+    let format = ext("[instruction_formats.R]\nimm = { type = \"value\", length = 32, encoding = [[31,0,0]] }\n[instructions.\"myext.word\"]\nformat = \"R\"\nargs = [\"imm\"]\nfields = {}\n");
+    assert!(matches!(
+        rv.load_single_cfg_string(&format),
+        Err(LoadError::DuplicateInstructionFormat(f)) if f == "R"
+    ));
+
+    let mut rv = load_rv32i();
+    rv.load_extension_string(&add, true).unwrap();
+    rv.load_extension_string(&format, true).unwrap();
+    assert_eq!(
+        assemble_instruction(&rv, "add a0, a1, a2").unwrap(),
+        0x00c5950bu32.to_le_bytes()
+    );
+    // the base keeps its R format, the extension uses its own
+    assert_eq!(
+        assemble_instruction(&rv, "sub a0, a1, a2").unwrap(),
+        0x40c58533u32.to_le_bytes()
+    );
+    assert_eq!(
+        assemble_instruction(&rv, "myext.word 0x12345678").unwrap(),
+        0x12345678u32.to_le_bytes()
+    );
+}
+
+#[test]
+fn test_compressed_auto_selection() {
+    use crate::emit::EmitOptions;
+    let mut rv = load_rv32i();
+    rv.load_single_cfg_file(std::path::Path::new("./cfg/c.toml"))
+        .expect("Parse error");
+    assert_eq!(rv.get_const("IALIGN"), Some(16));
+    let bin = |src: &str| assemble_with(&rv, src, &Default::default()).unwrap();
+    // c.addi a0, 4; c.swsp a0, 8(sp)
+    assert_eq!(
+        bin("addi a0, a0, 4\nsw a0, 8(sp)\n"),
+        [0x0511u16.to_le_bytes(), 0xc42au16.to_le_bytes()].concat()
+    );
+    assert_eq!(
+        bin("li a0, 5\nmv s0, a1\nret\nlw a1, 4(a0)\nlui a0, 0x1f000\nslli a0, a0, 3\n"),
+        bin(".option norvc\nc.li a0, 5\nc.mv s0, a1\nc.jr ra\nc.lw a1, 4(a0)\nc.lui a0, 0x1f000\nc.slli a0, 3\n")
+    );
+    // an immediate that doesn't fit keeps the 32-bit form, as the other registers do
+    let full = "addi a0, a0, 100\nlw a1, 4(t0)\nsw a0, 2(sp)\n";
+    assert_eq!(
+        bin(full),
+        assemble_with(&load_rv32i(), full, &Default::default()).unwrap()
+    );
+    assert_eq!(bin(".option norvc\naddi a0, a0, 4\n").len(), 4);
+    assert_eq!(
+        bin(".option push\n.option norvc\nnop\n.option pop\nnop\n").len(),
+        6
+    );
+    // instructions only need 16-bit alignment, padding gets compressed NOPs where needed
+    assert_eq!(bin(".byte 1\nnop\n"), [1, 0, 0x01, 0x00]);
+    assert_eq!(
+        bin("nop\n.balign 8\n"),
+        [0x01, 0x00, 0x01, 0x00, 0x13, 0x00, 0x00, 0x00]
+    );
+
+    // labels further ahead keep the full form, unless relaxation finds their final values
+    let forward = "beq a0, zero, end - $\nnop\nend:\n";
+    assert_eq!(bin(forward).len(), 6);
+    let relax = EmitOptions {
+        relax: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        assemble_with(&rv, forward, &relax).unwrap(),
+        bin(".option norvc\nc.beqz a0, 4\nc.nop\n")
+    );
+    let far = "beq a0, zero, end - $\n.space 0x100\nend:\n";
+    assert_eq!(assemble_with(&rv, far, &relax).unwrap().len(), 0x104);
+    // only compressing keeps the call sequence, which leaves the target out of c.j's reach
+    let compress_only = EmitOptions {
+        relax_compressed: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        assemble_with(&rv, forward, &compress_only).unwrap(),
+        bin(".option norvc\nc.beqz a0, 4\nc.nop\n")
+    );
+    let call = "j end - $\ncall end\n.space 2038\nend:\n";
+    // c.j and c.jal
+    assert_eq!(
+        assemble_with(&rv, call, &relax).unwrap().len(),
+        2 + 2 + 2038
+    );
+    assert_eq!(
+        assemble_with(&rv, call, &compress_only).unwrap().len(),
+        4 + 8 + 2038
+    );
+}
+
+#[test]
+fn test_li_expansion() {
+    use crate::emit::{EmitOptions, LiExpansion};
+    let rv = load_rv32i();
+    let words = |src: &str| assemble_words(&rv, src);
+    assert_eq!(words("li a0, -5"), words("addi a0, zero, -5"));
+    assert_eq!(words("li a0, 0x12345000"), words("lui a0, 0x12345000"));
+    assert_eq!(
+        words("li a0, 0x12345fff"),
+        words("lui a0, 0x12346000\naddi a0, a0, -1")
+    );
+    assert_eq!(words("li a0, 0xffffffff"), words("addi a0, zero, -1"));
+    // unknown values get the fixed form, so the space reserved for them doesn't change
+    assert_eq!(
+        words("li a0, end\nend:"),
+        words("lui a0, 0\naddi a0, a0, 8")
+    );
+
+    let fixed = EmitOptions {
+        li_expansion: LiExpansion::Fixed,
+        ..EmitOptions::default()
+    };
+    assert_eq!(
+        assemble_with(&rv, "li a0, 1\n", &fixed).unwrap(),
+        assemble_with(&rv, "lui a0, 0\naddi a0, a0, 1\n", &fixed).unwrap()
+    );
+    assert!(assemble_with(&rv, "li a0, 0x100000000\n", &Default::default()).is_err());
+}
+
+#[test]
+fn test_rv64() {
+    use crate::emit::EmitError;
+    let mut rv = crate::arch::RiscVSpec::new();
+    rv.load_single_cfg_file(std::path::Path::new("./cfg/rv64i.toml"))
+        .expect("Parse error");
+    let words = |src: &str| assemble_words(&rv, src);
+    assert_eq!(
+        words("ld a0, 8(sp)\nsd a0, -8(sp)\naddiw a0, a0, -1\nslli a0, a0, 63\nsraw a0, a1, a2"),
+        vec![
+            0x0081_3503,
+            0xfea1_3c23,
+            0xfff5_051b,
+            0x03f5_1513,
+            0x40c5_d53b
+        ]
+    );
+    assert_eq!(
+        words("srai a0, a0, 40\nsraiw a0, a0, 31\nlwu a1, 4(a0)"),
+        vec![0x4285_5513, 0x41f5_551b, 0x0045_6583]
+    );
+    // the sum is sign-extended from 32 bits like the value of lui
+    assert_eq!(
+        words("li a0, 0x7fffffff"),
+        words("lui a0, 0x80000000\naddiw a0, a0, -1")
+    );
+    assert_eq!(
+        words("li a0, 0xffffffff"),
+        words("addi a0, zero, 1\nslli a0, a0, 32\naddi a0, a0, -1")
+    );
+    assert_eq!(
+        words("li a0, 0x123456789abcdef0"),
+        words(
+            "lui a0, 0x247000\naddiw a0, a0, -1875\nslli a0, a0, 14\naddi a0, a0, -947\n\
+             slli a0, a0, 12\naddi a0, a0, 1511\nslli a0, a0, 13\naddi a0, a0, -272"
+        )
+    );
+    assert_eq!(words("li a0, -1"), words("addi a0, zero, -1"));
+    // values further ahead take lui+addiw, which only load sign-extended 32-bit values
+    let li_error = |src: &str, options: &crate::emit::EmitOptions| {
+        let err = assemble_with(&rv, src, options).unwrap_err();
+        match err.kind() {
+            EmitError::ValueOutOfRange(insn, 1, value, _, _) => (insn.clone(), *value),
+            e => panic!("unexpected error {:?}", e),
+        }
+    };
+    assert_eq!(
+        words("li a0, X\n.equ X, 0x7ffff800\n"),
+        words("lui a0, 0x80000000\naddiw a0, a0, -2048")
+    );
+    assert_eq!(
+        li_error("li a0, X\n.equ X, 0x80000000\n", &Default::default()),
+        ("li".to_owned(), 0x8000_0000)
+    );
+    assert_eq!(
+        li_error("li a0, X\n.equ X, 0x123456789\n", &Default::default()),
+        ("li".to_owned(), 0x1_2345_6789)
+    );
+    let fixed = crate::emit::EmitOptions {
+        li_expansion: crate::emit::LiExpansion::Fixed,
+        ..Default::default()
+    };
+    assert_eq!(
+        li_error("li a0, 0x123456789\n", &fixed),
+        ("li".to_owned(), 0x1_2345_6789)
+    );
+
+    let rv32 = load_rv32i();
+    assert!(matches!(
+        assemble_with(&rv32, "ld a0, 0(sp)\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(_, _))
+    ));
+}
+
+#[test]
+fn test_m_extension() {
+    use crate::emit::EmitError;
+    let load = |arch: &str| {
+        let mut rv = crate::arch::RiscVSpec::new();
+        rv.load_arch_cfg(&[std::path::PathBuf::from("./cfg")], arch, false)
+            .expect("Parse error");
+        rv
+    };
+    let rv32 = load("RV32IM");
+    assert_eq!(
+        assemble_words(
+            &rv32,
+            "mul a0, a1, a2\nmulhsu a0, a1, a2\ndivu t0, t1, t2\nremu a0, a0, a1"
+        ),
+        vec![0x02c5_8533, 0x02c5_a533, 0x0273_52b3, 0x02b5_7533]
+    );
+    assert!(matches!(
+        assemble_with(&rv32, "mulw a0, a1, a2\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(_, _))
+    ));
+    // the M spec goes on top of either base
+    let rv64 = load("RV64IM");
+    assert_eq!(
+        assemble_words(&rv64, "mulw a0, a1, a2\nremuw a0, a1, a2\ndiv a0, a1, a2"),
+        vec![0x02c5_853b, 0x02c5_f53b, 0x02c5_c533]
+    );
+}
+
+#[test]
+fn test_a_extension() {
+    use crate::emit::EmitError;
+    let mut rv = crate::arch::RiscVSpec::new();
+    rv.load_arch_cfg(&[std::path::PathBuf::from("./cfg")], "RV32IA", false)
+        .expect("Parse error");
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "lr.w a0, (a1)\nsc.w.rl a2, a3, (a1)\namoadd.w.aq a0, a1, (a2)\n\
+             amoswap.w.aqrl t0, t1, 0(sp)\namomaxu.w zero, a1, (a0)"
+        ),
+        vec![
+            0x1005_a52f,
+            0x1ad5_a62f,
+            0x04b6_252f,
+            0x0e61_22af,
+            0xe0b5_202f
+        ]
+    );
+    // the address can't have an offset
+    assert!(assemble_with(&rv, "lr.w a0, 4(a1)\n", &Default::default()).is_err());
+    assert!(matches!(
+        assemble_with(&rv, "lr.d a0, (a1)\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(_, _))
+    ));
+    assert!(matches!(
+        assemble_with(&load_rv32i(), "amoor.w.aq a0, a1, (a2)\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(_, ext)) if ext == "A"
+    ));
+    let text = crate::disasm::render_disassembly(&rv, &0x04b6_252fu32.to_le_bytes(), 0);
+    assert!(text.contains("amoadd.w.aq a0, a1, 0(a2)"), "{}", text);
+}
+
+#[test]
+fn test_floating_point() {
+    use crate::emit::EmitError;
+    let mut rv = crate::arch::RiscVSpec::new();
+    rv.load_arch_cfg(&[std::path::PathBuf::from("./cfg")], "RV32IFD", false)
+        .expect("Parse error");
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "fadd.s ft0, ft1, ft2\nfadd.d f0, f1, f2, rtz\nfmadd.s ft1, ft2, ft3, ft4\n\
+             fsqrt.d fa0, fa1\nfcvt.w.s a0, fa0, rtz\nfcvt.d.w fa0, a0\nfcvt.s.d fa0, fa1\n\
+             fmv.x.w a0, fa0\nfeq.s a0, fa0, fa1\nflw fa0, 4(sp)\nfsd fs0, -8(sp)"
+        ),
+        vec![
+            0x0020_f053,
+            0x0220_9053,
+            0x2031_70c3,
+            0x5a05_f553,
+            0xc005_1553,
+            0xd205_0553,
+            0x4015_f553,
+            0xe005_0553,
+            0xa0b5_2553,
+            0x0041_2507,
+            0xfe81_3c27,
+        ]
+    );
+    // x and f registers don't mix
+    assert!(matches!(
+        assemble_with(&rv, "fadd.s fa0, a0, fa1\n", &Default::default()),
+        Err(EmitError::RegisterNotAllowed(_, 1))
+    ));
+    assert!(assemble_with(&rv, "add a0, fa0, a1\n", &Default::default()).is_err());
+    assert!(assemble_with(&rv, "fcvt.l.s a0, fa0\n", &Default::default()).is_err());
+
+    assert_eq!(
+        assemble_with(&rv, ".float 1.5, -2\n.double 0.1\n", &Default::default()).unwrap(),
+        [
+            &1.5f32.to_le_bytes()[..],
+            &(-2f32).to_le_bytes(),
+            &0.1f64.to_le_bytes(),
+        ]
+        .concat()
+    );
+    // rounded to single precision from the literal, not from the double nearest to it
+    assert_eq!(
+        assemble_with(&rv, ".float 1.00000005960464478\n", &Default::default()).unwrap(),
+        0x3f80_0001u32.to_le_bytes()
+    );
+
+    let text = crate::disasm::render_disassembly(&rv, &0xc005_1553u32.to_le_bytes(), 0);
+    assert!(text.contains("fcvt.w.s a0, fa0, rtz"), "{}", text);
+}
+
+#[test]
+fn test_system_instructions() {
+    let mut rv = crate::arch::RiscVSpec::new();
+    rv.load_arch_cfg(&[std::path::PathBuf::from("./cfg")], "RV32IZifencei", false)
+        .expect("Parse error");
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "fence\nfence rw, w\nfence i, o\nfence.tso\nfence.i\nmret\nsret\nwfi\n\
+             sfence.vma\nsfence.vma a0\nsfence.vma a0, a1\necall\nebreak"
+        ),
+        vec![
+            0x0ff0_000f,
+            0x0310_000f,
+            0x0840_000f,
+            0x8330_000f,
+            0x0000_100f,
+            0x3020_0073,
+            0x1020_0073,
+            0x1050_0073,
+            0x1200_0073,
+            0x1205_0073,
+            0x12b5_0073,
+            0x0000_0073,
+            0x0010_0073,
+        ]
+    );
+    assert!(assemble_with(&rv, "fence rx, w\n", &Default::default()).is_err());
+    assert!(assemble_with(&load_rv32i(), "fence.i\n", &Default::default()).is_err());
+}
+
+#[test]
+fn test_bit_manipulation() {
+    use crate::emit::EmitError;
+    let load = |arch: &str| {
+        let mut rv = crate::arch::RiscVSpec::new();
+        rv.load_arch_cfg(&[std::path::PathBuf::from("./cfg")], arch, false)
+            .expect("Parse error");
+        rv
+    };
+    let rv32 = load("RV32IZbaZbbZbcZbs");
+    assert_eq!(
+        assemble_words(
+            &rv32,
+            "sh1add a0, a1, a2\nandn a0, a1, a2\nclz a0, a1\ncpop a0, a1\nzext.h a0, a1\n\
+             rori a0, a1, 31\nrev8 a0, a1\nclmulh a0, a1, a2\nbseti a0, a1, 31"
+        ),
+        vec![
+            0x20c5_a533,
+            0x40c5_f533,
+            0x6005_9513,
+            0x6025_9513,
+            0x0805_c533,
+            0x61f5_d513,
+            0x6985_d513,
+            0x0ac5_b533,
+            0x29f5_9513,
+        ]
+    );
+    assert!(matches!(
+        assemble_with(&rv32, "rori a0, a1, 32\n", &Default::default()),
+        Err(EmitError::ValueOutOfRange(_, 2, 32, 0, 31))
+    ));
+    assert!(matches!(
+        assemble_with(&rv32, "clzw a0, a1\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(_, _))
+    ));
+    assert!(matches!(
+        assemble_with(&load_rv32i(), "andn a0, a1, a2\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(_, ext)) if ext == "Zbb"
+    ));
+
+    // the shift selectors, zext.h and rev8 are encoded differently on RV64
+    let rv64 = load("RV64IZbaZbbZbs");
+    assert_eq!(
+        assemble_words(
+            &rv64,
+            "slli.uw a0, a1, 40\nzext.h a0, a1\nroriw a0, a1, 31\nrori a0, a1, 63\n\
+             rev8 a0, a1\nbseti a0, a1, 63"
+        ),
+        vec![
+            0x0a85_951b,
+            0x0805_c53b,
+            0x61f5_d51b,
+            0x63f5_d513,
+            0x6b85_d513,
+            0x2bf5_9513,
+        ]
+    );
+}
+
+#[test]
+fn test_vector() {
+    use crate::emit::EmitError;
+    let load = |arch: &str| {
+        let mut rv = crate::arch::RiscVSpec::new();
+        rv.load_arch_cfg(&[std::path::PathBuf::from("./cfg")], arch, false)
+            .expect("Parse error");
+        rv
+    };
+    let rv = load("RV32IFV");
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "vsetvli t0, a0, e32, m2, ta, ma\nvsetivli zero, 4, e8, mf2\nvle32.v v8, (a0)\n\
+             vse8.v v8, (a1), v0.t\nvlse64.v v4, (a0), a1\nvadd.vv v1, v2, v3\n\
+             vadd.vx v1, v2, a0, v0.t\nvsll.vi v1, v2, 31\nvmacc.vx v1, a0, v2\n\
+             vmerge.vim v1, v2, -1, v0\nvfadd.vf v1, v2, fa0\nvmv.x.s a0, v2"
+        ),
+        vec![
+            0x0d15_72d7,
+            0xc072_7057,
+            0x0205_6407,
+            0x0005_8427,
+            0x0ab5_7207,
+            0x0221_80d7,
+            0x0025_40d7,
+            0x962f_b0d7,
+            0xb625_60d7,
+            0x5c2f_b0d7,
+            0x0225_50d7,
+            0x4220_2557,
+        ]
+    );
+    // the mask, widening and narrowing, permutation, whole-register, fault-only-first, segment,
+    // extension, fixed-point, conversion and carry instructions
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "vmand.mm v1, v2, v3\nvwadd.vv v2, v4, v6\nvnsrl.wi v1, v2, 3\nvrgather.vi v1, v2, 7\n\
+             vcompress.vm v1, v2, v3\nvl1re32.v v1, (a0)\nvs1r.v v1, (a0)\nvlm.v v0, (a0)\n\
+             vle32ff.v v1, (a0), v0.t\nvzext.vf2 v1, v2\nvsaddu.vi v1, v2, -1\n\
+             vfcvt.x.f.v v1, v2\nvadc.vvm v1, v2, v3, v0\nvlseg2e32.v v2, (a0)"
+        ),
+        vec![
+            0x6621_a0d7,
+            0xc643_2157,
+            0xb221_b0d7,
+            0x3223_b0d7,
+            0x5e21_a0d7,
+            0x0285_6087,
+            0x0285_00a7,
+            0x02b5_0007,
+            0x0105_6087,
+            0x4a23_20d7,
+            0x822f_b0d7,
+            0x4a20_90d7,
+            0x4021_80d7,
+            0x2205_6107,
+        ]
+    );
+    // v, x and f registers don't mix
+    assert!(matches!(
+        assemble_with(&rv, "vadd.vv v1, a0, v3\n", &Default::default()),
+        Err(EmitError::RegisterNotAllowed(_, 1))
+    ));
+    assert!(assemble_with(&rv, "vfadd.vf v1, v2, a0\n", &Default::default()).is_err());
+    assert!(assemble_with(&rv, "vsetvli t0, a0, e7\n", &Default::default()).is_err());
+    assert!(matches!(
+        assemble_with(&rv, "vluxei64.v v1, (a0), v2\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(_, _))
+    ));
+    assert!(matches!(
+        assemble_with(&load_rv32i(), "vadd.vv v1, v2, v3\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(_, ext)) if ext == "V"
+    ));
+
+    // only masked instructions get the mask operand back
+    let bytes = assemble_with(
+        &rv,
+        "vadd.vv v1, v2, v3\nvadd.vx v1, v2, a0, v0.t\nvlse64.v v4, (a0), a1\n",
+        &Default::default(),
+    )
+    .unwrap();
+    let text = crate::disasm::render_disassembly(&rv, &bytes, 0);
+    let lines: Vec<&str> = text.lines().map(|l| &l[19..]).collect();
+    assert_eq!(
+        lines,
+        [
+            "vadd.vv v1, v2, v3",
+            "vadd.vx v1, v2, a0, v0.t",
+            "vlse64.v v4, 0(a0), a1"
+        ]
+    );
+}
+
+#[test]
+fn test_isa_string() {
+    use crate::arch::{arch_components, LoadError};
+    use crate::emit::EmitError;
+    let components = |arch: &str| arch_components(arch).expect("Invalid arch");
+    assert_eq!(
+        components("rv32imac_zicsr"),
+        ["RV32I", "M", "A", "C", "Zicsr"]
+    );
+    assert_eq!(
+        components("RV64G_Zba"),
+        ["RV64I", "M", "A", "F", "D", "Zicsr", "Zifencei", "Zba"]
+    );
+    assert_eq!(
+        components("rv32i2p1_m2p0_zicsr2p0"),
+        ["RV32I", "M", "Zicsr"]
+    );
+    // the spelling with capitalized extensions still works
+    assert_eq!(components("RV32IMZicsr"), ["RV32I", "M", "Zicsr"]);
+    for bad in ["rv32imac_", "rv32i_z", "rv32x", "arm"] {
+        assert!(
+            matches!(arch_components(bad), Err(LoadError::InvalidArchSpec)),
+            "{}",
+            bad
+        );
+    }
+
+    let mut rv = crate::arch::RiscVSpec::new();
+    rv.load_arch_cfg(
+        &[std::path::PathBuf::from("./cfg")],
+        "rv32imac_zicsr",
+        false,
+    )
+    .expect("Parse error");
+    assert_eq!(rv.get_const("IALIGN"), Some(16));
+    assert!(assemble_with(
+        &rv,
+        "mul a0, a1, a2\ncsrr a0, mstatus\n",
+        &Default::default()
+    )
+    .is_ok());
+    assert!(matches!(
+        assemble_with(&rv, "fadd.s fa0, fa1, fa2\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(_, ext)) if ext == "F"
+    ));
+
+    // the same specs built into the library, without reading the cfg directory
+    let mut builtin = crate::arch::RiscVSpec::new();
+    builtin.load_arch_builtin("rv32imac_zicsr").unwrap();
+    let src = "mul a0, a1, a2\ncsrr a0, mstatus\nc.addi a0, 1\n";
+    assert_eq!(
+        assemble_with(&builtin, src, &Default::default()).unwrap(),
+        assemble_with(&rv, src, &Default::default()).unwrap()
+    );
+    assert!(matches!(
+        crate::arch::RiscVSpec::new().load_arch_builtin("rv32iq"),
+        Err(LoadError::RequirementNotFound(name)) if name == "Q"
+    ));
+}
+
+#[test]
+#[cfg(feature = "capi")]
+fn test_c_interface() {
+    use crate::capi::*;
+    use std::ffi::{CStr, CString};
+
+    let source = CString::new("start:\nmul a0, a1, a2\nj start - $\n").unwrap();
+    let arch = CString::new("RV32IM").unwrap();
+    let options = RvasmOptions {
+        arch: arch.as_ptr(),
+        spec: std::ptr::null(),
+        pic: 0,
+        relax: 0,
+        warn_x0: 0,
+    };
+    let mut result = std::mem::MaybeUninit::<RvasmResult>::uninit();
+    unsafe {
+        assert_eq!(
+            rvasm_assemble(source.as_ptr(), &options, result.as_mut_ptr()),
+            RVASM_OK
+        );
+        let mut result = result.assume_init();
+        let bytes = std::slice::from_raw_parts(result.bytes, result.len);
+        assert_eq!(bytes, &[0x33, 0x85, 0xc5, 0x02, 0x6f, 0xf0, 0xdf, 0xff]);
+        let symbol = &*result.symbols;
+        assert_eq!(result.symbol_count, 1);
+        assert_eq!(
+            (CStr::from_ptr(symbol.name).to_str(), symbol.value),
+            (Ok("start"), 0)
+        );
+        let lines = std::slice::from_raw_parts(result.lines, result.line_count);
+        assert_eq!(
+            lines
+                .iter()
+                .map(|l| (l.address, l.line))
+                .collect::<Vec<_>>(),
+            vec![(0, 2), (4, 3)]
+        );
+        assert!(result.diagnostics.is_null());
+        rvasm_result_free(&mut result);
+        assert!(result.bytes.is_null() && result.symbols.is_null());
+        rvasm_result_free(&mut result);
+
+        // errors come back as diagnostics, without RV32IM's default options
+        let source = CString::new("mul a0, a1, a2\n").unwrap();
+        let mut result = std::mem::MaybeUninit::<RvasmResult>::uninit();
+        assert_eq!(
+            rvasm_assemble(source.as_ptr(), std::ptr::null(), result.as_mut_ptr()),
+            RVASM_ERROR
+        );
+        let mut result = result.assume_init();
+        assert_eq!((result.len, result.diagnostic_count), (0, 1));
+        let diagnostic = &*result.diagnostics;
+        assert_eq!(diagnostic.severity, 0);
+        assert!(CStr::from_ptr(diagnostic.message)
+            .to_str()
+            .unwrap()
+            .contains("mul"));
+        rvasm_result_free(&mut result);
+
+        let arch = CString::new("RV32IQ").unwrap();
+        let options = RvasmOptions {
+            arch: arch.as_ptr(),
+            ..options
+        };
+        let mut result = std::mem::MaybeUninit::<RvasmResult>::uninit();
+        assert_eq!(
+            rvasm_assemble(source.as_ptr(), &options, result.as_mut_ptr()),
+            RVASM_INVALID
+        );
+        rvasm_result_free(result.as_mut_ptr());
+    }
+}
+
+#[test]
+fn test_rvtable_directive() {
+    use crate::emit::EmitError;
+    use std::convert::TryInto;
+    let rv = load_rv32i();
+    let bin = assemble_with(
+        &rv,
+        "\
+start:
+addi a0, a0, 1
+table:
+.rvtable table, start, case1, case2
+case1:
+addi a0, a0, 2
+case2:
+",
+        &Default::default(),
+    )
+    .unwrap();
+    let entry = |i: usize| i32::from_le_bytes(bin[4 + 4 * i..8 + 4 * i].try_into().unwrap());
+    assert_eq!((entry(0), entry(1), entry(2)), (-4, 12, 16));
+    assert_eq!(bin.len(), 4 + 12 + 4);
+
+    assert!(matches!(
+        assemble_with(&rv, ".rvtable 0, 0x100000000\n", &Default::default()),
+        Err(EmitError::InvalidArgumentType(_, 1))
+    ));
+    assert!(matches!(
+        assemble_with(&rv, ".rvtable 0\n", &Default::default()),
+        Err(EmitError::InvalidArgumentCount(_))
+    ));
+}
+
+#[test]
+fn test_progress_diagnostics() {
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::{Diagnostic, EmitOptions};
+    use crate::parser::ast_from_str;
+
+    let rv = load_rv32i();
+    let src = "jal zero, end\n".to_owned() + &"addi a0, a0, 1\n".repeat(40) + "end:\n";
+    let ast = ast_from_str(&src, &rv).expect("Testcase parsing failed");
+    let infos = |verbosity| {
+        let options = EmitOptions {
+            verbosity,
+            progress_interval: 64,
+            ..EmitOptions::default()
+        };
+        let image = emit_image(&rv, &ast, &options).unwrap();
+        image
+            .diagnostics
+            .into_iter()
+            .filter_map(|d| match d {
+                Diagnostic::Info(msg) => Some(msg),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert!(infos(0).is_empty());
+    let progress = infos(1);
+    // 164 bytes cross the 64 byte interval twice, while the jump is still waiting for `end`
+    assert_eq!(progress.len(), 3);
+    assert!(progress[0].contains("64 bytes") && progress[0].contains("1 instructions waiting"));
+    assert!(progress[1].contains("128 bytes"));
+    assert!(progress[2].contains("164 bytes") && progress[2].contains("41 instructions"));
+    assert!(infos(2).iter().any(|m| m.starts_with("Resolution pass 1")));
+}
+
+#[test]
+fn test_elf_output() {
+    use crate::emit::elf::emit_elf;
+    use crate::parser::ast_from_str;
+    use std::convert::TryInto;
+
+    let rv = load_rv32i();
+    let src = "_start:\naddi a0, zero, 1\nloop:\njal zero, loop\n";
+    let ast = ast_from_str(src, &rv).expect("Testcase parsing failed");
+    let elf = emit_elf(&rv, &ast).unwrap();
+    let u16_at = |at: usize| u16::from_le_bytes(elf[at..at + 2].try_into().unwrap());
+    let u32_at = |at: usize| u32::from_le_bytes(elf[at..at + 4].try_into().unwrap());
+
+    assert_eq!(&elf[0..6], b"\x7fELF\x01\x01");
+    // an executable for RISC-V starting at _start, with one program header
+    assert_eq!((u16_at(16), u16_at(18)), (2, 243));
+    assert_eq!(u32_at(24), 0);
+    assert_eq!(u16_at(44), 1);
+    let text_offset = u32_at(52 + 4) as usize;
+    assert_eq!(
+        elf[text_offset..text_offset + 8],
+        assemble_with(&rv, src, &Default::default()).unwrap()[..]
+    );
+
+    // the labels end up in the string table
+    let (names, labels) = (b"\0_start\0", b"loop\0");
+    assert!(elf.windows(names.len()).any(|w| w == names));
+    assert!(elf.windows(labels.len()).any(|w| w == labels));
+
+    // without _start it's an object file
+    let ast = ast_from_str("addi a0, zero, 1\n", &rv).expect("Testcase parsing failed");
+    let elf = emit_elf(&rv, &ast).unwrap();
+    assert_eq!(u16::from_le_bytes([elf[16], elf[17]]), 1);
+
+    // a label where the section before ends is in the one starting there
+    let src = "nop\nnop\n.data\nmsg:\n.word 1\nend:\n";
+    let ast = ast_from_str(src, &rv).expect("Testcase parsing failed");
+    let elf = emit_elf(&rv, &ast).unwrap();
+    let u16_at = |at: usize| u16::from_le_bytes(elf[at..at + 2].try_into().unwrap()) as usize;
+    let u32_at = |at: usize| u32::from_le_bytes(elf[at..at + 4].try_into().unwrap()) as usize;
+    let header = |i: usize| u32_at(32) + i * 40;
+    let shstrtab = u32_at(header(u16_at(50)) + 16);
+    let section = |name: &[u8]| {
+        (0..u16_at(48))
+            .find(|i| elf[shstrtab + u32_at(header(*i))..].starts_with(name))
+            .unwrap()
+    };
+    let (symtab, strtab) = (section(b".symtab\0"), section(b".strtab\0"));
+    let (symbols, names) = (u32_at(header(symtab) + 16), u32_at(header(strtab) + 16));
+    let symbol = |name: &str| {
+        (0..u32_at(header(symtab) + 20) / 16)
+            .map(|i| symbols + i * 16)
+            .find(|at| elf[names + u32_at(*at)..].starts_with(format!("{}\0", name).as_bytes()))
+            .map(|at| (u16_at(at + 14), u32_at(at + 4)))
+            .unwrap()
+    };
+    let data = section(b".data\0");
+    assert_eq!(symbol("msg"), (data, 0));
+    assert_eq!(symbol("end"), (data, 4));
+}
+
+#[test]
+fn test_elf_debug_info() {
+    use crate::assemble::line_map;
+    use crate::emit::dwarf::{debug_line, DebugInfo, LineRow};
+    use crate::emit::elf::{Elf, ElfKind};
+    use crate::emit::{flatbin::emit_image, EmitOptions, OutputBackend};
+    use crate::parser::ast_from_str;
+    use std::convert::TryInto;
+
+    let rv = load_rv32i();
+    // the contents of the named sections of an ELF32 file
+    let sections = |elf: &[u8]| {
+        let u16_at = |at: usize| u16::from_le_bytes(elf[at..at + 2].try_into().unwrap()) as usize;
+        let u32_at = |at: usize| u32::from_le_bytes(elf[at..at + 4].try_into().unwrap()) as usize;
+        let header = |i: usize| u32_at(32) + i * 40;
+        let names = u32_at(header(u16_at(50)) + 16);
+        (0..u16_at(48))
+            .map(|i| {
+                let name = &elf[names + u32_at(header(i))..];
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap()];
+                let (offset, size) = (u32_at(header(i) + 16), u32_at(header(i) + 20));
+                (
+                    String::from_utf8(name.to_vec()).unwrap(),
+                    elf[offset..offset + size].to_vec(),
+                )
+            })
+            .collect::<std::collections::HashMap<_, _>>()
+    };
+    let debug_info = |src: &str, image: &crate::emit::AssembledImage| DebugInfo {
+        files: vec!["main.s".to_owned()],
+        directory: "/src".to_owned(),
+        producer: "rvasm".to_owned(),
+        rows: line_map(&[src], &image.instructions)
+            .iter()
+            .map(|e| LineRow {
+                address: e.address,
+                file: e.file,
+                line: e.line as u64,
+            })
+            .collect(),
+    };
+
+    let src = "_start:\naddi a0, zero, 1\n\nloop:\n  jal zero, loop\n.data\n.word 7\n";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap();
+    let debug = debug_info(src, &image);
+    let elf = Elf {
+        debug: Some(debug.clone()),
+        ..Elf::for_spec(&rv, ElfKind::Executable)
+    };
+    let found = sections(&elf.serialize(&image).unwrap());
+    // the code of .text at its final addresses, nothing left to relocate
+    assert_eq!(
+        debug.rows.iter().map(|r| r.line).collect::<Vec<_>>(),
+        vec![2, 5]
+    );
+    assert_eq!(
+        found[".debug_line"],
+        debug_line(&debug.files, &debug.rows, 8, 4)
+    );
+    assert!(!found.contains_key(".rela.debug_line"));
+    let info = &found[".debug_info"];
+    assert_eq!((&info[4..6], info[10]), (&[4, 0][..], 4));
+    let name = b"main.s\0/src\0rvasm\0";
+    assert!(info.windows(name.len()).any(|w| w == name));
+    assert!(found.contains_key(".debug_abbrev") && found.contains_key(".debug_ranges"));
+
+    // an object file has them relocated against the section symbol of .text
+    let options = EmitOptions {
+        external_symbols: true,
+        ..Default::default()
+    };
+    let src = "addi a0, zero, 1\ncall puts\n";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &options).unwrap();
+    let elf = Elf {
+        debug: Some(debug_info(src, &image)),
+        ..Elf::for_spec(&rv, ElfKind::Relocatable)
+    };
+    let found = sections(&elf.serialize(&image).unwrap());
+    let symtab = &found[".symtab"];
+    let relocated = |name: &str| {
+        found[name]
+            .chunks(12)
+            .map(|r| {
+                let info = u32::from_le_bytes(r[4..8].try_into().unwrap()) as usize;
+                let sym = &symtab[(info >> 8) * 16..(info >> 8) * 16 + 16];
+                // type and section of the symbol, type and addend of the relocation
+                (
+                    sym[12] & 0xf,
+                    u16::from_le_bytes([sym[14], sym[15]]),
+                    info & 0xff,
+                    u32::from_le_bytes(r[8..12].try_into().unwrap()),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(relocated(".rela.debug_line"), vec![(3, 1, 1, 0)]);
+    assert_eq!(
+        relocated(".rela.debug_ranges"),
+        vec![(3, 1, 1, 0), (3, 1, 1, 12)]
+    );
+    assert_eq!(relocated(".rela.debug_info").len(), 3);
+
+    // the line going back to the start of the second file, like an .include late in the first
+    let (first, second) = ("nop\n".repeat(9), "nop\n");
+    let src = format!("{}{}", first, second);
+    let image = emit_image(&rv, &ast_from_str(&src, &rv).unwrap(), &Default::default()).unwrap();
+    let debug = DebugInfo {
+        files: vec!["main.s".to_owned(), "inc.s".to_owned()],
+        rows: line_map(&[&first, second], &image.instructions)
+            .iter()
+            .map(|e| LineRow {
+                address: e.address,
+                file: e.file,
+                line: e.line as u64,
+            })
+            .collect(),
+        ..debug_info(&src, &image)
+    };
+    assert_eq!(
+        debug.rows.last(),
+        Some(&LineRow {
+            address: 36,
+            file: 1,
+            line: 1
+        })
+    );
+    let elf = Elf {
+        debug: Some(debug.clone()),
+        ..Elf::for_spec(&rv, ElfKind::Executable)
+    };
+    let found = sections(&elf.serialize(&image).unwrap());
+    assert_eq!(
+        found[".debug_line"],
+        debug_line(&debug.files, &debug.rows, 40, 4)
+    );
+}
+
+#[test]
+fn test_entry_directive() {
+    use crate::emit::elf::emit_elf;
+    use crate::emit::flatbin::emit_flat_binary_with_symbols;
+    use crate::emit::srec::{emit_srec, SrecAddrWidth};
+    use crate::emit::EmitError;
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let entry = |src: &str| {
+        emit_flat_binary_with_symbols(&rv, &ast_from_str(src, &rv).unwrap()).map(|b| b.entry)
+    };
+    let src = ".entry main\nhelper:\njalr zero, ra\n_start:\nmain:\njal ra, helper - $\n";
+    assert_eq!(entry(src).unwrap(), Some(4));
+    // _start is the entry by convention
+    assert_eq!(entry("nop\n_start:\nnop\n").unwrap(), Some(4));
+    assert_eq!(entry("nop\n").unwrap(), None);
+
+    // an executable starting at main, and the termination record of the S-records
+    let ast = ast_from_str(src, &rv).unwrap();
+    let elf = emit_elf(&rv, &ast).unwrap();
+    assert_eq!(u16::from_le_bytes([elf[16], elf[17]]), 2);
+    assert_eq!(u32::from_le_bytes([elf[24], elf[25], elf[26], elf[27]]), 4);
+    let srec = emit_srec(&rv, &ast, SrecAddrWidth::Bits16).unwrap();
+    assert_eq!(srec.lines().last(), Some("S9030004F8"));
+
+    assert!(matches!(
+        assemble_with(&rv, ".entry main\nnop\n", &Default::default()),
+        Err(EmitError::UnresolvedSymbol(name, 0, _)) if name == "main"
+    ));
+    assert!(matches!(
+        assemble_with(&rv, ".entry a\na:\nb:\n.entry b\n", &Default::default()),
+        Err(EmitError::DuplicateEntry(first, second)) if first == "a" && second == "b"
+    ));
+}
+
+#[test]
+fn test_external_symbol_relocations() {
+    use crate::emit::{flatbin::emit_image, EmitError, EmitOptions};
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let src = "\
+call puts
+lla a1, buf + 8
+jal ra, helper - $
+lui a2, %hi(table)
+lw a3, %lo(table)(a2)
+.word exit - 4
+";
+    let ast = ast_from_str(src, &rv).unwrap();
+    let options = EmitOptions {
+        external_symbols: true,
+        ..Default::default()
+    };
+    let image = emit_image(&rv, &ast, &options).unwrap();
+    let relocs: Vec<_> = image
+        .relocations
+        .iter()
+        .map(|r| (r.offset, r.kind.as_ref(), r.symbol.as_ref(), r.addend))
+        .collect();
+    assert_eq!(
+        relocs,
+        vec![
+            (0x00, "R_RISCV_CALL_PLT", "puts", 0),
+            (0x08, "R_RISCV_PCREL_HI20", "buf", 8),
+            (0x0c, "R_RISCV_PCREL_LO12_I", ".Lpcrel_hi0", 0),
+            (0x10, "R_RISCV_JAL", "helper", 0),
+            (0x14, "R_RISCV_HI20", "table", 0),
+            (0x18, "R_RISCV_LO12_I", "table", 0),
+            (0x1c, "R_RISCV_32", "exit", -4),
+        ]
+    );
+    // the linker fills in the fields left at zero
+    let words: Vec<u32> = image
+        .bytes
+        .chunks(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        .collect();
+    assert_eq!(
+        words,
+        vec![0x97, 0x80e7, 0x597, 0x58593, 0xef, 0x637, 0x62683, 0]
+    );
+    assert_eq!(image.symbols.get(".Lpcrel_hi0"), Some(&8));
+
+    // data too narrow for an address can't refer to them
+    assert!(matches!(
+        assemble_with(&rv, ".half exit\n", &options),
+        Err(EmitError::UnresolvedSymbol(name, 0, _)) if name == "exit"
+    ));
+    assert!(matches!(
+        assemble_with(&rv, "call puts\n", &Default::default()),
+        Err(EmitError::UnresolvedSymbol(name, 0, _)) if name == "puts"
+    ));
+
+    // written out %pcrel_hi/%pcrel_lo pairs, the low parts anywhere after their auipc
+    let src = "\
+here: auipc a0, %pcrel_hi(buf + 8)
+lw a1, %pcrel_lo(here)(a0)
+sw a1, %pcrel_lo(here)(a0)
+auipc a2, %pcrel_hi(buf + 0x801)
+";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &options).unwrap();
+    let relocs: Vec<_> = image
+        .relocations
+        .iter()
+        .map(|r| (r.offset, r.kind.as_ref(), r.symbol.as_ref(), r.addend))
+        .collect();
+    assert_eq!(
+        relocs,
+        vec![
+            (0x00, "R_RISCV_PCREL_HI20", "buf", 8),
+            (0x04, "R_RISCV_PCREL_LO12_I", ".Lpcrel_hi0", 0),
+            (0x08, "R_RISCV_PCREL_LO12_S", ".Lpcrel_hi0", 0),
+            (0x0c, "R_RISCV_PCREL_HI20", "buf", 0x801),
+        ]
+    );
+    assert!(image.section_relocations.is_empty());
+
+    // the source's own labels, where a linker placing the sections changes their offsets
+    let src = "\
+start:
+la a0, msg
+lui a1, %hi(msg)
+addi a1, a1, %lo(msg)
+call start
+j start - $
+.data
+msg:
+.word start, msg
+";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &options).unwrap();
+    assert!(image.relocations.is_empty());
+    let relocs: Vec<_> = image
+        .section_relocations
+        .iter()
+        .map(|r| (r.offset, r.kind.as_ref(), r.symbol.as_ref(), r.addend))
+        .collect();
+    assert_eq!(
+        relocs,
+        vec![
+            (0x00, "R_RISCV_PCREL_HI20", "msg", 0),
+            (0x04, "R_RISCV_PCREL_LO12_I", ".Lpcrel_hi0", 0),
+            (0x08, "R_RISCV_HI20", "msg", 0),
+            (0x0c, "R_RISCV_LO12_I", "msg", 0),
+            (0x20, "R_RISCV_32", "start", 0),
+            (0x24, "R_RISCV_32", "msg", 0),
+        ]
+    );
+    // and only the .Lpcrel_hi label of the auipc still relocated
+    assert_eq!(
+        image.symbols.keys().filter(|l| l.starts_with(".L")).count(),
+        1
+    );
+}
+
+#[test]
+fn test_linker_relax_relocations() {
+    use crate::emit::{flatbin::emit_image, EmitOptions};
+    use crate::parser::ast_from_str;
+    let rv = load_rv32i();
+    let src = "\
+start:
+call puts
+la a0, msg
+beq a0, a1, start - $
+j .Lout - $
+.align 3
+.Lout:
+ret
+msg: .word start
+";
+    let ast = ast_from_str(src, &rv).unwrap();
+    let options = EmitOptions {
+        external_symbols: true,
+        linker_relax: true,
+        ..Default::default()
+    };
+    let image = emit_image(&rv, &ast, &options).unwrap();
+    let relocs: Vec<_> = image
+        .relocations
+        .iter()
+        .map(|r| (r.offset, r.kind.as_ref(), r.symbol.as_ref(), r.addend))
+        .collect();
+    // the uses of labels of the source get relocated too, the scope-local .Lout through .Ltmp0
+    assert_eq!(
+        relocs,
+        vec![
+            (0x18, "R_RISCV_ALIGN", "", 4),
+            (0x00, "R_RISCV_CALL_PLT", "puts", 0),
+            (0x00, "R_RISCV_RELAX", "", 0),
+            (0x08, "R_RISCV_PCREL_HI20", "msg", 0),
+            (0x08, "R_RISCV_RELAX", "", 0),
+            (0x0c, "R_RISCV_PCREL_LO12_I", ".Lpcrel_hi0", 0),
+            (0x0c, "R_RISCV_RELAX", "", 0),
+            (0x10, "R_RISCV_BRANCH", "start", 0),
+            (0x14, "R_RISCV_JAL", ".Ltmp0", 0),
+            (0x20, "R_RISCV_32", "start", 0),
+        ]
+    );
+    assert_eq!(image.symbols.get(".Ltmp0"), Some(&0x1c));
+    // the offsets worked out here stay in place, with the most padding .align may need
+    let words: Vec<u32> = image
+        .bytes
+        .chunks(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        .collect();
+    assert_eq!(
+        words,
+        vec![0x97, 0x80e7, 0x517, 0x1850513, 0xfeb508e3, 0x0080006f, 0x13, 0x8067, 0]
+    );
+}
+
+#[test]
+fn test_data_directives() {
+    use crate::emit::EmitError;
+    let rv = load_rv32i();
+    let bin = assemble_with(
+        &rv,
+        "\
+table:
+.word label_a, label_b, 0x1234
+label_a:
+.byte 1, -1, 0xff
+label_b:
+.half 0xbeef, -2
+.dword 0x0123456789abcdef
+",
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        bin,
+        [
+            12, 0, 0, 0, 15, 0, 0, 0, 0x34, 0x12, 0, 0, 1, 0xff, 0xff, 0xef, 0xbe, 0xfe, 0xff,
+            0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01
+        ]
+    );
+
+    for (src, index, min, max) in [
+        (".byte 256\n", 0, -128, 255),
+        (".byte 1, -129\n", 1, -128, 255),
+        (".half 0x10000\n", 0, -0x8000, 0xffff),
+        (".word 0x100000000\n", 0, -0x8000_0000, 0xffff_ffff),
+    ] {
+        match assemble_with(&rv, src, &Default::default()) {
+            Err(EmitError::ValueOutOfRange(_, i, _, lo, hi)) => {
+                assert_eq!((i, lo, hi), (index, min, max), "{}", src)
+            }
+            r => panic!("{}: {:?}", src, r),
+        }
+    }
+    assert_eq!(
+        assemble_with(
+            &rv,
+            ".2byte 1, end\n.4byte -1\n.8byte end\nend:\n",
+            &Default::default()
+        )
+        .unwrap(),
+        assemble_with(
+            &rv,
+            ".half 1, end\n.word -1\n.dword end\nend:\n",
+            &Default::default()
+        )
+        .unwrap()
+    );
+    assert!(matches!(
+        assemble_with(&rv, ".word\n", &Default::default()),
+        Err(EmitError::InvalidArgumentCount(_))
+    ));
+}
+
+#[test]
+fn test_string_directives() {
+    use crate::emit::EmitError;
+    let rv = load_rv32i();
+    let bin = assemble_with(
+        &rv,
+        ".ascii \"hi\\n\"\n.asciz \"a\\tb\", \"\\\"q\\\"\\\\\\x41\\0\"\n.ascii \"; not a comment\"\n",
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(bin, b"hi\na\tb\0\"q\"\\A\0\0; not a comment".to_vec());
+    assert_eq!(
+        assemble_with(&rv, ".string \"ab\", \"\"\n", &Default::default()).unwrap(),
+        b"ab\0\0".to_vec()
+    );
+
+    for (src, seq) in [(".ascii \"\\q\"\n", "\\q"), (".asciz \"\\x4\"\n", "\\x4")] {
+        match assemble_with(&rv, src, &Default::default()) {
+            Err(EmitError::InvalidEscape(_, s)) => assert_eq!(s, seq),
+            other => panic!("{:?}", other),
+        }
+    }
+    assert!(matches!(
+        assemble_with(&rv, ".ascii 5\n", &Default::default()),
+        Err(EmitError::InvalidArgumentType(_, 0))
+    ));
+}
+
+#[test]
+fn test_align_directives() {
+    use crate::emit::EmitError;
+    let rv = load_rv32i();
+    let nop = assemble_with(&rv, "addi zero, zero, 0\n", &Default::default()).unwrap();
+    let assemble = |src| assemble_with(&rv, src, &Default::default()).unwrap();
+
+    // NOPs in text where a whole one fits, zeroes before it
+    let mut expected = vec![1, 0, 0, 0];
+    expected.extend_from_slice(&nop);
+    assert_eq!(assemble(".byte 1\n.balign 8\n"), expected);
+    assert_eq!(
+        assemble(".data\n.byte 1\n.align 3\n.byte 2\n"),
+        [1, 0, 0, 0, 0, 0, 0, 0, 2]
+    );
+    assert_eq!(
+        assemble(".byte 1\n.balign 4, 0xaa\n"),
+        [1, 0xaa, 0xaa, 0xaa]
+    );
+    // already aligned
+    assert_eq!(assemble(".word 7\n.align 2\n.balign 1\n"), [7, 0, 0, 0]);
+    // instructions are aligned by the same padding
+    let mut expected = vec![3, 0, 0, 0];
+    expected.extend_from_slice(&nop);
+    assert_eq!(assemble(".byte 3\naddi zero, zero, 0\n"), expected);
+    // .p2align takes a power like .align, and MAX skips aligning if it needs more padding
+    assert_eq!(
+        assemble(
+            ".data\n.byte 1\n.p2align 2, 0xee\n.byte 2\n.p2align 3, 0, 2\n.balign 2, 0xcc, 1\n"
+        ),
+        [1, 0xee, 0xee, 0xee, 2, 0xcc]
+    );
+
+    for src in [
+        ".balign 3\n",
+        ".p2align 40\n",
+        ".align 40\n",
+        ".balign 4, 256\n",
+        ".align fwd\nfwd:\n",
+    ] {
+        assert!(matches!(
+            assemble_with(&rv, src, &Default::default()),
+            Err(EmitError::InvalidArgumentType(_, _))
+        ));
+    }
+}
+
+#[test]
+fn test_space_directives() {
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::EmitError;
+    use crate::parser::ast_from_str;
+
+    let rv = load_rv32i();
+    let src = ".word 1\nbuf:\n.space 16\nafter:\n.skip 3, 0xee\n";
+    let ast = ast_from_str(src, &rv).expect("Testcase parsing failed");
+    let image = emit_image(&rv, &ast, &Default::default()).unwrap();
+    assert_eq!(image.symbols["after"] - image.symbols["buf"], 16);
+    assert_eq!(image.bytes.len(), 4 + 16 + 3);
+    assert!(image.bytes[4..20].iter().all(|b| *b == 0));
+    assert_eq!(image.bytes[20..], [0xee, 0xee, 0xee]);
+
+    assert!(matches!(
+        assemble_with(&rv, ".space end - . + 4\nend:\n", &Default::default()),
+        Err(EmitError::ResolutionDidNotConverge(_))
+    ));
+    assert!(matches!(
+        assemble_with(&rv, ".space 0x7fffffffffffffff\n", &Default::default()),
+        Err(EmitError::ImageTooLarge(_))
+    ));
+
+    assert_eq!(
+        assemble_with(
+            &rv,
+            ".equ N, 2\n.zero N + 1\n.fill N, 1, 0xab\n.fill N, 2, -2\n.fill 1, 3, 0x10203\n",
+            &Default::default()
+        )
+        .unwrap(),
+        [0, 0, 0, 0xab, 0xab, 0xfe, 0xff, 0xfe, 0xff, 3, 2, 1]
+    );
+    assert_eq!(
+        assemble_with(&rv, ".fill 3\n", &Default::default()).unwrap(),
+        [0, 0, 0]
+    );
+    assert!(matches!(
+        assemble_with(&rv, ".fill 1, 9, 0\n", &Default::default()),
+        Err(EmitError::InvalidArgumentType(_, 1))
+    ));
+    assert!(matches!(
+        assemble_with(&rv, ".fill 1, 1, 0x100\n", &Default::default()),
+        Err(EmitError::ValueOutOfRange(_, 2, 256, -128, 255))
+    ));
+    assert!(matches!(
+        assemble_with(&rv, ".zero 1, 2\n", &Default::default()),
+        Err(EmitError::InvalidArgumentCount(_))
+    ));
+    assert!(matches!(
+        assemble_with(&rv, ".fill 0x1000000000000, 8\n", &Default::default()),
+        Err(EmitError::ImageTooLarge(_))
+    ));
+}
+
+#[test]
+fn test_common_pseudo_instructions() {
+    let rv = load_rv32i();
+    assert_eq!(
+        assemble_words(&rv, "mv a0, a1\nnop\nret\nj . + 8\nj 4\n"),
+        assemble_words(
+            &rv,
+            "addi a0, a1, 0\naddi zero, zero, 0\njalr zero, ra, 0\njal zero, . + 8\njal zero, 4\n"
+        )
+    );
+    // both halves of call are relative to the auipc, also for labels further ahead
+    assert_eq!(
+        assemble_words(&rv, "call f\ncall t0, g\nf:\n.org 0x1010\ng:\n")[..4],
+        assemble_words(
+            &rv,
+            "auipc ra, 0\njalr ra, ra, 16\nauipc t0, 0x1000\njalr t0, t0, 8\n"
+        )[..]
+    );
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "tail f
+f:
+"
+        ),
+        assemble_words(
+            &rv,
+            "auipc t1, 0
+jalr zero, t1, 8
+"
+        )
+    );
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "jr t0\nnot a0, a1\nneg a0, a1\nseqz a0, a1\nsnez a0, a1\nsltz a0, a1\nsgtz a0, a1\n"
+        ),
+        assemble_words(
+            &rv,
+            "jalr zero, t0, 0\nxori a0, a1, -1\nsub a0, zero, a1\nsltiu a0, a1, 1\n\
+             sltu a0, zero, a1\nslt a0, a1, zero\nslt a0, zero, a1\n"
+        )
+    );
+    // branches comparing with zero and with their operands swapped, with labels in both directions
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "back:\nbeqz a0, back\nbnez a0, fwd\nblez a0, back\nbgez a0, fwd\nbltz a0, 8\n\
+             bgtz a0, fwd\nbgt a0, a1, back\nble a0, a1, fwd\nbgtu a0, a1, 4\nbleu a0, a1, fwd\nfwd:\n"
+        ),
+        assemble_words(
+            &rv,
+            "back:\nbeq a0, zero, back\nbne a0, zero, fwd\nbge zero, a0, back\nbge a0, zero, fwd\n\
+             blt a0, zero, 8\nblt zero, a0, fwd\nblt a1, a0, back\nbge a1, a0, fwd\nbltu a1, a0, 4\n\
+             bgeu a1, a0, fwd\nfwd:\n"
+        )
+    );
+    assert!(assemble_with(&rv, "beqz a0\n", &Default::default()).is_err());
+    assert!(assemble_with(&rv, "not a0, 1\n", &Default::default()).is_err());
+}
+
+#[test]
+fn test_relaxation() {
+    use crate::emit::EmitOptions;
+    let rv = load_rv32i();
+    let relax = EmitOptions {
+        relax: true,
+        ..Default::default()
+    };
+    let words = |src: &str, options: &EmitOptions| -> Vec<u32> {
+        assemble_with(&rv, src, options)
+            .unwrap()
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect()
+    };
+    // a call to a nearby label collapses to a jal, the code after it moves up
+    let near = "call f\ntail f\nla a0, f\nf:\nret\n";
+    assert_eq!(
+        words(near, &relax),
+        assemble_words(&rv, "jal ra, 12\njal zero, 8\naddi a0, zero, 12\nret\n")
+    );
+    assert_eq!(words(near, &Default::default()), assemble_words(&rv, near));
+    // a far one stays a pair
+    let far = "call f\n.org 0x100008\nf:\n";
+    assert_eq!(words(far, &relax)[..2], assemble_words(&rv, far)[..2]);
+    assert_eq!(
+        words(far, &relax)[..2],
+        assemble_words(&rv, "auipc ra, 0x100000\njalr ra, ra, 8\n")[..]
+    );
+    // only shortening the second call brings the first one in range
+    let chained = "call f\ncall g\ng:\n.space 0xffff0\nf:\n";
+    assert_eq!(
+        words(chained, &relax)[..2],
+        assemble_words(&rv, "jal ra, 0xffff8\njal ra, 4\n")[..]
+    );
+    // position-independent code can't use la's absolute form
+    let pic = EmitOptions {
+        pic: true,
+        ..relax.clone()
+    };
+    assert_eq!(
+        words("lla a0, f\ncall f\nf:\n", &pic),
+        assemble_words(&rv, "auipc a0, 0\naddi a0, a0, 12\njal ra, 4\n")
+    );
+}
+
+#[test]
+fn test_branch_relaxation() {
+    use crate::emit::{EmitError, EmitOptions};
+    let rv = load_rv32i();
+    let relax = EmitOptions {
+        relax_branches: true,
+        ..Default::default()
+    };
+    let words = |src: &str| -> Vec<u32> {
+        assemble_with(&rv, src, &relax)
+            .unwrap()
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect()
+    };
+    // the long form of the inner branch takes the outer one out of reach
+    let nested =
+        "beq a0, a1, end - $\n.space 0xff4\nblt a0, a1, far - $\nend:\n.space 0x1000\nfar:\n";
+    assert!(matches!(
+        assemble_with(&rv, nested, &Default::default()),
+        Err(EmitError::ValueOutOfRange(..))
+    ));
+    let nested = words(nested);
+    assert_eq!(
+        nested[..2],
+        assemble_words(&rv, "bne a0, a1, 8\njal zero, 0x1000\n")[..]
+    );
+    assert_eq!(
+        nested[0x3ff..0x401],
+        assemble_words(&rv, "bge a0, a1, 8\njal zero, 0x1004\n")[..]
+    );
+    // branches in reach and literal offsets stay as they are
+    assert_eq!(
+        words("beqz a0, end - $\nbne a0, a1, 8\nend:\n"),
+        assemble_words(&rv, "beq a0, zero, 8\nbne a0, a1, 8\n")
+    );
+
+    // jumps further than jal reaches go through auipc, into t1 if they don't link
+    let far = "start:\nnop\n.space 0x100000\njal zero, start - $\njal ra, start - $\n";
+    assert_eq!(
+        words(far)[0x40001..],
+        assemble_words(
+            &rv,
+            "auipc t1, 0xfff00000\njalr zero, t1, -4\nauipc ra, 0xfff00000\njalr ra, ra, -12\n"
+        )[..]
+    );
+}
+
+#[test]
+fn test_error_locations() {
+    use crate::assemble::{assemble, emit_error_json, line_column, render_emit_error};
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::EmitError;
+    use crate::parser::ast_from_str;
+
+    let rv = load_rv32i();
+    assert_eq!(line_column("ab\ncd", 4), (2, 2));
+    assert_eq!(
+        EmitError::InvalidInstruction("addii".to_owned(), None).to_string(),
+        "unknown instruction or macro 'addii'"
+    );
+
+    let result = assemble(
+        &rv,
+        "addi a0, a0, 1\n  addii a0, a0, 1\n",
+        &Default::default(),
+    );
+    assert_eq!(
+        format!("{:?}", result.diagnostics),
+        "[Error(\"2:3: error: unknown instruction or macro 'addii', did you mean 'addi'?\")]"
+    );
+
+    // a deferred jump failing once its target is known is reported where it was written
+    let src = "addi a0, a0, 1\njal zero, far - .\n.org 0x200000\nfar:\n";
+    let err = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap_err();
+    assert_eq!(err.location(), Some(15));
+    assert!(matches!(err.kind(), EmitError::ValueOutOfRange(..)));
+
+    // an unterminated conditional block is reported where it opens
+    let result = assemble(
+        &rv,
+        "nop\n.ifne 1\n.if 0\n.endif\nnop\n",
+        &Default::default(),
+    );
+    assert_eq!(
+        format!("{:?}", result.diagnostics),
+        "[Error(\"2:1: error: '.ifne' without a matching .if/.endif\")]"
+    );
+
+    // the rendered error points at the element in its line, without the comment
+    let src = "nop\n\n\n\n\n\n\n\n\nstart: addi a0, a0, 5000 ; too big\n";
+    let err = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap_err();
+    assert_eq!(
+        render_emit_error(&[("main.s", src)], &err),
+        "main.s:10:8: error: operand 3 of 'addi' is 5000, out of range [-2048, 2047]\n   |\n\
+         10 | start: addi a0, a0, 5000 ; too big\n   |        ^^^^^^^^^^^^^^^^^"
+    );
+    assert_eq!(
+        emit_error_json(&[("main.s", src)], &err),
+        "{\"code\": \"ValueOutOfRange\", \"severity\": \"error\", \"message\": \"operand 3 of 'addi' is 5000, \
+         out of range [-2048, 2047]\", \"file\": \"main.s\", \"line\": 10, \"column\": 8, \"length\": 17}"
+    );
+    let err = EmitError::InvalidInstruction("addii".to_owned(), None);
+    assert_eq!(
+        render_emit_error(&[("main.s", src)], &err),
+        "error: unknown instruction or macro 'addii'"
+    );
+    assert_eq!(
+        emit_error_json(&[("main.s", src)], &err),
+        "{\"code\": \"InvalidInstruction\", \"severity\": \"error\", \"message\": \"unknown instruction or \
+         macro 'addii'\", \"file\": null, \"line\": null, \"column\": null, \"length\": null}"
+    );
+
+    // parse errors point at the token the parser stopped at
+    let mut sources = Vec::new();
+    let src = "nop\nx: .word 1 +* 2\n".to_owned();
+    let err = crate::parser::resolve_includes(&rv, "main.s", src, &mut sources).unwrap_err();
+    let rendered = crate::assemble::render_include_error(&sources, &err);
+    assert!(rendered.starts_with("main.s:2:13: error: Parse error: expected one of "));
+    assert!(rendered.ends_with("\n  |\n2 | x: .word 1 +* 2\n  |             ^"));
+}
+
+#[test]
+fn test_error_recovery() {
+    use crate::assemble::assemble;
+    use crate::emit::{EmitError, EmitOptions};
+
+    let rv = load_rv32i();
+    let src =
+        "start:\naddii a0, a0, 1\nbeq a0, a1, nowhere - $\naddi a0, a0, 9999\n.byte 300\nnop\n";
+    let options = EmitOptions {
+        max_errors: 10,
+        ..Default::default()
+    };
+    let result = assemble(&rv, src, &options);
+    let messages: Vec<String> = result
+        .diagnostics
+        .iter()
+        .map(|d| format!("{:?}", d))
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            "Error(\"2:1: error: unknown instruction or macro 'addii', did you mean 'addi'?\")",
+            "Error(\"3:1: error: 'nowhere' is never defined (used at address 0x0)\")",
+            "Error(\"4:1: error: operand 3 of 'addi' is 9999, out of range [-2048, 2047]\")",
+            "Error(\"5:1: error: operand 1 of '.byte' is 300, out of range [-128, 255]\")",
+        ]
+    );
+
+    // stopping at the limit, and at errors leaving the blocks after them unknown
+    let limited = EmitOptions {
+        max_errors: 2,
+        ..Default::default()
+    };
+    match assemble_with(&rv, src, &limited) {
+        Err(EmitError::Multiple(errors)) => assert_eq!(errors.len(), 2),
+        r => panic!("{:?}", r),
+    }
+    match assemble_with(
+        &rv,
+        "addii a0, a0, 1\n.if missing\n.endif\nbogus\n",
+        &options,
+    ) {
+        Err(EmitError::Multiple(errors)) => assert_eq!(errors.len(), 2),
+        r => panic!("{:?}", r),
+    }
+    assert!(matches!(
+        assemble_with(&rv, "addii a0, a0, 1\nbogus\n", &Default::default())
+            .map_err(|e| e.into_kind()),
+        Err(EmitError::InvalidInstruction(..))
+    ));
+
+    // every line that doesn't parse is reported, not only the first
+    let result = assemble(
+        &rv,
+        "addi a0, a0, (\nnop\nlw a1, 4(\\\n)\n  j )\n",
+        &Default::default(),
+    );
+    let messages: Vec<String> = result
+        .diagnostics
+        .iter()
+        .map(|d| format!("{:?}", d))
+        .collect();
+    assert_eq!(messages.len(), 3, "{:?}", messages);
+    assert!(
+        messages[0].contains("Parse error: 1:15: expected"),
+        "{}",
+        messages[0]
+    );
+    assert!(messages[1].contains("Parse error: 4:"), "{}", messages[1]);
+    assert!(
+        messages[2].contains("Parse error: 5:5: expected"),
+        "{}",
+        messages[2]
+    );
+}
+
+#[test]
+fn test_unresolved_symbol() {
+    use crate::assemble::assemble;
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::EmitError;
+    use crate::parser::ast_from_str;
+
+    let rv = load_rv32i();
+    let src = "start:\naddi a0, a0, 1\njal zero, strat - .\n.word start, missing\n";
+    let err = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap_err();
+    // all of them in address order, located at the first use
+    match err.kind() {
+        EmitError::UnresolvedSymbols(symbols) => assert_eq!(
+            symbols,
+            &[
+                ("strat".to_owned(), 4, Some("start".to_owned())),
+                ("missing".to_owned(), 8, None)
+            ]
+        ),
+        e => panic!("{:?}", e),
+    }
+    assert_eq!(err.location(), Some(22));
+    assert!(err
+        .to_string()
+        .contains("'strat' (used at address 0x4, did you mean 'start'?), 'missing'"));
+
+    let result = assemble(&rv, ".word missing\n", &Default::default());
+    assert_eq!(
+        format!("{:?}", result.diagnostics),
+        "[Error(\"1:1: error: 'missing' is never defined (used at address 0x0)\")]"
+    );
+}
+
+#[test]
+fn test_ihex_output() {
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::ihex::to_ihex;
+    use crate::parser::ast_from_str;
+
+    let rv = load_rv32i();
+    let src =
+        "addi a0, a0, 1\n.org 0x20\n.ascii \"0123456789abcdefXYZ\"\n.org 0xfffc\n.word 1, 2, 3\n";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap();
+    let hex = to_ihex(&image).unwrap();
+    assert!(!hex.contains(":04000005"));
+
+    // decode it back, checking the checksums
+    let mut upper = 0;
+    let mut decoded = Vec::new();
+    for line in hex.lines() {
+        assert!(line.starts_with(':'));
+        let bytes: Vec<u8> = (1..line.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
+            .collect();
+        assert_eq!(bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b)), 0);
+        let (len, address, kind) = (
+            bytes[0] as usize,
+            u16::from_be_bytes([bytes[1], bytes[2]]),
+            bytes[3],
+        );
+        let data = &bytes[4..4 + len];
+        assert!(len <= 16);
+        match kind {
+            0 => decoded.push(((upper << 16) + address as usize, data.to_vec())),
+            1 => assert_eq!(line, ":00000001FF"),
+            4 => upper = u16::from_be_bytes([data[0], data[1]]) as usize,
+            _ => panic!("unexpected record {}", line),
+        }
+    }
+    assert_eq!(hex.lines().last(), Some(":00000001FF"));
+    // the gaps left by .org have no records
+    let covered: usize = decoded.iter().map(|(_, d)| d.len()).sum();
+    assert_eq!(covered, 4 + 19 + 12);
+    for (address, data) in decoded.iter() {
+        assert_eq!(&image.bytes[*address..address + data.len()], &data[..]);
+    }
+    // the words after 0xfffc are split at the 64K boundary
+    assert!(decoded.iter().any(|(a, d)| *a == 0xfffc && d.len() == 4));
+    assert!(decoded.iter().any(|(a, d)| *a == 0x10000 && d.len() == 8));
+
+    // the entry point goes into a start linear address record before the end
+    let src = "nop\n.entry main\nmain:\nnop\n";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap();
+    let hex = to_ihex(&image).unwrap();
+    let last: Vec<_> = hex.lines().rev().take(2).collect();
+    assert_eq!(last, vec![":00000001FF", ":0400000500000004F3"]);
+}
+
+#[test]
+fn test_srec_output() {
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::srec::{to_srec, SrecAddrWidth};
+    use crate::emit::EmitError;
+    use crate::parser::ast_from_str;
+
+    let rv = load_rv32i();
+    let src = "addi a0, a0, 1\n.org 0x20\n.ascii \"0123456789abcdefXYZ\"\n.org 0x10000\n_start:\n.word 1, 2, 3\n";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap();
+    assert_eq!(SrecAddrWidth::for_image(&image), SrecAddrWidth::Bits24);
+    assert!(matches!(
+        to_srec(&image, SrecAddrWidth::Bits16),
+        Err(EmitError::ImageTooLarge(0x1000c))
+    ));
+
+    for (width, data_kind, end_kind, address_bytes) in [
+        (SrecAddrWidth::Bits24, 2, 8, 3),
+        (SrecAddrWidth::Bits32, 3, 7, 4),
+    ] {
+        let srec = to_srec(&image, width).unwrap();
+        let lines: Vec<&str> = srec.lines().collect();
+        // decode it back, checking the byte counts and checksums
+        let mut decoded = Vec::new();
+        for line in lines.iter() {
+            assert!(line.starts_with('S'));
+            let kind = line[1..2].parse::<u8>().unwrap();
+            let bytes: Vec<u8> = (2..line.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
+                .collect();
+            assert_eq!(bytes[0] as usize, bytes.len() - 1);
+            assert_eq!(bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b)), 0xff);
+            let record_address_bytes = match kind {
+                0 | 5 => 2,
+                6 => 3,
+                _ => address_bytes,
+            };
+            let address = bytes[1..1 + record_address_bytes]
+                .iter()
+                .fold(0usize, |a, b| a << 8 | *b as usize);
+            let data = &bytes[1 + record_address_bytes..bytes.len() - 1];
+            match kind {
+                0 => assert_eq!(data, b"rvasm"),
+                k if k == data_kind => {
+                    assert!(data.len() <= 16);
+                    decoded.push((address, data.to_vec()));
+                }
+                5 => assert_eq!((address, data.len()), (decoded.len(), 0)),
+                // the entry is _start
+                k if k == end_kind => assert_eq!((address, data.len()), (0x10000, 0)),
+                _ => panic!("unexpected record {}", line),
+            }
+        }
+        assert!(lines[0].starts_with("S0"));
+        assert!(lines.last().unwrap().starts_with(&format!("S{}", end_kind)));
+        // the gaps left by .org have no records
+        let covered: usize = decoded.iter().map(|(_, d)| d.len()).sum();
+        assert_eq!(covered, 4 + 19 + 12);
+        for (address, data) in decoded.iter() {
+            assert_eq!(&image.bytes[*address..address + data.len()], &data[..]);
+        }
+    }
+    assert_eq!(
+        to_srec(
+            &emit_image(&rv, &ast_from_str("nop", &rv).unwrap(), &Default::default()).unwrap(),
+            SrecAddrWidth::Bits16
+        )
+        .unwrap(),
+        "S0080000727661736DCE\nS107000013000000E5\nS5030001FB\nS9030000FC\n"
+    );
+}
+
+#[test]
+fn test_verilog_hex_output() {
+    use crate::emit::verilog::emit_verilog_hex;
+    use crate::emit::EmitError;
+    use crate::parser::ast_from_str;
+
+    let rv = load_rv32i();
+    let hex = |src: &str, word_bytes| {
+        emit_verilog_hex(&rv, &ast_from_str(src, &rv).unwrap(), word_bytes)
+            .map_err(EmitError::into_kind)
+    };
+    assert_eq!(
+        hex(".word 0x12345678\n.byte 0xaa\n.org 0x20\n.half 0xbeef\n", 4).unwrap(),
+        "12345678\n000000aa\n@8\n0000beef\n"
+    );
+    assert_eq!(
+        hex(".org 8\n.word 0x12345678\n", 2).unwrap(),
+        "@4\n5678\n1234\n"
+    );
+    assert!(matches!(
+        hex(".org 2\n.word 1\n", 4),
+        Err(EmitError::UnalignedWord(2, 4))
+    ));
+}
+
+#[test]
+fn test_verilog_bin_output() {
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::verilog::to_verilog_bin;
+    use crate::parser::ast_from_str;
+
+    let rv = load_rv32i();
+    let image =
+        |src: &str| emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap();
+    assert_eq!(
+        to_verilog_bin(&image(".byte 0xa5, 0x0f\n.org 0x12\n.byte 1\n"), 1).unwrap(),
+        "10100101\n00001111\n@12\n00000001\n"
+    );
+    assert_eq!(
+        to_verilog_bin(&image(".org 0x10\n.dword 0x8000000000000001\n"), 8).unwrap(),
+        format!("@2\n1{}1\n", "0".repeat(62))
+    );
+}
+
+#[test]
+fn test_source_array_output() {
+    use crate::emit::array::{ArrayLanguage, SourceArray};
+    use crate::emit::{flatbin::emit_image, EmitError};
+    use crate::parser::ast_from_str;
+
+    let rv = load_rv32i();
+    let src = "addi a0, zero, 1\naddi a1, zero, 2\n.byte 7\n";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap();
+    assert_eq!(
+        SourceArray::new(ArrayLanguage::C).render(&image).unwrap(),
+        "#include <stdint.h>\n\nconst uint8_t program[9] = {\n    \
+         0x13, 0x05, 0x10, 0x00, 0x93, 0x05, 0x20, 0x00, 0x07,\n};\n"
+    );
+    // words of the image in memory order, the last one padded, with their addresses
+    let array = SourceArray {
+        name: "CODE".to_owned(),
+        word_bytes: 4,
+        per_line: 2,
+        address_comments: true,
+        ..SourceArray::new(ArrayLanguage::Rust)
+    };
+    assert_eq!(
+        array.render(&image).unwrap(),
+        "pub static CODE: [u32; 3] = [\n    /* 0x00000000 */ 0x00100513, 0x00200593,\n    \
+         /* 0x00000008 */ 0x00000007,\n];\n"
+    );
+
+    // names that wouldn't compile, and C arrays without elements, are errors
+    for name in ["my prog", "1st", "", "a-b"] {
+        let array = SourceArray {
+            name: name.to_owned(),
+            ..SourceArray::new(ArrayLanguage::C)
+        };
+        assert!(matches!(
+            array.render(&image),
+            Err(EmitError::InvalidArrayName(n)) if n == name
+        ));
+    }
+    let empty = emit_image(&rv, &ast_from_str("", &rv).unwrap(), &Default::default()).unwrap();
+    assert!(matches!(
+        SourceArray::new(ArrayLanguage::C).render(&empty),
+        Err(EmitError::EmptyArray)
+    ));
+    assert_eq!(
+        SourceArray::new(ArrayLanguage::Rust)
+            .render(&empty)
+            .unwrap(),
+        "pub static PROGRAM: [u8; 0] = [\n];\n"
+    );
+}
+
+#[test]
+fn test_disassembler() {
+    use crate::disasm::{decode, render_disassembly};
+    use crate::emit::Operand;
+
+    let mut rv = load_rv32i();
+    rv.load_single_cfg_file(std::path::Path::new("./cfg/zicsr.toml"))
+        .expect("Parse error");
+    // the text of each instruction, without its address and encoding
+    let texts = |rv: &crate::arch::RiscVSpec, bytes: &[u8]| {
+        render_disassembly(rv, bytes, 0)
+            .lines()
+            .map(|l| l.splitn(3, ' ').nth(2).unwrap().trim().to_owned())
+            .collect::<Vec<String>>()
+    };
+    let src = "lui a0, 0x12345000\nauipc t0, 0\naddi a0, a0, -1\nlw a1, 8(sp)\nsw a1, -4(s0)\n\
+               beq a0, a1, -8\njal ra, 2048\njalr zero, ra, 0\nsrai t1, t2, 3\nsub a2, a3, a4\n\
+               fence rw, w\necall\nebreak\ncsrrw t0, mstatus, t1\ncsrrsi zero, 0x7ff, 4\n";
+    let bin = assemble_with(&rv, src, &Default::default()).unwrap();
+    let lines = texts(&rv, &bin);
+    assert_eq!(lines[0], "lui a0, 0x12345000");
+    assert_eq!(lines[2], "addi a0, a0, -1");
+    assert_eq!(lines[4], "sw a1, -4(fp)");
+    assert_eq!(lines[5], "beq a0, a1, -8");
+    assert_eq!(lines[10], "fence rw, w");
+    assert_eq!(lines[13], "csrrw t0, mstatus, t1");
+    assert_eq!(lines[14], "csrrsi zero, 0x7ff, 4");
+    assert_eq!(
+        assemble_with(&rv, &lines.join("\n"), &Default::default()).unwrap(),
+        bin
+    );
+    assert_eq!(
+        render_disassembly(&rv, &[0x13, 0x05, 0x15, 0x00, 0, 0, 0, 0, 0xff], 0x100),
+        "00000100: 00150513 addi a0, a0, 1\n00000104: 00000000 .word 0x00000000\n\
+         00000108: ff       .byte 0xff\n"
+    );
+
+    // the most specific of the compressed forms matching wins, like c.jr over c.mv
+    rv.load_single_cfg_file(std::path::Path::new("./cfg/c.toml"))
+        .expect("Parse error");
+    let (insn, operands) = decode(&rv, &0x8082u16.to_le_bytes()).unwrap();
+    assert_eq!(insn.name, "c.jr");
+    assert_eq!(operands, [Operand::Register(1)]);
+    // zeroed memory isn't a c.addi4spn but the instruction defined to be illegal
+    assert_eq!(decode(&rv, &[0, 0]).unwrap().0.name, "c.unimp");
+    assert_eq!(
+        assemble_with(&rv, "c.unimp\n", &Default::default()).unwrap(),
+        [0, 0]
+    );
+    let src = ".option norvc\nc.addi a0, 1\nc.mv a0, a1\nc.addi16sp sp, -32\nc.lui a1, 0x1f000\n\
+               c.lw a0, 4(a1)\nc.lwsp a0, 8(sp)\nc.addi4spn s1, sp, 8\naddi a0, a0, 1\nc.ebreak\n";
+    let bin = assemble_with(&rv, src, &Default::default()).unwrap();
+    let lines = texts(&rv, &bin);
+    assert_eq!(lines[2], "c.addi16sp sp, -32");
+    assert_eq!(lines[4], "c.lw a0, 4(a1)");
+    assert_eq!(lines[7], "addi a0, a0, 1");
+    assert_eq!(
+        assemble_with(
+            &rv,
+            &format!(".option norvc\n{}", lines.join("\n")),
+            &Default::default()
+        )
+        .unwrap(),
+        bin
+    );
+}
+
+#[test]
+fn test_verify_encodings() {
+    use crate::arch::{FieldType, RiscVSpec};
+    use crate::disasm::instruction_text;
+    use crate::emit::{EmitError, EmitOptions, Operand};
+    let verify = EmitOptions {
+        verify_encodings: true,
+        ..Default::default()
+    };
+    // every instruction of the built-in specs, with operands spread over their ranges
+    for arch in [
+        "rv32imafdc_zicsr_zifencei",
+        "rv32iv_zba_zbb_zbc_zbs",
+        "rv64iv_zba_zbb",
+    ] {
+        let mut rv = RiscVSpec::new();
+        rv.load_arch_builtin(arch).unwrap();
+        for seed in 0..3u64 {
+            for insn in rv.get_all_instructions() {
+                let fmt = insn.get_format(&rv);
+                let operands: Vec<Operand> = insn
+                    .args
+                    .iter()
+                    .enumerate()
+                    .map(|(i, argid)| {
+                        let field = &fmt.fields[*argid];
+                        let pick = seed.wrapping_mul(2654435761) + i as u64 * 97 + 13;
+                        match field.vtype {
+                            FieldType::Register => {
+                                let registers = field
+                                    .allowed_registers
+                                    .clone()
+                                    .unwrap_or_else(|| (0..32).collect());
+                                Operand::Register(registers[pick as usize % registers.len()])
+                            }
+                            FieldType::Value | FieldType::Csr => {
+                                let (min, max) = field.value_range();
+                                let align = field.value_alignment();
+                                let steps = (max - min) as u64 / align + 1;
+                                Operand::Value((min + (pick % steps * align) as i64) as u64)
+                            }
+                        }
+                    })
+                    .collect();
+                let src = format!(
+                    ".option norvc\n{}\n",
+                    instruction_text(&rv, insn, &operands)
+                );
+                let ast = match crate::parser::ast_from_str(&src, &rv) {
+                    Ok(ast) => ast,
+                    // operands the syntax can't write
+                    Err(_) => continue,
+                };
+                if let Err(e) = crate::emit::flatbin::emit_image(&rv, &ast, &verify) {
+                    let e = e.into_kind();
+                    assert!(
+                        !matches!(e, EmitError::EncodingMismatch(..)),
+                        "{}: {}",
+                        arch,
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    let ext = |format: &str| {
+        format!(
+            "[meta]\nname = \"Broken\"\ncode = \"Xbroken\"\nspec = \"none\"\n\
+             [instruction_formats.X]\nopcode = {{ type = \"value\", length = 7, encoding = [[6,0,0]] }}\n\
+             rd = {{ type = \"register\", length = 5, encoding = [[4,0,7]] }}\n{}\n\
+             [instructions.\"x.op\"]\nformat = \"X\"\nargs = [\"rd\", \"imm\"]\nfields = {{ opcode = 0b0001011 }}\n",
+            format
+        )
+    };
+    // bit 11 of the immediate isn't encoded, which only values using it notice
+    let mut rv = load_rv32i();
+    rv.load_single_cfg_string(&ext(
+        "imm = { type = \"value\", length = 12, signed = true, encoding = [[10,0,20]] }",
+    ))
+    .unwrap();
+    assert!(assemble_with(&rv, "x.op a0, 5\nx.op a0, -1\n", &Default::default()).is_ok());
+    assert!(assemble_with(&rv, "x.op a0, 5\n", &verify).is_ok());
+    match assemble_with(&rv, "x.op a0, -1\n", &verify) {
+        Err(EmitError::EncodingMismatch(insn, written, decoded)) => {
+            assert_eq!(insn, "x.op");
+            assert_eq!(written, "x.op a0, -1");
+            assert_eq!(decoded, "x.op a0, 2047");
+        }
+        other => panic!("{:?}", other),
+    }
+    // fixed fields copied from another instruction without changing them
+    let mut rv = load_rv32i();
+    rv.load_single_cfg_string(
+        "[meta]\nname = \"Broken\"\ncode = \"Xbroken\"\nspec = \"none\"\n\
+         [instructions.\"x.sub\"]\nformat = \"R\"\nargs = [\"rd\", \"rs1\", \"rs2\"]\n\
+         fields = { opcode = 0b0110011, funct3 = 0, funct7 = 0 }\n",
+    )
+    .unwrap();
+    assert!(assemble_with(&rv, "add a0, a1, a2\n", &verify).is_ok());
+    // .insn writes the fields of a format, whichever instruction they make
+    assert!(assemble_with(&rv, ".insn r 0x33, 0, 0, a0, a1, a2\n", &verify).is_ok());
+    let e = assemble_with(&rv, "x.sub a0, a1, a2\n", &verify).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "'x.sub' encoded as `x.sub a0, a1, a2` decodes as `add a0, a1, a2`, the spec's encoding of it is wrong"
+    );
+}
+
+#[test]
+fn test_endianness() {
+    use crate::emit::{EmitOptions, Endianness};
+    let rv = load_rv32i();
+    let src =
+        ".half 0x1234\n.byte 0x56\n.ascii \"ab\"\n.balign 4\naddi a0, a0, 1\n.word 0x12345678\n";
+    let little = assemble_with(&rv, src, &Default::default()).unwrap();
+    let big_data = EmitOptions {
+        data_endianness: Endianness::Big,
+        ..Default::default()
+    };
+    let big_all = EmitOptions {
+        instruction_endianness: Endianness::Big,
+        ..big_data.clone()
+    };
+    let mut insn = little[8..12].to_vec();
+
+    // data words are swapped, bytes and strings aren't, instructions stay little endian
+    let data = assemble_with(&rv, src, &big_data).unwrap();
+    assert_eq!(data[..6], [0x12, 0x34, 0x56, b'a', b'b', 0]);
+    assert_eq!(data[8..12], insn[..]);
+    assert_eq!(data[12..], [0x12, 0x34, 0x56, 0x78]);
+
+    let all = assemble_with(&rv, src, &big_all).unwrap();
+    insn.reverse();
+    assert_eq!(all[8..12], insn[..]);
+    assert_eq!(all[12..], data[12..]);
+}
+
+#[test]
+fn test_abi_register_names() {
+    use crate::emit::EmitError;
+
+    let rv = load_rv32i();
+    assert_eq!(
+        assemble_words(&rv, "addi sp, sp, -16\nmv a0, t0\naddi fp, s0, 1\n"),
+        assemble_words(&rv, "addi x2, x2, -16\naddi x10, x5, 0\naddi x8, x8, 1\n")
+    );
+    // names aren't folded to lower case, and unknown ones stay identifiers instead of x0
+    assert!(matches!(
+        assemble_with(&rv, "addi q9, a0, 1\n", &Default::default()),
+        Err(EmitError::UnresolvedSymbol(ref name, _, _)) if name == "q9"
+    ));
+    assert!(assemble_with(&rv, "addi SP, a0, 1\n", &Default::default()).is_err());
+}
+
+#[test]
+fn test_csr_names() {
+    use crate::emit::EmitError;
+
+    let mut rv = load_rv32i();
+    rv.load_single_cfg_file(std::path::Path::new("./cfg/zicsr.toml"))
+        .expect("Parse error");
+    assert_eq!(rv.get_csr("mstatus"), Some(0x300));
+    assert_eq!(
+        assemble_words(&rv, "csrrw t0, mstatus, t1\n"),
+        vec![0x3003_12f3]
+    );
+    assert_eq!(
+        assemble_words(&rv, "csrrs a0, cycle, zero\ncsrrwi zero, mtvec, 4\n"),
+        assemble_words(&rv, "csrrs a0, 0xc00, zero\ncsrrwi zero, 0x305, 4\n")
+    );
+    // constants take precedence over CSR names
+    assert_eq!(
+        assemble_words(&rv, ".equ mepc, 0x7c0\ncsrrw zero, mepc, a0\n"),
+        assemble_words(&rv, "csrrw zero, 0x7c0, a0\n")
+    );
+    assert!(matches!(
+        assemble_with(&rv, "csrrw t0, mstatuss, t1\n", &Default::default()),
+        Err(EmitError::InvalidArgumentType(insn, 1)) if insn == "csrrw"
+    ));
+    assert!(matches!(
+        assemble_with(&rv, "csrrw t0, 0x1000, t1\n", &Default::default()),
+        Err(EmitError::ValueOutOfRange(_, 1, 0x1000, 0, 0xfff))
+    ));
+
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "csrr a0, mstatus\ncsrw mtvec, a0\ncsrs mie, t0\ncsrc mstatus, t1\n\
+             csrwi mscratch, 5\ncsrsi mstatus, 8\ncsrci mstatus, 0x1f\nrdcycle a0\nrdinstreth a1"
+        ),
+        assemble_words(
+            &rv,
+            "csrrs a0, mstatus, zero\ncsrrw zero, mtvec, a0\ncsrrs zero, mie, t0\n\
+             csrrc zero, mstatus, t1\ncsrrwi zero, mscratch, 5\ncsrrsi zero, mstatus, 8\n\
+             csrrci zero, mstatus, 0x1f\ncsrrs a0, cycle, zero\ncsrrs a1, instreth, zero"
+        )
+    );
+    assert!(matches!(
+        assemble_with(&load_rv32i(), "csrr a0, mstatus\n", &Default::default()),
+        Err(EmitError::ExtensionRequired(_, ext)) if ext == "Zicsr"
+    ));
+}
+
+#[test]
+fn test_operator_functions() {
+    use crate::emit::EmitError;
+    use crate::parser::ast_from_str;
+
+    let rv = load_rv32i();
+    // lui sets the upper bits and addi adds the sign-extended lower ones
+    let rebuild = |w: &[u32]| (w[0] & 0xffff_f000).wrapping_add(((w[1] as i32) >> 20) as u32);
+    for v in [
+        0u32,
+        0x7ff,
+        0x800,
+        0xfff,
+        0x1800,
+        0x1234_5678,
+        0x7fff_f800,
+        0x8000_0000,
+        0xffff_f800,
+        0xffff_ffff,
+    ] {
+        let src = format!("lui t0, %hi({0})\naddi t0, t0, %lo({0})\n", v);
+        assert_eq!(rebuild(&assemble_words(&rv, &src)), v, "{:#x}", v);
+    }
+    // forward references wait like the labels themselves
+    let words = assemble_words(
+        &rv,
+        "lui t0, %hi(msg)\naddi t0, t0, %lo(msg)\n.org 0x5ffc\nmsg:\n",
+    );
+    assert_eq!(rebuild(&words), 0x5ffc);
+    assert_eq!(
+        assemble_words(&rv, "lui a0, %hi(0x1800)\naddi a0, a0, %lo(0x1800)\n"),
+        assemble_words(&rv, "lui a0, 0x2000\naddi a0, a0, -0x800\n")
+    );
+
+    // %pcrel_lo refers to the auipc by its label, the offset is from the auipc
+    assert_eq!(
+        assemble_words(
+            &rv,
+            ".org 0x100\nhere: auipc a0, %pcrel_hi(msg)\naddi a0, a0, %pcrel_lo(here)\n.org 0x920\nmsg:\n"
+        )[0x40..0x42],
+        assemble_words(&rv, ".org 0x100\nauipc a0, 0x1000\naddi a0, a0, -0x7e0\n")[0x40..]
+    );
+    assert!(matches!(
+        assemble_with(&rv, "here: addi a0, a0, %pcrel_lo(here)\n", &Default::default()),
+        Err(EmitError::InvalidArgumentType(insn, 2)) if insn == "addi"
+    ));
+    assert!(ast_from_str("addi a0, a0, %low(4)\n", &rv).is_err());
+}
+
+#[test]
+fn test_operand_expressions() {
+    use crate::emit::EmitError;
+
+    let rv = load_rv32i();
+    let value = |expr: &str| assemble_words(&rv, &format!("addi a0, zero, {}\n", expr))[0] >> 20;
+    // C precedence, and the bitwise operators don't take the first half of && and ||
+    assert_eq!(value("1 + 2 * 3 % 4"), 3);
+    assert_eq!(value("1 << 2 + 1"), 8);
+    assert_eq!(value("1 | 2 ^ 3 & 6"), 1);
+    assert_eq!(value("6 & 3 == 3"), 0);
+    assert_eq!(value("(6 & 3) == 2"), 1);
+    assert_eq!(value("0 || 1 | 0"), 1);
+    assert_eq!(value("0 && 1 | 1"), 0);
+    assert_eq!(value("3 & 1 && 2"), 1);
+    assert_eq!(value("0x5a ^ 0xff"), 0xa5);
+    assert_eq!(value("0x10 | 3"), 0x13);
+    // unary operators bind to the operand right after them
+    assert_eq!(value("-2 + 3"), 1);
+    assert_eq!(value("~0x0f & 0xff"), 0xf0);
+    assert_eq!(value("-(1 << 2) + 5"), 1);
+    assert_eq!(value("~~1 + ((2))"), 3);
+    assert_eq!(value("'A' | 0x20"), u32::from(b'a'));
+    assert_eq!(
+        assemble_words(
+            &rv,
+            ".equ UART_BASE, 0x100\n.equ UART_THR, UART_BASE | 0x04\naddi a0, zero, UART_THR\n"
+        ),
+        assemble_words(&rv, "addi a0, zero, 0x104\n")
+    );
+
+    assert_eq!(
+        assemble_words(
+            &rv,
+            ".equ COUNT, 5\n.equ SIZE, COUNT*8\naddi a0, zero, SIZE\n"
+        ),
+        assemble_words(&rv, "addi a0, zero, 40\n")
+    );
+    // one side known and the other a label further ahead waits for the label
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "start:\naddi a0, zero, (end - start) * 2 + 1\nnop\nend:\n"
+        ),
+        assemble_words(&rv, "addi a0, zero, 17\naddi zero, zero, 0\n")
+    );
+    // and so do differences of labels that are both further ahead, in data too
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "li a0, (table_end - table) / 4\n.half end_msg - msg, 0\n\
+             msg: .ascii \"abc\"\n.byte 0\nend_msg:\ntable: .word 1, 2, 3\ntable_end:\n"
+        ),
+        assemble_words(
+            &rv,
+            "lui a0, 0\naddi a0, a0, 3\n.half 4, 0\n.word 0x636261, 1, 2, 3\n"
+        )
+    );
+    for expr in ["1 / (2 - 2)", "5 % 0"] {
+        assert!(matches!(
+            assemble_with(
+                &rv,
+                &format!("addi a0, zero, {}\n", expr),
+                &Default::default()
+            ),
+            Err(EmitError::InvalidArgumentType(_, 2))
+        ));
+    }
+
+    // offset(register) operands
+    assert_eq!(
+        assemble_words(
+            &rv,
+            ".equ offset, 8\nlw a0, offset+4(sp)\nsw a1, (sp)\n.insn s 0x23, 2, a0, 4(sp)\n"
+        ),
+        assemble_words(&rv, "lw a0, 12, sp\nsw a1, 0, sp\nsw a0, 4, sp\n")
+    );
+}
+
+#[test]
+fn test_incbin_directive() {
+    use crate::emit::flatbin::emit_image;
+    use crate::emit::EmitError;
+    use crate::parser::resolve_includes;
+
+    let rv = load_rv32i();
+    let dir = std::env::temp_dir().join(format!("rvasm-test-incbin-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    std::fs::write(dir.join("data/blob.bin"), [1, 2, 3, 4, 5]).unwrap();
+    let main = dir.join("main.s");
+    let assemble = |src: &str| {
+        std::fs::write(&main, src).unwrap();
+        let name = main.to_string_lossy();
+        let ast = resolve_includes(&rv, &name, src.to_owned(), &mut Vec::new()).unwrap();
+        emit_image(&rv, &ast, &Default::default())
+            .map(|image| image.bytes)
+            .map_err(EmitError::into_kind)
+    };
+    // the path starts from the directory of the source, like for .include
+    assert_eq!(
+        assemble(".byte 0xff\n.incbin \"data/blob.bin\", 1, 2\n.balign 4\nend: .incbin \"data/blob.bin\"\n")
+            .unwrap(),
+        [0xff, 2, 3, 0, 1, 2, 3, 4, 5]
+    );
+    assert_eq!(
+        assemble(".org 2\n.incbin \"data/blob.bin\", 5\n.byte 9\n").unwrap(),
+        [0, 0, 9]
+    );
+    assert!(matches!(
+        assemble(".incbin \"data/blob.bin\", 6\n"),
+        Err(EmitError::InvalidArgumentType(_, 1))
+    ));
+    assert!(matches!(
+        assemble(".incbin \"data/blob.bin\", 2, 4\n"),
+        Err(EmitError::InvalidArgumentType(_, 2))
+    ));
+    match assemble(".incbin \"data/missing.bin\"\n") {
+        Err(EmitError::IncbinRead(path, _)) => assert!(path.ends_with("missing.bin"), "{}", path),
+        r => panic!("Unexpected result {:?}", r),
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_include_directive() {
+    use crate::assemble::{assemble_files, LineMapEntry};
+    use crate::emit::{EmitError, EmitOptions};
+    use crate::parser::{resolve_includes, IncludeError};
+
+    let rv = load_rv32i();
+    let dir = std::env::temp_dir().join(format!("rvasm-test-include-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    let write = |name: &str, src: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, src).unwrap();
+        path
+    };
+    // relative to the including file, not the working directory
+    write("lib/constants.s", ".equ SIZE, 16\n.include \"macros.s\"\n");
+    write("lib/macros.s", "helper:\njalr zero, ra, 0\n");
+    let main = write(
+        "main.s",
+        "jal ra, helper\n.include \"lib/constants.s\"\naddi a0, zero, SIZE\naddi a0, a0, bad\n",
+    );
+
+    let result = assemble_files(&rv, std::slice::from_ref(&main), &Default::default());
+    let message = format!("{:?}", result.diagnostics);
+    assert!(
+        message.contains("main.s:4:1: error: 'bad' is never defined"),
+        "{}",
+        message
+    );
+    write(
+        "main.s",
+        "jal ra, helper\n.include \"lib/constants.s\"\naddi a0, zero, SIZE\n",
+    );
+    let result = assemble_files(&rv, std::slice::from_ref(&main), &Default::default());
+    assert!(result.succeeded(), "{:?}", result.diagnostics);
+    assert_eq!(
+        result.bytes,
+        assemble_with(
+            &rv,
+            "jal ra, helper\n.equ SIZE, 16\nhelper:\njalr zero, ra, 0\naddi a0, zero, SIZE\n",
+            &Default::default()
+        )
+        .unwrap()
+    );
+    // the included files come after the includer in the source list
+    assert_eq!(
+        result.line_map[1],
+        LineMapEntry {
+            address: 4,
+            file: 2,
+            line: 2,
+        }
+    );
+
+    // the include directories are searched after the directory of the includer, in order
+    std::fs::create_dir_all(dir.join("shared")).unwrap();
+    write("shared/regs.s", ".equ UART, 0x1000\n");
+    write("shared/first.s", ".equ UART, 0x2000\n");
+    write(
+        "lib/regs.s",
+        ".equ UART, 0x3000\n.incbin \"first.s\", 0, 1\n",
+    );
+    write("main.s", ".include \"regs.s\"\naddi a0, zero, UART >> 8\n");
+    let searching = EmitOptions {
+        include_dirs: vec![dir.join("lib"), dir.join("shared")],
+        ..Default::default()
+    };
+    let result = assemble_files(&rv, std::slice::from_ref(&main), &searching);
+    assert!(result.succeeded(), "{:?}", result.diagnostics);
+    assert_eq!(result.bytes, [b'.', 0, 0, 0, 0x13, 0x05, 0x00, 0x03]);
+    write("lib/first.s", "");
+    write("main.s", ".include \"first.s\"\naddi a0, zero, UART >> 8\n");
+    let result = assemble_files(&rv, std::slice::from_ref(&main), &searching);
+    let message = format!("{:?}", result.diagnostics);
+    assert!(message.contains("'UART' is never defined"), "{}", message);
+    assert!(!assemble_files(&rv, std::slice::from_ref(&main), &Default::default()).succeeded());
+
+    write(
+        "main.s",
+        "jal ra, helper\n.include \"lib/constants.s\"\naddi a0, zero, SIZE\n",
+    );
+    write("lib/macros.s", "ret\n.include \"constants.s\"\n");
+    let mut sources = Vec::new();
+    let source = std::fs::read_to_string(&main).unwrap();
+    let error = resolve_includes(&rv, &main.to_string_lossy(), source, &mut sources).unwrap_err();
+    assert!(matches!(error, IncludeError::Cycle(..)));
+    assert!(
+        error.to_string().contains("macros.s:2:1: error:"),
+        "{}",
+        error
+    );
+
+    let error = resolve_includes(
+        &rv,
+        "",
+        ".include \"missing.s\"\n".to_owned(),
+        &mut Vec::new(),
+    );
+    assert!(matches!(error, Err(IncludeError::Read(at, _, _)) if at == "1:1"));
+    assert!(matches!(
+        resolve_includes(&rv, "", ".include 4\n".to_owned(), &mut Vec::new()),
+        Err(IncludeError::InvalidInclude(_))
+    ));
+    // parse errors stay structured, at their line and column, reading the same on every run
+    let error = resolve_includes(
+        &rv,
+        "main.s",
+        "nop\nadd a0, (\n".to_owned(),
+        &mut Vec::new(),
+    )
+    .unwrap_err();
+    let parse = match &error {
+        IncludeError::Parse(name, errors) if name == "main.s" && errors.len() == 1 => {
+            errors[0].clone()
+        }
+        e => panic!("Unexpected error {:?}", e),
+    };
+    assert_eq!((parse.line, parse.column), (2, 10));
+    let message = error.to_string();
+    assert!(
+        message.starts_with("main.s:2:10: Parse error: expected "),
+        "{}",
+        message
+    );
+    for _ in 0..8 {
+        let again = resolve_includes(
+            &rv,
+            "main.s",
+            "nop\nadd a0, (\n".to_owned(),
+            &mut Vec::new(),
+        );
+        assert_eq!(again.unwrap_err().to_string(), message);
+    }
+    let json = crate::assemble::include_error_json(&error);
+    assert!(
+        json.starts_with(
+            "{\"code\": \"Parse\", \"severity\": \"error\", \"message\": \"Parse error: expected "
+        ),
+        "{}",
+        json
+    );
+    assert!(
+        json.ends_with("\"file\": \"main.s\", \"line\": 2, \"column\": 10, \"length\": null}"),
+        "{}",
+        json
+    );
+    // emitting without resolving
+    assert!(matches!(
+        assemble_with(&rv, ".include \"x.s\"\n", &Default::default()),
+        Err(EmitError::UnresolvedInclude)
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sections() {
+    use crate::emit::elf::{Elf, ElfKind};
+    use crate::emit::{flatbin::emit_image, EmitError, EmitOptions, OutputBackend};
+    use crate::parser::ast_from_str;
+    use std::convert::TryInto;
+    let rv = load_rv32i();
+    let src = "addi a0, zero, value\naddi a1, zero, buffer\n.data\nvalue: .word 7\n\
+               .bss\nbuffer: .space 16\n.text\nend: addi a2, zero, end\n";
+    let ast = ast_from_str(src, &rv).unwrap();
+    let image = emit_image(&rv, &ast, &Default::default()).unwrap();
+    // the code stays together, the data follows it and the .bss only has addresses
+    assert_eq!(image.symbols.get("value"), Some(&16));
+    assert_eq!(image.symbols.get("buffer"), Some(&24));
+    assert_eq!(image.symbols.get("end"), Some(&8));
+    let words: Vec<u32> = image
+        .bytes
+        .chunks(4)
+        .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+        .collect();
+    assert_eq!(words, vec![0x0100_0513, 0x0180_0593, 0x0080_0613, 0, 7]);
+    assert_eq!(image.written, vec![0..12, 16..20]);
+    let layout: Vec<_> = image
+        .sections
+        .iter()
+        .map(|s| (s.name.as_str(), s.base, s.size))
+        .collect();
+    assert_eq!(
+        layout,
+        vec![(".text", 0, 12), (".data", 16, 4), (".bss", 24, 16)]
+    );
+
+    let options = EmitOptions {
+        section_bases: vec![(".bss".to_owned(), 0x100), (".data".to_owned(), 0x40)],
+        ..Default::default()
+    };
+    let image = emit_image(&rv, &ast, &options).unwrap();
+    assert_eq!(image.symbols.get("value"), Some(&0x40));
+    assert_eq!(image.symbols.get("buffer"), Some(&0x100));
+    assert_eq!(image.bytes.len(), 0x44);
+
+    let ast = ast_from_str(".section .bss.stack\n.byte 0, 1\n", &rv).unwrap();
+    match emit_image(&rv, &ast, &Default::default()) {
+        Err(EmitError::NonZeroBss(name, 1)) => assert_eq!(name, ".bss.stack"),
+        r => panic!("Unexpected result {:?}", r.map(|i| i.bytes)),
+    }
+
+    // no file space for .bss in an ELF either
+    let ast = ast_from_str("addi a0, zero, 1\n.bss\n.space 0x10000\n", &rv).unwrap();
+    let image = emit_image(&rv, &ast, &Default::default()).unwrap();
+    let elf = Elf {
+        kind: ElfKind::Executable,
+        is_64: false,
+        flags: 0,
+        debug: None,
+    };
+    assert!(elf.serialize(&image).unwrap().len() < 0x1000);
+
+    let src = "nop\n.rodata\nmsg: .byte 1\n.data\ncounter: .word 0\n.rodata\n.byte 2\n";
+    let image = emit_image(&rv, &ast_from_str(src, &rv).unwrap(), &Default::default()).unwrap();
+    let layout: Vec<_> = image
+        .sections
+        .iter()
+        .map(|s| (s.name.as_str(), s.base, s.size))
+        .collect();
+    assert_eq!(
+        layout,
+        vec![(".text", 0, 4), (".rodata", 8, 2), (".data", 16, 4)]
+    );
+}
+
+#[test]
+fn test_macros() {
+    use crate::emit::EmitError;
+    let rv = load_rv32i();
+    let defs = ".macro addto dst, value\naddi \\dst, \\dst, \\value\n.wait:\nbeq \\dst, zero, .wait\n.endm\n";
+    assert_eq!(
+        assemble_words(&rv, &format!("{}start:\naddto a0, 4\naddto a1, 2 * -1\n", defs)),
+        assemble_words(
+            &rv,
+            "start:\naddi a0, a0, 4\n.1:\nbeq a0, zero, .1\naddi a1, a1, -2\n.2:\nbeq a1, zero, .2\n"
+        )
+    );
+
+    // nested calls, parameters in names and memory operands, spaces between parameters
+    let src = ".macro store width reg base\ns\\width \\reg, 4(\\base)\n.endm\n\
+               .macro save_all base\nstore w, a0, \\base\nstore h, a1, \\base\n.endm\n\
+               .macro w_label n\nlabel_\\n: .word label_\\n\n.endm\n\
+               save_all sp\nw_label 3\n";
+    assert_eq!(
+        assemble_words(&rv, src),
+        assemble_words(&rv, "sw a0, 4(sp)\nsh a1, 4(sp)\nlabel_3: .word 8\n")
+    );
+
+    // defaults for the parameters left out, and \@ numbering the expansions
+    let src = ".macro inc reg, by=1, base = zero\naddi \\reg, \\base, \\by\n.endm\n\
+               .macro spin\nspin\\@: j spin\\@\n.endm\n\
+               inc a0\ninc a1, 8\ninc a2, -2, a2\nspin\nspin\n.word spin4, spin5\n";
+    assert_eq!(
+        assemble_words(&rv, src),
+        assemble_words(
+            &rv,
+            "addi a0, zero, 1\naddi a1, zero, 8\naddi a2, a2, -2\nj 12\nj 16\n.word 12, 16\n"
+        )
+    );
+
+    let err = |src: &str| {
+        assemble_with(&rv, src, &Default::default())
+            .unwrap_err()
+            .into_kind()
+    };
+    assert!(matches!(err("save t0\n"), EmitError::InvalidInstruction(name, _) if name == "save"));
+    assert!(matches!(
+        err(".macro again\nagain\n.endm\nagain\n"),
+        EmitError::MacroTooDeep(_)
+    ));
+    assert!(matches!(
+        err(&format!("{}addto a0\n", defs)),
+        EmitError::InvalidArgumentCount(_)
+    ));
+    assert!(matches!(
+        err(&format!("{}addto a0, 1, 2\n", defs)),
+        EmitError::InvalidArgumentCount(_)
+    ));
+    assert!(matches!(
+        err(".macro m a\naddi \\b, zero, 1\n.endm\nm a0\n"),
+        EmitError::UnknownMacroParameter(_, param) if param == "\\b"
+    ));
+    assert!(matches!(
+        err(&format!("{}{}", defs, defs)),
+        EmitError::DuplicateMacro(_)
+    ));
+    assert!(matches!(err(".endm\n"), EmitError::UnbalancedMacro(_)));
+    assert!(matches!(
+        err(".macro m\nnop\n"),
+        EmitError::UnbalancedMacro(_)
+    ));
+}
+
+#[test]
+fn test_repetitions() {
+    use crate::emit::{EmitError, EmitOptions};
+    let rv = load_rv32i();
+    // local labels and \@ are unique to each repetition, registers are pasted in names by number
+    let src = ".equ N, 2\n.rept N + 1\n.wait: beq a0, zero, .wait\n.endr\n\
+               .irp reg, a1, t0\nentry_\\reg: addi \\reg, zero, 2 * 3\n.endr\n\
+               .rept 2\n.rept 2\n.byte 1\n.endr\n.endr\n.rept 2\n.word \\@\n.endr\n.rept 0\nbogus\n.endr\n\
+               .word entry_x5\n";
+    assert_eq!(
+        assemble_words(&rv, src),
+        assemble_words(
+            &rv,
+            ".1: beq a0, zero, .1\n.2: beq a0, zero, .2\n.3: beq a0, zero, .3\n\
+             addi a1, zero, 6\naddi t0, zero, 6\n.word 0x01010101, 12, 13\n.word 16\n"
+        )
+    );
+    // in a macro, with its parameters
+    let src = ".macro table n, value\n.rept \\n\n.byte \\value\n.endr\n.endm\ntable 3, 9\n";
+    assert_eq!(
+        assemble_with(&rv, src, &Default::default()).unwrap(),
+        [9, 9, 9]
+    );
+
+    let err = |src: &str| {
+        assemble_with(&rv, src, &Default::default())
+            .unwrap_err()
+            .into_kind()
+    };
+    assert!(matches!(err(".endr\n"), EmitError::UnbalancedMacro(_)));
+    assert!(matches!(
+        err(".rept 2\nnop\n"),
+        EmitError::UnbalancedMacro(d) if d == ".rept"
+    ));
+    assert!(matches!(
+        err(".rept -1\n.endr\n"),
+        EmitError::InvalidArgumentType(_, 0)
+    ));
+    assert_eq!(
+        assemble_with(
+            &rv,
+            ".rept count\n.byte 1\n.endr\n.equ count, 2\n",
+            &Default::default()
+        )
+        .unwrap(),
+        [1, 1]
+    );
+    assert!(matches!(
+        err(".rept missing\n.endr\n"),
+        EmitError::UnresolvedSymbol(name, 0, _) if name == "missing"
+    ));
+    assert!(matches!(
+        err(".irp 4, 1\n.endr\n"),
+        EmitError::InvalidArgumentType(_, 0)
+    ));
+    // counts the source controls can't make it allocate or loop for them
+    assert!(matches!(
+        err(".rept 0x1000000000000000\n.endr\nnop\n"),
+        EmitError::TooManyRepetitions(0x1000000000000000)
+    ));
+    let small = EmitOptions {
+        max_image_size: 16,
+        ..Default::default()
+    };
+    assert!(matches!(
+        assemble_with(&rv, ".rept 17\n.byte 1\n.endr\n", &small).map_err(|e| e.into_kind()),
+        Err(EmitError::TooManyRepetitions(17))
+    ));
+    assert_eq!(
+        assemble_with(
+            &rv,
+            ".rept 100000000\n.endr\n.byte 1\n",
+            &Default::default()
+        )
+        .unwrap(),
+        [1]
+    );
+}
+
+#[test]
+fn test_ifdef() {
+    use crate::emit::{EmitError, EmitOptions};
+    let rv = load_rv32i();
+    let addi = |v: u32| vec![0x0000_0513 | v << 20];
+    let src = ".ifdef DEBUG\naddi a0, zero, 1\n.else\naddi a0, zero, 2\n.endif\n";
+    assert_eq!(assemble_words(&rv, src), addi(2));
+    assert_eq!(
+        assemble_words(&rv, &format!(".equ DEBUG, 0\n{}", src)),
+        addi(1)
+    );
+    let debug = EmitOptions {
+        defsyms: vec![("DEBUG".to_owned(), 1)],
+        ..Default::default()
+    };
+    assert_eq!(
+        assemble_with(&rv, src, &debug).unwrap(),
+        0x0010_0513u32.to_le_bytes()
+    );
+    // constants of the spec and register aliases count, but not what is only defined further ahead
+    assert_eq!(
+        assemble_words(&rv, ".ifndef XLEN\naddi a0, zero, 1\n.endif\n.ifdef TMP\n.else\naddi a0, zero, 3\n.endif\n.equ TMP, t0\n"),
+        addi(3)
+    );
+    assert_eq!(
+        assemble_words(
+            &rv,
+            ".ifdef A\n.ifndef B\naddi a0, zero, 1\n.endif\n.else\naddi a0, zero, 2\n.endif\n"
+        ),
+        addi(2)
+    );
+
+    let opts = Default::default();
+    match assemble_with(&rv, ".ifdef DEBUG\n", &opts).map_err(|e| e.into_kind()) {
+        Err(EmitError::UnbalancedConditional(_)) => {}
+        r => panic!("Unexpected result {:?}", r),
+    }
+    assert!(assemble_with(&rv, ".ifndef DEBUG\n.endif\n.endif\n", &opts).is_err());
+    assert!(assemble_with(&rv, ".ifdef 1\n.endif\n", &opts).is_err());
+}
+
+#[test]
+fn test_listing() {
+    use crate::parser::ast_from_str;
+    use crate::report::emit_listing;
+    let rv = load_rv32i();
+    let src = ".equ STEP, 0x10\nstart: addi a0, a0, 1\n\njal zero, end\n.byte 1, 2, 3\n.org 0x10\nend: li a0, 0x12345\n";
+    let ast = ast_from_str(src, &rv).unwrap();
+    let listing = emit_listing(&rv, &ast, src, &Default::default()).unwrap();
+    let expected = [
+        "          = 0x10                   .equ STEP, 0x10",
+        "00000000: 00150513                 start: addi a0, a0, 1",
+        "",
+        "00000004: 0100006f                 jal zero, end",
+        "00000008: 01 02 03                 .byte 1, 2, 3",
+        "                                   .org 0x10",
+        "00000010: 00012537                 end: li a0, 0x12345",
+        "00000014: 34550513",
+    ];
+    assert_eq!(listing.lines().collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn test_set_redefinition() {
+    use crate::emit::EmitError;
+    let rv = load_rv32i();
+    let addi = |v: u32| 0x0000_0513 | v << 20;
+    assert_eq!(
+        assemble_words(
+            &rv,
+            ".set idx, 1\naddi a0, zero, idx\n.set idx, idx + 1\naddi a0, zero, idx\n.set idx, 7\n"
+        ),
+        vec![addi(1), addi(2)]
+    );
+    // an instruction waiting for a label keeps the value from before the next .set, and one
+    // using the name before any .set gets the first value
+    assert_eq!(
+        assemble_words(
+            &rv,
+            "addi a0, zero, first\n.set v, 3\n.set first, 4\naddi a0, zero, v + end - end\n.set v, 5\n.set first, 6\nend:\n"
+        ),
+        vec![addi(4), addi(3)]
+    );
+
+    let opts = Default::default();
+    for src in [
+        ".equ a, 1\n.equ a, 2\n",
+        ".set a, 1\n.equ a, 2\n",
+        ".equ a, 1\n.set a, 2\n",
+        "a:\n.set a, 1\n",
+    ] {
+        match assemble_with(&rv, src, &opts).map_err(|e| e.into_kind()) {
+            Err(EmitError::DuplicateConstant(name)) => assert_eq!(name, "a"),
+            r => panic!("Unexpected result {:?} for {:?}", r, src),
+        }
+    }
+}
+
+#[test]
+fn test_operand_range_and_alignment() {
+    use crate::emit::EmitError;
+    let rv = load_rv32i();
+    let err = |src: &str| {
+        assemble_with(&rv, src, &Default::default())
+            .unwrap_err()
+            .into_kind()
+    };
+    // 12 signed bits for loads, 13 for branches and 21 for jumps, both in multiples of 2
+    match err("lw a0, 2048(sp)\n") {
+        e @ EmitError::ValueOutOfRange(_, 1, 2048, -2048, 2047) => {
+            assert_eq!(
+                e.to_string(),
+                "operand 2 of 'lw' is 2048, out of range [-2048, 2047]"
+            )
+        }
+        e => panic!("Unexpected error {:?}", e),
+    }
+    match err("beq a0, a1, -4098\n") {
+        e @ EmitError::ValueOutOfRange(_, 2, -4098, -4096, 4094) => assert_eq!(
+            e.to_string(),
+            "operand 3 of 'beq' is -4098, out of range [-4096, 4094]"
+        ),
+        e => panic!("Unexpected error {:?}", e),
+    }
+    assert!(matches!(
+        err("jal zero, far - .\n.org 0x100000\nfar:\n"),
+        EmitError::ValueOutOfRange(_, 1, 0x100000, -0x100000, 0xffffe)
+    ));
+    match err("beq a0, a1, 3\n") {
+        e @ EmitError::ValueMisaligned(_, 2, 3, 2) => assert_eq!(
+            e.to_string(),
+            "operand 3 of 'beq' is 3, which isn't a multiple of 2"
+        ),
+        e => panic!("Unexpected error {:?}", e),
+    }
+    assert!(matches!(
+        err("jal ra, 0x101\n"),
+        EmitError::ValueMisaligned(_, 1, 0x101, 2)
+    ));
+    assert!(assemble_with(
+        &rv,
+        "beq a0, a1, -4096\njal zero, 0xffffe\n",
+        &Default::default()
+    )
+    .is_ok());
+}
+
+#[test]
+fn test_assembly_stats() {
+    use crate::arch::RiscVSpec;
+    use crate::emit::flatbin::emit_image;
+    use crate::parser::ast_from_str;
+    use crate::report::{render_stats, AssemblyStats, StatsFormat};
+    let mut rv = RiscVSpec::new();
+    rv.load_arch_builtin("rv32imc").unwrap();
+    let src = ".text\nc.addi a0, 1\nmul a1, a0, a0\n.align 4\nadd a1, a1, a2\n\
+               .insn r 0x33, 0, 0, a0, a1, a2\n.data\n.word 1, 2\n";
+    let ast = ast_from_str(src, &rv).unwrap();
+    let image = emit_image(&rv, &ast, &Default::default()).unwrap();
+    let stats = AssemblyStats::new(&rv, &image);
+    assert_eq!(stats.size, 32);
+    assert_eq!(
+        stats.sections,
+        vec![(".text".to_owned(), 22), (".data".to_owned(), 8)]
+    );
+    // c.addi and mul fill 6 bytes padded to 16, and .data starts at the next multiple of 8
+    assert_eq!(stats.padding, 10 + 2);
+    // the add compresses to c.add
+    assert_eq!((stats.compressed, stats.full_size), (2, 2));
+    let count =
+        |map: &std::collections::BTreeMap<String, usize>, name: &str| map.get(name).copied();
+    assert_eq!(count(&stats.mnemonics, "mul"), Some(1));
+    assert_eq!(count(&stats.mnemonics, "c.add"), Some(1));
+    assert_eq!(count(&stats.extensions, "C"), Some(2));
+    assert_eq!(count(&stats.extensions, "M"), Some(1));
+    assert_eq!(count(&stats.extensions, "other"), Some(1));
+    assert!(render_stats(&stats, StatsFormat::Text).starts_with("size: 32 bytes\n"));
+    let json = render_stats(&stats, StatsFormat::Json);
+    assert!(json.contains(
+        "\"sections\": [{\"name\": \".text\", \"size\": 22}, {\"name\": \".data\", \"size\": 8}]"
+    ));
+    assert!(json.contains("\"compressed\": 2,"));
+}
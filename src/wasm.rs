@@ -0,0 +1,110 @@
+//! JavaScript interface for assembling in the browser, like for an assembly playground. Built
+//! with `cargo rustc --release --target wasm32-unknown-unknown --no-default-features --features
+//! wasm --crate-type cdylib`, then `wasm-bindgen --target web` generates the bindings for it.
+
+use crate::arch::RiscVSpec;
+use crate::assemble::{diagnostic_json, emit_error_json, include_error_json, line_map};
+use crate::emit::{flatbin, Diagnostic, EmitOptions};
+use crate::parser::{self, IncludeError};
+use js_sys::{Array, Object, Reflect, Uint8Array, JSON};
+use wasm_bindgen::prelude::*;
+
+/// The field of the options object, none if it's missing or they aren't an object
+fn option(options: &JsValue, name: &str) -> Option<JsValue> {
+    if !options.is_object() {
+        return None;
+    }
+    Reflect::get(options, &JsValue::from_str(name))
+        .ok()
+        .filter(|value| !value.is_undefined() && !value.is_null())
+}
+
+fn flag(options: &JsValue, name: &str) -> bool {
+    option(options, name).is_some_and(|value| value.is_truthy())
+}
+
+fn set(object: &Object, name: &str, value: &JsValue) {
+    // setting a property of a plain object can't fail
+    Reflect::set(object, &JsValue::from_str(name), value).unwrap_throw();
+}
+
+/// The diagnostics as objects, parsed from their JSON (see emit_error_json)
+fn diagnostics(json: &[String]) -> Array {
+    json.iter()
+        .flat_map(|objects| objects.lines())
+        .map(|object| JSON::parse(object).unwrap_throw())
+        .collect()
+}
+
+fn load_spec(options: &JsValue) -> Result<RiscVSpec, String> {
+    let mut spec = RiscVSpec::new();
+    let arch = option(options, "arch").and_then(|a| a.as_string());
+    let arch = arch.as_deref().unwrap_or("RV32I");
+    spec.load_arch_builtin(arch)
+        .map_err(|e| format!("can't load architecture {}: {}", arch, e))?;
+    if let Some(extension) = option(options, "spec").and_then(|s| s.as_string()) {
+        spec.load_extension_string(&extension, false)
+            .map_err(|e| format!("can't load the spec: {}", e))?;
+    }
+    Ok(spec)
+}
+
+/// Assembles the source into an object with the `bytes` (a Uint8Array, empty if assembly
+/// failed), the `symbols` by name, the source `lines` of the instructions as
+/// `{address, line}` sorted by address, and the `diagnostics` as the objects of
+/// `--diagnostics json`, with their `code`, `severity`, `message`, `line`, `column` and
+/// `length`. The options can be left out or have the `arch` (like `rv32imc`, RV32I if none),
+/// a TOML `spec` to load on top, and the `pic`, `relax` and `warnX0` flags.
+#[wasm_bindgen]
+pub fn assemble(source: &str, options: JsValue) -> JsValue {
+    let result = Object::new();
+    let mut bytes = Vec::new();
+    let symbols = Object::new();
+    let lines = Array::new();
+    let json = match load_spec(&options) {
+        Err(message) => vec![diagnostic_json(None, &Diagnostic::Error(message))],
+        Ok(spec) => {
+            let emit_options = EmitOptions {
+                pic: flag(&options, "pic"),
+                relax: flag(&options, "relax"),
+                lint_x0_writes: flag(&options, "warnX0"),
+                ..EmitOptions::default()
+            };
+            match parser::ast_from_str_all(source, &spec) {
+                Err(errors) => vec![include_error_json(&IncludeError::Parse(
+                    String::new(),
+                    errors,
+                ))],
+                Ok(ast) => match flatbin::emit_image(&spec, &ast, &emit_options) {
+                    Err(e) => e
+                        .all()
+                        .iter()
+                        .map(|error| emit_error_json(&[("", source)], error))
+                        .collect(),
+                    Ok(image) => {
+                        for (name, value) in image.symbols.iter() {
+                            set(&symbols, name, &JsValue::from_f64(*value as f64));
+                        }
+                        for entry in line_map(&[source], &image.instructions) {
+                            let line = Object::new();
+                            set(&line, "address", &JsValue::from_f64(entry.address as f64));
+                            set(&line, "line", &JsValue::from_f64(entry.line as f64));
+                            lines.push(&line);
+                        }
+                        bytes = image.bytes;
+                        image
+                            .diagnostics
+                            .iter()
+                            .map(|d| diagnostic_json(None, d))
+                            .collect()
+                    }
+                },
+            }
+        }
+    };
+    set(&result, "bytes", &Uint8Array::from(&bytes[..]));
+    set(&result, "symbols", &symbols);
+    set(&result, "lines", &lines);
+    set(&result, "diagnostics", &diagnostics(&json));
+    result.into()
+}
@@ -0,0 +1,331 @@
+//! Reader for the subset of YAML that spec files need, giving the same tree as their TOML form:
+//! block mappings and sequences, flow `[...]` and `{...}` collections, quoted and plain strings,
+//! integers (also `0x`, `0o` and `0b` ones, with `_` separators), booleans and `#` comments.
+//! Anchors, tags, multi-line strings and multiple documents aren't supported.
+
+use toml::value::{Table, Value};
+
+/// A line with content, with the column it starts at and its number counting from 1
+struct Line {
+    indent: usize,
+    text: String,
+    number: usize,
+}
+
+/// Converts the document into a TOML tree, or gives the number of the line it can't read
+pub(crate) fn parse(content: &str) -> Result<Value, usize> {
+    let mut lines = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let body = strip_comment(line);
+        let text = body.trim_start();
+        if text.trim().is_empty() || text.trim() == "---" {
+            continue;
+        }
+        // indentation is done with spaces only
+        if body[..body.len() - text.len()].contains('\t') {
+            return Err(i + 1);
+        }
+        lines.push(Line {
+            indent: body.len() - text.len(),
+            text: text.trim_end().to_owned(),
+            number: i + 1,
+        });
+    }
+    if lines.is_empty() {
+        return Ok(Value::Table(Table::new()));
+    }
+    let mut pos = 0;
+    let indent = lines[0].indent;
+    let value = parse_block(&mut lines, &mut pos, indent)?;
+    match lines.get(pos) {
+        Some(line) => Err(line.number),
+        None => Ok(value),
+    }
+}
+
+/// The line up to a `#` starting a comment, which is one at the start or after a space outside
+/// of quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some('"'), '\\') => {
+                chars.next();
+            }
+            (Some(q), _) if c == q => quote = None,
+            (None, '#') if previous.is_whitespace() => return &line[..i],
+            _ => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+/// Block mapping or sequence of the lines at `indent`, starting with the one at `pos`
+fn parse_block(lines: &mut Vec<Line>, pos: &mut usize, indent: usize) -> Result<Value, usize> {
+    if is_sequence_item(&lines[*pos].text) {
+        parse_sequence(lines, pos, indent)
+    } else {
+        parse_mapping(lines, pos, indent)
+    }
+}
+
+fn is_sequence_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+fn parse_sequence(lines: &mut Vec<Line>, pos: &mut usize, indent: usize) -> Result<Value, usize> {
+    let mut items = Vec::new();
+    while let Some(line) = lines.get(*pos) {
+        if line.indent < indent {
+            break;
+        }
+        if line.indent > indent || !is_sequence_item(&line.text) {
+            return Err(line.number);
+        }
+        let number = line.number;
+        let rest = line.text[1..].trim_start().to_owned();
+        if rest.is_empty() {
+            *pos += 1;
+            items.push(parse_nested(lines, pos, indent)?);
+        } else if split_key(&rest).is_some() && !rest.starts_with(['[', '{']) {
+            // `- key: value` starts a mapping at the column of the key
+            let column = line.indent + line.text.len() - rest.len();
+            lines[*pos] = Line {
+                indent: column,
+                text: rest,
+                number,
+            };
+            items.push(parse_mapping(lines, pos, column)?);
+        } else {
+            items.push(parse_inline(&rest).ok_or(number)?);
+            *pos += 1;
+        }
+    }
+    Ok(Value::Array(items))
+}
+
+fn parse_mapping(lines: &mut Vec<Line>, pos: &mut usize, indent: usize) -> Result<Value, usize> {
+    let mut table = Table::new();
+    while let Some(line) = lines.get(*pos) {
+        if line.indent < indent {
+            break;
+        }
+        let number = line.number;
+        if line.indent > indent {
+            return Err(number);
+        }
+        let (key, rest) = split_key(&line.text).ok_or(number)?;
+        let value = if rest.is_empty() {
+            *pos += 1;
+            match lines.get(*pos) {
+                // a sequence can be at the indentation of its key
+                Some(next) if next.indent == indent && is_sequence_item(&next.text) => {
+                    parse_sequence(lines, pos, indent)?
+                }
+                _ => parse_nested(lines, pos, indent)?,
+            }
+        } else {
+            let value = parse_inline(&rest).ok_or(number)?;
+            *pos += 1;
+            value
+        };
+        if table.insert(key, value).is_some() {
+            return Err(number);
+        }
+    }
+    Ok(Value::Table(table))
+}
+
+/// Block indented under a line at `indent`, an empty mapping without one
+fn parse_nested(lines: &mut Vec<Line>, pos: &mut usize, indent: usize) -> Result<Value, usize> {
+    match lines.get(*pos) {
+        Some(next) if next.indent > indent => {
+            let nested = next.indent;
+            parse_block(lines, pos, nested)
+        }
+        _ => Ok(Value::Table(Table::new())),
+    }
+}
+
+/// Key and the rest of a `key: value` line
+fn split_key(text: &str) -> Option<(String, String)> {
+    let mut parser = Flow::new(text);
+    let key = if text.starts_with(['"', '\'']) {
+        parser.quoted()?
+    } else {
+        let end = text
+            .char_indices()
+            .find(|&(i, c)| c == ':' && matches!(text[i + 1..].chars().next(), None | Some(' ')))?
+            .0;
+        parser.pos = end;
+        text[..end].trim_end().to_owned()
+    };
+    parser.skip_spaces();
+    if !parser.eat(':') {
+        return None;
+    }
+    let rest = parser.rest();
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return None;
+    }
+    Some((key, rest.trim().to_owned()))
+}
+
+/// A value written on one line
+fn parse_inline(text: &str) -> Option<Value> {
+    let mut parser = Flow::new(text);
+    let value = match text.chars().next()? {
+        '[' | '{' | '"' | '\'' => parser.value()?,
+        _ => return Some(scalar(text)),
+    };
+    parser.skip_spaces();
+    parser.rest().is_empty().then_some(value)
+}
+
+/// Value of a plain (unquoted) scalar
+fn scalar(text: &str) -> Value {
+    let text = text.trim();
+    match text {
+        "true" => return Value::Boolean(true),
+        "false" => return Value::Boolean(false),
+        _ => {}
+    }
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let digits = digits.replace('_', "");
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x" | "0X") => (16, &digits[2..]),
+        Some("0o" | "0O") => (8, &digits[2..]),
+        Some("0b" | "0B") => (2, &digits[2..]),
+        _ => (10, &digits[..]),
+    };
+    match i64::from_str_radix(digits, radix) {
+        // from_str_radix takes a sign of its own
+        Ok(number) if !digits.starts_with(['+', '-']) => {
+            Value::Integer(if negative { -number } else { number })
+        }
+        _ => Value::String(text.to_owned()),
+    }
+}
+
+/// Parser of the values on one line
+struct Flow<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Flow<'a> {
+    fn new(text: &'a str) -> Self {
+        Flow { text, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_spaces(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.rest().starts_with(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        self.skip_spaces();
+        if self.eat('[') {
+            let mut items = Vec::new();
+            loop {
+                self.skip_spaces();
+                if self.eat(']') {
+                    return Some(Value::Array(items));
+                }
+                items.push(self.value()?);
+                self.skip_spaces();
+                if !self.eat(',') {
+                    self.skip_spaces();
+                    return self.eat(']').then_some(Value::Array(items));
+                }
+            }
+        } else if self.eat('{') {
+            let mut table = Table::new();
+            loop {
+                self.skip_spaces();
+                if self.eat('}') {
+                    return Some(Value::Table(table));
+                }
+                let key = match self.rest().chars().next()? {
+                    '"' | '\'' => self.quoted()?,
+                    _ => self.plain(true),
+                };
+                self.skip_spaces();
+                if !self.eat(':') || table.contains_key(&key) {
+                    return None;
+                }
+                let value = self.value()?;
+                table.insert(key, value);
+                self.skip_spaces();
+                if !self.eat(',') {
+                    self.skip_spaces();
+                    return self.eat('}').then_some(Value::Table(table));
+                }
+            }
+        } else if self.rest().starts_with(['"', '\'']) {
+            self.quoted().map(Value::String)
+        } else {
+            let text = self.plain(false);
+            (!text.is_empty()).then(|| scalar(&text))
+        }
+    }
+
+    /// Plain scalar in a flow collection, up to the next `,`, `]`, `}` (or `:` in a key)
+    fn plain(&mut self, key: bool) -> String {
+        let rest = self.rest();
+        let end = rest
+            .find(|c| matches!(c, ',' | ']' | '}') || (key && c == ':'))
+            .unwrap_or(rest.len());
+        self.pos += end;
+        rest[..end].trim().to_owned()
+    }
+
+    /// A double quoted string with its escapes, or a single quoted one where `''` is a quote
+    fn quoted(&mut self) -> Option<String> {
+        let quote = self.rest().chars().next()?;
+        self.pos += 1;
+        let mut out = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' if quote == '"' => out.push(match chars.next()?.1 {
+                    'n' => '\n',
+                    't' => '\t',
+                    '0' => '\0',
+                    c @ ('"' | '\\' | '/') => c,
+                    _ => return None,
+                }),
+                '\'' if quote == '\'' && self.rest()[i + 1..].starts_with('\'') => {
+                    out.push('\'');
+                    chars.next();
+                }
+                c if c == quote => {
+                    self.pos += i + 1;
+                    return Some(out);
+                }
+                c => out.push(c),
+            }
+        }
+        None
+    }
+}